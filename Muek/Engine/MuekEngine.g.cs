@@ -39,12 +39,21 @@ namespace Muek.Engine
         [DllImport(__DllName, EntryPoint = "init_vst_box", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
         internal static extern void init_vst_box();
 
+        /// <summary>
+        /// Returns the id of the new plugin instance, used to address its automation lanes.
+        /// </summary>
         [DllImport(__DllName, EntryPoint = "run_vst_instance_by_path", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
-        internal static extern void run_vst_instance_by_path(ushort* utf16_str, int utf16_len);
+        internal static extern uint run_vst_instance_by_path(ushort* utf16_str, int utf16_len);
 
+        /// <summary>
+        /// Returns the id of the new plugin instance, used to save and restore its state.
+        /// </summary>
         [DllImport(__DllName, EntryPoint = "run_vst_instance_by_path_with_handle", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
-        internal static extern void run_vst_instance_by_path_with_handle(ushort* utf16_str, int utf16_len, nuint hwnd);
+        internal static extern uint run_vst_instance_by_path_with_handle(ushort* utf16_str, int utf16_len, nuint hwnd);
 
+        /// <summary>
+        /// Probes the plugin in a separate process, so a broken plugin cannot crash the host.
+        /// </summary>
         [DllImport(__DllName, EntryPoint = "verify_vst_instance_by_path", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
         internal static extern ByteBuffer* verify_vst_instance_by_path(ushort* utf16_str, int utf16_len);
 
@@ -54,6 +63,12 @@ namespace Muek.Engine
         [DllImport(__DllName, EntryPoint = "sync_all_clips", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
         internal static extern void sync_all_clips(ClipProto* ptr, int len);
 
+        /// <summary>
+        /// Replaces the MIDI clips of every track, played to the track's inserts.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "sync_midi_clips", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void sync_midi_clips(MidiClipProto* ptr, int len);
+
         [DllImport(__DllName, EntryPoint = "spawn_audio_thread", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
         internal static extern void spawn_audio_thread();
 
@@ -70,6 +85,534 @@ namespace Muek.Engine
         [DllImport(__DllName, EntryPoint = "set_position_beat", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
         internal static extern void set_position_beat(float beat);
 
+        /// <summary>
+        /// 0 = off, 1 = read, 2 = write, 3 = touch, 4 = latch
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_automation_mode", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_automation_mode(int mode);
+
+        [DllImport(__DllName, EntryPoint = "get_automation_mode", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern int get_automation_mode();
+
+        /// <summary>
+        /// Returns the points of one lane as `AutomationPoint` structs.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_automation_lane", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_automation_lane(uint plugin_id, int param_index);
+
+        [DllImport(__DllName, EntryPoint = "set_automation_lane", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_automation_lane(uint plugin_id, int param_index, AutomationPoint* ptr, int len);
+
+        [DllImport(__DllName, EntryPoint = "clear_automation_lane", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void clear_automation_lane(uint plugin_id, int param_index);
+
+        /// <summary>
+        /// Serializes the plugin's chunk (or parameter values) for the project file.
+        /// Returns an empty buffer when the plugin is not loaded.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "save_plugin_state", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* save_plugin_state(uint plugin_id);
+
+        /// <summary>
+        /// Restores a state from `save_plugin_state`. If the instance is still being
+        /// created, the state is applied once it is initialized.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "load_plugin_state", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool load_plugin_state(uint plugin_id, byte* data_ptr, int len);
+
+        /// <summary>
+        /// Loads an `.fxp` or `.fxb` file into a plugin. Fails if the file was made by another plugin.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "load_fx_preset_file", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool load_fx_preset_file(uint plugin_id, ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Writes the current program (`as_bank = false`, `.fxp`) or all programs (`.fxb`).
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "save_fx_preset_file", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool save_fx_preset_file(uint plugin_id, ushort* utf16_str, int utf16_len, [MarshalAs(UnmanagedType.U1)] bool as_bank);
+
+        /// <summary>
+        /// Scans the `;` separated directories, updating the database at `db_path`.
+        /// Returns the database text: `plugin` and `blacklist` records, one per line,
+        /// fields separated by tabs.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "scan_plugins", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* scan_plugins(ushort* dirs_utf16, int dirs_len, ushort* db_utf16, int db_len);
+
+        [DllImport(__DllName, EntryPoint = "get_plugin_database", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_plugin_database(ushort* db_utf16, int db_len);
+
+        /// <summary>
+        /// Removes a plugin from the blacklist so the next scan probes it again.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "unblacklist_plugin", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool unblacklist_plugin(ushort* db_utf16, int db_len, ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Loads the plugin in a `muek_plugin_bridge` helper process. Loading happens in the
+        /// background; if it fails the id shows up in `take_crashed_plugins`.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "run_vst_instance_bridged", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern uint run_vst_instance_bridged(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Also removes the plugin from the insert chain it sits in.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "close_bridged_plugin", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void close_bridged_plugin(uint plugin_id);
+
+        /// <summary>
+        /// Ids (u32) of bridged plugins that crashed or failed to load since the last call.
+        /// Their inserts are bypassed.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "take_crashed_plugins", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* take_crashed_plugins();
+
+        /// <summary>
+        /// Appends a loaded plugin or built-in effect to the insert chain of a track.
+        /// Bridged plugins and effects move into the chain; in-process plugins keep
+        /// their editor window.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "add_track_insert", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool add_track_insert(ushort* utf16_str, int utf16_len, uint plugin_id);
+
+        /// <summary>
+        /// Takes the plugin out of its insert chain. A bridged plugin or built-in effect is closed.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "remove_track_insert", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool remove_track_insert(uint plugin_id);
+
+        /// <summary>
+        /// Closes a built-in effect that was created but never added to an insert chain.
+        /// Effects in a chain are closed with `remove_track_insert`.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "destroy_effect", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool destroy_effect(uint effect_id);
+
+        /// <summary>
+        /// Bus layout of a plugin from its reported input and output counts.
+        /// All zero when the plugin is not loaded.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_plugin_bus_layout", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern BusLayout get_plugin_bus_layout(uint plugin_id);
+
+        /// <summary>
+        /// Feeds a track from aux output bus `bus` (1 based) of a plugin in an insert chain,
+        /// e.g. one drum of a multi-output sampler. Fails if the routing would loop.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "route_plugin_output", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool route_plugin_output(uint plugin_id, int bus, ushort* utf16_str, int utf16_len);
+
+        [DllImport(__DllName, EntryPoint = "clear_track_source", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void clear_track_source(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Feeds the plugin's extra inputs from another track. An empty track id clears it.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_plugin_sidechain", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_plugin_sidechain(uint plugin_id, ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Plays the track's inserts with the MIDI a plugin emits, e.g. an arpeggiator
+        /// driving a synth on another track. Plugin id 0 disconnects.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_track_midi_source", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_track_midi_source(ushort* utf16_str, int utf16_len, uint plugin_id);
+
+        /// <summary>
+        /// Plays a MIDI message on the track's inserts at the next block, playing or not.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "send_track_midi", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool send_track_midi(ushort* utf16_str, int utf16_len, byte status, byte data1, byte data2);
+
+        /// <summary>
+        /// While armed, MIDI the plugin emits during playback is recorded.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "arm_plugin_midi_recording", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void arm_plugin_midi_recording(uint plugin_id, [MarshalAs(UnmanagedType.U1)] bool armed);
+
+        /// <summary>
+        /// MIDI recorded from the plugin since the last call, as `RecordedMidiEvent` structs.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "take_recorded_plugin_midi", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* take_recorded_plugin_midi(uint plugin_id);
+
+        /// <summary>
+        /// MIDI input ports, one per line as id, name and whether it is open, separated by tabs.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_midi_input_ports", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_midi_input_ports();
+
+        /// <summary>
+        /// Starts reading a MIDI input port into the armed or input track.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "open_midi_input", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool open_midi_input(ushort* utf16_str, int utf16_len);
+
+        [DllImport(__DllName, EntryPoint = "close_midi_input", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool close_midi_input(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Adds an input port fed by `send_virtual_midi_input`. Returns its id.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "create_virtual_midi_input", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* create_virtual_midi_input(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Delivers a message on an open virtual port, as a device would.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "send_virtual_midi_input", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool send_virtual_midi_input(ushort* utf16_str, int utf16_len, byte status, byte data1, byte data2);
+
+        /// <summary>
+        /// Plays the MIDI inputs on this track while no track is armed. An empty id plays none.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_midi_input_track", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_midi_input_track(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Armed tracks play the MIDI inputs and record them during playback.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "arm_track_midi_recording", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void arm_track_midi_recording(ushort* utf16_str, int utf16_len, [MarshalAs(UnmanagedType.U1)] bool armed);
+
+        /// <summary>
+        /// MIDI input recorded on the track since the last call, as `RecordedMidiEvent` structs.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "take_recorded_track_midi", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* take_recorded_track_midi(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// MIDI output ports as lines of `id\tname\tselected`.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_midi_output_ports", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_midi_output_ports();
+
+        /// <summary>
+        /// Sends clock and time code to a MIDI output port. An empty id sends them nowhere.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_midi_sync_output", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_midi_sync_output(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Adds an output port whose messages are taken with `take_virtual_midi_output`. Returns its id.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "create_virtual_midi_output", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* create_virtual_midi_output(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Raw bytes sent to virtual output ports since the last call.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "take_virtual_midi_output", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* take_virtual_midi_output();
+
+        /// <summary>
+        /// Sends MIDI Beat Clock with start, stop, continue and song position.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_midi_clock_output", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_midi_clock_output([MarshalAs(UnmanagedType.U1)] bool enabled);
+
+        /// <summary>
+        /// Sends MIDI Time Code at a rate: 0 is 24 fps, 1 is 25, 2 is 29.97 drop frame,
+        /// 3 is 30. Any other rate sends none.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_mtc_output", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_mtc_output(int rate);
+
+        /// <summary>
+        /// Follows the open MIDI inputs: 1 chases clock, tempo included, 2 chases
+        /// time code, 0 stops chasing. The transport plays the clips as last synced.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_midi_chase", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_midi_chase(uint source);
+
+        /// <summary>
+        /// Joins Ableton Link sessions on the local network, starting one at the
+        /// engine's tempo, or leaves them. The transport then follows the session
+        /// tempo and starts on its bar.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_link_enabled", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_link_enabled([MarshalAs(UnmanagedType.U1)] bool enabled);
+
+        /// <summary>
+        /// Other peers in the Link session.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_link_peers", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern uint get_link_peers();
+
+        /// <summary>
+        /// Sets a single tempo for the song, or for the whole Link session when
+        /// enabled; the engine follows the session.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_tempo", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_tempo(double bpm);
+
+        /// <summary>
+        /// How the transport goes with JACK's: 0 on its own, 1 following it, 2 also
+        /// as timebase master. False when not running on JACK.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_jack_transport_mode", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_jack_transport_mode(uint mode);
+
+        /// <summary>
+        /// Gives a track post-fader JACK ports, `name_1` and on, one per channel, or
+        /// takes them away when the name is empty. False when not running on JACK.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_jack_direct_out", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_jack_direct_out(ushort* track_utf16, int track_len, ushort* name_utf16, int name_len);
+
+        /// <summary>
+        /// Tempo at the play position, which may have come from a Link session.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_tempo", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern double get_tempo();
+
+        /// <summary>
+        /// Adds a bus to the routing graph, routed to the master. Returns false if the id is taken.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "create_mixer_bus", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool create_mixer_bus(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Removes a track or bus with its inserts. Whatever was routed to it goes to the master.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "remove_mixer_node", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool remove_mixer_node(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Routes the main output of a track or bus to a bus. An empty target means the master.
+        /// Fails if the routing would loop.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_mixer_output", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_mixer_output(ushort* node_utf16, int node_len, ushort* target_utf16, int target_len);
+
+        /// <summary>
+        /// Adds or updates an aux send. `level` is linear gain.
+        /// Fails if the routing would loop.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_mixer_send", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_mixer_send(ushort* node_utf16, int node_len, ushort* target_utf16, int target_len, float level, [MarshalAs(UnmanagedType.U1)] bool pre_fader);
+
+        [DllImport(__DllName, EntryPoint = "remove_mixer_send", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool remove_mixer_send(ushort* node_utf16, int node_len, ushort* target_utf16, int target_len);
+
+        /// <summary>
+        /// Fader of a track or bus, linear gain.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_mixer_volume", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern void set_mixer_volume(ushort* utf16_str, int utf16_len, float volume);
+
+        /// <summary>
+        /// Delay between the engine position and what is heard, in samples per channel.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_output_latency", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern uint get_output_latency();
+
+        /// <summary>
+        /// Time spent processing a block relative to its duration, smoothed. 1.0 or more means dropouts.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_dsp_load", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern float get_dsp_load();
+
+        /// <summary>
+        /// The position currently heard, i.e. the engine position minus the plugin latency.
+        /// Used for the playhead.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_audible_position_beat", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern float get_audible_position_beat();
+
+        /// <summary>
+        /// Names of the built-in effects, one per line.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_builtin_effects", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_builtin_effects();
+
+        /// <summary>
+        /// Creates a built-in effect, ready for `add_track_insert`. Returns 0 for an unknown name.
+        /// Effects share their ids with plugins.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "create_effect", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern uint create_effect(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Puts the built-in synth on a track whose insert chain is empty, so MIDI played on it
+        /// sounds before any instrument is loaded. Returns the id of the first insert of the
+        /// track, 0 on failure.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "ensure_track_instrument", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern uint ensure_track_instrument(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Parameters of a built-in effect, one per line: name, unit, min, max, default,
+        /// steps (0 when continuous) and the `|` separated step labels, separated by tabs.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_effect_params", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_effect_params(uint effect_id);
+
+        /// <summary>
+        /// Value of a parameter in its own unit, NaN if there is no such effect or parameter.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_effect_param", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern float get_effect_param(uint effect_id, int param_index);
+
+        /// <summary>
+        /// Sets a parameter in its own unit, clamped to its range. Never waits for the audio thread.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_effect_param", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_effect_param(uint effect_id, int param_index, float value);
+
+        /// <summary>
+        /// Serializes a built-in effect for the project file. Empty if there is no such effect.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "save_effect_state", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* save_effect_state(uint effect_id);
+
+        /// <summary>
+        /// Restores a state from `save_effect_state`. Fails if it belongs to another effect.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "load_effect_state", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool load_effect_state(uint effect_id, byte* data_ptr, int len);
+
+        /// <summary>
+        /// Level of the effect's output in dBFS (f32) at `points` frequencies spaced
+        /// logarithmically from 20 Hz to 20 kHz. Empty if the effect has no analyzer.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_effect_spectrum", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_effect_spectrum(uint effect_id, int points);
+
+        /// <summary>
+        /// Names of the effect's meters, one per line. Empty if it has none.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_effect_meter_names", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_effect_meter_names(uint effect_id);
+
+        /// <summary>
+        /// Current meter values (f32) in the order of `get_effect_meter_names`.
+        /// Gain reduction is in positive dB, the largest of the last block.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_effect_meters", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_effect_meters(uint effect_id);
+
+        /// <summary>
+        /// Loads an impulse response file into a convolution effect. The file is decoded
+        /// before returning; preparing it for the engine continues in the background.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "load_effect_ir", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool load_effect_ir(uint effect_id, ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// The loaded impulse response as path, channels and length in seconds, separated by tabs.
+        /// Empty if nothing is loaded.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_effect_ir", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_effect_ir(uint effect_id);
+
+        /// <summary>
+        /// Loads an SF2 bank or SFZ instrument into a sampler. Samples are decoded before
+        /// returning.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "load_sampler_instrument", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool load_sampler_instrument(uint effect_id, ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// The loaded instrument as path, presets, zones and sample length in seconds, separated
+        /// by tabs. Empty if nothing is loaded.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_sampler_instrument", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_sampler_instrument(uint effect_id);
+
+        /// <summary>
+        /// Presets of the loaded instrument, one per line as bank, program and name separated by
+        /// tabs, in the order the Preset parameter counts them.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_sampler_presets", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_sampler_presets(uint effect_id);
+
+        /// <summary>
+        /// Loads a sample into a pad of a drum machine, or clears the pad for an empty path.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "load_drum_pad", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool load_drum_pad(uint effect_id, uint pad, ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// One line per pad with the sample path and its length in seconds, separated by a tab.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_drum_pads", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_drum_pads(uint effect_id);
+
+        /// <summary>
+        /// Sets a step of the grid. Velocity 0 makes it a rest; probability is in percent.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "set_drum_step", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool set_drum_step(uint effect_id, uint pad, uint step, byte velocity, byte probability);
+
+        /// <summary>
+        /// Velocity and probability bytes of every step, 32 steps for each of the 16 pads.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_drum_steps", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_drum_steps(uint effect_id);
+
+        /// <summary>
+        /// Response of a parametric EQ in dB (f32) at the same frequencies as `get_effect_spectrum`.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_eq_curve", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_eq_curve(uint effect_id, int points);
+
+        /// <summary>
+        /// Reads a Standard MIDI File and makes its tempo map the engine's.
+        /// Returns its tracks with channel messages as lines of tab-separated fields,
+        /// by kind: `track name length`, then `event beat status data1 data2` for each
+        /// message of that track. Empty if the file can't be read.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "import_midi_file", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* import_midi_file(ushort* utf16_str, int utf16_len);
+
+        /// <summary>
+        /// Writes MIDI clips to a Standard MIDI File with the engine's tempo map, a
+        /// track per track id, named after it. Clips play as they would in the engine.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "export_midi_file", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        [return: MarshalAs(UnmanagedType.U1)]
+        internal static extern bool export_midi_file(ushort* utf16_str, int utf16_len, MidiClipProto* ptr, int len);
+
+        /// <summary>
+        /// The engine's tempo map as lines of tab-separated fields, by kind:
+        /// `tempo beat bpm`, `time beat numerator denominator`, `key beat sharps minor`
+        /// and `marker beat name`.
+        /// </summary>
+        [DllImport(__DllName, EntryPoint = "get_tempo_map", CallingConvention = CallingConvention.Cdecl, ExactSpelling = true)]
+        internal static extern ByteBuffer* get_tempo_map();
+
 
     }
 
@@ -102,6 +645,46 @@ namespace Muek.Engine
         public int clip_id_len;
         public float start_time;
         public float end_time;
+        public ushort* track_id;
+        public int track_id_len;
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    internal unsafe partial struct MidiClipProto
+    {
+        public float start_time;
+        public float end_time;
+        public float offset;
+        public float loop_length;
+        public ushort* track_id;
+        public int track_id_len;
+        public RecordedMidiEvent* events;
+        public int events_len;
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    internal unsafe partial struct AutomationPoint
+    {
+        public float beat;
+        public float value;
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    internal unsafe partial struct BusLayout
+    {
+        public int inputs;
+        public int outputs;
+        public int sidechain_channels;
+        public int aux_output_buses;
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    internal unsafe partial struct RecordedMidiEvent
+    {
+        public float beat;
+        public byte status;
+        public byte data1;
+        public byte data2;
     }
 
 
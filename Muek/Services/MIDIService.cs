@@ -1,4 +1,11 @@
 using System;
+using System.Collections.Generic;
+using System.Globalization;
+using System.IO;
+using System.Linq;
+using System.Runtime.InteropServices;
+using System.Text;
+using Muek.Engine;
 using Muek.Views;
 using NAudio.Midi;
 
@@ -26,48 +33,288 @@ public class MidiService
         Data.AddTrack();
     }
 
-    public void ImportMidi(string filename)
+    /// <summary>
+    /// Reads a Standard MIDI File with the engine, which also takes its tempo map.
+    /// Track 0 holds the tempo, meter, key and markers, the tracks after it the channel messages.
+    /// </summary>
+    public unsafe void ImportMidi(string filename)
     {
-        try
+        string[] lines;
+        fixed (char* path = filename)
         {
-            var fileData = new MidiFile(filename);
-            Data = new MidiEventCollection(1, fileData.DeltaTicksPerQuarterNote);
-            for (int i = 1; i < Data.Tracks; i++)
+            var b = MuekEngine.import_midi_file((ushort*)path, filename.Length);
+            lines = Encoding.UTF8.GetString(b->AsSpan()).Split('\n', StringSplitOptions.RemoveEmptyEntries);
+        }
+
+        var tracks = new List<(string Name, List<RecordedMidiEvent> Events)>();
+        foreach (var line in lines)
+        {
+            var fields = line.Split('\t');
+            switch (fields[0])
             {
-                Data.RemoveTrack(i);
+                case "track":
+                    tracks.Add((fields[1], []));
+                    break;
+                case "event" when tracks.Count > 0:
+                    tracks[^1].Events.Add(new RecordedMidiEvent
+                    {
+                        beat = float.Parse(fields[1], CultureInfo.InvariantCulture),
+                        status = byte.Parse(fields[2]),
+                        data1 = byte.Parse(fields[3]),
+                        data2 = byte.Parse(fields[4])
+                    });
+                    break;
             }
-            for (int i = 0; i < fileData.Tracks; i++)
+        }
+
+        if (tracks.Count == 0)
+        {
+            new DialogWindow().ShowError($"Cannot import {Path.GetFileName(filename)}");
+            return;
+        }
+
+        Data = new MidiEventCollection(1, 480);
+        Data.AddTrack(ReadTempoMap());
+        foreach (var (name, events) in tracks)
+            Data.AddTrack(ToMidiEvents(name, events.ToArray()));
+
+        foreach (var track in Data)
+        {
+            var endTime = track.Count == 0 ? 0 : track.Max(e => e.AbsoluteTime);
+            track.Add(new MetaEvent(MetaEventType.EndTrack, 0, endTime));
+        }
+    }
+
+    /// <summary>
+    /// Name of a track after track 0, empty when the file gave it none.
+    /// </summary>
+    public string GetTrackName(int track)
+    {
+        return Data[track].OfType<TextEvent>()
+            .FirstOrDefault(e => e.MetaEventType == MetaEventType.SequenceTrackName)?.Text ?? "";
+    }
+
+    /// <summary>
+    /// The notes of a track after track 0, timed the way the piano roll draws them.
+    /// </summary>
+    public List<PianoRoll.Note> GetNotes(int track)
+    {
+        var notes = new List<PianoRoll.Note>();
+        foreach (var on in Data[track].OfType<NoteOnEvent>())
+        {
+            if (on.OffEvent == null)
+                continue;
+            notes.Add(new PianoRoll.Note
             {
-                Data.AddTrack();
-                foreach (var e in fileData.Events[i])
+                Name = on.NoteNumber,
+                StartTime = on.AbsoluteTime / (double)Data.DeltaTicksPerQuarterNote * 4,
+                EndTime = (on.AbsoluteTime + on.NoteLength) / (double)Data.DeltaTicksPerQuarterNote * 4,
+                Velocity = on.Velocity
+            });
+        }
+
+        return notes;
+    }
+
+    /// <summary>
+    /// Writes the tracks after track 0 with the engine, along with its tempo map.
+    /// </summary>
+    public unsafe void ExportMidi(string filename)
+    {
+        var ticksPerBeat = Data.DeltaTicksPerQuarterNote * 4.0;
+        var clips = new List<MidiClipProto>();
+        var handles = new List<GCHandle>();
+        for (var i = 1; i < Data.Tracks; i++)
+        {
+            var name = GetTrackName(i);
+            if (name.Length == 0)
+                name = $"Track {i}";
+            var events = Data[i]
+                .Where(e => e is not MetaEvent && e is not SysexEvent)
+                .Select(e =>
                 {
-                    if(e is NoteEvent @note)
+                    var message = e.GetAsShortMessage();
+                    return new RecordedMidiEvent
                     {
-                        if(note.Velocity != 0)
-                            Data.AddEvent(note, i);
-                        // Console.WriteLine($"Add New Note: {e}");
-                    }
-                    else if(e is MetaEvent @event && @event.MetaEventType != MetaEventType.EndTrack)
-                        Data.AddEvent(@event,i);
-                }
-                Data.AddEvent(new MetaEvent(MetaEventType.EndTrack,0,0),i);
-            }
+                        beat = (float)(e.AbsoluteTime / ticksPerBeat),
+                        status = (byte)message,
+                        data1 = (byte)(message >> 8),
+                        data2 = (byte)(message >> 16)
+                    };
+                })
+                .ToArray();
+            if (events.Length == 0)
+                continue;
+
+            var eventHandle = GCHandle.Alloc(events, GCHandleType.Pinned);
+            var nameHandle = GCHandle.Alloc(name, GCHandleType.Pinned);
+            handles.Add(eventHandle);
+            handles.Add(nameHandle);
+            clips.Add(new MidiClipProto
+            {
+                start_time = 0,
+                // one tick past the last message, so it still plays
+                end_time = (float)((events.Max(e => e.beat) * ticksPerBeat + 1) / ticksPerBeat),
+                track_id = (ushort*)nameHandle.AddrOfPinnedObject(),
+                track_id_len = name.Length,
+                events = (RecordedMidiEvent*)eventHandle.AddrOfPinnedObject(),
+                events_len = events.Length
+            });
         }
-        catch(ArgumentOutOfRangeException @exception)
+
+        var protoArr = clips.ToArray();
+        bool ok;
+        fixed (char* path = filename)
+        fixed (MidiClipProto* clipPtr = protoArr)
         {
+            ok = MuekEngine.export_midi_file((ushort*)path, filename.Length, clipPtr, protoArr.Length);
+        }
+
+        foreach (var handle in handles)
+            handle.Free();
+        if (!ok)
+            new DialogWindow().ShowError($"Cannot export {Path.GetFileName(filename)}");
+    }
 
-            new DialogWindow().ShowError(@exception.Message);
-            // Console.Error.WriteLine(@exception.Message);
+    private unsafe List<MidiEvent> ReadTempoMap()
+    {
+        var ticksPerBeat = Data.DeltaTicksPerQuarterNote * 4.0;
+        var b = MuekEngine.get_tempo_map();
+        var lines = Encoding.UTF8.GetString(b->AsSpan()).Split('\n', StringSplitOptions.RemoveEmptyEntries);
+        var events = new List<MidiEvent>();
+        foreach (var line in lines)
+        {
+            var fields = line.Split('\t');
+            var time = (long)Math.Round(double.Parse(fields[1], CultureInfo.InvariantCulture) * ticksPerBeat);
+            switch (fields[0])
+            {
+                case "tempo":
+                    var bpm = double.Parse(fields[2], CultureInfo.InvariantCulture);
+                    events.Add(new TempoEvent((int)Math.Round(60_000_000 / bpm), time));
+                    break;
+                case "time":
+                    // the file format keeps the denominator as a power of two
+                    var power = (int)Math.Log2(int.Parse(fields[3]));
+                    events.Add(new TimeSignatureEvent(time, int.Parse(fields[2]), power, 24, 8));
+                    break;
+                case "key":
+                    events.Add(new KeySignatureEvent(int.Parse(fields[2]), int.Parse(fields[3]), time));
+                    break;
+                case "marker":
+                    events.Add(new TextEvent(fields[2], MetaEventType.Marker, time));
+                    break;
+            }
         }
+
+        return events;
     }
 
-    public void ExportMidi(string filename)
+    private List<MidiEvent> ToMidiEvents(string name, RecordedMidiEvent[] events)
     {
-        MidiFile.Export(filename,Data);
+        var ticksPerBeat = Data.DeltaTicksPerQuarterNote * 4.0;
+        var track = new List<MidiEvent>();
+        if (name.Length > 0)
+            track.Add(new TextEvent(name, MetaEventType.SequenceTrackName, 0));
+        var sounding = new List<NoteOnEvent>();
+        var endTime = 0L;
+        foreach (var e in events)
+        {
+            var time = (long)Math.Round(e.beat * ticksPerBeat);
+            endTime = Math.Max(endTime, time);
+            // NAudio channels count from 1
+            var channel = (e.status & 0x0f) + 1;
+            switch (e.status & 0xf0)
+            {
+                case 0x90 when e.data2 > 0:
+                    var on = new NoteOnEvent(time, channel, e.data1, e.data2, 0);
+                    sounding.Add(on);
+                    track.Add(on);
+                    break;
+                case 0x80:
+                case 0x90:
+                    var started = sounding.FindIndex(n => n.Channel == channel && n.NoteNumber == e.data1);
+                    if (started < 0)
+                        break;
+                    sounding[started].OffEvent.AbsoluteTime = time;
+                    track.Add(sounding[started].OffEvent);
+                    sounding.RemoveAt(started);
+                    break;
+                case 0xa0:
+                    track.Add(new NoteEvent(time, channel, MidiCommandCode.KeyAfterTouch, e.data1, e.data2));
+                    break;
+                case 0xb0:
+                    track.Add(new ControlChangeEvent(time, channel, (MidiController)e.data1, e.data2));
+                    break;
+                case 0xc0:
+                    track.Add(new PatchChangeEvent(time, channel, e.data1));
+                    break;
+                case 0xd0:
+                    track.Add(new ChannelAfterTouchEvent(time, channel, e.data1));
+                    break;
+                case 0xe0:
+                    track.Add(new PitchWheelChangeEvent(time, channel, e.data2 << 7 | e.data1));
+                    break;
+            }
+        }
+
+        // notes the file never ends last until its end
+        foreach (var on in sounding)
+        {
+            on.OffEvent.AbsoluteTime = endTime;
+            track.Add(on.OffEvent);
+        }
+
+        return track;
     }
 
+    /// <summary>
+    /// Track whose instrument plays previewed notes.
+    /// </summary>
+    public string? TrackId { get; set; }
+
     public void PlayNote(int noteNumber, int velocity)
     {
-        //TODO
+        SendToTrack(0x90, noteNumber, velocity);
+    }
+
+    public void StopNote(int noteNumber)
+    {
+        SendToTrack(0x80, noteNumber, 0);
+    }
+
+    /// <summary>
+    /// Plays the open MIDI inputs on this track while no track is armed.
+    /// </summary>
+    public static unsafe void SetInputTrack(string trackId)
+    {
+        EnsureInstrument(trackId);
+        fixed (char* id = trackId)
+        {
+            MuekEngine.set_midi_input_track((ushort*)id, trackId.Length);
+        }
+    }
+
+    /// <summary>
+    /// Gives the track the built-in synth unless its insert chain already has something to play the notes.
+    /// </summary>
+    public static unsafe void EnsureInstrument(string trackId)
+    {
+        fixed (char* id = trackId)
+        {
+            MuekEngine.ensure_track_instrument((ushort*)id, trackId.Length);
+        }
+    }
+
+    private unsafe void SendToTrack(byte status, int data1, int data2)
+    {
+        if (TrackId == null)
+            return;
+        if (status == 0x90)
+            EnsureInstrument(TrackId);
+        fixed (char* id = TrackId)
+        {
+            MuekEngine.send_track_midi((ushort*)id, TrackId.Length, status, (byte)Math.Clamp(data1, 0, 127),
+                (byte)Math.Clamp(data2, 0, 127));
+        }
     }
 }
\ No newline at end of file
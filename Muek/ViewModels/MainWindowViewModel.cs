@@ -1,6 +1,7 @@
 ﻿using System;
 using System.Collections.Generic;
 using System.Collections.ObjectModel;
+using System.Runtime.InteropServices;
 using System.Threading;
 using CommunityToolkit.Mvvm.ComponentModel;
 using CommunityToolkit.Mvvm.Input;
@@ -19,6 +20,7 @@ public partial class MainWindowViewModel : ViewModelBase
     partial void OnBpmChanged(float value)
     {
         DataStateService.Bpm = value;
+        unsafe { MuekEngine.set_tempo(value); }
     }
 
     private float PlayPosition
@@ -59,15 +61,21 @@ public partial class MainWindowViewModel : ViewModelBase
         {
             foreach (var clip in track.Clips)
             {
+                if (clip.Notes is not null)
+                    continue;
                 var id = clip.Proto.Id;
+                var trackId = track.Id;
                 fixed (char* idStr = id)
+                fixed (char* trackIdStr = trackId)
                 {
                     var proto = new ClipProto()
                     {
                         clip_id = (ushort*)idStr,
                         clip_id_len = id.Length,
                         end_time = (float)(clip.Proto.StartBeat + clip.Proto.Duration),
-                        start_time = (float)clip.StartBeat
+                        start_time = (float)clip.StartBeat,
+                        track_id = (ushort*)trackIdStr,
+                        track_id_len = trackId.Length
                     };
                     clips.Add(proto);
                 }
@@ -79,11 +87,74 @@ public partial class MainWindowViewModel : ViewModelBase
                 MuekEngine.sync_all_clips(clipPtr, protoArr.Length);
             }
         }
-        
+
+        SyncMidiClips();
         MuekEngine.stream_play(PlayPosition);
         AudioService.TriggerAudioStarted();
     }
 
+    /// <summary>
+    /// Sends the notes of the pattern clips to the engine, repeating the pattern through each clip.
+    /// </summary>
+    public static unsafe void SyncMidiClips()
+    {
+        var clips = new List<MidiClipProto>();
+        var handles = new List<GCHandle>();
+        foreach (var track in DataStateService.Tracks)
+        {
+            foreach (var clip in track.Clips)
+            {
+                if (clip.Notes is null)
+                    continue;
+                MidiService.EnsureInstrument(track.Id);
+                var events = new List<RecordedMidiEvent>();
+                foreach (var note in clip.Notes)
+                {
+                    var key = (byte)Math.Clamp(note.Name, 0, 127);
+                    events.Add(new RecordedMidiEvent
+                    {
+                        beat = (float)(note.StartTime / DataStateService.Subdivisions),
+                        status = 0x90,
+                        data1 = key,
+                        data2 = (byte)Math.Clamp(note.Velocity, 1, 127)
+                    });
+                    events.Add(new RecordedMidiEvent
+                    {
+                        beat = (float)(note.EndTime / DataStateService.Subdivisions),
+                        status = 0x80,
+                        data1 = key
+                    });
+                }
+
+                var eventArr = events.ToArray();
+                var eventHandle = GCHandle.Alloc(eventArr, GCHandleType.Pinned);
+                var trackHandle = GCHandle.Alloc(track.Id, GCHandleType.Pinned);
+                handles.Add(eventHandle);
+                handles.Add(trackHandle);
+                clips.Add(new MidiClipProto
+                {
+                    start_time = (float)clip.StartBeat,
+                    end_time = (float)(clip.StartBeat + clip.Duration),
+                    offset = (float)clip.Offset,
+                    loop_length = (float)clip.SourceDuration,
+                    track_id = (ushort*)trackHandle.AddrOfPinnedObject(),
+                    track_id_len = track.Id.Length,
+                    events = (RecordedMidiEvent*)eventHandle.AddrOfPinnedObject(),
+                    events_len = eventArr.Length
+                });
+            }
+        }
+
+        var protoArr = clips.ToArray();
+        fixed (MidiClipProto* clipPtr = protoArr)
+        {
+            MuekEngine.sync_midi_clips(clipPtr, protoArr.Length);
+        }
+
+        foreach (var handle in handles)
+            handle.Free();
+    }
+
     private void Stop()
     {
         PlayIcon = "fa-play";
@@ -25,6 +25,7 @@ public partial class TrackViewModel : ViewModelBase
         if (value)
         {
             DataStateService.ActiveTrack = this;
+            MidiService.SetInputTrack(Id);
         }
     }
 
@@ -84,6 +84,9 @@ public partial class PianoRoll : UserControl
 
     private int _currentHoverNote = -1;
 
+    private readonly MidiService _preview = new();
+    private int _previewNote = -1;
+
     private bool _isDrawing = false;
     private bool _isDragging = false;
     private bool _isEditing = false;
@@ -885,6 +888,16 @@ public partial class PianoRoll : UserControl
         base.OnPointerPressed(e);
         
         _pressedMousePosition = e.GetPosition(this);
+
+        if (IsPianoBar && e.Properties.IsLeftButtonPressed && _currentHoverNote >= 0)
+        {
+            // 试听: 优先用引用该 Pattern 的轨道, 否则用选中的轨道
+            _preview.TrackId = DataStateService.Tracks
+                                   .FirstOrDefault(t => t.Clips.Any(c => c.LinkedPattern == Pattern))?.Id
+                               ?? DataStateService.Tracks.FirstOrDefault(t => t.Selected)?.Id;
+            _previewNote = _currentHoverNote;
+            _preview.PlayNote(_previewNote, 100);
+        }
         
         if (!IsPianoBar)
         {
@@ -977,6 +990,11 @@ public partial class PianoRoll : UserControl
     {
         if(!ViewHelper.IsDesktopPlatform()) return;
         base.OnPointerReleased(e);
+        if (_previewNote != -1)
+        {
+            _preview.StopNote(_previewNote);
+            _previewNote = -1;
+        }
         if (!IsPianoBar)
         {
             if (e.GetPosition(this) == _pressedMousePosition)
@@ -1401,30 +1419,7 @@ public partial class PianoRoll : UserControl
             {
                 CurrentChannel = i;
                 Notes.Clear();
-                for (var index = 0; index < midi.Data[i].Count; index++)
-                {
-                    var note = midi.Data[i][index];
-                    if (note.GetType() == typeof(NoteOnEvent))
-                    {
-                        try
-                        {
-                            Notes.Add(new Note()
-                            {
-                                Name = ((NoteOnEvent)note).NoteNumber,
-                                // Color = NoteColor3,
-                                StartTime = ((NoteOnEvent)note).AbsoluteTime /
-                                    (double)midi.Data.DeltaTicksPerQuarterNote * 4,
-                                EndTime = (((NoteOnEvent)note).AbsoluteTime + ((NoteOnEvent)note).NoteLength) /
-                                    (double)midi.Data.DeltaTicksPerQuarterNote * 4,
-                                Velocity = ((NoteOnEvent)note).Velocity
-                            });
-                        }
-                        catch (Exception @exception)
-                        {
-                            Console.Error.WriteLine(@exception.Message);
-                        }
-                    }
-                }
+                Notes.AddRange(midi.GetNotes(i));
             }
 
             CurrentChannel = 1;
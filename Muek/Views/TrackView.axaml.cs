@@ -16,7 +16,6 @@ using Muek.Engine;
 using Muek.Models;
 using Muek.Services;
 using Muek.ViewModels;
-using NAudio.Midi;
 using NAudio.Wave;
 
 namespace Muek.Views;
@@ -129,7 +128,7 @@ public partial class TrackView : UserControl
             {
                 try
                 {
-                    float currentBeat = MuekEngine.get_current_position_beat();
+                    float currentBeat = MuekEngine.get_audible_position_beat();
 
                     if (Math.Abs(_playHeadPosX - currentBeat) > 0.001)
                     {
@@ -299,77 +298,29 @@ public partial class TrackView : UserControl
                 if(ext == ".mid")
                     isMidi = true;
 
+                if (isMidi)
+                {
+                    AddMidiClips(file, beat, trackIndex);
+                    continue;
+                }
+
                 var durationSec = GetAudioDurationInSeconds(file);
                 var durationBeats = (durationSec / 60f) * DataStateService.Bpm / Subdivisions;
 
-                List<PianoRoll.Note>[]? notes = null;
-
-                Clip newClip;
-                if(!isMidi)
-                    newClip = new Clip
-                    {
-                        Name = Path.GetFileNameWithoutExtension(file),
-                        StartBeat = beat,
-                        Duration = durationBeats,
-                        Path = file,
-                        Id = Guid.NewGuid().ToString(),
-                        // CachedWaveform = AudioService.DecodeFromFile(file, 48000, 2).ToArray()
-                    };
-                else
+                var newClip = new Clip
                 {
-                    notes = [[],[],[],[],[],[],[],[],[],[],[],[],[],[],[],[]];
-                    var midi = new MidiService();
-                    midi.ImportMidi(file);
-                    for (int i = 1; i < midi.Data.Tracks; i++)
-                    {
-                        for (int j = 0; j < midi.Data[i].Count; j++)
-                        {
-                            var note = midi.Data[i][j];
-                            if (note.GetType() == typeof(NoteOnEvent))
-                            {
-                                try
-                                {
-                                    notes[i-1].Add(new PianoRoll.Note()
-                                    {
-                                        Name = ((NoteOnEvent)note).NoteNumber,
-                                        // Color = NoteColor3,
-                                        StartTime = ((NoteOnEvent)note).AbsoluteTime /
-                                            (double)midi.Data.DeltaTicksPerQuarterNote * 4,
-                                        EndTime = (((NoteOnEvent)note).AbsoluteTime + ((NoteOnEvent)note).NoteLength) /
-                                            (double)midi.Data.DeltaTicksPerQuarterNote * 4,
-                                        Velocity = ((NoteOnEvent)note).Velocity
-                                    });
-                                }
-                                catch (Exception @exception)
-                                {
-                                    Console.Error.WriteLine(@exception.Message);
-                                }
-                            }
-                        }
-                    }
-                    double trackEnd = 0;
-                    foreach (var noteList in notes)
-                    {
-                        foreach (var note in noteList)
-                            trackEnd = double.Max(trackEnd, note.EndTime);
-                    }
-                    trackEnd /= Subdivisions;
-                    trackEnd /= DataStateService.Midi2TrackFactor;
-                    newClip = new Clip
-                    {
-                        Name = Path.GetFileNameWithoutExtension(file),
-                        StartBeat = beat,
-                        Duration = trackEnd,
-                        // Path = file,
-                        Id = Guid.NewGuid().ToString(),
-                    };
-                    Console.WriteLine($"Add Midi Clip: Duration: {trackEnd}");
-                }
+                    Name = Path.GetFileNameWithoutExtension(file),
+                    StartBeat = beat,
+                    Duration = durationBeats,
+                    Path = file,
+                    Id = Guid.NewGuid().ToString(),
+                    // CachedWaveform = AudioService.DecodeFromFile(file, 48000, 2).ToArray()
+                };
 
                 // 确保轨道存在
                 if (trackIndex >= 0 && trackIndex < DataStateService.Tracks.Count)
                 {
-                    DataStateService.Tracks[trackIndex].AddClip(newClip,notes);
+                    DataStateService.Tracks[trackIndex].AddClip(newClip);
                 }
 
                 // var track = DataStateService.Tracks[trackIndex].Proto;
@@ -377,11 +328,52 @@ public partial class TrackView : UserControl
                 // OffsetY = MaxOffsetY;
             }
 
+            MainWindowViewModel.SyncMidiClips();
             _isDropping = false;
             InvalidateVisual();
         }
     }
 
+    /// <summary>
+    /// Puts each track of a MIDI file in a clip on a track of its own, starting with the one it was
+    /// dropped on and adding tracks as needed.
+    /// </summary>
+    private static void AddMidiClips(string file, double beat, int trackIndex)
+    {
+        if (trackIndex < 0)
+            return;
+        var midi = new MidiService();
+        midi.ImportMidi(file);
+        var index = trackIndex;
+        for (var i = 1; i < midi.Data.Tracks; i++)
+        {
+            var notes = midi.GetNotes(i);
+            if (notes.Count == 0)
+                continue;
+            var name = midi.GetTrackName(i);
+            if (name.Length == 0)
+                name = Path.GetFileNameWithoutExtension(file);
+            while (index >= DataStateService.Tracks.Count)
+                DataStateService.AddTrack(name);
+
+            var trackEnd = notes.Max(n => n.EndTime) / Subdivisions / DataStateService.Midi2TrackFactor;
+            var newClip = new Clip
+            {
+                Name = name,
+                StartBeat = beat,
+                Duration = trackEnd,
+                Id = Guid.NewGuid().ToString(),
+            };
+            // a pattern keeps a list of notes per channel
+            var channels = Enumerable.Range(0, 16)
+                .Select(channel => channel == 0 ? notes : new List<PianoRoll.Note>())
+                .ToArray();
+            DataStateService.Tracks[index].AddClip(newClip, channels);
+            Console.WriteLine($"Add Midi Clip: Duration: {trackEnd}");
+            index++;
+        }
+    }
+
     private float GetAudioDurationInSeconds(string path)
     {
         try
@@ -6,3 +6,5 @@ set source "target/debug/libmuek_engine.so"
 set destination "../Muek/"
 
 cp -f $source $destination
+cp -f target/debug/muek_plugin_probe $destination
+cp -f target/debug/muek_plugin_bridge $destination
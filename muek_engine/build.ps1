@@ -4,3 +4,5 @@ $source = "target/debug/muek_engine.dll"
 $destination ="../Muek"
 
 Copy-Item -Path $source -Destination $destination -Force
+Copy-Item -Path "target/debug/muek_plugin_probe.exe" -Destination $destination -Force
+Copy-Item -Path "target/debug/muek_plugin_bridge.exe" -Destination $destination -Force
@@ -4,6 +4,9 @@ fn main() {
         .input_extern_file("src/lib.rs")
         .input_extern_file("src/protos/byte_buffer.rs")
         .input_extern_file("src/protos/tracks_proto.rs")
+        .input_extern_file("src/automation.rs")
+        .input_extern_file("src/mixer.rs")
+        .input_extern_file("src/midi.rs")
         .csharp_dll_name("muek_engine")
         .csharp_namespace("Muek.Engine")
         .csharp_class_name("MuekEngine")
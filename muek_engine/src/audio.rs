@@ -1,32 +1,234 @@
 use std::{
+    collections::HashMap,
     sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     },
-    thread,
+    thread::{self, Thread},
     time::Instant,
 };
 
 use bon::Builder;
 use cpal::{
-    Stream,
+    BufferSize, Stream, StreamConfig, SupportedBufferSize,
     traits::{DeviceTrait, HostTrait, StreamTrait},
 };
 
-use crate::lazy_states::CLIP_CACHES;
+#[cfg(target_os = "linux")]
+use crate::jack_backend::JackBackend;
+use crate::{
+    lazy_states::{AUTOMATION, AUTOMATION_GESTURES, CLIP_CACHES, LINK, MIDI_INPUTS, MIDI_SYNC},
+    link::LinkState,
+    midi::MidiEvent,
+    midi_input::{InputQueue, QUEUE_CAPACITY},
+    midi_sync::{Chase, Follow, SyncLink, SyncMessage, Transmitter},
+    mixer::{BlockContext, Mixer},
+    tempo_map::TempoMap,
+};
+
+/// How fast the reported DSP load follows the last block.
+const DSP_LOAD_SMOOTHING: f32 = 0.1;
 
 pub struct AudioEngine {
     pub config: AudioConfig,
     pub state: Arc<EngineState>,
     pub stream: Mutex<Option<Stream>>,
-    pub rendered_clips: Vec<RenderedClip>,
+    /// Used instead of the stream while a JACK server runs.
+    #[cfg(target_os = "linux")]
+    pub jack: Mutex<Option<JackBackend>>,
 }
 
 pub struct EngineState {
     pub pos_idx: AtomicU64,
-    pub buffer: Mutex<Vec<f32>>,
+    pub mixer: Mutex<Mixer>,
     pub start_time: Mutex<Option<Instant>>,
     pub is_playing: AtomicBool,
+    /// Share of the block's duration spent processing it, as `f32` bits.
+    pub dsp_load: AtomicU32,
+    pub tempo_map: Mutex<TempoMap>,
+    /// Audio clips, placed on their tracks' rendered audio by beat.
+    pub rendered_clips: Mutex<Vec<RenderedClip>>,
+    /// Set by the audio thread when a plugin reports a new latency.
+    latency_dirty: AtomicBool,
+    /// Tempo a sync source asked for from the audio thread.
+    tempo_request: Mutex<Option<TempoRequest>>,
+    /// Set when plugins queued automation gestures.
+    gestures_pending: AtomicBool,
+    /// Does the work the audio thread must not do itself.
+    housekeeper: OnceLock<Thread>,
+}
+
+impl Drop for EngineState {
+    fn drop(&mut self) {
+        // lets the housekeeper see that it is no longer needed
+        if let Some(housekeeper) = self.housekeeper.get() {
+            housekeeper.unpark();
+        }
+    }
+}
+
+impl EngineState {
+    fn new(config: &AudioConfig) -> Arc<Self> {
+        let state = Arc::new(Self {
+            pos_idx: AtomicU64::new(0),
+            mixer: Mutex::new(Mixer::new(config.channels as usize)),
+            start_time: Mutex::new(None),
+            is_playing: AtomicBool::new(false),
+            dsp_load: AtomicU32::new(0),
+            tempo_map: Mutex::new(TempoMap::new(config.bpm as f64)),
+            rendered_clips: Mutex::new(Vec::new()),
+            latency_dirty: AtomicBool::new(false),
+            tempo_request: Mutex::new(None),
+            gestures_pending: AtomicBool::new(false),
+            housekeeper: OnceLock::new(),
+        });
+
+        let weak = Arc::downgrade(&state);
+        match thread::Builder::new()
+            .name("muek-housekeeper".to_owned())
+            .spawn(move || Self::run_housekeeper(weak))
+        {
+            Ok(handle) => {
+                let _ = state.housekeeper.set(handle.thread().clone());
+            }
+            Err(e) => eprintln!("Failed to start engine housekeeper: {}", e),
+        }
+        state
+    }
+
+    fn run_housekeeper(weak: Weak<Self>) {
+        loop {
+            thread::park();
+            let Some(state) = weak.upgrade() else {
+                return;
+            };
+            if state.latency_dirty.swap(false, Ordering::AcqRel) {
+                state.mixer.lock().unwrap().update_latency();
+            }
+            if state.gestures_pending.swap(false, Ordering::AcqRel) {
+                AUTOMATION_GESTURES.drain_into(&mut AUTOMATION.lock().unwrap());
+            }
+            let request = state.tempo_request.lock().unwrap().take();
+            if let Some(request) = request {
+                state.retempo(request.bpm, request.sample_rate, request.channels);
+            }
+        }
+    }
+
+    /// Called from the audio thread; the delays are rebuilt on the housekeeper.
+    fn request_latency_update(&self) {
+        self.latency_dirty.store(true, Ordering::Release);
+        if let Some(housekeeper) = self.housekeeper.get() {
+            housekeeper.unpark();
+        }
+    }
+
+    /// Called after queueing to `AUTOMATION_GESTURES`, which the housekeeper
+    /// applies.
+    pub fn request_gestures(&self) {
+        self.gestures_pending.store(true, Ordering::Release);
+        if let Some(housekeeper) = self.housekeeper.get() {
+            housekeeper.unpark();
+        }
+    }
+
+    /// Called from the audio thread; the song is retempoed on the housekeeper.
+    /// A request the housekeeper hasn't taken yet is replaced.
+    pub fn request_tempo(&self, bpm: f64, sample_rate: u32, channels: u16) {
+        let Ok(mut request) = self.tempo_request.try_lock() else {
+            // asked again next block
+            return;
+        };
+        *request = Some(TempoRequest {
+            bpm,
+            sample_rate,
+            channels,
+        });
+        drop(request);
+        if let Some(housekeeper) = self.housekeeper.get() {
+            housekeeper.unpark();
+        }
+    }
+
+    /// Makes the song a single tempo, staying at the beat it's at, and moves
+    /// the audio clips to where their beats now fall.
+    fn retempo(&self, bpm: f64, sample_rate: u32, channels: u16) {
+        self.change_tempo(sample_rate, channels, |tempo_map| tempo_map.set_bpm(bpm));
+    }
+
+    /// Replaces the tempo map, e.g. with an imported song's, staying at the
+    /// beat it's at and moving the audio clips along.
+    fn replace_tempo_map(&self, new: TempoMap, sample_rate: u32, channels: u16) {
+        self.change_tempo(sample_rate, channels, |tempo_map| *tempo_map = new);
+    }
+
+    fn change_tempo(&self, sample_rate: u32, channels: u16, change: impl FnOnce(&mut TempoMap)) {
+        {
+            let mut tempo_map = self.tempo_map.lock().unwrap();
+            let previous = tempo_map.clone();
+            change(&mut tempo_map);
+            // the audio thread may move on meanwhile, so whichever
+            // position it's at is mapped
+            let _ = self
+                .pos_idx
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pos_idx| {
+                    let beat = previous.beat_at_sample(pos_idx, sample_rate, channels);
+                    Some(tempo_map.sample_at_beat(beat, sample_rate, channels))
+                });
+        }
+        self.render(sample_rate, channels);
+    }
+
+    /// Lays the audio clips out on their tracks by the tempo map. The audio
+    /// is built before the mixer is locked and the old audio freed after.
+    fn render(&self, sample_rate: u32, channels: u16) {
+        let tempo_map = self.tempo_map.lock().unwrap().clone();
+        let mut buffers: HashMap<String, Vec<f32>> = HashMap::new();
+        for clip in self.rendered_clips.lock().unwrap().iter() {
+            let buffer = buffers.entry(clip.track_id.clone()).or_default();
+
+            let start = tempo_map.sample_at_beat(clip.start_beat, sample_rate, channels) as usize;
+            let end = tempo_map.sample_at_beat(clip.end_beat, sample_rate, channels) as usize;
+
+            if buffer.len() < end {
+                buffer.resize(end, 0.0);
+            }
+
+            let target_len = end.saturating_sub(start);
+
+            let valid_len = std::cmp::min(target_len, clip.samples.len());
+
+            unsafe {
+                for i in 0..valid_len {
+                    let sample = *clip.samples.get_unchecked(i);
+                    let target = buffer.get_unchecked_mut(start + i);
+
+                    *target += sample;
+                }
+            }
+        }
+
+        let mut mixer = self.mixer.lock().unwrap();
+        let retired: Vec<Vec<f32>> = mixer
+            .tracks
+            .iter_mut()
+            .map(|track| std::mem::take(&mut track.rendered))
+            .collect();
+        for (track_id, buffer) in buffers {
+            mixer.track_mut(&track_id).rendered = buffer;
+        }
+        drop(mixer);
+        drop(retired);
+    }
+
+    /// Engine beat at the current position.
+    pub fn position_beat(&self, config: &AudioConfig) -> f32 {
+        let pos_idx = self.pos_idx.load(Ordering::SeqCst);
+        self.tempo_map
+            .lock()
+            .unwrap()
+            .beat_at_sample(pos_idx, config.sample_rate, config.channels) as f32
+    }
 }
 
 #[derive(Clone, Builder)]
@@ -37,9 +239,17 @@ pub struct AudioConfig {
     pub bpm: f32,
 }
 
+struct TempoRequest {
+    bpm: f64,
+    sample_rate: u32,
+    channels: u16,
+}
+
 pub struct RenderedClip {
-    pub start_sample_idx: u64,
-    pub end_sample_idx: u64,
+    pub track_id: String,
+    /// Engine beats, so the clip stays put when the tempo changes.
+    pub start_beat: f64,
+    pub end_beat: f64,
     pub samples: Vec<f32>,
 }
 
@@ -48,18 +258,36 @@ impl AudioEngine {
         Self {
             config: config.clone(),
             stream: Mutex::new(None),
-            rendered_clips: Vec::new(),
+            #[cfg(target_os = "linux")]
+            jack: Mutex::new(None),
             // buffer: vec![0.0; config.buffer_size],
-            state: Arc::new(EngineState {
-                pos_idx: AtomicU64::new(0),
-                buffer: Mutex::new(Vec::new()),
-                start_time: Mutex::new(None),
-                is_playing: AtomicBool::new(false),
-            }),
+            state: EngineState::new(config),
         }
     }
 
-    pub fn spawn(&self) {
+    pub fn spawn(&mut self) {
+        #[cfg(target_os = "linux")]
+        match JackBackend::connect() {
+            Ok(client) => {
+                self.adopt_stream(client.sample_rate() as u32, client.buffer_size() as usize);
+                let processor = BlockProcessor::new(self);
+                match JackBackend::open(client, &self.config, self.state.clone(), processor) {
+                    Ok(backend) => {
+                        *self.jack.lock().unwrap() = Some(backend);
+                        return;
+                    }
+                    Err(e) => println!(
+                        "[start_output] Cannot run on JACK, using the default host: {}",
+                        e
+                    ),
+                }
+            }
+            Err(e) => println!(
+                "[start_output] No JACK server, using the default host: {}",
+                e
+            ),
+        }
+
         let host = cpal::default_host();
         let device = host
             .default_output_device()
@@ -73,27 +301,24 @@ impl AudioEngine {
 
         let err_fn = |err| eprintln!("stream error: {}", err);
 
-        let config = supported_config.into();
+        // ask for our block size, so it stays the most a plugin is handed at once
+        let mut config: StreamConfig = supported_config.config();
+        let buffer_size = match supported_config.buffer_size() {
+            SupportedBufferSize::Range { min, max } => {
+                let size = (self.config.buffer_size as u32).clamp(*min, *max);
+                config.buffer_size = BufferSize::Fixed(size);
+                size as usize
+            }
+            SupportedBufferSize::Unknown => self.config.buffer_size,
+        };
+        self.adopt_stream(config.sample_rate.0, buffer_size);
 
-        let state_clone = self.state.clone();
+        let mut processor = BlockProcessor::new(self);
 
         let stream = device
             .build_output_stream(
                 &config,
-                move |output: &mut [f32], _| {
-                    if state_clone.is_playing.load(Ordering::SeqCst) {
-                        let buffer = state_clone.buffer.lock().unwrap();
-                        for out in output.iter_mut() {
-                            let idx = state_clone.pos_idx.fetch_add(1, Ordering::SeqCst);
-                            let s = buffer.get(idx as usize).unwrap_or(&0.0);
-                            *out = *s;
-                        }
-                    } else {
-                        for out in output.iter_mut() {
-                            *out = 0.0;
-                        }
-                    }
-                },
+                move |output: &mut [f32], _| processor.process(output),
                 err_fn,
                 None,
             )
@@ -109,85 +334,249 @@ impl AudioEngine {
         });
     }
 
+    /// Takes over the rate and block size the device runs at, re-initializing
+    /// the loaded plugins when they differ from what they were set up for.
+    pub fn adopt_stream(&mut self, sample_rate: u32, buffer_size: usize) {
+        if self.config.sample_rate == sample_rate && self.config.buffer_size == buffer_size {
+            return;
+        }
+        println!(
+            "[start_output] Running at {} Hz, {} frames",
+            sample_rate, buffer_size
+        );
+        self.config.sample_rate = sample_rate;
+        self.config.buffer_size = buffer_size;
+
+        #[cfg(target_os = "windows")]
+        for plugin in crate::lazy_states::LOADED_PLUGINS.lock().unwrap().values() {
+            plugin
+                .lock()
+                .unwrap()
+                .set_stream(sample_rate as f32, buffer_size as i64);
+        }
+    }
+
     pub fn play(&mut self, beat: f32) {
-        self.render();
+        self.state
+            .render(self.config.sample_rate, self.config.channels);
         // self.state.pos_idx.store(0, Ordering::SeqCst);
         self.set_pos_beat(beat);
-        *self.state.start_time.lock().unwrap() = Some(Instant::now());
-        self.state.is_playing.store(true, Ordering::SeqCst);
+        self.state.mixer.lock().unwrap().reset();
+        let now = Instant::now();
+        *self.state.start_time.lock().unwrap() = Some(now);
+        // the audio thread plays once the JACK transport rolls
+        #[cfg(target_os = "linux")]
+        if let Some(jack) = &*self.jack.lock().unwrap()
+            && jack.start(self.state.pos_idx.load(Ordering::SeqCst))
+        {
+            return;
+        }
+        // with Link the audio thread starts on the session's bar
+        let held = LINK.lock().unwrap().state().request_start(beat as f64, now);
+        self.state.is_playing.store(!held, Ordering::SeqCst);
     }
 
     pub fn stop(&self) -> bool {
-        let is_playing = self.state.is_playing.load(Ordering::SeqCst);
+        let waiting = LINK.lock().unwrap().state().cancel_start();
+        #[cfg(target_os = "linux")]
+        let waiting = waiting
+            || self
+                .jack
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(JackBackend::stop)
+                .unwrap_or(false);
+        let is_playing = self.state.is_playing.load(Ordering::SeqCst) || waiting;
+        let beat = self.get_position_beat();
         if is_playing {
             self.set_pos_beat(0.0);
         }
         self.state.is_playing.store(false, Ordering::SeqCst);
         // self.state.pos_idx.store(0, Ordering::SeqCst);
         *self.state.start_time.lock().unwrap() = None;
+        let mut automation = AUTOMATION.lock().unwrap();
+        // what plugins recorded up to the stop
+        AUTOMATION_GESTURES.drain_into(&mut automation);
+        automation.on_transport_stop(beat);
         is_playing
     }
 
-    fn render(&mut self) {
-        let total_samples = self
-            .rendered_clips
-            .iter()
-            .map(|c| c.end_sample_idx)
-            .max()
-            .unwrap_or(0);
+    /// Output latency of the mix in samples per channel.
+    pub fn latency(&self) -> usize {
+        self.state.mixer.lock().unwrap().total_latency()
+    }
 
-        let mut buffer = self.state.buffer.lock().unwrap();
-        buffer.clear();
-        buffer.resize(total_samples as usize, 0.0);
+    /// Smoothed share of the block's duration the last blocks took to process, 1.0 is an overrun.
+    pub fn dsp_load(&self) -> f32 {
+        f32::from_bits(self.state.dsp_load.load(Ordering::Relaxed))
+    }
 
-        let buffer_len = buffer.len();
+    pub fn get_position_beat(&self) -> f32 {
+        self.state.position_beat(&self.config)
+    }
 
-        for clip in &self.rendered_clips {
-            let start = clip.start_sample_idx as usize;
-            let end = clip.end_sample_idx as usize;
+    /// Engine beat at an interleaved sample index, following the tempo map.
+    pub fn beat_at_sample(&self, sample_idx: u64) -> f32 {
+        self.state.tempo_map.lock().unwrap().beat_at_sample(
+            sample_idx,
+            self.config.sample_rate,
+            self.config.channels,
+        ) as f32
+    }
 
-            let target_len = end.saturating_sub(start);
+    /// Interleaved sample index at an engine beat, following the tempo map.
+    pub fn sample_at_beat(&self, beat: f32) -> u64 {
+        self.state.tempo_map.lock().unwrap().sample_at_beat(
+            beat as f64,
+            self.config.sample_rate,
+            self.config.channels,
+        )
+    }
 
-            let copy_len = std::cmp::min(target_len, clip.samples.len());
+    /// Tempo at the current position.
+    pub fn bpm(&self) -> f64 {
+        let beat = self.get_position_beat() as f64;
+        self.state.tempo_map.lock().unwrap().bpm_at(beat)
+    }
 
-            if start >= buffer_len {
-                continue;
-            }
-            let valid_len = std::cmp::min(copy_len, buffer_len - start);
+    /// Makes the song a single tempo, staying at the beat it's at.
+    pub fn set_bpm(&self, bpm: f64) {
+        self.state
+            .retempo(bpm, self.config.sample_rate, self.config.channels);
+    }
 
-            unsafe {
-                for i in 0..valid_len {
-                    let sample = *clip.samples.get_unchecked(i);
-                    let target = buffer.get_unchecked_mut(start + i);
+    /// Takes on a whole tempo map, staying at the beat it's at.
+    pub fn set_tempo_map(&self, tempo_map: TempoMap) {
+        self.state
+            .replace_tempo_map(tempo_map, self.config.sample_rate, self.config.channels);
+    }
 
-                    *target += sample;
+    pub fn set_pos_beat(&self, beat: f32) {
+        self.state
+            .pos_idx
+            .store(self.sample_at_beat(beat), Ordering::SeqCst);
+    }
+}
+
+/// Everything the audio callback keeps between blocks, whichever host runs it.
+pub struct BlockProcessor {
+    state: Arc<EngineState>,
+    config: AudioConfig,
+    midi_input: Arc<InputQueue>,
+    input_events: Vec<MidiEvent>,
+    midi_sync: Arc<SyncLink>,
+    sync_events: Vec<(Instant, SyncMessage)>,
+    chase: Chase,
+    transmitter: Transmitter,
+    link: Arc<LinkState>,
+}
+
+impl BlockProcessor {
+    pub fn new(engine: &AudioEngine) -> Self {
+        Self {
+            state: engine.state.clone(),
+            config: engine.config.clone(),
+            midi_input: MIDI_INPUTS.lock().unwrap().queue(),
+            input_events: Vec::with_capacity(QUEUE_CAPACITY),
+            midi_sync: MIDI_SYNC.lock().unwrap().link(),
+            sync_events: Vec::with_capacity(QUEUE_CAPACITY),
+            chase: Chase::default(),
+            transmitter: Transmitter::default(),
+            link: LINK.lock().unwrap().state(),
+        }
+    }
+
+    /// Follows the sync sources, then plays one interleaved block into `output`.
+    pub fn process(&mut self, output: &mut [f32]) {
+        let started = Instant::now();
+        let frames = output.len() / self.config.channels.max(1) as usize;
+        self.midi_input.take_sync(&mut self.sync_events);
+        for (at, message) in &self.sync_events {
+            self.chase.receive(*at, message);
+        }
+        if let Ok(tempo_map) = self.state.tempo_map.try_lock() {
+            let follow = self.chase.follow(
+                self.midi_sync.chase(),
+                started,
+                self.state.is_playing.load(Ordering::SeqCst),
+                self.state.pos_idx.load(Ordering::SeqCst),
+                frames,
+                &tempo_map,
+                self.config.sample_rate,
+                self.config.channels,
+            );
+            self.apply(follow);
+        }
+        if let Ok(tempo_map) = self.state.tempo_map.try_lock() {
+            let follow = self.link.follow(
+                started,
+                self.state.is_playing.load(Ordering::SeqCst),
+                self.state.pos_idx.load(Ordering::SeqCst),
+                frames,
+                &tempo_map,
+                self.config.sample_rate,
+                self.config.channels,
+            );
+            self.apply(follow);
+        }
+
+        let is_playing = self.state.is_playing.load(Ordering::SeqCst);
+        // stopped, the position holds but instruments and tails still sound
+        let idx = if is_playing {
+            self.state
+                .pos_idx
+                .fetch_add(output.len() as u64, Ordering::SeqCst)
+        } else {
+            self.state.pos_idx.load(Ordering::SeqCst)
+        };
+        // never wait on the UI thread, drop the block instead
+        match (self.state.mixer.try_lock(), self.state.tempo_map.try_lock()) {
+            (Ok(mut mixer), Ok(tempo_map)) => {
+                let ctx = block_context(&self.config, &tempo_map, idx, is_playing);
+                drop(tempo_map);
+                self.midi_input.take_block(
+                    started,
+                    frames,
+                    ctx.sample_rate,
+                    &mut self.input_events,
+                );
+                mixer.push_input_midi(&self.input_events, &ctx);
+                mixer.process(idx, output, &ctx);
+                if mixer.latency_changed() {
+                    self.state.request_latency_update();
                 }
+                let start_frame = idx / self.config.channels.max(1) as u64;
+                self.transmitter
+                    .block(&self.midi_sync, started, start_frame, frames, &ctx);
             }
+            _ => output.fill(0.0),
         }
-    }
 
-    pub fn get_position_beat(&self) -> f32 {
-        let pos_idx = self.state.pos_idx.load(Ordering::SeqCst);
-        sample_idx_to_beat(
-            pos_idx,
-            self.config.bpm,
-            self.config.sample_rate,
-            4,
-            self.config.channels.try_into().unwrap(),
-        )
+        if frames == 0 {
+            return;
+        }
+        let block_secs = frames as f32 / self.config.sample_rate as f32;
+        let load = started.elapsed().as_secs_f32() / block_secs;
+        let previous = f32::from_bits(self.state.dsp_load.load(Ordering::Relaxed));
+        let smoothed = previous + (load - previous) * DSP_LOAD_SMOOTHING;
+        self.state
+            .dsp_load
+            .store(smoothed.to_bits(), Ordering::Relaxed);
     }
 
-    pub fn set_pos_beat(&self, beat: f32) {
-        self.state.pos_idx.store(
-            beat_to_sample_idx(
-                beat,
-                self.config.bpm,
-                self.config.sample_rate,
-                4,
-                self.config.channels.try_into().unwrap(),
-            ),
-            Ordering::SeqCst,
-        );
+    /// Moves the transport where a sync source wants it, the tempo is left
+    /// to the housekeeper. Called with the tempo map locked, so the
+    /// housekeeper can't move the position in between.
+    fn apply(&self, follow: Follow) {
+        if let Some(bpm) = follow.bpm {
+            self.state
+                .request_tempo(bpm, self.config.sample_rate, self.config.channels);
+        }
+        if let Some((playing, pos_idx)) = follow.transport {
+            self.state.pos_idx.store(pos_idx, Ordering::SeqCst);
+            self.state.is_playing.store(playing, Ordering::SeqCst);
+        }
     }
 }
 
@@ -196,27 +585,175 @@ pub fn cache_clip_data(id: &str, data: Vec<f32>) {
     cache.insert(id.to_string(), data);
 }
 
-pub fn beat_to_sample_idx(
-    beat: f32,
-    bpm: f32,
-    sample_rate: u32,
-    scale_factor: u8,
-    channels: u8,
-) -> u64 {
-    let secs = beat / bpm * 60.0;
-    let index = secs * sample_rate as f32 * scale_factor as f32 * channels as f32;
-
-    index as u64 - (index as u64 % 2)
+fn block_context(
+    config: &AudioConfig,
+    tempo_map: &TempoMap,
+    pos_idx: u64,
+    is_playing: bool,
+) -> BlockContext {
+    let sample_rate = config.sample_rate as f32;
+    let start_beat = tempo_map.beat_at_sample(pos_idx, config.sample_rate, config.channels);
+    let bpm = tempo_map.bpm_at(start_beat) as f32;
+    BlockContext {
+        sample_rate,
+        bpm,
+        start_beat: start_beat as f32,
+        // one engine beat spans 4 quarter notes
+        beats_per_sample: bpm / 60.0 / sample_rate / 4.0,
+        is_playing,
+    }
 }
 
-pub fn sample_idx_to_beat(
-    sample_idx: u64,
-    bpm: f32,
-    sample_rate: u32,
-    scale_factor: u8,
-    channels: u8,
-) -> f32 {
-    let played_secs = sample_idx as f32 / channels as f32 / sample_rate as f32;
-    let total_secs = played_secs / scale_factor as f32;
-    total_secs * (bpm / 60.0)
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    use super::*;
+    use crate::{
+        mixer::{Buses, Insert},
+        tempo_map::TempoChange,
+    };
+
+    /// An insert whose latency the test changes from outside.
+    struct Lookahead(Arc<AtomicUsize>);
+
+    impl Insert for Lookahead {
+        fn id(&self) -> u32 {
+            1
+        }
+
+        fn io(&self) -> (usize, usize) {
+            (2, 2)
+        }
+
+        fn process(&mut self, _: &mut [Vec<f32>], _: &mut Buses, _: &BlockContext) {}
+
+        fn latency(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn housekeeper_applies_new_plugin_latency() {
+        let config = AudioConfig::builder()
+            .sample_rate(48000)
+            .channels(2)
+            .buffer_size(256)
+            .bpm(120.0)
+            .build();
+        let state = EngineState::new(&config);
+        let latency = Arc::new(AtomicUsize::new(0));
+        state
+            .mixer
+            .lock()
+            .unwrap()
+            .add_insert("track", Box::new(Lookahead(latency.clone())));
+
+        latency.store(64, Ordering::Relaxed);
+        assert!(state.mixer.lock().unwrap().latency_changed());
+        state.request_latency_update();
+
+        let started = Instant::now();
+        while state.mixer.lock().unwrap().total_latency() != 64 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!state.mixer.lock().unwrap().latency_changed());
+    }
+
+    #[test]
+    fn requested_tempo_keeps_the_beat_and_moves_the_clips() {
+        // at 240 bpm an engine beat is 1000 frames
+        let config = AudioConfig::builder()
+            .sample_rate(1000)
+            .channels(1)
+            .buffer_size(100)
+            .bpm(240.0)
+            .build();
+        let state = EngineState::new(&config);
+        state.rendered_clips.lock().unwrap().push(RenderedClip {
+            track_id: "audio".to_owned(),
+            start_beat: 1.0,
+            end_beat: 2.0,
+            samples: vec![1.0; 1000],
+        });
+        state.render(1000, 1);
+        let rendered =
+            |idx: usize| state.mixer.lock().unwrap().track("audio").unwrap().rendered[idx];
+        assert_eq!((rendered(999), rendered(1000)), (0.0, 1.0));
+
+        state.pos_idx.store(1500, Ordering::SeqCst);
+        state.request_tempo(120.0, 1000, 1);
+
+        let started = Instant::now();
+        while state
+            .mixer
+            .lock()
+            .unwrap()
+            .track("audio")
+            .unwrap()
+            .rendered
+            .len()
+            != 4000
+        {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(state.tempo_map.lock().unwrap().bpm_at(0.0), 120.0);
+        // still half way through beat 1
+        assert_eq!(state.pos_idx.load(Ordering::SeqCst), 3000);
+        assert_eq!(
+            (rendered(1999), rendered(2000), rendered(3000)),
+            (0.0, 1.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn replaced_tempo_map_keeps_the_beat_and_moves_the_clips() {
+        let config = AudioConfig::builder()
+            .sample_rate(1000)
+            .channels(1)
+            .buffer_size(100)
+            .bpm(240.0)
+            .build();
+        let state = EngineState::new(&config);
+        state.rendered_clips.lock().unwrap().push(RenderedClip {
+            track_id: "audio".to_owned(),
+            start_beat: 1.0,
+            end_beat: 2.0,
+            samples: vec![1.0; 1000],
+        });
+        state.render(1000, 1);
+        state.pos_idx.store(1500, Ordering::SeqCst);
+
+        // half the tempo from beat 1 on
+        let mut tempo_map = TempoMap::new(240.0);
+        tempo_map.set_tempos(vec![
+            TempoChange {
+                beat: 0.0,
+                bpm: 240.0,
+            },
+            TempoChange {
+                beat: 1.0,
+                bpm: 120.0,
+            },
+        ]);
+        state.replace_tempo_map(tempo_map, 1000, 1);
+
+        assert_eq!(state.tempo_map.lock().unwrap().tempos().len(), 2);
+        // still half way through beat 1
+        assert_eq!(state.pos_idx.load(Ordering::SeqCst), 2000);
+        let mixer = state.mixer.lock().unwrap();
+        let rendered = &mixer.track("audio").unwrap().rendered;
+        assert_eq!(rendered.len(), 3000);
+        assert_eq!(
+            (
+                rendered[999],
+                rendered[1000],
+                rendered[1999],
+                rendered[2000]
+            ),
+            (0.0, 1.0, 1.0, 0.0)
+        );
+    }
 }
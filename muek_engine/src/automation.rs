@@ -0,0 +1,545 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// Number of samples between two automation reads during playback.
+pub const AUTOMATION_SUB_BLOCK: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutomationMode {
+    Off,
+    Read,
+    Write,
+    Touch,
+    Latch,
+}
+
+impl AutomationMode {
+    pub fn from_i32(mode: i32) -> Self {
+        match mode {
+            1 => AutomationMode::Read,
+            2 => AutomationMode::Write,
+            3 => AutomationMode::Touch,
+            4 => AutomationMode::Latch,
+            _ => AutomationMode::Off,
+        }
+    }
+
+    pub fn as_i32(self) -> i32 {
+        match self {
+            AutomationMode::Off => 0,
+            AutomationMode::Read => 1,
+            AutomationMode::Write => 2,
+            AutomationMode::Touch => 3,
+            AutomationMode::Latch => 4,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutomationPoint {
+    pub beat: f32,
+    pub value: f32,
+}
+
+/// (plugin id, parameter index)
+pub type LaneKey = (u32, i32);
+
+#[derive(Default)]
+pub struct AutomationLane {
+    pub points: Vec<AutomationPoint>,
+    /// beat of the last point written in the current pass
+    last_write: Option<f32>,
+    last_value: f32,
+}
+
+/// Linear interpolation between points, holding the first/last value outside.
+fn value_at(points: &[AutomationPoint], beat: f32) -> Option<f32> {
+    let first = points.first()?;
+    let last = points.last()?;
+    if beat <= first.beat {
+        return Some(first.value);
+    }
+    if beat >= last.beat {
+        return Some(last.value);
+    }
+
+    let idx = points.partition_point(|p| p.beat <= beat);
+    let a = points[idx - 1];
+    let b = points[idx];
+    let span = b.beat - a.beat;
+    if span <= f32::EPSILON {
+        return Some(b.value);
+    }
+    Some(a.value + (b.value - a.value) * (beat - a.beat) / span)
+}
+
+impl AutomationLane {
+    pub fn value_at(&self, beat: f32) -> Option<f32> {
+        value_at(&self.points, beat)
+    }
+
+    /// Writes a point, overwriting everything passed since the previous write of this pass.
+    pub fn write(&mut self, beat: f32, value: f32) {
+        // the playhead jumped back, start a new pass
+        if self.last_write.is_some_and(|b| b > beat) {
+            self.last_write = None;
+        }
+
+        match self.last_write {
+            Some(from) => self.points.retain(|p| p.beat <= from || p.beat > beat),
+            None => self.points.retain(|p| p.beat != beat),
+        }
+
+        let idx = self.points.partition_point(|p| p.beat <= beat);
+        self.points.insert(idx, AutomationPoint { beat, value });
+
+        self.last_write = Some(beat);
+        self.last_value = value;
+    }
+
+    /// Ends the current write pass.
+    pub fn punch_out(&mut self) {
+        self.last_write = None;
+    }
+}
+
+/// The lanes one plugin plays back, as (parameter index, points).
+#[derive(Default)]
+pub struct PluginLanes {
+    lanes: Vec<(i32, Vec<AutomationPoint>)>,
+}
+
+impl PluginLanes {
+    /// Parameter values at `beat`.
+    pub fn values_at(&self, beat: f32) -> impl Iterator<Item = (i32, f32)> + '_ {
+        self.lanes
+            .iter()
+            .filter_map(move |(index, points)| Some((*index, value_at(points, beat)?)))
+    }
+}
+
+#[derive(Default)]
+struct Handoff {
+    ready: Option<Box<PluginLanes>>,
+    /// The lanes replaced last, freed here rather than on the audio thread.
+    retired: Option<Box<PluginLanes>>,
+}
+
+/// Hands a plugin the lanes it plays back whenever they change. The audio
+/// thread only ever tries the lock and keeps its lanes when it's taken.
+#[derive(Default)]
+pub struct LaneFeed {
+    handoff: Mutex<Handoff>,
+}
+
+impl LaneFeed {
+    /// Swaps in lanes published since the last call.
+    pub fn update(&self, current: &mut Box<PluginLanes>) {
+        if let Ok(mut handoff) = self.handoff.try_lock()
+            && let Some(ready) = handoff.ready.take()
+        {
+            handoff.retired = Some(std::mem::replace(current, ready));
+        }
+    }
+
+    fn publish(&self, lanes: PluginLanes) {
+        let mut handoff = self.handoff.lock().unwrap();
+        handoff.retired = None;
+        handoff.ready = Some(Box::new(lanes));
+    }
+}
+
+/// Room for gestures between two drains of a `GestureQueue`.
+const GESTURE_CAPACITY: usize = 1024;
+
+/// A parameter change or touch reported by a plugin, at the beat of the
+/// block it was processing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gesture {
+    Begin(LaneKey),
+    Automate {
+        key: LaneKey,
+        value: f32,
+        beat: f32,
+        is_playing: bool,
+    },
+    End(LaneKey, f32),
+}
+
+/// Gestures on their way from plugin callbacks, which may come on the audio
+/// thread, to the store. Pushing only ever tries the lock and drops the
+/// gesture when it's taken or the queue is full.
+pub struct GestureQueue {
+    gestures: Mutex<Vec<Gesture>>,
+}
+
+impl Default for GestureQueue {
+    fn default() -> Self {
+        Self {
+            gestures: Mutex::new(Vec::with_capacity(GESTURE_CAPACITY)),
+        }
+    }
+}
+
+impl GestureQueue {
+    /// False when the gesture was dropped.
+    pub fn push(&self, gesture: Gesture) -> bool {
+        let Ok(mut gestures) = self.gestures.try_lock() else {
+            return false;
+        };
+        if gestures.len() >= GESTURE_CAPACITY {
+            return false;
+        }
+        gestures.push(gesture);
+        true
+    }
+
+    /// Applies the queued gestures in the order they came.
+    pub fn drain_into(&self, store: &mut AutomationStore) {
+        let gestures = std::mem::replace(
+            &mut *self.gestures.lock().unwrap(),
+            Vec::with_capacity(GESTURE_CAPACITY),
+        );
+        for gesture in gestures {
+            store.apply(gesture);
+        }
+    }
+}
+
+pub struct AutomationStore {
+    pub mode: AutomationMode,
+    lanes: HashMap<LaneKey, AutomationLane>,
+    /// parameters currently held by the user (between begin_edit and end_edit)
+    touching: HashSet<LaneKey>,
+    /// parameters that keep writing until the transport stops
+    latched: HashSet<LaneKey>,
+    /// Playback of each loaded plugin, republished whenever what it reads changes.
+    feeds: HashMap<u32, Arc<LaneFeed>>,
+}
+
+impl Default for AutomationStore {
+    fn default() -> Self {
+        Self {
+            mode: AutomationMode::Read,
+            lanes: HashMap::new(),
+            touching: HashSet::new(),
+            latched: HashSet::new(),
+            feeds: HashMap::new(),
+        }
+    }
+}
+
+impl AutomationStore {
+    /// Switches mode at `beat`, ending every write pass there.
+    pub fn set_mode(&mut self, mode: AutomationMode, beat: f32) {
+        self.advance(beat);
+        self.mode = mode;
+        self.punch_out_all();
+        self.publish_all();
+    }
+
+    pub fn lane(&self, key: LaneKey) -> Option<&AutomationLane> {
+        self.lanes.get(&key)
+    }
+
+    /// Replaces the points of a lane, sorted by beat.
+    pub fn set_lane(&mut self, key: LaneKey, mut points: Vec<AutomationPoint>) {
+        points.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+        self.lanes.entry(key).or_default().points = points;
+        self.publish(key.0);
+    }
+
+    pub fn clear_lane(&mut self, key: LaneKey) {
+        self.lanes.remove(&key);
+        self.publish(key.0);
+    }
+
+    /// The feed a plugin instance plays its lanes from.
+    pub fn feed(&mut self, plugin_id: u32) -> Arc<LaneFeed> {
+        let feed = self.feeds.entry(plugin_id).or_default().clone();
+        self.publish(plugin_id);
+        feed
+    }
+
+    pub fn remove_feed(&mut self, plugin_id: u32) {
+        self.feeds.remove(&plugin_id);
+    }
+
+    pub fn begin_edit(&mut self, key: LaneKey) {
+        self.touching.insert(key);
+        if self.mode == AutomationMode::Latch {
+            self.latched.insert(key);
+        }
+        self.publish(key.0);
+    }
+
+    /// Releases a parameter at `beat`; in touch mode that ends its write pass.
+    pub fn end_edit(&mut self, key: LaneKey, beat: f32) {
+        if self.mode == AutomationMode::Touch {
+            self.advance(beat);
+        }
+        self.touching.remove(&key);
+        if self.mode == AutomationMode::Touch
+            && let Some(lane) = self.lanes.get_mut(&key)
+        {
+            lane.punch_out();
+        }
+        self.publish(key.0);
+    }
+
+    pub fn apply(&mut self, gesture: Gesture) {
+        match gesture {
+            Gesture::Begin(key) => self.begin_edit(key),
+            Gesture::Automate {
+                key,
+                value,
+                beat,
+                is_playing,
+            } => self.automate(key, value, beat, is_playing),
+            Gesture::End(key, beat) => self.end_edit(key, beat),
+        }
+    }
+
+    /// Called when a plugin reports a parameter change.
+    pub fn automate(&mut self, key: LaneKey, value: f32, beat: f32, is_playing: bool) {
+        if !is_playing || !self.is_recording(key) {
+            return;
+        }
+        self.lanes.entry(key).or_default().write(beat, value);
+    }
+
+    pub fn is_recording(&self, key: LaneKey) -> bool {
+        match self.mode {
+            AutomationMode::Write => true,
+            AutomationMode::Touch => self.touching.contains(&key),
+            AutomationMode::Latch => self.touching.contains(&key) || self.latched.contains(&key),
+            AutomationMode::Off | AutomationMode::Read => false,
+        }
+    }
+
+    /// Writes the held value of every recording lane at `beat`, so a parameter
+    /// that was held without moving still overwrites the points it passed.
+    fn advance(&mut self, beat: f32) {
+        for (key, lane) in self.lanes.iter_mut() {
+            let recording = match self.mode {
+                AutomationMode::Write => lane.last_write.is_some(),
+                AutomationMode::Touch => self.touching.contains(key),
+                AutomationMode::Latch => self.latched.contains(key),
+                AutomationMode::Off | AutomationMode::Read => false,
+            };
+            if recording && lane.last_write.is_some_and(|b| b < beat) {
+                let value = lane.last_value;
+                lane.write(beat, value);
+            }
+        }
+    }
+
+    /// Whether a lane plays back, which it doesn't while being recorded.
+    fn plays_back(&self, key: LaneKey) -> bool {
+        !matches!(self.mode, AutomationMode::Off | AutomationMode::Write) && !self.is_recording(key)
+    }
+
+    /// All lanes belonging to one plugin instance.
+    pub fn lanes_of(&self, plugin_id: u32) -> impl Iterator<Item = (i32, &AutomationLane)> {
+        self.lanes
+            .iter()
+            .filter(move |((id, _), _)| *id == plugin_id)
+            .map(|((_, index), lane)| (*index, lane))
+    }
+
+    /// Ends every write pass at the beat the transport stopped at.
+    pub fn on_transport_stop(&mut self, beat: f32) {
+        self.advance(beat);
+        self.latched.clear();
+        self.punch_out_all();
+        self.publish_all();
+    }
+
+    /// Hands the plugin the lanes it should play back now.
+    fn publish(&self, plugin_id: u32) {
+        let Some(feed) = self.feeds.get(&plugin_id) else {
+            return;
+        };
+        let lanes = self
+            .lanes_of(plugin_id)
+            .filter(|(index, _)| self.plays_back((plugin_id, *index)))
+            .map(|(index, lane)| (index, lane.points.clone()))
+            .collect();
+        feed.publish(PluginLanes { lanes });
+    }
+
+    fn publish_all(&self) {
+        for &plugin_id in self.feeds.keys() {
+            self.publish(plugin_id);
+        }
+    }
+
+    fn punch_out_all(&mut self) {
+        for lane in self.lanes.values_mut() {
+            lane.punch_out();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(beat: f32, value: f32) -> AutomationPoint {
+        AutomationPoint { beat, value }
+    }
+
+    fn lane(points: &[(f32, f32)]) -> AutomationLane {
+        AutomationLane {
+            points: points.iter().map(|&(b, v)| point(b, v)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn beats(lane: &AutomationLane) -> Vec<f32> {
+        lane.points.iter().map(|p| p.beat).collect()
+    }
+
+    #[test]
+    fn value_at_interpolates_and_holds_the_ends() {
+        let lane = lane(&[(1.0, 0.0), (2.0, 1.0), (2.0, 0.5), (3.0, 0.5)]);
+        assert_eq!(AutomationLane::default().value_at(1.0), None);
+        assert_eq!(lane.value_at(0.0), Some(0.0));
+        assert_eq!(lane.value_at(1.5), Some(0.5));
+        assert_eq!(lane.value_at(2.5), Some(0.5));
+        assert_eq!(lane.value_at(9.0), Some(0.5));
+    }
+
+    #[test]
+    fn value_at_steps_on_points_sharing_a_beat() {
+        let lane = lane(&[(0.0, 0.0), (1.0, 0.2), (1.0, 0.8), (2.0, 0.8)]);
+        assert_eq!(lane.value_at(0.5), Some(0.1));
+        assert_eq!(lane.value_at(1.0), Some(0.8));
+    }
+
+    #[test]
+    fn write_overwrites_what_the_pass_went_over() {
+        let mut lane = lane(&[(0.0, 0.1), (1.0, 0.2), (1.5, 0.3), (2.0, 0.4), (3.0, 0.5)]);
+        lane.write(0.5, 0.9);
+        lane.write(2.0, 0.7);
+        assert_eq!(beats(&lane), [0.0, 0.5, 2.0, 3.0]);
+        assert_eq!(lane.value_at(2.0), Some(0.7));
+        assert_eq!(lane.value_at(3.0), Some(0.5));
+    }
+
+    #[test]
+    fn write_starts_a_new_pass_after_a_jump_back() {
+        let mut lane = lane(&[(0.0, 0.1), (0.75, 0.2), (4.0, 0.3)]);
+        lane.write(2.0, 0.5);
+        lane.write(3.0, 0.6);
+        // back before the pass, the points between stay
+        lane.write(0.5, 0.9);
+        assert_eq!(beats(&lane), [0.0, 0.5, 0.75, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn write_after_punch_out_only_replaces_its_beat() {
+        let mut lane = lane(&[(0.0, 0.1), (1.0, 0.2), (2.0, 0.3)]);
+        lane.write(0.5, 0.4);
+        lane.punch_out();
+        lane.write(2.0, 0.9);
+        assert_eq!(beats(&lane), [0.0, 0.5, 1.0, 2.0]);
+        assert_eq!(lane.value_at(2.0), Some(0.9));
+    }
+
+    fn played(feed: &LaneFeed, lanes: &mut Box<PluginLanes>, beat: f32) -> Vec<(i32, f32)> {
+        feed.update(lanes);
+        lanes.values_at(beat).collect()
+    }
+
+    #[test]
+    fn feed_leaves_out_lanes_being_recorded() {
+        let mut store = AutomationStore::default();
+        let feed = store.feed(7);
+        let mut lanes = Box::default();
+        store.set_lane((7, 0), vec![point(0.0, 0.25)]);
+        store.set_lane((7, 1), vec![point(0.0, 0.5)]);
+        store.set_lane((8, 0), vec![point(0.0, 1.0)]);
+        store.set_mode(AutomationMode::Touch, 0.0);
+
+        let mut values = played(&feed, &mut lanes, 1.0);
+        values.sort_by_key(|&(index, _)| index);
+        assert_eq!(values, [(0, 0.25), (1, 0.5)]);
+
+        store.begin_edit((7, 1));
+        assert_eq!(played(&feed, &mut lanes, 1.0), [(0, 0.25)]);
+    }
+
+    #[test]
+    fn touch_writes_the_held_value_up_to_the_release() {
+        let mut store = AutomationStore::default();
+        let feed = store.feed(1);
+        let mut lanes = Box::default();
+        store.set_lane(
+            (1, 0),
+            vec![point(0.0, 0.0), point(2.0, 0.2), point(5.0, 0.5)],
+        );
+        store.set_mode(AutomationMode::Touch, 0.0);
+
+        store.begin_edit((1, 0));
+        store.automate((1, 0), 0.8, 1.0, true);
+        store.end_edit((1, 0), 3.0);
+
+        assert_eq!(beats(store.lane((1, 0)).unwrap()), [0.0, 1.0, 3.0, 5.0]);
+        assert_eq!(played(&feed, &mut lanes, 3.0), [(0, 0.8)]);
+        assert_eq!(played(&feed, &mut lanes, 4.0), [(0, 0.65)]);
+    }
+
+    #[test]
+    fn latch_keeps_writing_until_the_transport_stops() {
+        let mut store = AutomationStore::default();
+        store.set_mode(AutomationMode::Latch, 0.0);
+        store.begin_edit((1, 0));
+        store.automate((1, 0), 0.3, 1.0, true);
+        store.end_edit((1, 0), 1.5);
+        assert!(store.is_recording((1, 0)));
+
+        store.on_transport_stop(4.0);
+        assert!(!store.is_recording((1, 0)));
+        assert_eq!(beats(store.lane((1, 0)).unwrap()), [1.0, 4.0]);
+    }
+
+    #[test]
+    fn queued_gestures_record_like_direct_ones() {
+        let queue = GestureQueue::default();
+        let mut store = AutomationStore::default();
+        store.set_mode(AutomationMode::Touch, 0.0);
+        assert!(queue.push(Gesture::Begin((1, 0))));
+        assert!(queue.push(Gesture::Automate {
+            key: (1, 0),
+            value: 0.8,
+            beat: 1.0,
+            is_playing: true,
+        }));
+        assert!(queue.push(Gesture::End((1, 0), 3.0)));
+        assert!(store.lane((1, 0)).is_none());
+
+        queue.drain_into(&mut store);
+        assert_eq!(beats(store.lane((1, 0)).unwrap()), [1.0, 3.0]);
+        assert!(!store.is_recording((1, 0)));
+    }
+
+    #[test]
+    fn full_or_busy_queues_drop_gestures() {
+        let queue = GestureQueue::default();
+        for _ in 0..GESTURE_CAPACITY {
+            assert!(queue.push(Gesture::Begin((1, 0))));
+        }
+        assert!(!queue.push(Gesture::Begin((1, 0))));
+
+        let mut store = AutomationStore::default();
+        queue.drain_into(&mut store);
+        let held = queue.gestures.lock().unwrap();
+        assert!(!queue.push(Gesture::Begin((1, 0))));
+        assert!(held.is_empty());
+        assert_eq!(held.capacity(), GESTURE_CAPACITY);
+    }
+}
@@ -0,0 +1,208 @@
+// Hosts one plugin for the engine's bridged mode. Blocks arrive through the
+// shared memory rings in `shm_bridge.rs`; a crash here only takes down this process.
+
+use std::{
+    env,
+    io::Read,
+    path::Path,
+    process::ExitCode,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use vst::{
+    api,
+    buffer::SendEventBuffer,
+    event::{Event, MidiEvent},
+    host::{Host, HostBuffer, PluginLoader},
+    plugin::Plugin,
+};
+
+#[allow(dead_code)]
+#[path = "../shm_bridge.rs"]
+mod shm_bridge;
+
+use shm_bridge::{
+    BlockRequest, MAX_BLOCK, MAX_CHANNELS, MAX_MIDI_EVENTS, MidiEventRecord, READY_FAILED,
+    READY_OK, SharedBridge,
+};
+
+#[derive(Default)]
+struct BridgeHost {
+    /// MIDI the plugin sent during the current block.
+    midi_out: Mutex<Vec<MidiEventRecord>>,
+}
+
+impl Host for BridgeHost {
+    fn process_events(&self, events: &api::Events) {
+        let mut midi_out = self.midi_out.lock().unwrap();
+        for event in events.events() {
+            if let Event::Midi(midi) = event {
+                midi_out.push(MidiEventRecord {
+                    delta_frames: midi.delta_frames.max(0) as u32,
+                    data: midi.data,
+                    _pad: 0,
+                });
+            }
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 5 {
+        eprintln!("usage: muek_plugin_bridge <plugin path> <shm path> <sample rate> <block size>");
+        return ExitCode::from(2);
+    }
+    let mut sample_rate: f32 = args[3].parse().unwrap_or(44100.0);
+    let mut block_size: i64 = args[4].parse().unwrap_or(512);
+
+    let shm = match SharedBridge::open(Path::new(&args[2]), false) {
+        Ok(shm) => shm,
+        Err(e) => {
+            eprintln!("Failed to open bridge: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let host = Arc::new(Mutex::new(BridgeHost::default()));
+    let instance = PluginLoader::load(Path::new(&args[1]), host.clone())
+        .map_err(|e| e.to_string())
+        .and_then(|mut loader| loader.instance().map_err(|e| e.to_string()));
+    let mut plugin = match instance {
+        Ok(plugin) => plugin,
+        Err(e) => {
+            eprintln!("Failed to load plugin: {}", e);
+            shm.set_ready(READY_FAILED, 0, 0, 0);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    plugin.init();
+    plugin.set_sample_rate(sample_rate);
+    plugin.set_block_size(block_size);
+    plugin.resume();
+
+    let info = plugin.get_info();
+    let ins = (info.inputs.max(0) as usize).min(MAX_CHANNELS);
+    let outs = (info.outputs.max(0) as usize).min(MAX_CHANNELS);
+    shm.set_ready(
+        READY_OK,
+        ins as u32,
+        outs as u32,
+        info.initial_delay.max(0) as u32,
+    );
+
+    // the engine holds our stdin, EOF means it is gone
+    let host_alive = Arc::new(AtomicBool::new(true));
+    {
+        let host_alive = host_alive.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            let mut stdin = std::io::stdin();
+            while matches!(stdin.read(&mut buf), Ok(n) if n > 0) {}
+            host_alive.store(false, Ordering::Release);
+        });
+    }
+
+    let mut host_buffer: HostBuffer<f32> = HostBuffer::new(ins, outs);
+    let mut send_events = SendEventBuffer::new(MAX_MIDI_EVENTS);
+    let mut inputs = vec![vec![0.0f32; MAX_BLOCK]; ins];
+    let mut outputs = vec![vec![0.0f32; MAX_BLOCK]; outs];
+    let mut interleaved = vec![0.0f32; MAX_BLOCK * ins.max(outs).max(1)];
+    let mut midi = vec![MidiEventRecord::default(); MAX_MIDI_EVENTS];
+
+    let mut idle = 0u32;
+    while !shm.is_shutdown() && host_alive.load(Ordering::Acquire) {
+        let mut request = [BlockRequest::default()];
+        if !shm.control.pop_slice(&mut request) {
+            // spin briefly for low latency, then back off
+            idle += 1;
+            if idle < 2000 {
+                thread::yield_now();
+            } else {
+                thread::sleep(Duration::from_millis(1));
+            }
+            continue;
+        }
+        idle = 0;
+
+        let frames = (request[0].frames as usize).min(MAX_BLOCK);
+        let events = (request[0].midi_events as usize).min(MAX_MIDI_EVENTS);
+
+        // the engine's device may have changed rate, or handed us a bigger block
+        let rate_changed = request[0].sample_rate > 0.0 && request[0].sample_rate != sample_rate;
+        if rate_changed || frames as i64 > block_size {
+            if rate_changed {
+                sample_rate = request[0].sample_rate;
+            }
+            block_size = block_size.max(frames as i64);
+            plugin.suspend();
+            plugin.set_sample_rate(sample_rate);
+            plugin.set_block_size(block_size);
+            plugin.resume();
+        }
+
+        shm.midi_in.pop_slice(&mut midi[..events]);
+        shm.audio_in.pop_slice(&mut interleaved[..frames * ins]);
+
+        for f in 0..frames {
+            for (ch, input) in inputs.iter_mut().enumerate() {
+                input[f] = interleaved[f * ins + ch];
+            }
+        }
+
+        if events > 0 {
+            send_events.send_events_to_plugin(
+                midi[..events].iter().map(|m| MidiEvent {
+                    data: m.data,
+                    delta_frames: m.delta_frames as i32,
+                    live: true,
+                    note_length: None,
+                    note_offset: None,
+                    detune: 0,
+                    note_off_velocity: 0,
+                }),
+                &mut plugin,
+            );
+        }
+
+        {
+            let ins_slices: Vec<&[f32]> = inputs.iter().map(|c| &c[..frames]).collect();
+            let mut outs_slices: Vec<&mut [f32]> =
+                outputs.iter_mut().map(|c| &mut c[..frames]).collect();
+            let mut audio_buffer = host_buffer.bind(&ins_slices, &mut outs_slices);
+            plugin.process(&mut audio_buffer);
+        }
+
+        // MIDI goes first, the engine reads it once the audio has arrived
+        {
+            let host = host.lock().unwrap();
+            let mut midi_out = host.midi_out.lock().unwrap();
+            let len = midi_out.len().min(MAX_MIDI_EVENTS);
+            if !shm.midi_out.push_slice(&midi_out[..len]) {
+                eprintln!("MIDI output ring full, dropping {} events", len);
+            }
+            midi_out.clear();
+        }
+
+        for f in 0..frames {
+            for (ch, output) in outputs.iter().enumerate() {
+                interleaved[f * outs + ch] = output[f];
+            }
+        }
+        while !shm.audio_out.push_slice(&interleaved[..frames * outs]) {
+            if shm.is_shutdown() {
+                break;
+            }
+            thread::yield_now();
+        }
+    }
+
+    plugin.suspend();
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,60 @@
+// Loads a single plugin and prints its metadata as `key=value` lines.
+// Runs as a child process of the scanner so a crashing plugin only kills the probe.
+
+use std::{
+    env,
+    path::Path,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+};
+
+use vst::{
+    host::{Host, PluginLoader},
+    plugin::{Category, Plugin},
+};
+
+struct ProbeHost;
+
+impl Host for ProbeHost {}
+
+fn main() -> ExitCode {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("usage: muek_plugin_probe <plugin path>");
+        return ExitCode::from(2);
+    };
+
+    let host = Arc::new(Mutex::new(ProbeHost));
+
+    let mut loader = match PluginLoader::load(Path::new(&path), host) {
+        Ok(loader) => loader,
+        Err(e) => {
+            eprintln!("Failed to load plugin: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut plugin = match loader.instance() {
+        Ok(plugin) => plugin,
+        Err(e) => {
+            eprintln!("Failed to create instance: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    plugin.init();
+    let info = plugin.get_info();
+
+    println!("name={}", info.name);
+    println!("vendor={}", info.vendor);
+    println!("category={:?}", info.category);
+    println!("version={}", info.version);
+    println!("unique_id={}", info.unique_id);
+    println!(
+        "is_instrument={}",
+        matches!(info.category, Category::Synth) as u8
+    );
+    println!("inputs={}", info.inputs);
+    println!("outputs={}", info.outputs);
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,157 @@
+// Bounds-checked reading of the binary formats the engine loads: plugin and
+// effect states, fxp/fxb presets and MIDI files. A short or hostile input
+// fails with the format's own error instead of panicking or allocating what
+// its length fields claim.
+
+use anyhow::anyhow;
+
+#[derive(Clone, Copy)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    endian: Endian,
+    /// Returned when a read runs past the end, e.g. "preset file truncated".
+    truncated: &'static str,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8], endian: Endian, truncated: &'static str) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            endian,
+            truncated,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    /// Bytes left to read.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.pos)
+    }
+
+    pub fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow!(self.truncated))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    /// `count` items of `size` bytes, checked before anything is allocated for them.
+    pub fn take_items(&mut self, count: usize, size: usize) -> anyhow::Result<&'a [u8]> {
+        let len = count
+            .checked_mul(size)
+            .ok_or_else(|| anyhow!(self.truncated))?;
+        self.take(len)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn peek(&self) -> anyhow::Result<u8> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| anyhow!(self.truncated))
+    }
+
+    pub fn byte(&mut self) -> anyhow::Result<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        let bytes = self.array()?;
+        Ok(match self.endian {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        })
+    }
+
+    pub fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// A non-negative size field stored as `i32`.
+    pub fn size(&mut self) -> anyhow::Result<usize> {
+        let len = self.i32()?;
+        usize::try_from(len).map_err(|_| anyhow!("negative size {}", len))
+    }
+
+    /// `count` values of `f32`, as from `take_items`.
+    pub fn f32s(&mut self, count: usize) -> anyhow::Result<Vec<f32>> {
+        let endian = self.endian;
+        let bytes = self.take_items(count, 4)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| {
+                let b = b.try_into().unwrap();
+                match endian {
+                    Endian::Little => f32::from_le_bytes(b),
+                    Endian::Big => f32::from_be_bytes(b),
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_both_byte_orders() {
+        let bytes = [0, 0, 0x80, 0x3f, 0x12, 0x34, 0x56, 0x78];
+        let mut little = ByteReader::new(&bytes, Endian::Little, "short");
+        assert_eq!(little.f32().unwrap(), 1.0);
+        assert_eq!(little.u32().unwrap(), 0x7856_3412);
+        assert!(little.is_empty());
+
+        let mut big = ByteReader::new(&bytes[4..], Endian::Big, "short");
+        assert_eq!(big.i32().unwrap(), 0x1234_5678);
+    }
+
+    #[test]
+    fn short_reads_fail_with_the_formats_message() {
+        let mut reader = ByteReader::new(&[1, 2, 3], Endian::Big, "preset file truncated");
+        let error = reader.u32().unwrap_err();
+        assert_eq!(error.to_string(), "preset file truncated");
+        // a failed read consumes nothing
+        assert_eq!(reader.remaining(), 3);
+        assert_eq!(reader.take(3).unwrap(), [1, 2, 3]);
+        assert!(reader.byte().is_err());
+        assert!(reader.peek().is_err());
+    }
+
+    #[test]
+    fn huge_counts_fail_before_allocating() {
+        let mut reader = ByteReader::new(&[0; 8], Endian::Little, "short");
+        assert!(reader.take_items(usize::MAX, 4).is_err());
+        assert!(reader.f32s(usize::MAX / 2).is_err());
+        assert_eq!(reader.f32s(2).unwrap(), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn negative_sizes_are_refused() {
+        let mut reader = ByteReader::new(&[0xff; 4], Endian::Big, "short");
+        assert!(reader.size().is_err());
+    }
+}
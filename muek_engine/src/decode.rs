@@ -252,4 +252,20 @@ pub fn auto_decode(path: &str) -> Option<(Vec<f32>, usize, u32)> {
     } else {
         symphonia_decode(path)
     }
-}
\ No newline at end of file
+}
+
+/// Raw 16 bit little endian PCM, as stored inside SoundFont banks. `low` holds
+/// the extra least significant byte of each sample in 24 bit banks.
+pub fn pcm16_decode(data: &[u8], low: Option<&[u8]>) -> Vec<f32> {
+    let frames = data.len() / 2;
+    let low = low.filter(|low| low.len() >= frames);
+    (0..frames)
+        .map(|i| {
+            let high = i16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) as i32;
+            match low {
+                Some(low) => ((high << 8) | low[i] as i32) as f32 / 8_388_608.0,
+                None => high as f32 / 32768.0,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,95 @@
+// Spectrum feed for the UI. The audio thread writes into a lock-free ring;
+// the FFT runs on whichever thread asks for a frame.
+
+use std::{
+    f32::consts::TAU,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use rustfft::{FftPlanner, num_complex::Complex};
+
+/// Samples per analysis frame.
+pub const SPECTRUM_SIZE: usize = 8192;
+
+/// Lowest and highest frequency shown by the UI.
+pub const MIN_FREQ: f32 = 20.0;
+pub const MAX_FREQ: f32 = 20000.0;
+
+/// `points` frequencies spaced evenly on a log scale over the audible range.
+pub fn log_frequencies(points: usize) -> Vec<f32> {
+    let ratio = (MAX_FREQ / MIN_FREQ).ln();
+    (0..points)
+        .map(|i| MIN_FREQ * (ratio * i as f32 / (points.max(2) - 1) as f32).exp())
+        .collect()
+}
+
+pub struct Spectrum {
+    ring: Vec<AtomicU32>,
+    pos: AtomicUsize,
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        Self {
+            ring: (0..SPECTRUM_SIZE).map(|_| AtomicU32::new(0)).collect(),
+            pos: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Spectrum {
+    /// Feeds the mono sum of `buffer`.
+    pub fn push(&self, buffer: &[Vec<f32>]) {
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        let scale = 1.0 / buffer.len().max(1) as f32;
+        let mut pos = self.pos.load(Ordering::Relaxed);
+        for f in 0..frames {
+            let sample: f32 = buffer.iter().map(|ch| ch[f]).sum::<f32>() * scale;
+            self.ring[pos].store(sample.to_bits(), Ordering::Relaxed);
+            pos = (pos + 1) % SPECTRUM_SIZE;
+        }
+        self.pos.store(pos, Ordering::Release);
+    }
+
+    /// Level in dBFS at each of `freqs`, taking the loudest bin between neighbouring points.
+    pub fn magnitudes(&self, sample_rate: f32, freqs: &[f32]) -> Vec<f32> {
+        let start = self.pos.load(Ordering::Acquire);
+        let mut bins: Vec<Complex<f32>> = (0..SPECTRUM_SIZE)
+            .map(|i| {
+                let sample =
+                    f32::from_bits(self.ring[(start + i) % SPECTRUM_SIZE].load(Ordering::Relaxed));
+                let window = 0.5 - 0.5 * (TAU * i as f32 / SPECTRUM_SIZE as f32).cos();
+                Complex::new(sample * window, 0.0)
+            })
+            .collect();
+        FftPlanner::new()
+            .plan_fft_forward(SPECTRUM_SIZE)
+            .process(&mut bins);
+
+        // a full scale sine reads 0 dB through the Hann window
+        let scale = 4.0 / SPECTRUM_SIZE as f32;
+        let bin_hz = sample_rate / SPECTRUM_SIZE as f32;
+        let last = SPECTRUM_SIZE / 2;
+        let level = |bin: usize| 20.0 * (bins[bin.min(last)].norm() * scale).max(1e-9).log10();
+
+        freqs
+            .iter()
+            .enumerate()
+            .map(|(i, &freq)| {
+                let below = if i > 0 {
+                    (freqs[i - 1] * freq).sqrt()
+                } else {
+                    freq
+                };
+                let above = freqs.get(i + 1).map(|f| (f * freq).sqrt()).unwrap_or(freq);
+                let lo = (below / bin_hz).round() as usize;
+                let hi = (above / bin_hz).round() as usize;
+                if hi > lo {
+                    (lo..hi).map(level).fold(f32::MIN, f32::max)
+                } else {
+                    level((freq / bin_hz).round() as usize)
+                }
+            })
+            .collect()
+    }
+}
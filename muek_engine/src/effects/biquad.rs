@@ -0,0 +1,177 @@
+// Second order IIR sections with the RBJ cookbook designs. Coefficients are
+// computed and run in f64 so low frequency filters stay quiet.
+
+use std::f64::consts::{PI, TAU};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Coeffs {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Default for Coeffs {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Q of stage `stage` of `stages` cascaded sections forming a Butterworth filter.
+pub fn butterworth_q(stage: usize, stages: usize) -> f32 {
+    let order = (stages * 2) as f64;
+    (1.0 / (2.0 * ((2 * stage + 1) as f64 * PI / (2.0 * order)).sin())) as f32
+}
+
+impl Coeffs {
+    pub const IDENTITY: Self = Self {
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+        a1: 0.0,
+        a2: 0.0,
+    };
+
+    /// cos(w0), sin(w0)/2Q, with the frequency kept below Nyquist.
+    fn prewarp(sample_rate: f32, freq: f32, q: f32) -> (f64, f64) {
+        let freq = (freq as f64).clamp(1.0, sample_rate as f64 * 0.49);
+        let w0 = TAU * freq / sample_rate as f64;
+        (w0.cos(), w0.sin() / (2.0 * (q as f64).max(0.01)))
+    }
+
+    fn normalized(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    pub fn peaking(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        let a = 10f64.powf(gain_db as f64 / 40.0);
+        Self::normalized(
+            1.0 + alpha * a,
+            -2.0 * cos,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos,
+            1.0 - alpha / a,
+        )
+    }
+
+    pub fn low_shelf(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        let a = 10f64.powf(gain_db as f64 / 40.0);
+        let k = 2.0 * a.sqrt() * alpha;
+        Self::normalized(
+            a * ((a + 1.0) - (a - 1.0) * cos + k),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - k),
+            (a + 1.0) + (a - 1.0) * cos + k,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - k,
+        )
+    }
+
+    pub fn high_shelf(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        let a = 10f64.powf(gain_db as f64 / 40.0);
+        let k = 2.0 * a.sqrt() * alpha;
+        Self::normalized(
+            a * ((a + 1.0) + (a - 1.0) * cos + k),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - k),
+            (a + 1.0) - (a - 1.0) * cos + k,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - k,
+        )
+    }
+
+    pub fn low_pass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalized(
+            (1.0 - cos) / 2.0,
+            1.0 - cos,
+            (1.0 - cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    pub fn high_pass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalized(
+            (1.0 + cos) / 2.0,
+            -(1.0 + cos),
+            (1.0 + cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    pub fn notch(sample_rate: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::normalized(1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    /// Squared magnitude, for evaluating many sections at the same frequency.
+    pub fn power_at(&self, t: &Trig) -> f64 {
+        let num_re = self.b0 + self.b1 * t.c1 + self.b2 * t.c2;
+        let num_im = -(self.b1 * t.s1 + self.b2 * t.s2);
+        let den_re = 1.0 + self.a1 * t.c1 + self.a2 * t.c2;
+        let den_im = -(self.a1 * t.s1 + self.a2 * t.s2);
+        (num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)
+    }
+}
+
+/// cos and sin of w and 2w for one frequency.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Trig {
+    c1: f64,
+    s1: f64,
+    c2: f64,
+    s2: f64,
+}
+
+impl Trig {
+    pub fn new(sample_rate: f32, freq: f32) -> Self {
+        let w = TAU * freq as f64 / sample_rate as f64;
+        Self {
+            c1: w.cos(),
+            s1: w.sin(),
+            c2: (2.0 * w).cos(),
+            s2: (2.0 * w).sin(),
+        }
+    }
+}
+
+/// Transposed direct form II section.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Biquad {
+    pub coeffs: Coeffs,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        let c = &self.coeffs;
+        let x = x as f64;
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y as f32
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
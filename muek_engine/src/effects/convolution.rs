@@ -0,0 +1,769 @@
+// Convolution reverb. Impulse responses are decoded, trimmed, stretched,
+// resampled and transformed on a loader thread; the audio thread only swaps in
+// the finished convolver. Convolution is overlap-save over partitions of the
+// IR, either all of the block size or growing eightfold from it, with the
+// early segments hiding the latency of the larger ones.
+
+use std::{
+    f64::consts::PI,
+    sync::{
+        Arc, Mutex, OnceLock, Weak,
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, Thread},
+};
+
+use anyhow::anyhow;
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use super::{Effect, ParamInfo, delay::Ring};
+use crate::{
+    decode,
+    mixer::{BlockContext, Buses, DelayLine},
+};
+
+pub const NAME: &str = "Convolution Reverb";
+
+const BLOCK_LABELS: &[&str] = &["64", "128", "256", "512", "1024"];
+
+const CONVOLUTION_PARAMS: &[ParamInfo] = &[
+    ParamInfo::new("Pre-Delay", "ms", 0.0, 500.0, 0.0),
+    ParamInfo::new("Start", "ms", 0.0, 2000.0, 0.0),
+    ParamInfo::new("Length", "%", 1.0, 100.0, 100.0),
+    ParamInfo::new("Stretch", "%", 50.0, 200.0, 100.0),
+    ParamInfo::switch("Normalize", true),
+    ParamInfo::choice("Partitioning", &["Uniform", "Non-Uniform"], 1),
+    ParamInfo::choice("Block", BLOCK_LABELS, 2),
+    ParamInfo::new("Mix", "%", 0.0, 100.0, 30.0),
+];
+
+/// Parameters that change the convolver and so need a rebuild, from `Start` to `Block`.
+const SHAPE_PARAMS: std::ops::Range<usize> = 1..7;
+
+const MAX_PRE_DELAY_MS: f32 = 500.0;
+/// Partitions stop growing at this size.
+const MAX_BLOCK: usize = 8192;
+/// Fade applied where the IR is cut short.
+const FADE_MS: f32 = 10.0;
+
+fn block_size(choice: f32) -> usize {
+    64 << (choice as usize).min(BLOCK_LABELS.len() - 1)
+}
+
+/// A decoded IR file, one `Vec` per channel.
+struct Source {
+    path: String,
+    channels: Vec<Vec<f32>>,
+    sample_rate: u32,
+}
+
+fn decode(path: &str) -> anyhow::Result<Source> {
+    let (samples, channels, sample_rate) =
+        decode::auto_decode(path).ok_or_else(|| anyhow!("cannot decode {}", path))?;
+    if channels == 0 || samples.is_empty() {
+        anyhow::bail!("{} has no audio", path);
+    }
+    // anything beyond stereo is ignored
+    let kept = channels.min(2);
+    let channels = (0..kept)
+        .map(|ch| samples.iter().skip(ch).step_by(channels).copied().collect())
+        .collect();
+    Ok(Source {
+        path: path.to_owned(),
+        channels,
+        sample_rate,
+    })
+}
+
+/// Windowed sinc resampling by `ratio` (output rate over input rate).
+fn resample(input: &[f32], ratio: f64) -> Vec<f32> {
+    if (ratio - 1.0).abs() < 1e-9 {
+        return input.to_vec();
+    }
+    const ZERO_CROSSINGS: f64 = 16.0;
+    let cutoff = ratio.min(1.0);
+    let half = ZERO_CROSSINGS / cutoff;
+    let len = (input.len() as f64 * ratio).ceil() as usize;
+
+    (0..len)
+        .map(|i| {
+            let t = i as f64 / ratio;
+            let first = (t - half).ceil().max(0.0) as usize;
+            let last = ((t + half).floor() as usize).min(input.len() - 1);
+            (first..=last)
+                .map(|k| {
+                    let x = t - k as f64;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * cutoff * x).sin() / (PI * x)
+                    };
+                    let window = 0.5 + 0.5 * (PI * x / half).cos();
+                    input[k] as f64 * sinc * window
+                })
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+/// Settings a convolver is built from.
+#[derive(Clone, Copy, PartialEq)]
+struct Shape {
+    start_ms: f32,
+    length: f32,
+    stretch: f32,
+    normalize: bool,
+    non_uniform: bool,
+    block: usize,
+    sample_rate: f32,
+    channels: usize,
+}
+
+/// Cuts, stretches and resamples the IR to the engine rate.
+fn prepare(source: &Source, shape: &Shape) -> Vec<Vec<f32>> {
+    let rate = source.sample_rate as f32;
+    let total = source.channels[0].len();
+    let start = ((shape.start_ms * 0.001 * rate) as usize).min(total - 1);
+    let len = (((total - start) as f32 * shape.length) as usize).max(1);
+    let fade = ((FADE_MS * 0.001 * rate) as usize).min(len / 2);
+    let ratio = shape.sample_rate as f64 * shape.stretch as f64 / rate as f64;
+
+    let mut channels: Vec<Vec<f32>> = source
+        .channels
+        .iter()
+        .map(|ch| {
+            let mut ir = ch[start..start + len].to_vec();
+            if len < total - start {
+                for (i, sample) in ir[len - fade..].iter_mut().enumerate() {
+                    *sample *= 1.0 - i as f32 / fade as f32;
+                }
+            }
+            resample(&ir, ratio)
+        })
+        .collect();
+
+    if shape.normalize {
+        // unit energy on the louder channel keeps noise-like input at its level
+        let energy = channels
+            .iter()
+            .map(|ch| ch.iter().map(|x| x * x).sum::<f32>())
+            .fold(0.0, f32::max);
+        if energy > 0.0 {
+            let scale = 1.0 / energy.sqrt();
+            channels.iter_mut().flatten().for_each(|x| *x *= scale);
+        }
+    }
+    channels
+}
+
+/// A run of equal partitions, convolving every input channel.
+struct Segment {
+    block: usize,
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    /// Spectra of the partitions by IR channel.
+    kernel: Vec<Vec<Vec<Complex<f32>>>>,
+    /// Last two blocks of input by channel.
+    windows: Vec<Vec<f32>>,
+    /// Spectra of past input blocks by channel, newest at `history_pos`.
+    history: Vec<Vec<Vec<Complex<f32>>>>,
+    history_pos: usize,
+    /// Output of the last block by channel, played while the next one fills.
+    outputs: Vec<Vec<f32>>,
+    fill: usize,
+    spectrum: Vec<Complex<f32>>,
+    sum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Segment {
+    /// `ir` holds the part of each IR channel this segment covers, already offset.
+    fn new(planner: &mut FftPlanner<f32>, block: usize, ir: &[Vec<f32>], channels: usize) -> Self {
+        let size = block * 2;
+        let fft = planner.plan_fft_forward(size);
+        let ifft = planner.plan_fft_inverse(size);
+        let mut scratch = vec![
+            Complex::default();
+            fft.get_inplace_scratch_len()
+                .max(ifft.get_inplace_scratch_len())
+        ];
+
+        let partitions = ir
+            .iter()
+            .map(|ch| ch.len())
+            .max()
+            .unwrap_or(0)
+            .div_ceil(block)
+            .max(1);
+        let kernel = ir
+            .iter()
+            .map(|ch| {
+                (0..partitions)
+                    .map(|p| {
+                        let mut bins = vec![Complex::default(); size];
+                        let part = ch.iter().skip(p * block).take(block);
+                        for (bin, &sample) in bins.iter_mut().zip(part) {
+                            bin.re = sample;
+                        }
+                        fft.process_with_scratch(&mut bins, &mut scratch);
+                        bins
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            block,
+            fft,
+            ifft,
+            kernel,
+            windows: vec![vec![0.0; size]; channels],
+            history: vec![vec![vec![Complex::default(); size]; partitions]; channels],
+            history_pos: 0,
+            outputs: vec![vec![0.0; block]; channels],
+            fill: 0,
+            spectrum: vec![Complex::default(); size],
+            sum: vec![Complex::default(); size],
+            scratch,
+        }
+    }
+
+    /// Adds the segment's output for `frames` of `input` to `output`, `block` frames late.
+    fn process(&mut self, input: &[Vec<f32>], output: &mut [Vec<f32>], frames: usize) {
+        let mut f = 0;
+        while f < frames {
+            let n = (self.block - self.fill).min(frames - f);
+            for ch in 0..self.windows.len() {
+                let window = &mut self.windows[ch][self.block + self.fill..];
+                window[..n].copy_from_slice(&input[ch][f..f + n]);
+                let played = &self.outputs[ch][self.fill..self.fill + n];
+                for (out, y) in output[ch][f..f + n].iter_mut().zip(played) {
+                    *out += y;
+                }
+            }
+            self.fill += n;
+            f += n;
+            if self.fill == self.block {
+                self.convolve();
+            }
+        }
+    }
+
+    /// Runs one block through the frequency domain.
+    fn convolve(&mut self) {
+        let size = self.block * 2;
+        let partitions = self.history[0].len();
+        let scale = 1.0 / size as f32;
+
+        for ch in 0..self.windows.len() {
+            let window = &mut self.windows[ch];
+            let spectrum = &mut self.history[ch][self.history_pos];
+            for (bin, &sample) in spectrum.iter_mut().zip(window.iter()) {
+                *bin = Complex::new(sample, 0.0);
+            }
+            self.fft.process_with_scratch(spectrum, &mut self.scratch);
+            window.copy_within(self.block.., 0);
+
+            let kernel = &self.kernel[ch.min(self.kernel.len() - 1)];
+            self.sum.fill(Complex::default());
+            for (p, part) in kernel.iter().enumerate() {
+                let past = &self.history[ch][(self.history_pos + partitions - p) % partitions];
+                for ((acc, x), h) in self.sum.iter_mut().zip(past).zip(part) {
+                    *acc += x * h;
+                }
+            }
+            self.spectrum.copy_from_slice(&self.sum);
+            self.ifft
+                .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+            for (out, bin) in self.outputs[ch]
+                .iter_mut()
+                .zip(&self.spectrum[self.block..])
+            {
+                *out = bin.re * scale;
+            }
+        }
+
+        self.history_pos = (self.history_pos + 1) % partitions;
+        self.fill = 0;
+    }
+
+    fn reset(&mut self) {
+        self.windows.iter_mut().for_each(|w| w.fill(0.0));
+        self.history
+            .iter_mut()
+            .flatten()
+            .for_each(|bins| bins.fill(Complex::default()));
+        self.outputs.iter_mut().for_each(|o| o.fill(0.0));
+        self.fill = 0;
+    }
+}
+
+/// All segments of one IR, ready for the audio thread.
+pub struct Convolver {
+    shape: Shape,
+    segments: Vec<Segment>,
+}
+
+impl Convolver {
+    fn new(ir: &[Vec<f32>], shape: Shape) -> Self {
+        let mut planner = FftPlanner::new();
+        let first = shape.block;
+        let len = ir.iter().map(|ch| ch.len()).max().unwrap_or(0);
+
+        let mut segments = Vec::new();
+        let (mut offset, mut block) = (0, first);
+        while offset < len {
+            let last = !shape.non_uniform || block * 8 > MAX_BLOCK;
+            let span = if last {
+                len - offset
+            } else {
+                (block * 8).min(len - offset)
+            };
+            // pad the front so the larger block's extra latency lines up with the first
+            let pad = offset + first - block;
+            let part: Vec<Vec<f32>> = ir
+                .iter()
+                .map(|ch| {
+                    let end = (offset + span).min(ch.len());
+                    let mut padded = vec![0.0; pad];
+                    padded.extend_from_slice(&ch[offset.min(end)..end]);
+                    padded
+                })
+                .collect();
+            segments.push(Segment::new(&mut planner, block, &part, shape.channels));
+            offset += span;
+            block *= 8;
+        }
+
+        Self { shape, segments }
+    }
+
+    /// Writes the wet signal for `input` into `output`, `shape.block` frames late.
+    fn process(&mut self, input: &[Vec<f32>], output: &mut [Vec<f32>], frames: usize) {
+        output.iter_mut().for_each(|ch| ch[..frames].fill(0.0));
+        for segment in &mut self.segments {
+            segment.process(input, output, frames);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.segments.iter_mut().for_each(Segment::reset);
+    }
+}
+
+#[derive(Default)]
+struct Handoff {
+    ready: Option<Box<Convolver>>,
+    /// The convolver replaced last, freed here rather than on the audio thread.
+    retired: Option<Box<Convolver>>,
+}
+
+/// The IR of a convolution reverb, shared between the host, the audio thread and the loader.
+pub struct Impulse {
+    /// File the effect should use, empty for none.
+    path: Mutex<String>,
+    source: Mutex<Option<Source>>,
+    /// Shape parameters as set on the audio thread, indexed like `SHAPE_PARAMS`.
+    shape: [AtomicU32; SHAPE_PARAMS.end - SHAPE_PARAMS.start],
+    sample_rate: AtomicU32,
+    channels: AtomicUsize,
+    /// Bumped whenever the convolver has to be rebuilt.
+    generation: AtomicU64,
+    handoff: Mutex<Handoff>,
+    loader: OnceLock<Thread>,
+}
+
+impl Drop for Impulse {
+    fn drop(&mut self) {
+        // lets the loader see that it is no longer needed
+        if let Some(loader) = self.loader.get() {
+            loader.unpark();
+        }
+    }
+}
+
+impl Impulse {
+    fn new() -> Arc<Self> {
+        let impulse = Arc::new(Self {
+            path: Mutex::new(String::new()),
+            source: Mutex::new(None),
+            shape: std::array::from_fn(|i| {
+                AtomicU32::new(CONVOLUTION_PARAMS[SHAPE_PARAMS.start + i].default.to_bits())
+            }),
+            sample_rate: AtomicU32::new(0),
+            channels: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            handoff: Mutex::new(Handoff::default()),
+            loader: OnceLock::new(),
+        });
+
+        let weak = Arc::downgrade(&impulse);
+        match thread::Builder::new()
+            .name("muek-ir-loader".to_owned())
+            .spawn(move || Self::run_loader(weak))
+        {
+            Ok(handle) => {
+                let _ = impulse.loader.set(handle.thread().clone());
+            }
+            Err(e) => eprintln!("Failed to start IR loader: {}", e),
+        }
+        impulse
+    }
+
+    fn run_loader(weak: Weak<Self>) {
+        let mut built = 0;
+        loop {
+            thread::park();
+            let Some(impulse) = weak.upgrade() else {
+                return;
+            };
+            let generation = impulse.generation.load(Ordering::Acquire);
+            if generation != built {
+                built = generation;
+                impulse.rebuild();
+            }
+        }
+    }
+
+    fn request_rebuild(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        if let Some(loader) = self.loader.get() {
+            loader.unpark();
+        }
+    }
+
+    /// Decodes `path` on the calling thread and hands it to the loader.
+    pub fn load(&self, path: &str) -> anyhow::Result<()> {
+        let source = decode(path)?;
+        *self.source.lock().unwrap() = Some(source);
+        *self.path.lock().unwrap() = path.to_owned();
+        self.request_rebuild();
+        Ok(())
+    }
+
+    pub fn path(&self) -> String {
+        self.path.lock().unwrap().clone()
+    }
+
+    /// Channels and length in seconds of the loaded file.
+    pub fn info(&self) -> Option<(usize, f32)> {
+        let source = self.source.lock().unwrap();
+        source.as_ref().map(|s| {
+            (
+                s.channels.len(),
+                s.channels[0].len() as f32 / s.sample_rate as f32,
+            )
+        })
+    }
+
+    fn shape(&self) -> Shape {
+        let value = |idx: usize| {
+            f32::from_bits(self.shape[idx - SHAPE_PARAMS.start].load(Ordering::Relaxed))
+        };
+        Shape {
+            start_ms: value(1),
+            length: value(2) / 100.0,
+            stretch: value(3) / 100.0,
+            normalize: value(4) >= 0.5,
+            non_uniform: value(5) >= 0.5,
+            block: block_size(value(6)),
+            sample_rate: f32::from_bits(self.sample_rate.load(Ordering::Relaxed)),
+            channels: self.channels.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Runs on the loader thread.
+    fn rebuild(&self) {
+        let path = self.path();
+        let mut source = self.source.lock().unwrap();
+        // a restored state names a file that has not been decoded yet
+        if !path.is_empty() && source.as_ref().is_none_or(|s| s.path != path) {
+            match decode(&path) {
+                Ok(decoded) => *source = Some(decoded),
+                Err(e) => {
+                    eprintln!("Failed to load impulse response: {}", e);
+                    return;
+                }
+            }
+        }
+
+        let shape = self.shape();
+        let Some(source) = source.as_ref() else {
+            return;
+        };
+        if shape.sample_rate <= 0.0 || shape.channels == 0 {
+            return;
+        }
+
+        let ir = prepare(source, &shape);
+        let convolver = Box::new(Convolver::new(&ir, shape));
+        let mut handoff = self.handoff.lock().unwrap();
+        handoff.ready = Some(convolver);
+        handoff.retired = None;
+    }
+}
+
+pub struct ConvolutionReverb {
+    impulse: Arc<Impulse>,
+    pre_delay_ms: f32,
+    block: usize,
+    mix: f32,
+    sample_rate: f32,
+    convolver: Option<Box<Convolver>>,
+    pre_delay: Vec<Ring>,
+    dry_delay: DelayLine,
+    wet: Vec<Vec<f32>>,
+}
+
+impl Default for ConvolutionReverb {
+    fn default() -> Self {
+        Self {
+            impulse: Impulse::new(),
+            pre_delay_ms: 0.0,
+            block: block_size(CONVOLUTION_PARAMS[6].default),
+            mix: 0.3,
+            sample_rate: 44100.0,
+            convolver: None,
+            pre_delay: Vec::new(),
+            dry_delay: DelayLine::default(),
+            wet: Vec::new(),
+        }
+    }
+}
+
+impl ConvolutionReverb {
+    fn max_pre_delay(&self) -> usize {
+        (MAX_PRE_DELAY_MS * 0.001 * self.sample_rate) as usize + 1
+    }
+}
+
+impl Effect for ConvolutionReverb {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        CONVOLUTION_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => self.pre_delay_ms = value,
+            7 => self.mix = value / 100.0,
+            _ if SHAPE_PARAMS.contains(&idx) => {
+                if idx == 6 {
+                    self.block = block_size(value);
+                }
+                self.impulse.shape[idx - SHAPE_PARAMS.start]
+                    .store(value.to_bits(), Ordering::Relaxed);
+                self.impulse.request_rebuild();
+            }
+            _ => {}
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        let max_pre_delay = self.max_pre_delay();
+        self.pre_delay
+            .iter_mut()
+            .for_each(|r| r.resize(max_pre_delay));
+        self.impulse
+            .sample_rate
+            .store(sample_rate.to_bits(), Ordering::Relaxed);
+        self.impulse.request_rebuild();
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        let channels = buffer.len();
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        if self.pre_delay.len() != channels {
+            let max_pre_delay = self.max_pre_delay();
+            self.pre_delay.resize_with(channels, || {
+                let mut ring = Ring::default();
+                ring.resize(max_pre_delay);
+                ring
+            });
+            self.impulse.channels.store(channels, Ordering::Relaxed);
+            self.impulse.request_rebuild();
+        }
+        if self.wet.len() != channels || self.wet[0].len() < frames {
+            self.wet = vec![vec![0.0; frames]; channels];
+        }
+
+        if let Ok(mut handoff) = self.impulse.handoff.try_lock()
+            && let Some(ready) = handoff.ready.take()
+        {
+            handoff.retired = self.convolver.replace(ready);
+        }
+
+        // a convolver built for other settings stays silent until its replacement arrives
+        let convolver = self
+            .convolver
+            .as_mut()
+            .filter(|c| c.shape.block == self.block && c.shape.channels == channels);
+        match convolver {
+            Some(convolver) => convolver.process(buffer, &mut self.wet, frames),
+            None => self.wet.iter_mut().for_each(|ch| ch.fill(0.0)),
+        }
+
+        self.dry_delay.set_delay(channels, self.block);
+        self.dry_delay.process(buffer);
+
+        let pre_delay = (self.pre_delay_ms * 0.001 * self.sample_rate) as usize;
+        for ((channel, wet), ring) in buffer.iter_mut().zip(&self.wet).zip(&mut self.pre_delay) {
+            for (sample, &w) in channel.iter_mut().zip(wet) {
+                ring.push(w);
+                *sample = *sample * (1.0 - self.mix) + ring.tap(pre_delay + 1) * self.mix;
+            }
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.block
+    }
+
+    fn reset(&mut self) {
+        self.pre_delay.iter_mut().for_each(Ring::clear);
+        self.dry_delay.reset();
+        if let Some(convolver) = &mut self.convolver {
+            convolver.reset();
+        }
+    }
+
+    /// The path of the IR file.
+    fn save_state(&self) -> Vec<u8> {
+        self.impulse.path().into_bytes()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let path = String::from_utf8(data.to_vec())?;
+        *self.impulse.path.lock().unwrap() = path;
+        self.impulse.request_rebuild();
+        Ok(())
+    }
+
+    fn impulse(&self) -> Option<Arc<Impulse>> {
+        Some(self.impulse.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::tests::{ctx, noise, run};
+
+    fn shape(block: usize, non_uniform: bool, sample_rate: f32) -> Shape {
+        Shape {
+            start_ms: 0.0,
+            length: 1.0,
+            stretch: 1.0,
+            normalize: false,
+            non_uniform,
+            block,
+            sample_rate,
+            channels: 1,
+        }
+    }
+
+    fn source(ir: Vec<f32>, sample_rate: u32) -> Source {
+        Source {
+            path: String::new(),
+            channels: vec![ir],
+            sample_rate,
+        }
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let ir = noise(3000, 11);
+        let input = noise(6000, 23);
+        for (block, non_uniform) in [(64, false), (64, true), (256, false), (256, true)] {
+            let mut convolver = Convolver::new(
+                std::slice::from_ref(&ir),
+                shape(block, non_uniform, 48000.0),
+            );
+            let mut output = Vec::new();
+            // blocks that straddle the partitions
+            for chunk in input.chunks(100) {
+                let mut wet = vec![vec![0.0; chunk.len()]];
+                convolver.process(&[chunk.to_vec()], &mut wet, chunk.len());
+                output.extend(wet.remove(0));
+            }
+
+            for (n, &y) in output.iter().enumerate() {
+                let expected: f32 = (0..ir.len().min((n + 1).saturating_sub(block)))
+                    .map(|k| ir[k] * input[n - block - k])
+                    .sum();
+                assert!(
+                    (y - expected).abs() < 1e-3,
+                    "{} {} at {}",
+                    block,
+                    non_uniform,
+                    n
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn start_and_length_trim_the_ir() {
+        let ir: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+        let shape = Shape {
+            start_ms: 100.0,
+            length: 0.5,
+            ..shape(64, false, 1000.0)
+        };
+        let prepared = prepare(&source(ir, 1000), &shape);
+        assert_eq!(prepared[0].len(), 450);
+        assert_eq!(prepared[0][..5], [100.0, 101.0, 102.0, 103.0, 104.0]);
+        // faded out over the last 10 ms
+        assert_eq!(prepared[0][439], 539.0);
+        assert!(prepared[0][449] < 55.0);
+    }
+
+    #[test]
+    fn resampling_scales_the_ir_length() {
+        let ir = noise(4410, 5);
+        for (rate, stretch, expected) in [
+            (44100.0, 1.0, 4410),
+            (48000.0, 1.0, 4800),
+            (96000.0, 1.0, 9600),
+            (22050.0, 1.0, 2205),
+            (44100.0, 2.0, 8820),
+        ] {
+            let shape = Shape {
+                stretch,
+                ..shape(64, false, rate)
+            };
+            let prepared = prepare(&source(ir.clone(), 44100), &shape);
+            assert_eq!(prepared[0].len(), expected, "{} {}", rate, stretch);
+        }
+    }
+
+    #[test]
+    fn pre_delay_follows_the_convolver_latency() {
+        let rate = 1000.0;
+        let mut reverb = ConvolutionReverb::default();
+        reverb.set_sample_rate(rate);
+        for (idx, value) in [(0, 20.0), (6, 0.0), (7, 100.0)] {
+            reverb.set_param(idx, value);
+        }
+        let ir = vec![0.0, 0.0, 0.0, 1.0];
+        reverb.convolver = Some(Box::new(Convolver::new(&[ir], shape(64, true, rate))));
+
+        let ctx = ctx(rate, 120.0);
+        let mut output = Vec::new();
+        for start in (0..256).step_by(32) {
+            let mut buffer = vec![vec![0.0; 32]];
+            if start == 0 {
+                buffer[0][0] = 1.0;
+            }
+            run(&mut reverb, &mut buffer, &[], &ctx);
+            output.extend(buffer.remove(0));
+        }
+        let at = output.iter().position(|&x| x != 0.0).unwrap();
+        assert_eq!(at, reverb.latency() + 20 + 3);
+        assert!((output[at] - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,359 @@
+// Stereo delay. Times are free in milliseconds or follow the engine tempo as
+// note values; the feedback path is band limited and can bounce between the
+// channels. The first two channels are the stereo pair, others follow the right.
+
+use std::f32::consts::{FRAC_PI_2, TAU};
+
+use super::{
+    Effect, ParamInfo,
+    biquad::{Biquad, Coeffs},
+};
+use crate::mixer::{BlockContext, Buses};
+
+/// Ring buffer read at a fractional distance behind the last written sample.
+#[derive(Default)]
+pub struct Ring {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Ring {
+    /// Clears the ring and makes room for `len` samples of delay.
+    pub fn resize(&mut self, len: usize) {
+        self.buf = vec![0.0; len + 2];
+        self.pos = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.fill(0.0);
+    }
+
+    #[inline]
+    pub fn push(&mut self, sample: f32) {
+        self.buf[self.pos] = sample;
+        self.pos = (self.pos + 1) % self.buf.len();
+    }
+
+    /// The sample written `delay` pushes ago, 1 being the last one.
+    #[inline]
+    pub fn tap(&self, delay: usize) -> f32 {
+        let len = self.buf.len();
+        self.buf[(self.pos + len - delay.clamp(1, len - 1)) % len]
+    }
+
+    /// Linear interpolation between taps.
+    #[inline]
+    pub fn read(&self, delay: f32) -> f32 {
+        let whole = delay.floor();
+        let frac = delay - whole;
+        let a = self.tap(whole as usize);
+        let b = self.tap(whole as usize + 1);
+        a + (b - a) * frac
+    }
+}
+
+/// Note values for synced times, with their length in quarter notes.
+const NOTE_LENGTHS: &[f32] = &[
+    0.125,
+    0.25 * 2.0 / 3.0,
+    0.25,
+    0.375,
+    0.5 * 2.0 / 3.0,
+    0.5,
+    0.75,
+    2.0 / 3.0,
+    1.0,
+    1.5,
+    2.0,
+    4.0,
+];
+
+const NOTE_LABELS: &[&str] = &[
+    "1/32", "1/16T", "1/16", "1/16D", "1/8T", "1/8", "1/8D", "1/4T", "1/4", "1/4D", "1/2", "1/1",
+];
+
+const DELAY_PARAMS: &[ParamInfo] = &[
+    ParamInfo::switch("Sync", true),
+    ParamInfo::new("Time Left", "ms", 1.0, 2000.0, 375.0),
+    ParamInfo::new("Time Right", "ms", 1.0, 2000.0, 375.0),
+    ParamInfo::choice("Note Left", NOTE_LABELS, 8),
+    ParamInfo::choice("Note Right", NOTE_LABELS, 8),
+    ParamInfo::new("Feedback", "%", 0.0, 100.0, 35.0),
+    ParamInfo::switch("Ping-Pong", false),
+    ParamInfo::new("Low Cut", "Hz", 20.0, 2000.0, 100.0),
+    ParamInfo::new("High Cut", "Hz", 1000.0, 20000.0, 8000.0),
+    ParamInfo::new("Mod Rate", "Hz", 0.05, 5.0, 0.5),
+    ParamInfo::new("Mod Depth", "ms", 0.0, 10.0, 0.0),
+    ParamInfo::new("Mix", "%", 0.0, 100.0, 30.0),
+];
+
+/// Longest delay, synced times at slow tempos are capped to it.
+const MAX_DELAY_SECS: f32 = 4.0;
+/// How fast the read position follows time and tempo changes.
+const GLIDE_MS: f32 = 50.0;
+
+pub struct Delay {
+    sync: bool,
+    time_ms: [f32; 2],
+    note: [usize; 2],
+    feedback: f32,
+    ping_pong: bool,
+    low_cut: f32,
+    high_cut: f32,
+    mod_rate: f32,
+    mod_depth_ms: f32,
+    mix: f32,
+    sample_rate: f32,
+    glide: f32,
+    /// Current delay of each side in samples.
+    delay: [f32; 2],
+    phase: f32,
+    lines: Vec<Ring>,
+    low_pass: Vec<Biquad>,
+    high_pass: Vec<Biquad>,
+    wet: Vec<f32>,
+    /// Filtered and scaled feedback of each channel.
+    returns: Vec<f32>,
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Self {
+            sync: true,
+            time_ms: [375.0; 2],
+            note: [8; 2],
+            feedback: 0.35,
+            ping_pong: false,
+            low_cut: 100.0,
+            high_cut: 8000.0,
+            mod_rate: 0.5,
+            mod_depth_ms: 0.0,
+            mix: 0.3,
+            sample_rate: 44100.0,
+            glide: 0.0,
+            delay: [0.0; 2],
+            phase: 0.0,
+            lines: Vec::new(),
+            low_pass: Vec::new(),
+            high_pass: Vec::new(),
+            wet: Vec::new(),
+            returns: Vec::new(),
+        }
+    }
+}
+
+impl Delay {
+    fn max_delay(&self) -> usize {
+        ((MAX_DELAY_SECS + 0.02) * self.sample_rate) as usize
+    }
+
+    fn update_filters(&mut self) {
+        let low_cut = Coeffs::high_pass(self.sample_rate, self.low_cut, 0.707);
+        let high_cut = Coeffs::low_pass(self.sample_rate, self.high_cut, 0.707);
+        self.high_pass.iter_mut().for_each(|f| f.coeffs = low_cut);
+        self.low_pass.iter_mut().for_each(|f| f.coeffs = high_cut);
+    }
+
+    /// Delay of one side in samples for the current tempo.
+    fn target(&self, side: usize, bpm: f32) -> f32 {
+        let secs = if self.sync {
+            let bpm = if bpm > 0.0 { bpm } else { 120.0 };
+            NOTE_LENGTHS[self.note[side]] * 60.0 / bpm
+        } else {
+            self.time_ms[side] * 0.001
+        };
+        (secs.min(MAX_DELAY_SECS) * self.sample_rate).max(1.0)
+    }
+}
+
+impl Effect for Delay {
+    fn name(&self) -> &'static str {
+        "Delay"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        DELAY_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => self.sync = value >= 0.5,
+            1 | 2 => self.time_ms[idx - 1] = value,
+            3 | 4 => self.note[idx - 3] = (value as usize).min(NOTE_LENGTHS.len() - 1),
+            5 => self.feedback = value / 100.0,
+            6 => self.ping_pong = value >= 0.5,
+            7 => {
+                self.low_cut = value;
+                self.update_filters();
+            }
+            8 => {
+                self.high_cut = value;
+                self.update_filters();
+            }
+            9 => self.mod_rate = value,
+            10 => self.mod_depth_ms = value,
+            11 => self.mix = value / 100.0,
+            _ => {}
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.glide = (-1.0 / (GLIDE_MS * 0.001 * sample_rate)).exp();
+        let max_delay = self.max_delay();
+        self.lines.iter_mut().for_each(|l| l.resize(max_delay));
+        self.update_filters();
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, ctx: &BlockContext) {
+        let channels = buffer.len();
+        if self.lines.len() != channels {
+            let max_delay = self.max_delay();
+            self.lines.resize_with(channels, || {
+                let mut ring = Ring::default();
+                ring.resize(max_delay);
+                ring
+            });
+            self.low_pass.resize_with(channels, Biquad::default);
+            self.high_pass.resize_with(channels, Biquad::default);
+            self.wet.resize(channels, 0.0);
+            self.returns.resize(channels, 0.0);
+            self.update_filters();
+        }
+
+        let target = [self.target(0, ctx.bpm), self.target(1, ctx.bpm)];
+        if self.delay[0] == 0.0 {
+            self.delay = target;
+        }
+        let depth = self.mod_depth_ms * 0.001 * self.sample_rate;
+        let step = TAU * self.mod_rate / self.sample_rate;
+        let ping_pong = self.ping_pong && channels >= 2;
+
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        for f in 0..frames {
+            let mut read = [0.0; 2];
+            for side in 0..2 {
+                self.delay[side] = target[side] + self.glide * (self.delay[side] - target[side]);
+                // the right side's LFO runs a quarter turn behind
+                let lfo = (self.phase + side as f32 * FRAC_PI_2).sin();
+                read[side] = self.delay[side] + depth * (1.0 + lfo);
+            }
+            self.phase = (self.phase + step) % TAU;
+
+            for (ch, wet) in self.wet.iter_mut().enumerate() {
+                *wet = self.lines[ch].read(read[ch.min(1)]);
+            }
+
+            for ch in 0..channels {
+                self.returns[ch] = self.feedback
+                    * self.high_pass[ch].process(self.low_pass[ch].process(self.wet[ch]));
+            }
+            for ch in 0..channels {
+                // ping-pong feeds the mono input to the left and swaps the returns
+                let input = match ch {
+                    0 if ping_pong => (buffer[0][f] + buffer[1][f]) * 0.5 + self.returns[1],
+                    1 if ping_pong => self.returns[0],
+                    _ => buffer[ch][f] + self.returns[ch],
+                };
+                self.lines[ch].push(input);
+            }
+
+            for (channel, wet) in buffer.iter_mut().zip(&self.wet) {
+                channel[f] = channel[f] * (1.0 - self.mix) + wet * self.mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.lines.iter_mut().for_each(Ring::clear);
+        self.low_pass.iter_mut().for_each(Biquad::reset);
+        self.high_pass.iter_mut().for_each(Biquad::reset);
+        self.delay = [0.0; 2];
+        self.phase = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::tests::{ctx, run};
+
+    const RATE: f32 = 48000.0;
+
+    /// Fully wet, with the feedback filters opened up.
+    fn delay(values: &[(usize, f32)]) -> Delay {
+        let mut delay = Delay::default();
+        delay.set_sample_rate(RATE);
+        for &(idx, value) in [(7, 20.0), (8, 20000.0), (11, 100.0)].iter().chain(values) {
+            delay.set_param(idx, value);
+        }
+        delay
+    }
+
+    /// Feeds an impulse to the left channel and returns both channels.
+    fn impulse(delay: &mut Delay, bpm: f32, frames: usize) -> Vec<Vec<f32>> {
+        let ctx = ctx(RATE, bpm);
+        let mut output = vec![Vec::new(), Vec::new()];
+        for start in (0..frames).step_by(512) {
+            let len = 512.min(frames - start);
+            let mut buffer = vec![vec![0.0; len]; 2];
+            if start == 0 {
+                buffer[0][0] = 1.0;
+            }
+            run(delay, &mut buffer, &[], &ctx);
+            for (out, channel) in output.iter_mut().zip(buffer) {
+                out.extend(channel);
+            }
+        }
+        output
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn synced_time_follows_the_tempo() {
+        for (note, bpm, expected) in [(8, 120.0, 24000), (8, 150.0, 19200), (5, 90.0, 16000)] {
+            let mut delay = delay(&[(0, 1.0), (3, note as f32), (5, 0.0)]);
+            let output = impulse(&mut delay, bpm, 30000);
+            let at = output[0].iter().position(|&x| x != 0.0).unwrap();
+            assert_eq!(at, expected, "{} at {}", NOTE_LABELS[note], bpm);
+        }
+    }
+
+    #[test]
+    fn ping_pong_alternates_channels() {
+        let mut delay = delay(&[(0, 0.0), (1, 10.0), (2, 10.0), (5, 50.0), (6, 1.0)]);
+        let output = impulse(&mut delay, 120.0, 2400);
+
+        let echo = 480;
+        for n in 1..4 {
+            let window = n * echo..n * echo + echo / 2;
+            let (left, right) = (
+                energy(&output[0][window.clone()]),
+                energy(&output[1][window]),
+            );
+            let (loud, quiet) = if n % 2 == 1 {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            // only the feedback filters' ringing leaks across
+            assert!(loud > 1e-3 && quiet < loud * 1e-3, "echo {}", n);
+        }
+    }
+
+    #[test]
+    fn feedback_below_one_decays() {
+        let mut delay = delay(&[(0, 0.0), (1, 10.0), (2, 10.0), (5, 90.0)]);
+        let output = impulse(&mut delay, 120.0, 96000);
+
+        let echoes: Vec<f32> = output[0].chunks(480).skip(1).map(energy).collect();
+        assert!(echoes.windows(2).all(|pair| pair[1] < pair[0]));
+        let tail = output[0][91200..]
+            .iter()
+            .fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!(tail < 1e-3, "{}", tail);
+    }
+}
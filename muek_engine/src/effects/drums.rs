@@ -0,0 +1,700 @@
+// Drum machine: sixteen pads of one sample each, played by MIDI notes from
+// C1 up or by a step grid that runs with the transport. Like the parameters,
+// the grid lives in atomics, so editing it never takes the mixer lock. Pad
+// samples load on the host and are picked up at the next block.
+
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    thread,
+};
+
+use anyhow::{anyhow, bail};
+
+use super::{
+    Effect, ParamInfo,
+    envelope::{Adsr, Envelope},
+    sampler::{Sample, hermite, pan_gains},
+    utility::db_to_gain,
+};
+use crate::{
+    byte_reader::{ByteReader, Endian},
+    decode,
+    midi::Message,
+    mixer::{BlockContext, Buses},
+};
+
+pub const NAME: &str = "Drum Machine";
+
+pub const PADS: usize = 16;
+pub const MAX_STEPS: usize = 32;
+/// Note of the first pad, C1 as in General MIDI drum maps.
+const FIRST_NOTE: u8 = 36;
+const MAX_VOICES: usize = 32;
+/// Release of a voice cut off by a pad of its choke group.
+const CHOKE_SECS: f32 = 0.005;
+/// Transport jumps smaller than this many frames are rounding, not seeks.
+const BEAT_TOLERANCE_FRAMES: f64 = 64.0;
+
+const PAD_PARAMS: usize = 7;
+const VOLUME: usize = PADS * PAD_PARAMS;
+const SEQUENCER: usize = VOLUME + 1;
+const STEPS: usize = VOLUME + 2;
+const RATE: usize = VOLUME + 3;
+const SWING: usize = VOLUME + 4;
+
+const CHOKE_GROUPS: &[&str] = &["Off", "1", "2", "3", "4", "5", "6", "7", "8"];
+const RATES: &[&str] = &["1/8", "1/16", "1/32", "1/8T", "1/16T"];
+/// Length of a step for each of `RATES`, in engine beats.
+const STEP_LENGTHS: &[f64] = &[1.0 / 8.0, 1.0 / 16.0, 1.0 / 32.0, 1.0 / 12.0, 1.0 / 24.0];
+
+macro_rules! drum_params {
+    ($($n:literal),*) => {
+        &[
+            $(
+                ParamInfo::new(concat!("Pad ", $n, " Tune"), "st", -24.0, 24.0, 0.0),
+                ParamInfo::new(concat!("Pad ", $n, " Gain"), "dB", -60.0, 12.0, 0.0),
+                ParamInfo::new(concat!("Pad ", $n, " Pan"), "%", -100.0, 100.0, 0.0),
+                ParamInfo::choice(concat!("Pad ", $n, " Choke"), CHOKE_GROUPS, 0),
+                ParamInfo::new(concat!("Pad ", $n, " Attack"), "ms", 0.0, 1000.0, 0.0),
+                ParamInfo::new(concat!("Pad ", $n, " Hold"), "ms", 0.0, 10000.0, 10000.0),
+                ParamInfo::new(concat!("Pad ", $n, " Decay"), "ms", 1.0, 5000.0, 50.0),
+            )*
+            ParamInfo::new("Volume", "dB", -60.0, 12.0, 0.0),
+            ParamInfo::switch("Sequencer", true),
+            ParamInfo::new("Steps", "", 1.0, MAX_STEPS as f32, 16.0),
+            ParamInfo::choice("Rate", RATES, 1),
+            ParamInfo::new("Swing", "%", 50.0, 75.0, 50.0),
+        ]
+    };
+}
+
+const DRUM_PARAMS: &[ParamInfo] =
+    drum_params![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+/// The decoded sample of a pad.
+pub struct PadSample {
+    sample: Sample,
+    sample_rate: f32,
+}
+
+#[derive(Default)]
+struct Handoff {
+    ready: [Option<Arc<PadSample>>; PADS],
+    /// Samples replaced last, freed here rather than on the audio thread.
+    retired: [Option<Arc<PadSample>>; PADS],
+}
+
+/// Pad samples and the step grid, shared between the host and the audio thread.
+pub struct DrumKit {
+    paths: Mutex<[String; PADS]>,
+    samples: Mutex<[Option<Arc<PadSample>>; PADS]>,
+    handoff: Mutex<Handoff>,
+    /// Per pad then step, the velocity in the low byte, 0 for a rest, and the
+    /// probability in percent in the next.
+    steps: Vec<AtomicU32>,
+}
+
+impl Default for DrumKit {
+    fn default() -> Self {
+        Self {
+            paths: Mutex::default(),
+            samples: Mutex::default(),
+            handoff: Mutex::default(),
+            steps: (0..PADS * MAX_STEPS).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+}
+
+impl DrumKit {
+    /// Decodes `path` into `pad` on the calling thread. An empty path clears the pad.
+    pub fn load_pad(&self, pad: usize, path: &str) -> anyhow::Result<()> {
+        if pad >= PADS {
+            bail!("no pad {}", pad);
+        }
+        let sample = if path.is_empty() {
+            None
+        } else {
+            let (samples, channels, sample_rate) =
+                decode::auto_decode(path).ok_or_else(|| anyhow!("cannot decode {}", path))?;
+            Some(Arc::new(PadSample {
+                sample: Sample::from_interleaved(&samples, channels),
+                sample_rate: sample_rate as f32,
+            }))
+        };
+        self.paths.lock().unwrap()[pad] = path.to_owned();
+        self.samples.lock().unwrap()[pad] = sample.clone();
+        let mut handoff = self.handoff.lock().unwrap();
+        // the audio thread takes an empty sample as a cleared pad
+        handoff.ready[pad] = Some(sample.unwrap_or_else(|| {
+            Arc::new(PadSample {
+                sample: Sample {
+                    channels: Vec::new(),
+                },
+                sample_rate: 0.0,
+            })
+        }));
+        handoff.retired[pad] = None;
+        Ok(())
+    }
+
+    /// For restored states, which arrive while the mixer is locked.
+    fn load_in_background(self: &Arc<Self>, paths: Vec<String>) {
+        let kit = self.clone();
+        let spawned = thread::Builder::new()
+            .name("muek-drum-loader".to_owned())
+            .spawn(move || {
+                for (pad, path) in paths.iter().enumerate() {
+                    if let Err(e) = kit.load_pad(pad, path) {
+                        eprintln!("Failed to load drum pad {}: {}", pad + 1, e);
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("Failed to start drum loader: {}", e);
+        }
+    }
+
+    /// Path and length in seconds of every pad, empty for unused pads.
+    pub fn pads(&self) -> Vec<(String, f32)> {
+        let paths = self.paths.lock().unwrap();
+        let samples = self.samples.lock().unwrap();
+        paths
+            .iter()
+            .zip(samples.iter())
+            .map(|(path, sample)| {
+                let secs = sample
+                    .as_ref()
+                    .map_or(0.0, |s| s.sample.frames() as f32 / s.sample_rate);
+                (path.clone(), secs)
+            })
+            .collect()
+    }
+
+    /// False if there is no such pad or step.
+    pub fn set_step(&self, pad: usize, step: usize, velocity: u8, probability: u8) -> bool {
+        if pad >= PADS || step >= MAX_STEPS {
+            return false;
+        }
+        let value = velocity.min(127) as u32 | (probability.min(100) as u32) << 8;
+        self.steps[pad * MAX_STEPS + step].store(value, Ordering::Relaxed);
+        true
+    }
+
+    /// Velocity, 0 for a rest, and probability in percent.
+    pub fn step(&self, pad: usize, step: usize) -> (u8, u8) {
+        let value = self.steps[pad * MAX_STEPS + step].load(Ordering::Relaxed);
+        (value as u8, (value >> 8) as u8)
+    }
+
+    /// Velocity and probability of every step, pad by pad.
+    pub fn steps(&self) -> Vec<u8> {
+        (0..PADS * MAX_STEPS)
+            .flat_map(|i| {
+                let (velocity, probability) = self.step(i / MAX_STEPS, i % MAX_STEPS);
+                [velocity, probability]
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Pad {
+    tune: f32,
+    gain: f32,
+    pan: f32,
+    choke: u32,
+    /// Attack, hold and decay in seconds.
+    envelope: [f32; 3],
+}
+
+impl Default for Pad {
+    fn default() -> Self {
+        Self {
+            tune: 0.0,
+            gain: 1.0,
+            pan: 0.0,
+            choke: 0,
+            envelope: [0.0, 10.0, 0.05],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Voice {
+    pad: usize,
+    /// Position in the sample, in frames.
+    pos: f64,
+    step: f64,
+    gains: [f32; 2],
+    env: Envelope,
+    adsr: Adsr,
+    /// Frames until the decay starts.
+    hold: u64,
+    /// Order of the trigger, the oldest voice is stolen first.
+    age: u64,
+    choke: u32,
+}
+
+impl Voice {
+    /// Adds the voice to `left` and `right` until they end or the voice does.
+    fn render(&mut self, sample: &Sample, out: [&mut [f32]; 2]) {
+        let [left, right] = out;
+        let frames = sample.frames();
+        let read = |data: &[f32], idx: usize| data.get(idx).copied().unwrap_or(0.0);
+
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            let at = |data: &[f32]| {
+                let before = if i > 0 { data[i - 1] } else { data[i] };
+                hermite(before, data[i], read(data, i + 1), read(data, i + 2), t)
+            };
+            if self.hold > 0 {
+                self.hold -= 1;
+                if self.hold == 0 {
+                    self.env.release();
+                }
+            }
+            let level = self.env.next(&self.adsr);
+            if let [mono] = &sample.channels[..] {
+                let s = at(mono) * level;
+                *l += s * self.gains[0];
+                *r += s * self.gains[1];
+            } else {
+                *l += at(&sample.channels[0]) * self.gains[0] * level;
+                *r += at(&sample.channels[1]) * self.gains[1] * level;
+            }
+
+            self.pos += self.step;
+            if self.pos >= frames as f64 {
+                self.env.kill();
+            }
+            if !self.env.is_active() {
+                break;
+            }
+        }
+    }
+}
+
+pub struct DrumMachine {
+    kit: Arc<DrumKit>,
+    samples: [Option<Arc<PadSample>>; PADS],
+    pads: [Pad; PADS],
+    volume: f32,
+    sequencer: bool,
+    steps: usize,
+    rate: usize,
+    /// Share of a pair of steps before the second, 0.5 for straight time.
+    swing: f64,
+    sample_rate: f32,
+    voices: [Voice; MAX_VOICES],
+    next_age: u64,
+    random: u32,
+    /// Engine beat the last block ended at.
+    next_beat: f64,
+    /// Frame, pad and velocity of the steps due in the block.
+    triggers: Vec<(usize, usize, u8)>,
+    mix: [Vec<f32>; 2],
+}
+
+impl Default for DrumMachine {
+    fn default() -> Self {
+        Self {
+            kit: Arc::new(DrumKit::default()),
+            samples: Default::default(),
+            pads: [Pad::default(); PADS],
+            volume: 1.0,
+            sequencer: true,
+            steps: 16,
+            rate: 1,
+            swing: 0.5,
+            sample_rate: 44100.0,
+            voices: [Voice::default(); MAX_VOICES],
+            next_age: 0,
+            random: 0x9e37_79b9,
+            next_beat: f64::NAN,
+            triggers: Vec::with_capacity(PADS * MAX_STEPS),
+            mix: [Vec::new(), Vec::new()],
+        }
+    }
+}
+
+impl DrumMachine {
+    /// Uniform in 0..1, xorshift.
+    fn next_random(&mut self) -> f32 {
+        let mut x = self.random;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random = x;
+        (x >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::NoteOn { note, velocity } => {
+                if let Some(pad) = note
+                    .checked_sub(FIRST_NOTE)
+                    .filter(|&p| (p as usize) < PADS)
+                {
+                    self.trigger(pad as usize, velocity);
+                }
+            }
+            Message::Controller { number: 120, .. } => {
+                self.voices.iter_mut().for_each(|v| v.env.kill());
+            }
+            // pads are one-shots, note-offs don't end them
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self, pad: usize, velocity: u8) {
+        let Some(sample) = self.samples[pad].as_ref().filter(|s| s.sample.frames() > 0) else {
+            return;
+        };
+        let settings = self.pads[pad];
+        let stereo = sample.sample.channels.len() > 1;
+        let step = 2f64.powf(settings.tune as f64 / 12.0) * sample.sample_rate as f64
+            / self.sample_rate as f64;
+
+        if settings.choke != 0 {
+            let choke = Adsr::new(0.0, 0.0, 1.0, CHOKE_SECS, self.sample_rate);
+            for voice in self
+                .voices
+                .iter_mut()
+                .filter(|v| v.env.is_active() && v.choke == settings.choke)
+            {
+                voice.adsr = choke;
+                voice.hold = 0;
+                voice.env.release();
+            }
+        }
+
+        let slot = self
+            .voices
+            .iter()
+            .position(|v| !v.env.is_active())
+            .unwrap_or_else(|| {
+                (0..MAX_VOICES)
+                    .min_by_key(|&i| self.voices[i].age)
+                    .unwrap_or(0)
+            });
+        self.next_age += 1;
+        let [attack, hold, decay] = settings.envelope;
+        let v = velocity as f32 / 127.0;
+        let level = settings.gain * v * v;
+
+        let voice = &mut self.voices[slot];
+        *voice = Voice {
+            pad,
+            pos: 0.0,
+            step,
+            gains: pan_gains(settings.pan, stereo).map(|g| g * level),
+            env: Envelope::default(),
+            adsr: Adsr::new(attack, 0.0, 1.0, decay, self.sample_rate),
+            hold: (((attack + hold) * self.sample_rate) as u64).max(1),
+            age: self.next_age,
+            choke: settings.choke,
+        };
+        voice.env.trigger();
+    }
+
+    /// Collects the steps that fall into the block, with swing applied to every
+    /// second step.
+    fn sequence(&mut self, ctx: &BlockContext, frames: usize) {
+        self.triggers.clear();
+        let bps = ctx.beats_per_sample as f64;
+        if !self.sequencer || !ctx.is_playing || bps <= 0.0 || frames == 0 {
+            self.next_beat = f64::NAN;
+            return;
+        }
+        // continue exactly where the last block ended, the f32 beat is rounded
+        let mut start = ctx.start_beat as f64;
+        if (start - self.next_beat).abs() < BEAT_TOLERANCE_FRAMES * bps {
+            start = self.next_beat;
+        }
+        let end = start + frames as f64 * bps;
+        self.next_beat = end;
+
+        let step_len = STEP_LENGTHS[self.rate];
+        let swing = (self.swing * 2.0 - 1.0) * step_len;
+        let mut k = (start / step_len).floor() as i64 - 1;
+        while (k as f64) * step_len < end {
+            let beat = k as f64 * step_len + if k & 1 == 1 { swing } else { 0.0 };
+            if beat >= start && beat < end {
+                let frame = (((beat - start) / bps) as usize).min(frames - 1);
+                let step = k.rem_euclid(self.steps as i64) as usize;
+                for pad in 0..PADS {
+                    let (velocity, probability) = self.kit.step(pad, step);
+                    if velocity > 0
+                        && self.next_random() * 100.0 < probability as f32
+                        && self.triggers.len() < self.triggers.capacity()
+                    {
+                        self.triggers.push((frame, pad, velocity));
+                    }
+                }
+            }
+            k += 1;
+        }
+    }
+
+    fn render(&mut self, start: usize, end: usize) {
+        let [left, right] = &mut self.mix;
+        for voice in self.voices.iter_mut().filter(|v| v.env.is_active()) {
+            if let Some(sample) = &self.samples[voice.pad] {
+                voice.render(
+                    &sample.sample,
+                    [&mut left[start..end], &mut right[start..end]],
+                );
+            }
+        }
+    }
+}
+
+impl Effect for DrumMachine {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        DRUM_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            idx if idx < VOLUME => {
+                let pad = &mut self.pads[idx / PAD_PARAMS];
+                match idx % PAD_PARAMS {
+                    0 => pad.tune = value,
+                    1 => pad.gain = db_to_gain(value),
+                    2 => pad.pan = value / 100.0,
+                    3 => pad.choke = value.round() as u32,
+                    param => pad.envelope[param - 4] = value / 1000.0,
+                }
+            }
+            VOLUME => self.volume = db_to_gain(value),
+            SEQUENCER => self.sequencer = value >= 0.5,
+            STEPS => self.steps = (value.round() as usize).clamp(1, MAX_STEPS),
+            RATE => self.rate = (value.round() as usize).min(RATES.len() - 1),
+            SWING => self.swing = value as f64 / 100.0,
+            _ => {}
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.voices.iter_mut().for_each(|v| v.env.kill());
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, ctx: &BlockContext) {
+        if let Ok(mut handoff) = self.kit.handoff.try_lock() {
+            let Handoff { ready, retired } = &mut *handoff;
+            for (pad, (ready, retired)) in ready.iter_mut().zip(retired).enumerate() {
+                if let Some(sample) = ready.take() {
+                    for voice in self.voices.iter_mut().filter(|v| v.pad == pad) {
+                        voice.env.kill();
+                    }
+                    *retired = self.samples[pad].replace(sample);
+                }
+            }
+        }
+
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        for mix in &mut self.mix {
+            mix.clear();
+            mix.resize(frames, 0.0);
+        }
+        self.sequence(ctx, frames);
+
+        // notes and steps split the block so each lands on its frame
+        let mut events = buses.midi_in.iter().peekable();
+        let mut due = 0;
+        let mut f = 0;
+        while f < frames {
+            while let Some(event) = events.next_if(|e| e.delta_frames as usize <= f) {
+                self.handle(event.message());
+            }
+            while let Some(&(_, pad, velocity)) = self.triggers.get(due).filter(|t| t.0 <= f) {
+                self.trigger(pad, velocity);
+                due += 1;
+            }
+            let next_event = events
+                .peek()
+                .map_or(frames, |e| (e.delta_frames as usize).min(frames));
+            let next_step = self.triggers.get(due).map_or(frames, |t| t.0);
+            let end = next_event.min(next_step);
+            self.render(f, end);
+            f = end;
+        }
+        for event in events {
+            self.handle(event.message());
+        }
+
+        let [left, right] = &self.mix;
+        if buffer.len() == 1 {
+            for ((out, l), r) in buffer[0].iter_mut().zip(left).zip(right) {
+                *out += (l + r) * 0.5 * self.volume;
+            }
+        } else {
+            for (ch, channel) in buffer.iter_mut().enumerate() {
+                let side = if ch == 0 { left } else { right };
+                for (out, s) in channel.iter_mut().zip(side) {
+                    *out += s * self.volume;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.voices = [Voice::default(); MAX_VOICES];
+        self.next_beat = f64::NAN;
+    }
+
+    /// Pad paths, each after its length as u32, then the grid as from `DrumKit::steps`.
+    fn save_state(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (path, _) in self.kit.pads() {
+            bytes.extend_from_slice(&(path.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(path.as_bytes());
+        }
+        bytes.extend(self.kit.steps());
+        bytes
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut reader = ByteReader::new(data, Endian::Little, "drum machine state is truncated");
+        let mut paths = Vec::with_capacity(PADS);
+        for _ in 0..PADS {
+            let len = reader.u32()? as usize;
+            paths.push(String::from_utf8(reader.take(len)?.to_vec())?);
+        }
+        let steps = reader.take_items(PADS * MAX_STEPS, 2)?;
+        for (i, step) in steps.chunks_exact(2).enumerate() {
+            self.kit
+                .set_step(i / MAX_STEPS, i % MAX_STEPS, step[0], step[1]);
+        }
+
+        let current: Vec<String> = self.kit.pads().into_iter().map(|(path, _)| path).collect();
+        if paths != current {
+            self.kit.load_in_background(paths);
+        }
+        Ok(())
+    }
+
+    fn drum_kit(&self) -> Option<Arc<DrumKit>> {
+        Some(self.kit.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        effects::tests::{ctx, run},
+        midi::MidiEvent,
+    };
+
+    /// At this rate and 240 bpm an engine beat is 1024 frames, exactly in f32.
+    const SAMPLE_RATE: f32 = 1024.0;
+
+    /// Every pad holds a second of full scale DC.
+    fn drums() -> DrumMachine {
+        let mut drums = DrumMachine::default();
+        drums.set_sample_rate(SAMPLE_RATE);
+        for sample in &mut drums.samples {
+            *sample = Some(Arc::new(PadSample {
+                sample: Sample {
+                    channels: vec![vec![1.0; 2048]],
+                },
+                sample_rate: SAMPLE_RATE,
+            }));
+        }
+        drums
+    }
+
+    fn playing(start_beat: f32) -> BlockContext {
+        BlockContext {
+            start_beat,
+            is_playing: true,
+            ..ctx(SAMPLE_RATE, 240.0)
+        }
+    }
+
+    fn pads_sounding(drums: &DrumMachine) -> Vec<usize> {
+        let mut pads: Vec<usize> = drums
+            .voices
+            .iter()
+            .filter(|v| v.env.is_active())
+            .map(|v| v.pad)
+            .collect();
+        pads.sort();
+        pads
+    }
+
+    #[test]
+    fn choke_group_cuts_the_other_pads() {
+        let mut drums = drums();
+        for pad in [0, 1] {
+            drums.set_param(pad * PAD_PARAMS + 3, 1.0);
+        }
+        let note = |pad: u8| MidiEvent::new(0, [0x90, FIRST_NOTE + pad, 100]);
+        let ctx = ctx(SAMPLE_RATE, 240.0);
+
+        let mut buffer = vec![vec![0.0; 32]; 2];
+        run(&mut drums, &mut buffer, &[note(0), note(2)], &ctx);
+        assert_eq!(pads_sounding(&drums), [0, 2]);
+
+        // pad 1 chokes pad 0, which fades out within a few milliseconds
+        let mut buffer = vec![vec![0.0; 32]; 2];
+        run(&mut drums, &mut buffer, &[note(1)], &ctx);
+        assert_eq!(pads_sounding(&drums), [1, 2]);
+    }
+
+    #[test]
+    fn swing_delays_every_second_step() {
+        for (swing, expected) in [(50.0, [0, 64, 128, 192]), (75.0, [0, 96, 128, 224])] {
+            let mut drums = drums();
+            drums.set_param(SWING, swing);
+            for step in 0..4 {
+                drums.kit.set_step(0, step, 100, 100);
+            }
+            drums.sequence(&playing(0.0), 256);
+            let frames: Vec<usize> = drums.triggers.iter().map(|t| t.0).collect();
+            assert_eq!(frames, expected, "{}", swing);
+        }
+    }
+
+    #[test]
+    fn steps_keep_their_probability_and_velocity() {
+        let mut drums = drums();
+        drums.kit.set_step(0, 0, 127, 100);
+        drums.kit.set_step(1, 4, 64, 100);
+        drums.kit.set_step(2, 8, 100, 0);
+
+        // sixteen bars of sixteenths, a bar a block
+        let mut triggers = Vec::new();
+        for bar in 0..16 {
+            drums.sequence(&playing(bar as f32), 1024);
+            triggers.extend(
+                drums
+                    .triggers
+                    .iter()
+                    .map(|&(_, pad, velocity)| (pad, velocity)),
+            );
+        }
+        assert_eq!(triggers.iter().filter(|t| **t == (0, 127)).count(), 16);
+        assert_eq!(triggers.iter().filter(|t| **t == (1, 64)).count(), 16);
+        assert_eq!(triggers.len(), 32);
+
+        // played at the square of the velocity
+        drums.reset();
+        let mut buffer = vec![vec![0.0; 1024]; 2];
+        run(&mut drums, &mut buffer, &[], &playing(0.0));
+        assert!((buffer[0][0] - 1.0).abs() < 1e-6);
+        let quarter = (64.0f32 / 127.0).powi(2);
+        assert!((buffer[0][256] - 1.0 - quarter).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,681 @@
+// Compressor, gate/expander and lookahead limiter. Each computes one gain per
+// frame from a detector shared by all channels, keyed either by the input or
+// by an external sidechain from the routing graph, and reports the largest
+// gain reduction of the last block in dB.
+
+use std::{f32::consts::PI, sync::Arc};
+
+use super::{Effect, Meters, ParamInfo, utility::db_to_gain};
+use crate::mixer::{BlockContext, Buses, DelayLine};
+
+const GAIN_REDUCTION: &[&str] = &["Gain Reduction"];
+
+/// One pole smoothing coefficient reaching ~63% after `ms`.
+fn time_coeff(ms: f32, sample_rate: f32) -> f32 {
+    if ms <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (ms * 0.001 * sample_rate)).exp()
+    }
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-9).log10()
+}
+
+/// The channels the detector listens to: the sidechain when asked for and routed, else the input.
+fn key<'a>(buffer: &'a [Vec<f32>], sidechain: &'a [Vec<f32>], external: bool) -> &'a [Vec<f32>] {
+    if external && !sidechain.is_empty() {
+        sidechain
+    } else {
+        buffer
+    }
+}
+
+fn peak_at(key: &[Vec<f32>], frame: usize) -> f32 {
+    key.iter()
+        .map(|ch| ch.get(frame).map_or(0.0, |x| x.abs()))
+        .fold(0.0, f32::max)
+}
+
+fn apply_gains(buffer: &mut [Vec<f32>], gains: &[f32]) {
+    for channel in buffer.iter_mut() {
+        for (sample, gain) in channel.iter_mut().zip(gains) {
+            *sample *= *gain;
+        }
+    }
+}
+
+const COMPRESSOR_PARAMS: &[ParamInfo] = &[
+    ParamInfo::new("Threshold", "dB", -60.0, 0.0, -18.0),
+    ParamInfo::new("Ratio", ":1", 1.0, 20.0, 4.0),
+    ParamInfo::new("Knee", "dB", 0.0, 24.0, 6.0),
+    ParamInfo::new("Attack", "ms", 0.1, 200.0, 10.0),
+    ParamInfo::new("Release", "ms", 5.0, 2000.0, 120.0),
+    ParamInfo::new("Makeup", "dB", 0.0, 24.0, 0.0),
+    ParamInfo::choice("Detection", &["Peak", "RMS"], 0),
+    ParamInfo::switch("Sidechain", false),
+];
+
+/// RMS detection window.
+const RMS_MS: f32 = 10.0;
+
+/// Feed-forward compressor with a soft knee.
+pub struct Compressor {
+    threshold: f32,
+    ratio: f32,
+    knee: f32,
+    attack_ms: f32,
+    release_ms: f32,
+    makeup: f32,
+    rms: bool,
+    external: bool,
+    sample_rate: f32,
+    attack: f32,
+    release: f32,
+    rms_coeff: f32,
+    mean_square: f32,
+    /// Smoothed gain in dB, 0 or below.
+    gain_db: f32,
+    gains: Vec<f32>,
+    meters: Arc<Meters>,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self {
+            threshold: -18.0,
+            ratio: 4.0,
+            knee: 6.0,
+            attack_ms: 10.0,
+            release_ms: 120.0,
+            makeup: 0.0,
+            rms: false,
+            external: false,
+            sample_rate: 44100.0,
+            attack: 0.0,
+            release: 0.0,
+            rms_coeff: 0.0,
+            mean_square: 0.0,
+            gain_db: 0.0,
+            gains: Vec::new(),
+            meters: Arc::new(Meters::new(GAIN_REDUCTION)),
+        }
+    }
+}
+
+impl Compressor {
+    fn update_coeffs(&mut self) {
+        self.attack = time_coeff(self.attack_ms, self.sample_rate);
+        self.release = time_coeff(self.release_ms, self.sample_rate);
+        self.rms_coeff = time_coeff(RMS_MS, self.sample_rate);
+    }
+
+    /// Gain in dB for a detector level in dB.
+    fn static_gain(&self, level: f32) -> f32 {
+        let over = level - self.threshold;
+        let slope = 1.0 / self.ratio - 1.0;
+        if 2.0 * over < -self.knee {
+            0.0
+        } else if 2.0 * over.abs() <= self.knee {
+            slope * (over + self.knee / 2.0).powi(2) / (2.0 * self.knee)
+        } else {
+            slope * over
+        }
+    }
+}
+
+impl Effect for Compressor {
+    fn name(&self) -> &'static str {
+        "Compressor"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        COMPRESSOR_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => self.threshold = value,
+            1 => self.ratio = value.max(1.0),
+            2 => self.knee = value,
+            3 => self.attack_ms = value,
+            4 => self.release_ms = value,
+            5 => self.makeup = value,
+            6 => self.rms = value >= 0.5,
+            7 => self.external = value >= 0.5,
+            _ => return,
+        }
+        self.update_coeffs();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_coeffs();
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, _ctx: &BlockContext) {
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        self.gains.resize(frames, 1.0);
+
+        let key = key(buffer, buses.sidechain, self.external);
+        let mut reduction = 0.0f32;
+        for f in 0..frames {
+            let peak = peak_at(key, f);
+            let level = if self.rms {
+                self.mean_square =
+                    self.rms_coeff * self.mean_square + (1.0 - self.rms_coeff) * peak * peak;
+                self.mean_square.sqrt()
+            } else {
+                peak
+            };
+
+            let target = self.static_gain(gain_to_db(level));
+            let coeff = if target < self.gain_db {
+                self.attack
+            } else {
+                self.release
+            };
+            self.gain_db = target + coeff * (self.gain_db - target);
+            reduction = reduction.max(-self.gain_db);
+            self.gains[f] = db_to_gain(self.gain_db + self.makeup);
+        }
+
+        apply_gains(buffer, &self.gains);
+        self.meters.set(0, reduction);
+    }
+
+    fn uses_sidechain(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) {
+        self.mean_square = 0.0;
+        self.gain_db = 0.0;
+    }
+
+    fn meters(&self) -> Option<Arc<Meters>> {
+        Some(self.meters.clone())
+    }
+}
+
+const GATE_PARAMS: &[ParamInfo] = &[
+    ParamInfo::new("Threshold", "dB", -80.0, 0.0, -40.0),
+    ParamInfo::new("Ratio", ":1", 1.0, 20.0, 20.0),
+    ParamInfo::new("Range", "dB", 0.0, 80.0, 80.0),
+    ParamInfo::new("Attack", "ms", 0.01, 50.0, 1.0),
+    ParamInfo::new("Hold", "ms", 0.0, 500.0, 20.0),
+    ParamInfo::new("Release", "ms", 5.0, 2000.0, 150.0),
+    ParamInfo::switch("Sidechain", false),
+];
+
+/// Downward expander; at high ratios it works as a gate, `Range` limits the attenuation.
+pub struct Gate {
+    threshold: f32,
+    ratio: f32,
+    range: f32,
+    attack_ms: f32,
+    hold_ms: f32,
+    release_ms: f32,
+    external: bool,
+    sample_rate: f32,
+    attack: f32,
+    release: f32,
+    hold: usize,
+    /// Frames left before the release may start.
+    hold_left: usize,
+    gain_db: f32,
+    gains: Vec<f32>,
+    meters: Arc<Meters>,
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self {
+            threshold: -40.0,
+            ratio: 20.0,
+            range: 80.0,
+            attack_ms: 1.0,
+            hold_ms: 20.0,
+            release_ms: 150.0,
+            external: false,
+            sample_rate: 44100.0,
+            attack: 0.0,
+            release: 0.0,
+            hold: 0,
+            hold_left: 0,
+            gain_db: 0.0,
+            gains: Vec::new(),
+            meters: Arc::new(Meters::new(GAIN_REDUCTION)),
+        }
+    }
+}
+
+impl Gate {
+    fn update_coeffs(&mut self) {
+        self.attack = time_coeff(self.attack_ms, self.sample_rate);
+        self.release = time_coeff(self.release_ms, self.sample_rate);
+        self.hold = (self.hold_ms * 0.001 * self.sample_rate) as usize;
+    }
+}
+
+impl Effect for Gate {
+    fn name(&self) -> &'static str {
+        "Gate"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        GATE_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => self.threshold = value,
+            1 => self.ratio = value.max(1.0),
+            2 => self.range = value,
+            3 => self.attack_ms = value,
+            4 => self.hold_ms = value,
+            5 => self.release_ms = value,
+            6 => self.external = value >= 0.5,
+            _ => return,
+        }
+        self.update_coeffs();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_coeffs();
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, _ctx: &BlockContext) {
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        self.gains.resize(frames, 1.0);
+
+        let key = key(buffer, buses.sidechain, self.external);
+        let mut reduction = 0.0f32;
+        for f in 0..frames {
+            let level = gain_to_db(peak_at(key, f));
+            let target = if level >= self.threshold {
+                self.hold_left = self.hold;
+                0.0
+            } else {
+                ((level - self.threshold) * (self.ratio - 1.0)).max(-self.range)
+            };
+
+            if target > self.gain_db {
+                self.gain_db = target + self.attack * (self.gain_db - target);
+            } else if self.hold_left > 0 {
+                self.hold_left -= 1;
+            } else {
+                self.gain_db = target + self.release * (self.gain_db - target);
+            }
+            reduction = reduction.max(-self.gain_db);
+            self.gains[f] = db_to_gain(self.gain_db);
+        }
+
+        apply_gains(buffer, &self.gains);
+        self.meters.set(0, reduction);
+    }
+
+    fn uses_sidechain(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) {
+        self.gain_db = 0.0;
+        self.hold_left = 0;
+    }
+
+    fn meters(&self) -> Option<Arc<Meters>> {
+        Some(self.meters.clone())
+    }
+}
+
+const LIMITER_PARAMS: &[ParamInfo] = &[
+    ParamInfo::new("Input", "dB", 0.0, 24.0, 0.0),
+    ParamInfo::new("Ceiling", "dB", -12.0, 0.0, -0.3),
+    ParamInfo::new("Release", "ms", 1.0, 1000.0, 50.0),
+    ParamInfo::switch("True Peak", true),
+    ParamInfo::switch("Sidechain", false),
+];
+
+const LOOKAHEAD_MS: f32 = 1.5;
+
+/// Taps of the interpolator estimating peaks between samples.
+const TP_TAPS: usize = 12;
+/// The detector looks at the sample this many frames back, so the interpolator can see ahead.
+const TP_DELAY: usize = TP_TAPS / 2;
+/// Points checked between two samples: 4x oversampling.
+const TP_PHASES: usize = 3;
+
+/// Brickwall limiter. The gain needed for each frame is held over the lookahead
+/// window and then averaged over it, so it has fully arrived when that frame
+/// leaves the delay line.
+pub struct Limiter {
+    input: f32,
+    ceiling: f32,
+    release_ms: f32,
+    true_peak: bool,
+    external: bool,
+    sample_rate: f32,
+    release: f32,
+    lookahead: usize,
+    /// Interpolation filters at 1/4, 2/4 and 3/4 of a sample.
+    taps: [[f32; TP_TAPS]; TP_PHASES],
+    /// Last `TP_TAPS` detector inputs per channel.
+    history: Vec<[f32; TP_TAPS]>,
+    history_pos: usize,
+    /// Gain each frame of the window needs, and the released gain fed to the average.
+    required: Vec<f32>,
+    smoothed: Vec<f32>,
+    window_pos: usize,
+    released: f32,
+    delay: DelayLine,
+    gains: Vec<f32>,
+    meters: Arc<Meters>,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        let mut taps = [[0.0; TP_TAPS]; TP_PHASES];
+        for (p, phase) in taps.iter_mut().enumerate() {
+            let frac = (p + 1) as f32 / (TP_PHASES + 1) as f32;
+            for (k, tap) in phase.iter_mut().enumerate() {
+                let d = k as f32 - TP_DELAY as f32 + frac;
+                let sinc = if d == 0.0 {
+                    1.0
+                } else {
+                    (PI * d).sin() / (PI * d)
+                };
+                let window = 0.5 + 0.5 * (PI * d / TP_DELAY as f32).cos();
+                *tap = sinc * window;
+            }
+            let sum: f32 = phase.iter().sum();
+            for tap in phase.iter_mut() {
+                *tap /= sum;
+            }
+        }
+
+        let mut limiter = Self {
+            input: 1.0,
+            ceiling: db_to_gain(-0.3),
+            release_ms: 50.0,
+            true_peak: true,
+            external: false,
+            sample_rate: 0.0,
+            release: 0.0,
+            lookahead: 0,
+            taps,
+            history: Vec::new(),
+            history_pos: 0,
+            required: Vec::new(),
+            smoothed: Vec::new(),
+            window_pos: 0,
+            released: 1.0,
+            delay: DelayLine::default(),
+            gains: Vec::new(),
+            meters: Arc::new(Meters::new(GAIN_REDUCTION)),
+        };
+        limiter.set_sample_rate(44100.0);
+        limiter
+    }
+}
+
+impl Limiter {
+    /// Loudest point around the sample `TP_DELAY` frames back in `history`.
+    fn detect(&self, history: &[f32; TP_TAPS]) -> f32 {
+        let at = |k: usize| history[(self.history_pos + TP_TAPS - 1 - k) % TP_TAPS];
+        let mut peak = at(TP_DELAY).abs();
+        if self.true_peak {
+            for phase in &self.taps {
+                let value: f32 = phase.iter().enumerate().map(|(k, t)| t * at(k)).sum();
+                peak = peak.max(value.abs());
+            }
+        }
+        peak
+    }
+}
+
+impl Effect for Limiter {
+    fn name(&self) -> &'static str {
+        "Limiter"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        LIMITER_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => self.input = db_to_gain(value),
+            1 => self.ceiling = db_to_gain(value),
+            2 => {
+                self.release_ms = value;
+                self.release = time_coeff(value, self.sample_rate);
+            }
+            3 => self.true_peak = value >= 0.5,
+            4 => self.external = value >= 0.5,
+            _ => {}
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.release = time_coeff(self.release_ms, sample_rate);
+        self.lookahead = ((LOOKAHEAD_MS * 0.001 * sample_rate) as usize).max(1);
+        self.required = vec![1.0; self.lookahead];
+        self.smoothed = vec![1.0; self.lookahead];
+        self.window_pos = 0;
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, _ctx: &BlockContext) {
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        self.gains.resize(frames, 1.0);
+
+        let key = key(buffer, buses.sidechain, self.external);
+        if self.history.len() != key.len() {
+            self.history.resize(key.len(), [0.0; TP_TAPS]);
+        }
+
+        let mut lowest = 1.0f32;
+        for f in 0..frames {
+            for (history, channel) in self.history.iter_mut().zip(key) {
+                history[self.history_pos] = channel[f] * self.input;
+            }
+            self.history_pos = (self.history_pos + 1) % TP_TAPS;
+            let peak = self
+                .history
+                .iter()
+                .map(|h| self.detect(h))
+                .fold(0.0, f32::max);
+
+            let required = if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1.0
+            };
+            self.required[self.window_pos] = required;
+            let held = self.required.iter().copied().fold(1.0, f32::min);
+            self.released = if held < self.released {
+                held
+            } else {
+                held + self.release * (self.released - held)
+            };
+            self.smoothed[self.window_pos] = self.released;
+            self.window_pos = (self.window_pos + 1) % self.lookahead;
+
+            let gain = self.smoothed.iter().sum::<f32>() / self.lookahead as f32;
+            lowest = lowest.min(gain);
+            self.gains[f] = gain * self.input;
+        }
+
+        self.delay
+            .set_delay(buffer.len(), self.lookahead - 1 + TP_DELAY);
+        self.delay.process(buffer);
+        apply_gains(buffer, &self.gains);
+        self.meters.set(0, -gain_to_db(lowest));
+    }
+
+    fn latency(&self) -> usize {
+        self.lookahead - 1 + TP_DELAY
+    }
+
+    fn uses_sidechain(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) {
+        self.history.iter_mut().for_each(|h| *h = [0.0; TP_TAPS]);
+        self.required.fill(1.0);
+        self.smoothed.fill(1.0);
+        self.released = 1.0;
+        self.delay.reset();
+    }
+
+    fn meters(&self) -> Option<Arc<Meters>> {
+        Some(self.meters.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::tests::{ctx, noise, run};
+
+    const RATE: f32 = 48000.0;
+
+    fn set(effect: &mut dyn Effect, values: &[(usize, f32)]) {
+        effect.set_sample_rate(RATE);
+        for &(idx, value) in values {
+            effect.set_param(idx, value);
+        }
+    }
+
+    /// Runs `input` on both channels in blocks of 512, returning the left channel.
+    fn process(effect: &mut dyn Effect, input: &[f32]) -> Vec<f32> {
+        let ctx = ctx(RATE, 120.0);
+        let mut output = Vec::new();
+        for block in input.chunks(512) {
+            let mut buffer = vec![block.to_vec(), block.to_vec()];
+            run(effect, &mut buffer, &[], &ctx);
+            output.extend_from_slice(&buffer[0]);
+        }
+        output
+    }
+
+    /// Hard knee and fast times, so it settles on the static curve.
+    fn compressor(threshold: f32, ratio: f32) -> Compressor {
+        let mut compressor = Compressor::default();
+        set(
+            &mut compressor,
+            &[(0, threshold), (1, ratio), (2, 0.0), (3, 0.1), (4, 5.0)],
+        );
+        compressor
+    }
+
+    #[test]
+    fn compressor_settles_on_its_ratio() {
+        for (threshold, ratio, level) in
+            [(-20.0, 4.0, -8.0), (-30.0, 2.0, -6.0), (-10.0, 10.0, 0.0)]
+        {
+            let mut compressor = compressor(threshold, ratio);
+            let input = db_to_gain(level);
+            let output = process(&mut compressor, &vec![input; 4800]);
+            let reduction = -gain_to_db(output[4799] / input);
+            let expected = (level - threshold) * (1.0 - 1.0 / ratio);
+            assert!(
+                (reduction - expected).abs() < 0.05,
+                "{} {}",
+                reduction,
+                expected
+            );
+        }
+
+        let mut compressor = compressor(-20.0, 4.0);
+        let quiet = db_to_gain(-30.0);
+        let output = process(&mut compressor, &vec![quiet; 4800]);
+        assert_eq!(output[4799], quiet);
+    }
+
+    #[test]
+    fn gate_closes_below_its_threshold() {
+        let mut gate = Gate::default();
+        set(&mut gate, &[(0, -40.0), (2, 60.0), (4, 10.0), (5, 20.0)]);
+
+        let loud = db_to_gain(-20.0);
+        let output = process(&mut gate, &vec![loud; 4800]);
+        assert_eq!(output[4799], loud);
+
+        // attenuated by the full range once hold and release are over
+        let quiet = db_to_gain(-50.0);
+        let output = process(&mut gate, &vec![quiet; 9600]);
+        assert_eq!(output[0], quiet);
+        let reduction = -gain_to_db(output[9599] / quiet);
+        assert!((reduction - 60.0).abs() < 0.1, "{}", reduction);
+    }
+
+    #[test]
+    fn sidechain_drives_the_compressor() {
+        let mut compressor = compressor(-20.0, 4.0);
+        compressor.set_param(7, 1.0);
+        let (input, key) = (db_to_gain(-30.0), db_to_gain(-8.0));
+
+        let ctx = ctx(RATE, 120.0);
+        let sidechain = vec![vec![key; 4800]; 2];
+        let mut buffer = vec![vec![input; 4800]; 2];
+        let mut midi_out = Vec::new();
+        let mut buses = Buses {
+            sidechain: &sidechain,
+            aux_outputs: &mut [],
+            midi_in: &[],
+            midi_out: &mut midi_out,
+        };
+        compressor.process(&mut buffer, &mut buses, &ctx);
+        let reduction = -gain_to_db(buffer[0][4799] / input);
+        assert!((reduction - 9.0).abs() < 0.05, "{}", reduction);
+        assert!((compressor.meters.values()[0] - 9.0).abs() < 0.05);
+
+        // without a routed sidechain it listens to its input, which is below threshold
+        compressor.reset();
+        let output = process(&mut compressor, &vec![input; 4800]);
+        assert_eq!(output[4799], input);
+    }
+
+    #[test]
+    fn limiter_keeps_true_peaks_under_the_ceiling() {
+        let mut limiter = Limiter::default();
+        set(&mut limiter, &[(0, 12.0), (1, -1.0), (2, 20.0), (3, 1.0)]);
+        let ceiling = db_to_gain(-1.0);
+
+        // a quarter rate sine sampled 45 degrees off its peaks, then noise
+        let mut input: Vec<f32> = (0..9600)
+            .map(|f| (std::f32::consts::FRAC_PI_2 * f as f32 + PI / 4.0).sin())
+            .collect();
+        input.extend(noise(9600, 7));
+        let output = process(&mut limiter, &input);
+
+        assert!(output.iter().all(|x| x.abs() <= ceiling * 1.0001));
+        // the sine's true peak is its sample peak times sqrt 2
+        let sine_peak = output[4800..9600]
+            .iter()
+            .fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!(sine_peak * 2f32.sqrt() <= ceiling * 1.01, "{}", sine_peak);
+        assert!(sine_peak * 2f32.sqrt() > ceiling * 0.95);
+    }
+
+    #[test]
+    fn limiter_latency_is_its_lookahead() {
+        let mut limiter = Limiter::default();
+        set(&mut limiter, &[(3, 0.0)]);
+        let lookahead = (LOOKAHEAD_MS * 0.001 * RATE) as usize;
+        assert!(limiter.latency() >= lookahead);
+
+        // the peak, quiet enough to pass untouched, leaves after exactly the latency
+        let mut input = vec![0.0; 1024];
+        input[100] = 0.5;
+        let output = process(&mut limiter, &input);
+        let at = output.iter().position(|&x| x != 0.0).unwrap();
+        assert_eq!(at, 100 + limiter.latency());
+        assert_eq!(output[at], 0.5);
+    }
+}
@@ -0,0 +1,108 @@
+// ADSR envelopes for the built-in instruments. The attack is linear, decay
+// and release are exponential and cover 60 dB in their set time.
+
+/// Distance an exponential segment covers in its time, -60 dB.
+const SEGMENT_RANGE: f32 = 6.907_755;
+/// Level below which a release counts as finished.
+const SILENCE: f32 = 1e-4;
+
+/// Per sample steps of an envelope, made from times in seconds and a sustain level.
+#[derive(Clone, Copy)]
+pub struct Adsr {
+    attack_step: f32,
+    decay_coeff: f32,
+    sustain: f32,
+    release_coeff: f32,
+}
+
+impl Adsr {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32, sample_rate: f32) -> Self {
+        let coeff = |secs: f32| (-SEGMENT_RANGE / (secs * sample_rate).max(1.0)).exp();
+        Self {
+            attack_step: 1.0 / (attack * sample_rate).max(1.0),
+            decay_coeff: coeff(decay),
+            sustain: sustain.clamp(0.0, 1.0),
+            release_coeff: coeff(release),
+        }
+    }
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self::new(0.0, 0.0, 1.0, 0.0, 44100.0)
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Stage {
+    #[default]
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Envelope {
+    stage: Stage,
+    level: f32,
+}
+
+impl Envelope {
+    /// Attacks from the current level, so retriggering a sounding voice doesn't click.
+    pub fn trigger(&mut self) {
+        self.stage = Stage::Attack;
+    }
+
+    pub fn release(&mut self) {
+        if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+        }
+    }
+
+    /// Silences at once.
+    pub fn kill(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    pub fn is_released(&self) -> bool {
+        matches!(self.stage, Stage::Release | Stage::Idle)
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// Advances one sample and returns the new level.
+    pub fn next(&mut self, adsr: &Adsr) -> f32 {
+        match self.stage {
+            Stage::Idle => {}
+            Stage::Attack => {
+                self.level += adsr.attack_step;
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level = adsr.sustain + (self.level - adsr.sustain) * adsr.decay_coeff;
+                if (self.level - adsr.sustain).abs() < SILENCE {
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => self.level = adsr.sustain,
+            Stage::Release => {
+                self.level *= adsr.release_coeff;
+                if self.level < SILENCE {
+                    self.kill();
+                }
+            }
+        }
+        self.level
+    }
+}
@@ -0,0 +1,581 @@
+// Eight band parametric EQ. Bands run as cascaded biquads, or in linear phase
+// mode as one FIR built from the magnitude of those biquads and applied with
+// FFT overlap-add, which costs `FIR_BLOCK + FIR_TAPS / 2` samples of latency.
+// State for `MAX_CHANNELS` is allocated up front; further channels pass dry.
+
+use std::{f32::consts::TAU, sync::Arc};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use super::{
+    Effect, EffectParams, ParamInfo,
+    analyzer::Spectrum,
+    biquad::{Biquad, Coeffs, Trig, butterworth_q},
+    utility::db_to_gain,
+};
+use crate::mixer::{BlockContext, Buses};
+
+pub const NAME: &str = "Parametric EQ";
+
+const BANDS: usize = 8;
+const BAND_PARAMS: usize = 6;
+/// Sections of a 48 dB/oct band.
+const MAX_STAGES: usize = 4;
+const MAX_CHANNELS: usize = 8;
+
+const OUTPUT: usize = BANDS * BAND_PARAMS;
+const LINEAR_PHASE: usize = OUTPUT + 1;
+
+const FIR_TAPS: usize = 4096;
+const FIR_BLOCK: usize = 2048;
+const FIR_FFT: usize = 8192;
+
+const BAND_TYPES: &[&str] = &[
+    "Bell",
+    "Low Shelf",
+    "High Shelf",
+    "High Pass",
+    "Low Pass",
+    "Notch",
+];
+const SLOPES: &[&str] = &["12 dB/oct", "24 dB/oct", "36 dB/oct", "48 dB/oct"];
+
+macro_rules! eq_params {
+    ($(($n:literal, $kind:expr, $freq:expr, $on:expr)),*) => {
+        &[
+            $(
+                ParamInfo::switch(concat!("Band ", $n, " On"), $on),
+                ParamInfo::choice(concat!("Band ", $n, " Type"), BAND_TYPES, $kind),
+                ParamInfo::new(concat!("Band ", $n, " Freq"), "Hz", 20.0, 20000.0, $freq),
+                ParamInfo::new(concat!("Band ", $n, " Gain"), "dB", -24.0, 24.0, 0.0),
+                ParamInfo::new(concat!("Band ", $n, " Q"), "", 0.1, 18.0, 0.71),
+                ParamInfo::choice(concat!("Band ", $n, " Slope"), SLOPES, 0),
+            )*
+            ParamInfo::new("Output", "dB", -24.0, 24.0, 0.0),
+            ParamInfo::switch("Linear Phase", false),
+        ]
+    };
+}
+
+pub const PARAMS: &[ParamInfo] = eq_params![
+    (1, 3, 30.0, false),
+    (2, 1, 100.0, true),
+    (3, 0, 250.0, true),
+    (4, 0, 800.0, true),
+    (5, 0, 2500.0, true),
+    (6, 0, 6000.0, true),
+    (7, 2, 10000.0, true),
+    (8, 4, 18000.0, false)
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum BandType {
+    Bell,
+    LowShelf,
+    HighShelf,
+    HighPass,
+    LowPass,
+    Notch,
+}
+
+impl BandType {
+    fn from_index(idx: usize) -> Self {
+        match idx {
+            1 => Self::LowShelf,
+            2 => Self::HighShelf,
+            3 => Self::HighPass,
+            4 => Self::LowPass,
+            5 => Self::Notch,
+            _ => Self::Bell,
+        }
+    }
+}
+
+/// Biquad sections of one band, identity past `len`.
+#[derive(Clone, Copy, Default)]
+struct Sections {
+    coeffs: [Coeffs; MAX_STAGES],
+    len: usize,
+}
+
+/// Designs every enabled band from the parameter values.
+fn design(values: &[f32], sample_rate: f32) -> [Sections; BANDS] {
+    let mut bands = [Sections::default(); BANDS];
+    for (band, sections) in bands.iter_mut().enumerate() {
+        let v = &values[band * BAND_PARAMS..(band + 1) * BAND_PARAMS];
+        if v[0] < 0.5 {
+            continue;
+        }
+        let (kind, freq, gain, q) = (BandType::from_index(v[1] as usize), v[2], v[3], v[4]);
+        let stages = v[5] as usize + 1;
+
+        match kind {
+            BandType::Bell => sections.coeffs[0] = Coeffs::peaking(sample_rate, freq, q, gain),
+            BandType::LowShelf => {
+                sections.coeffs[0] = Coeffs::low_shelf(sample_rate, freq, q, gain)
+            }
+            BandType::HighShelf => {
+                sections.coeffs[0] = Coeffs::high_shelf(sample_rate, freq, q, gain)
+            }
+            BandType::Notch => sections.coeffs[0] = Coeffs::notch(sample_rate, freq, q),
+            BandType::HighPass | BandType::LowPass => {
+                for stage in 0..stages {
+                    // a single section keeps the band's Q for a resonant cut
+                    let q = if stages == 1 {
+                        q
+                    } else {
+                        butterworth_q(stage, stages)
+                    };
+                    sections.coeffs[stage] = if kind == BandType::HighPass {
+                        Coeffs::high_pass(sample_rate, freq, q)
+                    } else {
+                        Coeffs::low_pass(sample_rate, freq, q)
+                    };
+                }
+                sections.len = stages;
+                continue;
+            }
+        }
+        sections.len = 1;
+    }
+    bands
+}
+
+/// Response of the EQ in dB at `freqs`, for drawing its curve.
+pub fn curve(params: &EffectParams, sample_rate: f32, freqs: &[f32]) -> Vec<f32> {
+    let values: Vec<f32> = (0..PARAMS.len())
+        .map(|idx| params.get(idx).unwrap_or(0.0))
+        .collect();
+    let bands = design(&values, sample_rate);
+    freqs
+        .iter()
+        .map(|&freq| {
+            let trig = Trig::new(sample_rate, freq);
+            let power: f64 = bands
+                .iter()
+                .flat_map(|b| b.coeffs[..b.len].iter())
+                .map(|c| c.power_at(&trig))
+                .product();
+            10.0 * power.max(1e-18).log10() as f32 + values[OUTPUT]
+        })
+        .collect()
+}
+
+pub struct Equalizer {
+    values: Vec<f32>,
+    sample_rate: f32,
+    bands: [Sections; BANDS],
+    /// Per channel, `MAX_STAGES` sections per band.
+    filters: Vec<[Biquad; BANDS * MAX_STAGES]>,
+    /// Channels of the last block.
+    channels: usize,
+    dirty: bool,
+    output: f32,
+    output_current: f32,
+    linear_phase: bool,
+    fir: LinearPhase,
+    spectrum: Arc<Spectrum>,
+}
+
+impl Default for Equalizer {
+    fn default() -> Self {
+        Self {
+            values: PARAMS.iter().map(|p| p.default).collect(),
+            sample_rate: 44100.0,
+            bands: [Sections::default(); BANDS],
+            filters: vec![[Biquad::default(); BANDS * MAX_STAGES]; MAX_CHANNELS],
+            channels: 0,
+            dirty: true,
+            output: 1.0,
+            output_current: 1.0,
+            linear_phase: false,
+            fir: LinearPhase::new(44100.0),
+            spectrum: Arc::new(Spectrum::default()),
+        }
+    }
+}
+
+impl Equalizer {
+    fn update(&mut self) {
+        self.dirty = false;
+        self.bands = design(&self.values, self.sample_rate);
+        self.output = db_to_gain(self.values[OUTPUT]);
+
+        if self.linear_phase {
+            self.fir.design(&self.bands);
+        } else {
+            for filters in &mut self.filters {
+                for (band, sections) in self.bands.iter().enumerate() {
+                    for stage in 0..MAX_STAGES {
+                        filters[band * MAX_STAGES + stage].coeffs = if stage < sections.len {
+                            sections.coeffs[stage]
+                        } else {
+                            Coeffs::IDENTITY
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Effect for Equalizer {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        if idx == LINEAR_PHASE {
+            let linear_phase = value >= 0.5;
+            if linear_phase != self.linear_phase {
+                self.linear_phase = linear_phase;
+                self.reset();
+            }
+        }
+        if let Some(v) = self.values.get_mut(idx) {
+            *v = value;
+        }
+        self.dirty = true;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.fir.set_sample_rate(sample_rate);
+        self.dirty = true;
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        if self.channels != buffer.len() {
+            self.channels = buffer.len();
+            self.reset();
+        }
+        if self.dirty {
+            self.update();
+        }
+
+        if self.linear_phase {
+            self.fir.process(buffer);
+        } else {
+            for (channel, filters) in buffer.iter_mut().zip(self.filters.iter_mut()) {
+                for (band, sections) in self.bands.iter().enumerate() {
+                    for filter in &mut filters[band * MAX_STAGES..band * MAX_STAGES + sections.len]
+                    {
+                        for sample in channel.iter_mut() {
+                            *sample = filter.process(*sample);
+                        }
+                    }
+                }
+            }
+        }
+
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0).max(1) as f32;
+        let step = (self.output - self.output_current) / frames;
+        for channel in buffer.iter_mut() {
+            for (f, sample) in channel.iter_mut().enumerate() {
+                *sample *= self.output_current + step * f as f32;
+            }
+        }
+        self.output_current = self.output;
+
+        self.spectrum.push(buffer);
+    }
+
+    fn latency(&self) -> usize {
+        if self.linear_phase {
+            FIR_BLOCK + FIR_TAPS / 2
+        } else {
+            0
+        }
+    }
+
+    fn reset(&mut self) {
+        for filters in &mut self.filters {
+            for filter in filters.iter_mut() {
+                filter.reset();
+            }
+        }
+        self.fir.reset();
+        self.dirty = true;
+    }
+
+    fn spectrum(&self) -> Option<Arc<Spectrum>> {
+        Some(self.spectrum.clone())
+    }
+}
+
+/// FIR filtering by FFT overlap-add, one block of `FIR_BLOCK` frames at a time.
+struct LinearPhase {
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    /// Spectrum of the FIR.
+    kernel: Vec<Complex<f32>>,
+    work: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// cos/sin terms at every bin up to Nyquist, for `sample_rate`.
+    trig: Vec<Trig>,
+    sample_rate: f32,
+    input: Vec<Vec<f32>>,
+    output: Vec<Vec<f32>>,
+    overlap: Vec<Vec<f32>>,
+    /// Channels of the last block, at most `MAX_CHANNELS`.
+    channels: usize,
+    pos: usize,
+}
+
+impl LinearPhase {
+    fn new(sample_rate: f32) -> Self {
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(FIR_FFT);
+        let ifft = planner.plan_fft_inverse(FIR_FFT);
+        let scratch_len = fft
+            .get_inplace_scratch_len()
+            .max(ifft.get_inplace_scratch_len());
+
+        // starts as a unit impulse at the center tap
+        let mut kernel = vec![Complex::default(); FIR_FFT];
+        kernel[FIR_TAPS / 2] = Complex::new(1.0, 0.0);
+        fft.process(&mut kernel);
+
+        let mut fir = Self {
+            fft,
+            ifft,
+            kernel,
+            work: vec![Complex::default(); FIR_FFT],
+            scratch: vec![Complex::default(); scratch_len],
+            trig: Vec::with_capacity(FIR_FFT / 2 + 1),
+            sample_rate: 0.0,
+            input: vec![vec![0.0; FIR_BLOCK]; MAX_CHANNELS],
+            output: vec![vec![0.0; FIR_BLOCK]; MAX_CHANNELS],
+            overlap: vec![vec![0.0; FIR_FFT - FIR_BLOCK]; MAX_CHANNELS],
+            channels: 0,
+            pos: 0,
+        };
+        fir.set_sample_rate(sample_rate);
+        fir
+    }
+
+    /// Refills `trig` in place, without allocating.
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.trig.clear();
+        self.trig.extend(
+            (0..=FIR_FFT / 2)
+                .map(|k| Trig::new(sample_rate, k as f32 * sample_rate / FIR_FFT as f32)),
+        );
+    }
+
+    /// Builds a symmetric FIR with the magnitude response of `bands`.
+    fn design(&mut self, bands: &[Sections]) {
+        for (k, trig) in self.trig.iter().enumerate() {
+            let power: f64 = bands
+                .iter()
+                .flat_map(|b| b.coeffs[..b.len].iter())
+                .map(|c| c.power_at(trig))
+                .product();
+            let magnitude = Complex::new(power.sqrt() as f32, 0.0);
+            self.work[k] = magnitude;
+            if k > 0 && k < FIR_FFT / 2 {
+                self.work[FIR_FFT - k] = magnitude;
+            }
+        }
+        // zero phase impulse, centered on index 0
+        self.ifft
+            .process_with_scratch(&mut self.work, &mut self.scratch);
+
+        let scale = 1.0 / FIR_FFT as f32;
+        for n in 0..FIR_FFT {
+            self.kernel[n] = if n < FIR_TAPS {
+                let tap = self.work[(n + FIR_FFT - FIR_TAPS / 2) % FIR_FFT].re * scale;
+                let window = 0.5 - 0.5 * (TAU * n as f32 / FIR_TAPS as f32).cos();
+                Complex::new(tap * window, 0.0)
+            } else {
+                Complex::default()
+            };
+        }
+        self.fft
+            .process_with_scratch(&mut self.kernel, &mut self.scratch);
+    }
+
+    fn reset(&mut self) {
+        for buf in self
+            .input
+            .iter_mut()
+            .chain(&mut self.output)
+            .chain(&mut self.overlap)
+        {
+            buf.fill(0.0);
+        }
+        self.pos = 0;
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>]) {
+        let channels = buffer.len().min(MAX_CHANNELS);
+        if self.channels != channels {
+            self.channels = channels;
+            self.reset();
+        }
+
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        for f in 0..frames {
+            for (ch, channel) in buffer.iter_mut().take(channels).enumerate() {
+                self.input[ch][self.pos] = channel[f];
+                channel[f] = self.output[ch][self.pos];
+            }
+            self.pos += 1;
+            if self.pos == FIR_BLOCK {
+                self.pos = 0;
+                for ch in 0..channels {
+                    self.convolve(ch);
+                }
+            }
+        }
+    }
+
+    fn convolve(&mut self, ch: usize) {
+        for (w, x) in self
+            .work
+            .iter_mut()
+            .zip(self.input[ch].iter().copied().chain(std::iter::repeat(0.0)))
+        {
+            *w = Complex::new(x, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.work, &mut self.scratch);
+        for (w, k) in self.work.iter_mut().zip(&self.kernel) {
+            *w *= *k;
+        }
+        self.ifft
+            .process_with_scratch(&mut self.work, &mut self.scratch);
+
+        let scale = 1.0 / FIR_FFT as f32;
+        let (output, overlap) = (&mut self.output[ch], &mut self.overlap[ch]);
+        for i in 0..FIR_BLOCK {
+            output[i] = self.work[i].re * scale + overlap[i];
+        }
+        let tail = overlap.len();
+        for i in 0..tail {
+            let carried = if i + FIR_BLOCK < tail {
+                overlap[i + FIR_BLOCK]
+            } else {
+                0.0
+            };
+            overlap[i] = carried + self.work[FIR_BLOCK + i].re * scale;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::tests::{ctx, run};
+
+    const RATE: f32 = 48000.0;
+
+    /// Only band 1 on, set to `kind`.
+    fn equalizer(kind: BandType, freq: f32, gain: f32, slope: usize) -> Equalizer {
+        let mut eq = Equalizer::default();
+        eq.set_sample_rate(RATE);
+        for band in 0..BANDS {
+            eq.set_param(band * BAND_PARAMS, 0.0);
+        }
+        let kind = [
+            BandType::Bell,
+            BandType::LowShelf,
+            BandType::HighShelf,
+            BandType::HighPass,
+            BandType::LowPass,
+            BandType::Notch,
+        ]
+        .iter()
+        .position(|&k| k == kind)
+        .unwrap();
+        for (idx, value) in [1.0, kind as f32, freq, gain, 0.71, slope as f32]
+            .into_iter()
+            .enumerate()
+        {
+            eq.set_param(idx, value);
+        }
+        eq
+    }
+
+    /// Phase kept in f64 so the sine stays clean well below -100 dB.
+    fn sine(freq: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|f| {
+                let cycles = (f as f64 * freq as f64 / RATE as f64).fract();
+                (std::f64::consts::TAU * cycles).sin() as f32
+            })
+            .collect()
+    }
+
+    /// Runs a stereo copy of `input` through in blocks of 512, returning the left channel.
+    fn filter(eq: &mut Equalizer, input: &[f32]) -> Vec<f32> {
+        let ctx = ctx(RATE, 120.0);
+        let mut output = Vec::new();
+        for block in input.chunks(512) {
+            let mut buffer = vec![block.to_vec(), block.to_vec()];
+            run(eq, &mut buffer, &[], &ctx);
+            output.extend_from_slice(&buffer[0]);
+        }
+        output
+    }
+
+    /// Level in dB of a sine settled over `tail`, relative to full scale.
+    fn level(tail: &[f32]) -> f32 {
+        let power = tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32;
+        10.0 * (2.0 * power).log10()
+    }
+
+    /// Response to a sine at `freq`, once the filter has settled.
+    fn response(eq: &mut Equalizer, freq: f32) -> f32 {
+        eq.reset();
+        let output = filter(eq, &sine(freq, 48000));
+        level(&output[24000..])
+    }
+
+    #[test]
+    fn bell_reaches_its_gain_at_the_centre() {
+        let mut eq = equalizer(BandType::Bell, 1000.0, 12.0, 0);
+        assert!((response(&mut eq, 1000.0) - 12.0).abs() < 0.1);
+        assert!(response(&mut eq, 125.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn cuts_fall_by_twelve_db_per_octave_per_section() {
+        for slope in 0..SLOPES.len() {
+            let expected = 12.0 * (slope + 1) as f32;
+
+            let mut high_pass = equalizer(BandType::HighPass, 1000.0, 0.0, slope);
+            let octave = response(&mut high_pass, 500.0) - response(&mut high_pass, 250.0);
+            assert!((octave - expected).abs() < 1.0, "{} {}", slope, octave);
+
+            let mut low_pass = equalizer(BandType::LowPass, 1000.0, 0.0, slope);
+            let octave = response(&mut low_pass, 2000.0) - response(&mut low_pass, 4000.0);
+            assert!((octave - expected).abs() < 1.5, "{} {}", slope, octave);
+        }
+    }
+
+    #[test]
+    fn linear_phase_delays_the_minimum_phase_magnitude() {
+        for freq in [1000.0, 3000.0] {
+            let mut eq = equalizer(BandType::Bell, 2000.0, 9.0, 0);
+            let gain = db_to_gain(response(&mut eq, freq));
+
+            eq.set_param(LINEAR_PHASE, 1.0);
+            let latency = eq.latency();
+            let input = sine(freq, 4 * latency);
+            let output = filter(&mut eq, &input);
+            for f in 2 * latency..output.len() {
+                let expected = gain * input[f - latency];
+                assert!((output[f] - expected).abs() < 0.01, "{} {}", freq, f);
+            }
+        }
+    }
+}
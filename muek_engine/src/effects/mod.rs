@@ -0,0 +1,559 @@
+// Built-in effects. They run as inserts next to VST plugins, on every platform.
+// Parameters live in atomics shared with the host, so changing one never
+// takes the mixer lock; the insert picks up new values at the next block.
+
+pub mod analyzer;
+mod biquad;
+pub mod convolution;
+mod delay;
+pub mod drums;
+mod dynamics;
+mod envelope;
+pub mod eq;
+pub mod oversample;
+mod reverb;
+pub mod sampler;
+mod saturation;
+mod synth;
+mod utility;
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
+
+use anyhow::bail;
+
+use crate::{
+    byte_reader::{ByteReader, Endian},
+    mixer::{BlockContext, Buses, Insert},
+};
+
+use analyzer::Spectrum;
+use convolution::Impulse;
+use drums::DrumKit;
+use sampler::SampleBank;
+
+const STATE_MAGIC: &[u8; 4] = b"MKFX";
+const STATE_VERSION: u8 = 1;
+
+/// Put on tracks that play MIDI before any instrument is loaded.
+pub const DEFAULT_INSTRUMENT: &str = "Synth";
+
+/// Names accepted by `create`.
+pub const BUILTIN_EFFECTS: &[&str] = &[
+    "Gain",
+    "Polarity",
+    "Stereo Width",
+    "DC Blocker",
+    eq::NAME,
+    "Compressor",
+    "Gate",
+    "Limiter",
+    "Delay",
+    "Reverb",
+    convolution::NAME,
+    "Saturation",
+    "Synth",
+    sampler::NAME,
+    drums::NAME,
+];
+
+pub fn create(name: &str) -> Option<Box<dyn Effect>> {
+    let effect: Box<dyn Effect> = match name {
+        "Gain" => Box::new(utility::Gain::default()),
+        "Polarity" => Box::new(utility::Polarity::default()),
+        "Stereo Width" => Box::new(utility::StereoWidth::default()),
+        "DC Blocker" => Box::new(utility::DcBlocker::default()),
+        eq::NAME => Box::new(eq::Equalizer::default()),
+        "Compressor" => Box::new(dynamics::Compressor::default()),
+        "Gate" => Box::new(dynamics::Gate::default()),
+        "Limiter" => Box::new(dynamics::Limiter::default()),
+        "Delay" => Box::new(delay::Delay::default()),
+        "Reverb" => Box::new(reverb::Reverb::default()),
+        convolution::NAME => Box::new(convolution::ConvolutionReverb::default()),
+        "Saturation" => Box::new(saturation::Saturation::default()),
+        "Synth" => Box::new(synth::Synth::default()),
+        sampler::NAME => Box::new(sampler::Sampler::default()),
+        drums::NAME => Box::new(drums::DrumMachine::default()),
+        _ => return None,
+    };
+    Some(effect)
+}
+
+pub struct ParamInfo {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    /// Number of discrete values for switches and choices, 0 when continuous.
+    pub steps: u32,
+    /// Names of the steps of a choice.
+    pub labels: &'static [&'static str],
+}
+
+impl ParamInfo {
+    pub const fn new(
+        name: &'static str,
+        unit: &'static str,
+        min: f32,
+        max: f32,
+        default: f32,
+    ) -> Self {
+        Self {
+            name,
+            unit,
+            min,
+            max,
+            default,
+            steps: 0,
+            labels: &[],
+        }
+    }
+
+    pub const fn switch(name: &'static str, default: bool) -> Self {
+        Self {
+            name,
+            unit: "",
+            min: 0.0,
+            max: 1.0,
+            default: if default { 1.0 } else { 0.0 },
+            steps: 2,
+            labels: &["Off", "On"],
+        }
+    }
+
+    /// Selects one of `labels` by index.
+    pub const fn choice(
+        name: &'static str,
+        labels: &'static [&'static str],
+        default: usize,
+    ) -> Self {
+        Self {
+            name,
+            unit: "",
+            min: 0.0,
+            max: (labels.len() - 1) as f32,
+            default: default as f32,
+            steps: labels.len() as u32,
+            labels,
+        }
+    }
+
+    /// Rounds to a step and keeps the value in range.
+    pub fn constrain(&self, value: f32) -> f32 {
+        let value = value.clamp(self.min, self.max);
+        if self.steps > 1 {
+            let step = (self.max - self.min) / (self.steps - 1) as f32;
+            self.min + ((value - self.min) / step).round() * step
+        } else {
+            value
+        }
+    }
+}
+
+/// A DSP processor that runs inside the engine.
+pub trait Effect: Send {
+    /// Also identifies the effect in saved states.
+    fn name(&self) -> &'static str;
+
+    fn params(&self) -> &'static [ParamInfo];
+
+    /// Called on the audio thread before a block whenever a value changed,
+    /// and for every parameter before the first block.
+    fn set_param(&mut self, idx: usize, value: f32);
+
+    /// Called before the first block and when the rate changes.
+    fn set_sample_rate(&mut self, _sample_rate: f32) {}
+
+    /// Processes `buffer` in place, one `Vec` per engine channel.
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, ctx: &BlockContext);
+
+    /// Samples of delay the effect adds.
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Reads a sidechain as wide as the main bus from `Buses::sidechain`.
+    fn uses_sidechain(&self) -> bool {
+        false
+    }
+
+    /// Clears filter memories, tails and the like.
+    fn reset(&mut self) {}
+
+    /// State beyond the parameter values.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Feed for a spectrum display, if the effect has one.
+    fn spectrum(&self) -> Option<Arc<Spectrum>> {
+        None
+    }
+
+    /// Readouts such as gain reduction, if the effect has any.
+    fn meters(&self) -> Option<Arc<Meters>> {
+        None
+    }
+
+    /// Impulse response slot of a convolution effect.
+    fn impulse(&self) -> Option<Arc<Impulse>> {
+        None
+    }
+
+    /// Instrument file slot of a sampler.
+    fn sample_bank(&self) -> Option<Arc<SampleBank>> {
+        None
+    }
+
+    /// Pads and step grid of a drum machine.
+    fn drum_kit(&self) -> Option<Arc<DrumKit>> {
+        None
+    }
+}
+
+/// Current parameter values of an effect, written by the host and read by the audio thread.
+pub struct EffectParams {
+    /// Name of the effect they belong to.
+    pub name: &'static str,
+    info: &'static [ParamInfo],
+    values: Vec<AtomicU32>,
+}
+
+impl EffectParams {
+    fn new(name: &'static str, info: &'static [ParamInfo]) -> Self {
+        Self {
+            name,
+            info,
+            values: info
+                .iter()
+                .map(|p| AtomicU32::new(p.default.to_bits()))
+                .collect(),
+        }
+    }
+
+    pub fn info(&self) -> &'static [ParamInfo] {
+        self.info
+    }
+
+    pub fn get(&self, idx: usize) -> Option<f32> {
+        let value = self.values.get(idx)?.load(Ordering::Relaxed);
+        Some(f32::from_bits(value))
+    }
+
+    /// False if there is no such parameter.
+    pub fn set(&self, idx: usize, value: f32) -> bool {
+        let (Some(info), Some(slot)) = (self.info.get(idx), self.values.get(idx)) else {
+            return false;
+        };
+        slot.store(info.constrain(value).to_bits(), Ordering::Relaxed);
+        true
+    }
+}
+
+/// Values the audio thread reports for display, one per name.
+pub struct Meters {
+    pub names: &'static [&'static str],
+    values: Vec<AtomicU32>,
+}
+
+impl Meters {
+    pub fn new(names: &'static [&'static str]) -> Self {
+        Self {
+            names,
+            values: names.iter().map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    pub fn set(&self, idx: usize, value: f32) {
+        if let Some(slot) = self.values.get(idx) {
+            slot.store(value.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn values(&self) -> Vec<f32> {
+        self.values
+            .iter()
+            .map(|v| f32::from_bits(v.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Puts an `Effect` into an insert chain.
+pub struct EffectInsert {
+    id: u32,
+    channels: usize,
+    effect: Box<dyn Effect>,
+    params: Arc<EffectParams>,
+    /// Values the effect last saw, NaN before the first block.
+    applied: Vec<f32>,
+    sample_rate: f32,
+}
+
+impl EffectInsert {
+    pub fn new(id: u32, channels: usize, effect: Box<dyn Effect>) -> Self {
+        let params = Arc::new(EffectParams::new(effect.name(), effect.params()));
+        Self {
+            id,
+            channels,
+            applied: vec![f32::NAN; params.values.len()],
+            effect,
+            params,
+            sample_rate: 0.0,
+        }
+    }
+
+    pub fn params(&self) -> Arc<EffectParams> {
+        self.params.clone()
+    }
+
+    pub fn spectrum(&self) -> Option<Arc<Spectrum>> {
+        self.effect.spectrum()
+    }
+
+    pub fn meters(&self) -> Option<Arc<Meters>> {
+        self.effect.meters()
+    }
+
+    pub fn impulse(&self) -> Option<Arc<Impulse>> {
+        self.effect.impulse()
+    }
+
+    pub fn sample_bank(&self) -> Option<Arc<SampleBank>> {
+        self.effect.sample_bank()
+    }
+
+    pub fn drum_kit(&self) -> Option<Arc<DrumKit>> {
+        self.effect.drum_kit()
+    }
+
+    /// magic, version, name, parameter values, then the effect's own state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.push(STATE_VERSION);
+
+        let name = self.effect.name().as_bytes();
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name);
+
+        bytes.extend_from_slice(&(self.params.values.len() as u32).to_le_bytes());
+        for idx in 0..self.params.values.len() {
+            bytes.extend_from_slice(&self.params.get(idx).unwrap_or(0.0).to_le_bytes());
+        }
+
+        let extra = self.effect.save_state();
+        bytes.extend_from_slice(&(extra.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&extra);
+        bytes
+    }
+
+    /// Restores a state from `save_state`. Parameters missing from an older state keep their value.
+    pub fn load_state(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut reader = ByteReader::new(bytes, Endian::Little, "effect state truncated");
+
+        if reader.take(4)? != STATE_MAGIC {
+            bail!("not an effect state");
+        }
+        let version = reader.take(1)?[0];
+        if version != STATE_VERSION {
+            bail!("unsupported effect state version {}", version);
+        }
+
+        let len = reader.u32()? as usize;
+        let name = String::from_utf8_lossy(reader.take(len)?);
+        if name != self.effect.name() {
+            bail!("state is for {}, not {}", name, self.effect.name());
+        }
+
+        let count = reader.u32()? as usize;
+        let values = reader.f32s(count)?;
+        let len = reader.u32()? as usize;
+        let extra = reader.take(len)?;
+
+        self.effect.load_state(extra)?;
+        for (idx, value) in values.into_iter().enumerate() {
+            self.params.set(idx, value);
+        }
+        self.effect.reset();
+        Ok(())
+    }
+
+    /// Hands changed parameter values and the sample rate to the effect.
+    fn sync(&mut self, ctx: &BlockContext) {
+        if ctx.sample_rate != self.sample_rate && ctx.sample_rate > 0.0 {
+            self.sample_rate = ctx.sample_rate;
+            self.effect.set_sample_rate(ctx.sample_rate);
+        }
+        for (idx, applied) in self.applied.iter_mut().enumerate() {
+            let value = self.params.get(idx).unwrap_or(0.0);
+            if value != *applied {
+                *applied = value;
+                self.effect.set_param(idx, value);
+            }
+        }
+    }
+}
+
+impl Insert for EffectInsert {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn io(&self) -> (usize, usize) {
+        let sidechain = if self.effect.uses_sidechain() {
+            self.channels
+        } else {
+            0
+        };
+        (self.channels + sidechain, self.channels)
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, ctx: &BlockContext) {
+        self.sync(ctx);
+        self.effect.process(buffer, buses, ctx);
+    }
+
+    fn latency(&self) -> usize {
+        self.effect.latency()
+    }
+
+    fn as_effect_mut(&mut self) -> Option<&mut EffectInsert> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::MidiEvent;
+
+    fn insert(name: &str) -> EffectInsert {
+        EffectInsert::new(1, 2, create(name).unwrap())
+    }
+
+    /// Every parameter at its maximum, so none is left at the default by accident.
+    fn turn_up(insert: &EffectInsert) {
+        let params = insert.params();
+        for (idx, info) in params.info().iter().enumerate() {
+            params.set(idx, info.max);
+        }
+    }
+
+    fn values(insert: &EffectInsert) -> Vec<f32> {
+        let params = insert.params();
+        (0..params.info().len())
+            .map(|idx| params.get(idx).unwrap())
+            .collect()
+    }
+
+    /// Stopped at beat 0, one beat being a whole note as in the engine.
+    pub(super) fn ctx(sample_rate: f32, bpm: f32) -> BlockContext {
+        BlockContext {
+            sample_rate,
+            bpm,
+            start_beat: 0.0,
+            beats_per_sample: bpm / 60.0 / sample_rate / 4.0,
+            is_playing: false,
+        }
+    }
+
+    /// Uniform in -1..1 from a xorshift seeded with `seed`, which must not be 0.
+    pub(super) fn noise(frames: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..frames)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 * 2.0 - 1.0
+            })
+            .collect()
+    }
+
+    /// Processes one block without sidechain or aux outputs, returning the MIDI out.
+    pub(super) fn run(
+        effect: &mut dyn Effect,
+        buffer: &mut [Vec<f32>],
+        midi_in: &[MidiEvent],
+        ctx: &BlockContext,
+    ) -> Vec<MidiEvent> {
+        let mut midi_out = Vec::new();
+        let mut buses = Buses {
+            sidechain: &[],
+            aux_outputs: &mut [],
+            midi_in,
+            midi_out: &mut midi_out,
+        };
+        effect.process(buffer, &mut buses, ctx);
+        midi_out
+    }
+
+    #[test]
+    fn every_effect_state_round_trips() {
+        for name in BUILTIN_EFFECTS {
+            let saved = insert(name);
+            turn_up(&saved);
+            let bytes = saved.save_state();
+
+            let mut loaded = insert(name);
+            loaded.load_state(&bytes).unwrap();
+            assert_eq!(values(&loaded), values(&saved), "{}", name);
+        }
+    }
+
+    #[test]
+    fn drum_steps_round_trip() {
+        let saved = insert(drums::NAME);
+        let kit = saved.drum_kit().unwrap();
+        kit.set_step(3, 5, 100, 50);
+        let bytes = saved.save_state();
+
+        let mut loaded = insert(drums::NAME);
+        loaded.load_state(&bytes).unwrap();
+        assert_eq!(loaded.drum_kit().unwrap().steps(), kit.steps());
+    }
+
+    #[test]
+    fn refuses_the_state_of_another_effect() {
+        let bytes = insert("Gain").save_state();
+        assert!(insert("Delay").load_state(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncated_states_change_nothing() {
+        for name in ["Compressor", drums::NAME] {
+            let saved = insert(name);
+            turn_up(&saved);
+            let bytes = saved.save_state();
+
+            let mut loaded = insert(name);
+            let defaults = values(&loaded);
+            for len in 0..bytes.len() {
+                assert!(
+                    loaded.load_state(&bytes[..len]).is_err(),
+                    "{} {}",
+                    name,
+                    len
+                );
+                assert_eq!(values(&loaded), defaults);
+            }
+        }
+    }
+
+    #[test]
+    fn huge_counts_are_refused() {
+        let mut bytes = STATE_MAGIC.to_vec();
+        bytes.push(STATE_VERSION);
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(b"Gain");
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(insert("Gain").load_state(&bytes).is_err());
+    }
+}
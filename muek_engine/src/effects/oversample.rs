@@ -0,0 +1,321 @@
+// Oversampling for nonlinear processing. Each doubling is a pair of polyphase
+// half-band FIR filters designed with a Kaiser window; the first stage is the
+// steep one, later stages only have to keep images out of the audible band.
+// A short delay in the innermost domain keeps the latency a whole number of
+// samples at the base rate.
+
+use std::f64::consts::PI;
+
+/// Factors accepted by `Oversampler::new`.
+pub const FACTORS: &[usize] = &[1, 2, 4, 8, 16];
+
+/// Taps of the half-band filter of each stage, all of the form 4k + 3.
+const STAGE_TAPS: [usize; 4] = [95, 27, 19, 19];
+/// About 100 dB of stopband attenuation.
+const KAISER_BETA: f64 = 10.0;
+
+/// Zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Low-pass at a quarter of the sample rate, split into even and odd phases.
+fn halfband(taps: usize) -> (Vec<f32>, Vec<f32>) {
+    let center = (taps - 1) as f64 / 2.0;
+    let mut h: Vec<f64> = (0..taps)
+        .map(|n| {
+            let x = n as f64 - center;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * x / 2.0).sin() / (PI * x / 2.0)
+            };
+            let r = x / center;
+            let window =
+                bessel_i0(KAISER_BETA * (1.0 - r * r).max(0.0).sqrt()) / bessel_i0(KAISER_BETA);
+            sinc * window
+        })
+        .collect();
+    let sum: f64 = h.iter().sum();
+    h.iter_mut().for_each(|x| *x /= sum);
+
+    let even = h.iter().step_by(2).map(|&x| x as f32).collect();
+    let odd = h.iter().skip(1).step_by(2).map(|&x| x as f32).collect();
+    (even, odd)
+}
+
+/// Most recent samples of a filter input.
+struct History {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl History {
+    fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0.0; len.max(1)],
+            pos: 0,
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, x: f32) {
+        self.pos = (self.pos + 1) % self.buf.len();
+        self.buf[self.pos] = x;
+    }
+
+    /// Dot product of the taps with the history, newest sample against the first tap.
+    #[inline]
+    fn dot(&self, taps: &[f32]) -> f32 {
+        let len = self.buf.len();
+        taps.iter()
+            .enumerate()
+            .map(|(j, t)| t * self.buf[(self.pos + len - j) % len])
+            .sum()
+    }
+
+    /// The sample pushed `delay` pushes before the newest.
+    #[inline]
+    fn delayed(&self, delay: usize) -> f32 {
+        let len = self.buf.len();
+        self.buf[(self.pos + len - delay) % len]
+    }
+
+    fn clear(&mut self) {
+        self.buf.fill(0.0);
+    }
+}
+
+/// One doubling: the interpolator on the way up and the decimator on the way down.
+struct Stage {
+    even: Vec<f32>,
+    odd: Vec<f32>,
+    up: History,
+    down_even: History,
+    down_odd: History,
+}
+
+impl Stage {
+    fn new(taps: usize) -> Self {
+        let (even, odd) = halfband(taps);
+        Self {
+            up: History::new(even.len()),
+            down_even: History::new(even.len()),
+            down_odd: History::new(odd.len()),
+            even,
+            odd,
+        }
+    }
+
+    fn upsample(&mut self, input: &[f32], output: &mut [f32]) {
+        for (x, pair) in input.iter().zip(output.chunks_exact_mut(2)) {
+            self.up.push(*x);
+            // zero stuffing halves the level, the factor 2 restores it
+            pair[0] = 2.0 * self.up.dot(&self.even);
+            pair[1] = 2.0 * self.up.dot(&self.odd);
+        }
+    }
+
+    fn downsample(&mut self, input: &[f32], output: &mut [f32]) {
+        for (pair, y) in input.chunks_exact(2).zip(output.iter_mut()) {
+            self.down_even.push(pair[0]);
+            *y = self.down_even.dot(&self.even) + self.down_odd.dot(&self.odd);
+            self.down_odd.push(pair[1]);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.up.clear();
+        self.down_even.clear();
+        self.down_odd.clear();
+    }
+}
+
+/// Runs a per-sample function at a multiple of the sample rate.
+pub struct Oversampler {
+    factor: usize,
+    /// Stages per channel.
+    channels: Vec<Vec<Stage>>,
+    /// Delay in the innermost domain that rounds the latency up, per channel.
+    pads: Vec<History>,
+    pad: usize,
+    latency: usize,
+    scratch: [Vec<f32>; 2],
+}
+
+impl Oversampler {
+    /// `factor` is rounded down to one of `FACTORS`.
+    pub fn new(factor: usize, channels: usize) -> Self {
+        let stages = FACTORS
+            .iter()
+            .rposition(|&f| f <= factor.max(1))
+            .unwrap_or(0);
+        let factor = 1 << stages;
+
+        // delay of all stages counted at the innermost rate
+        let inner: usize = STAGE_TAPS[..stages]
+            .iter()
+            .enumerate()
+            .map(|(s, taps)| (taps - 1) / 2 * (factor >> s))
+            .sum();
+        let pad = (factor - inner % factor) % factor;
+
+        Self {
+            factor,
+            channels: (0..channels)
+                .map(|_| {
+                    STAGE_TAPS[..stages]
+                        .iter()
+                        .map(|&t| Stage::new(t))
+                        .collect()
+                })
+                .collect(),
+            pads: (0..channels).map(|_| History::new(pad + 1)).collect(),
+            pad,
+            latency: (inner + pad) / factor,
+            scratch: [Vec::new(), Vec::new()],
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// Delay added at the base rate.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Replaces every sample of `buffer` by `f(channel, sample)` evaluated at the higher rate.
+    pub fn process(&mut self, buffer: &mut [Vec<f32>], mut f: impl FnMut(usize, f32) -> f32) {
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        let size = frames * self.factor;
+        for scratch in &mut self.scratch {
+            if scratch.len() < size {
+                scratch.resize(size, 0.0);
+            }
+        }
+
+        for (ch, (channel, (stages, pad))) in buffer
+            .iter_mut()
+            .zip(self.channels.iter_mut().zip(&mut self.pads))
+            .enumerate()
+        {
+            if stages.is_empty() {
+                channel.iter_mut().for_each(|x| *x = f(ch, *x));
+                continue;
+            }
+
+            let [a, b] = &mut self.scratch;
+            let mut len = frames;
+            stages[0].upsample(channel, &mut a[..len * 2]);
+            len *= 2;
+            for stage in &mut stages[1..] {
+                stage.upsample(&a[..len], &mut b[..len * 2]);
+                len *= 2;
+                std::mem::swap(a, b);
+            }
+
+            for x in a[..len].iter_mut() {
+                let y = f(ch, *x);
+                if self.pad > 0 {
+                    pad.push(y);
+                    *x = pad.delayed(self.pad);
+                } else {
+                    *x = y;
+                }
+            }
+
+            for stage in stages[1..].iter_mut().rev() {
+                stage.downsample(&a[..len], &mut b[..len / 2]);
+                len /= 2;
+                std::mem::swap(a, b);
+            }
+            stages[0].downsample(&a[..len], channel);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.channels.iter_mut().flatten().for_each(Stage::reset);
+        self.pads.iter_mut().for_each(History::clear);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Level in dB of the part of `samples` after `skip`, relative to a full scale sine.
+    fn level(samples: &[f32], skip: usize) -> f32 {
+        let tail = &samples[skip..];
+        let power = tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32;
+        10.0 * (2.0 * power).max(1e-30).log10()
+    }
+
+    #[test]
+    fn round_trip_is_a_pure_delay_in_the_passband() {
+        for &factor in FACTORS {
+            let mut oversampler = Oversampler::new(factor, 1);
+            let latency = oversampler.latency();
+            let input: Vec<f32> = (0..4096)
+                .map(|f| (2.0 * PI * 1000.0 * f as f64 / 48000.0).sin() as f32)
+                .collect();
+            let mut buffer = vec![input.clone()];
+            oversampler.process(&mut buffer, |_, x| x);
+
+            for f in 512..4096 {
+                let expected = input[f - latency];
+                assert!(
+                    (buffer[0][f] - expected).abs() < 1e-3,
+                    "{}x at {}",
+                    factor,
+                    f
+                );
+            }
+            assert!(level(&buffer[0], 512).abs() < 0.01, "{}x", factor);
+        }
+    }
+
+    #[test]
+    fn latency_is_where_an_impulse_peaks() {
+        for &factor in FACTORS {
+            let mut oversampler = Oversampler::new(factor, 1);
+            let mut buffer = vec![vec![0.0; 256]];
+            buffer[0][0] = 1.0;
+            oversampler.process(&mut buffer, |_, x| x);
+            let peak = (0..256)
+                .max_by(|&a, &b| buffer[0][a].abs().total_cmp(&buffer[0][b].abs()))
+                .unwrap();
+            assert_eq!(peak, oversampler.latency(), "{}x", factor);
+        }
+    }
+
+    #[test]
+    fn rejects_what_is_made_above_nyquist() {
+        for &factor in &FACTORS[1..] {
+            let mut oversampler = Oversampler::new(factor, 1);
+            // a tone at 0.7 of the base rate, made in the oversampled domain
+            let mut n = 0;
+            let mut buffer = vec![vec![0.0; 8192]];
+            oversampler.process(&mut buffer, |_, _| {
+                n += 1;
+                (2.0 * PI * 0.7 * n as f64 / factor as f64).sin() as f32
+            });
+            let level = level(&buffer[0], 512);
+            assert!(level < -90.0, "{}x {}", factor, level);
+        }
+    }
+}
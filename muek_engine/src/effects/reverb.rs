@@ -0,0 +1,275 @@
+// Algorithmic reverb: a pre-delay, a multi-tap early reflection pattern and a
+// feedback delay network of eight lines mixed by a Hadamard matrix for the
+// late tail. The input is the mono sum of the first two channels.
+
+use std::f32::consts::TAU;
+
+use super::{Effect, ParamInfo, delay::Ring};
+use crate::mixer::{BlockContext, Buses};
+
+const REVERB_PARAMS: &[ParamInfo] = &[
+    ParamInfo::new("Size", "%", 0.0, 100.0, 50.0),
+    ParamInfo::new("Decay", "s", 0.1, 20.0, 2.0),
+    ParamInfo::new("Pre-Delay", "ms", 0.0, 250.0, 10.0),
+    ParamInfo::new("Damping", "Hz", 1000.0, 20000.0, 6000.0),
+    ParamInfo::new("Early/Late", "%", 0.0, 100.0, 70.0),
+    ParamInfo::new("Width", "%", 0.0, 100.0, 100.0),
+    ParamInfo::new("Mix", "%", 0.0, 100.0, 25.0),
+];
+
+const LINES: usize = 8;
+/// Line lengths at the middle size, mutually prime-ish so the echoes do not pile up.
+const LINE_MS: [f32; LINES] = [31.7, 37.3, 41.9, 47.1, 53.3, 59.9, 66.7, 73.1];
+/// Signs used to spread the input over the lines and to pick each side's output.
+const INPUT_SIGNS: [f32; LINES] = [1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, -1.0];
+const OUTPUT_SIGNS: [[f32; LINES]; 2] = [
+    [1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0],
+    [1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0],
+];
+
+const EARLY_TAPS: usize = 8;
+/// Reflection times per side at the middle size.
+const EARLY_MS: [[f32; EARLY_TAPS]; 2] = [
+    [4.3, 8.7, 12.1, 17.9, 21.7, 27.1, 31.3, 37.9],
+    [5.1, 9.9, 13.3, 16.3, 23.9, 28.7, 34.1, 39.7],
+];
+const EARLY_LEVEL: f32 = 0.35;
+
+const MAX_PRE_DELAY_MS: f32 = 250.0;
+
+/// Scale of all times, 0.25 to 1.75 over the size range.
+fn size_scale(size: f32) -> f32 {
+    0.25 + 1.5 * size
+}
+
+/// In place, orthogonal so the network neither gains nor loses energy.
+fn hadamard(v: &mut [f32; LINES]) {
+    let mut h = 1;
+    while h < LINES {
+        for i in (0..LINES).step_by(h * 2) {
+            for j in i..i + h {
+                let (a, b) = (v[j], v[j + h]);
+                v[j] = a + b;
+                v[j + h] = a - b;
+            }
+        }
+        h *= 2;
+    }
+    let scale = 1.0 / (LINES as f32).sqrt();
+    v.iter_mut().for_each(|x| *x *= scale);
+}
+
+pub struct Reverb {
+    size: f32,
+    decay: f32,
+    pre_delay_ms: f32,
+    damping: f32,
+    early_late: f32,
+    width: f32,
+    mix: f32,
+    sample_rate: f32,
+    pre_delay: Ring,
+    early: Ring,
+    lines: [Ring; LINES],
+    lengths: [usize; LINES],
+    /// Per pass gain of each line for the decay time.
+    gains: [f32; LINES],
+    damp_coeff: f32,
+    damp_state: [f32; LINES],
+    early_taps: [[(usize, f32); EARLY_TAPS]; 2],
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Self {
+            size: 0.5,
+            decay: 2.0,
+            pre_delay_ms: 10.0,
+            damping: 6000.0,
+            early_late: 0.7,
+            width: 1.0,
+            mix: 0.25,
+            sample_rate: 44100.0,
+            pre_delay: Ring::default(),
+            early: Ring::default(),
+            lines: Default::default(),
+            lengths: [1; LINES],
+            gains: [0.0; LINES],
+            damp_coeff: 1.0,
+            damp_state: [0.0; LINES],
+            early_taps: [[(1, 0.0); EARLY_TAPS]; 2],
+        }
+    }
+}
+
+impl Reverb {
+    fn samples(&self, ms: f32) -> usize {
+        (ms * 0.001 * self.sample_rate) as usize
+    }
+
+    /// Recomputes lengths, gains and taps. Only the ring buffers depend on the rate alone.
+    fn update(&mut self) {
+        let scale = size_scale(self.size);
+        self.lengths = LINE_MS.map(|ms| self.samples(ms * scale).max(1));
+        let decay_samples = self.decay * self.sample_rate;
+        self.gains = self
+            .lengths
+            .map(|length| 10f32.powf(-3.0 * length as f32 / decay_samples));
+        for (side, taps) in self.early_taps.iter_mut().enumerate() {
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let gain = 1.0 - 0.7 * i as f32 / EARLY_TAPS as f32;
+                let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+                *tap = (
+                    ((EARLY_MS[side][i] * scale * 0.001 * self.sample_rate) as usize).max(1),
+                    gain * sign,
+                );
+            }
+        }
+        self.damp_coeff = 1.0 - (-TAU * self.damping / self.sample_rate).exp();
+    }
+}
+
+impl Effect for Reverb {
+    fn name(&self) -> &'static str {
+        "Reverb"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        REVERB_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => self.size = value / 100.0,
+            1 => self.decay = value,
+            2 => self.pre_delay_ms = value,
+            3 => self.damping = value,
+            4 => self.early_late = value / 100.0,
+            5 => self.width = value / 100.0,
+            6 => self.mix = value / 100.0,
+            _ => return,
+        }
+        self.update();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        let largest = size_scale(1.0);
+        self.pre_delay.resize(self.samples(MAX_PRE_DELAY_MS) + 1);
+        self.early
+            .resize(self.samples(EARLY_MS[1][EARLY_TAPS - 1] * largest) + 1);
+        let lengths = LINE_MS.map(|ms| self.samples(ms * largest) + 1);
+        for (line, length) in self.lines.iter_mut().zip(lengths) {
+            line.resize(length);
+        }
+        self.update();
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        let pre_delay = self.samples(self.pre_delay_ms);
+        let stereo = buffer.len().min(2);
+
+        for f in 0..frames {
+            let input = buffer[..stereo].iter().map(|ch| ch[f]).sum::<f32>() / stereo as f32;
+            self.pre_delay.push(input);
+            let x = self.pre_delay.tap(pre_delay + 1);
+
+            self.early.push(x);
+            let mut early = [0.0; 2];
+            for (side, taps) in self.early_taps.iter().enumerate() {
+                early[side] = taps
+                    .iter()
+                    .map(|&(delay, gain)| self.early.tap(delay) * gain)
+                    .sum::<f32>()
+                    * EARLY_LEVEL;
+            }
+
+            let mut outs = [0.0; LINES];
+            let mut feedback = [0.0; LINES];
+            for i in 0..LINES {
+                outs[i] = self.lines[i].tap(self.lengths[i]);
+                self.damp_state[i] += self.damp_coeff * (outs[i] - self.damp_state[i]);
+                feedback[i] = self.damp_state[i] * self.gains[i];
+            }
+            hadamard(&mut feedback);
+            for i in 0..LINES {
+                self.lines[i].push(feedback[i] + x * INPUT_SIGNS[i]);
+            }
+
+            let mut wet = [0.0; 2];
+            for side in 0..2 {
+                let late: f32 = outs
+                    .iter()
+                    .zip(OUTPUT_SIGNS[side])
+                    .map(|(out, sign)| out * sign)
+                    .sum::<f32>()
+                    / LINES as f32;
+                wet[side] = early[side] * (1.0 - self.early_late) + late * self.early_late;
+            }
+            let mid = (wet[0] + wet[1]) * 0.5;
+            let side = (wet[0] - wet[1]) * 0.5 * self.width;
+            let wet = [mid + side, mid - side];
+
+            for (ch, channel) in buffer.iter_mut().enumerate() {
+                channel[f] = channel[f] * (1.0 - self.mix) + wet[ch.min(1)] * self.mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pre_delay.clear();
+        self.early.clear();
+        self.lines.iter_mut().for_each(Ring::clear);
+        self.damp_state = [0.0; LINES];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::tests::{ctx, run};
+
+    const RATE: f32 = 48000.0;
+
+    #[test]
+    fn tail_decays_at_its_decay_time() {
+        let mut reverb = Reverb::default();
+        reverb.set_sample_rate(RATE);
+        for (idx, value) in [(1, 1.0), (2, 0.0), (6, 100.0)] {
+            reverb.set_param(idx, value);
+        }
+
+        let ctx = ctx(RATE, 120.0);
+        let mut output = Vec::new();
+        for start in (0..4 * RATE as usize).step_by(512) {
+            let mut buffer = vec![vec![0.0; 512]; 2];
+            if start == 0 {
+                buffer[0][0] = 1.0;
+                buffer[1][0] = 1.0;
+            }
+            run(&mut reverb, &mut buffer, &[], &ctx);
+            output.extend_from_slice(&buffer[0]);
+        }
+        assert!(output.iter().all(|x| x.is_finite()));
+
+        // a tenth of a second of tail, in dB
+        let level = |secs: f32| {
+            let start = (secs * RATE) as usize;
+            let window = &output[start..start + RATE as usize / 10];
+            10.0 * (window.iter().map(|x| x * x).sum::<f32>()).log10()
+        };
+        let mut levels = (2..30).map(|tenth| level(tenth as f32 / 10.0));
+        let first = levels.next().unwrap();
+        assert!(
+            levels
+                .clone()
+                .zip(levels.clone().skip(1))
+                .all(|(a, b)| b < a)
+        );
+        // 60 dB per second, helped down by the damping
+        let one_second_on = level(1.2);
+        assert!(first - one_second_on > 55.0, "{}", first - one_second_on);
+        assert!(first - one_second_on < 75.0, "{}", first - one_second_on);
+    }
+}
@@ -0,0 +1,776 @@
+// Sample player for SoundFont 2 banks and SFZ instruments. Loading parses the
+// file and decodes its samples off the audio thread, which picks the finished
+// program up at its next block. Zones are chosen by key, velocity, round
+// robin and trigger; every voice runs its own envelope.
+
+pub mod sf2;
+pub mod sfz;
+
+use std::{
+    f32::consts::{FRAC_PI_4, SQRT_2},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::bail;
+
+use super::{
+    Effect, ParamInfo,
+    envelope::{Adsr, Envelope},
+    utility::db_to_gain,
+};
+use crate::{
+    midi::Message,
+    mixer::{BlockContext, Buses},
+};
+
+pub const NAME: &str = "Sampler";
+
+const SAMPLER_PARAMS: &[ParamInfo] = &[
+    ParamInfo::new("Preset", "", 0.0, 127.0, 0.0),
+    ParamInfo::new("Volume", "dB", -60.0, 12.0, 0.0),
+    ParamInfo::new("Transpose", "st", -24.0, 24.0, 0.0),
+    ParamInfo::new("Tune", "ct", -100.0, 100.0, 0.0),
+    ParamInfo::choice("Envelope", &["Instrument", "Custom"], 0),
+    ParamInfo::new("Attack", "ms", 0.0, 5000.0, 1.0),
+    ParamInfo::new("Decay", "ms", 1.0, 5000.0, 500.0),
+    ParamInfo::new("Sustain", "%", 0.0, 100.0, 100.0),
+    ParamInfo::new("Release", "ms", 1.0, 10000.0, 300.0),
+    ParamInfo::new("Velocity", "%", 0.0, 100.0, 100.0),
+    ParamInfo::new("Voices", "", 1.0, MAX_VOICES as f32, 64.0),
+    ParamInfo::new("Bend Range", "st", 0.0, 24.0, 2.0),
+    ParamInfo::switch("Release Triggers", true),
+];
+
+const MAX_VOICES: usize = 128;
+/// Release of a voice cut off by another note of its group.
+const CHOKE_SECS: f32 = 0.005;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopMode {
+    NoLoop,
+    /// Plays to the end whatever the note-off.
+    OneShot,
+    Continuous,
+    /// Loops until the note is released.
+    Sustain,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    Attack,
+    Release,
+    /// Only when no other key is down.
+    First,
+    /// Only when another key is down.
+    Legato,
+}
+
+/// Decoded audio, one `Vec` per channel.
+pub struct Sample {
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl Sample {
+    /// Keeps the first two of `channels` interleaved channels.
+    pub fn from_interleaved(samples: &[f32], channels: usize) -> Self {
+        let channels = channels.max(1);
+        Self {
+            channels: (0..channels.min(2))
+                .map(|ch| samples.iter().skip(ch).step_by(channels).copied().collect())
+                .collect(),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.channels.first().map(|c| c.len()).unwrap_or(0)
+    }
+}
+
+/// A sample mapped to a range of keys and velocities, with how to play it.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    /// Index into `Program::samples`.
+    pub sample: usize,
+    pub sample_rate: f32,
+    pub keys: (u8, u8),
+    pub velocities: (u8, u8),
+    /// Key at which the sample plays at its recorded pitch.
+    pub root: f32,
+    pub tune: f32,
+    /// Cents per key.
+    pub key_track: f32,
+    pub gain: f32,
+    /// -1 to 1.
+    pub pan: f32,
+    /// Frames of the sample, the end exclusive.
+    pub start: usize,
+    pub end: usize,
+    pub loop_mode: LoopMode,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    /// Attack, decay and release in seconds, sustain as a level.
+    pub envelope: [f32; 4],
+    pub trigger: Trigger,
+    /// Attenuation of release triggers in dB per second the key was down.
+    pub rt_decay: f32,
+    pub seq_length: u32,
+    /// 1 based position in the round robin.
+    pub seq_position: u32,
+    /// Range of the random value, drawn per note, this zone plays in.
+    pub random: (f32, f32),
+    /// Starting this zone cuts voices whose `off_by` is its group, 0 for none.
+    pub group: u32,
+    pub off_by: u32,
+    /// Share of the level that follows velocity.
+    pub vel_track: f32,
+}
+
+impl Default for Zone {
+    fn default() -> Self {
+        Self {
+            sample: 0,
+            sample_rate: 44100.0,
+            keys: (0, 127),
+            velocities: (0, 127),
+            root: 60.0,
+            tune: 0.0,
+            key_track: 100.0,
+            gain: 1.0,
+            pan: 0.0,
+            start: 0,
+            end: usize::MAX,
+            loop_mode: LoopMode::NoLoop,
+            loop_start: 0,
+            loop_end: 0,
+            envelope: [0.0, 0.0, 1.0, 0.001],
+            trigger: Trigger::Attack,
+            rt_decay: 0.0,
+            seq_length: 1,
+            seq_position: 1,
+            random: (0.0, 1.0),
+            group: 0,
+            off_by: 0,
+            vel_track: 1.0,
+        }
+    }
+}
+
+impl Zone {
+    fn matches(&self, note: u8, velocity: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&note)
+            && (self.velocities.0..=self.velocities.1).contains(&velocity)
+    }
+
+    fn loops(&self) -> bool {
+        matches!(self.loop_mode, LoopMode::Continuous | LoopMode::Sustain)
+    }
+}
+
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u8,
+    pub zones: Vec<Zone>,
+}
+
+/// A loaded instrument file.
+pub struct Program {
+    pub presets: Vec<Preset>,
+    pub samples: Vec<Sample>,
+}
+
+impl Program {
+    /// Picks the format by extension.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let mut program = match extension.as_str() {
+            "sf2" => sf2::load(path)?,
+            "sfz" => sfz::load(path)?,
+            _ => bail!("{} is neither an SF2 nor an SFZ file", path),
+        };
+        program.validate();
+        if program.presets.iter().all(|p| p.zones.is_empty()) {
+            bail!("{} has no playable zones", path);
+        }
+        Ok(program)
+    }
+
+    /// Keeps every zone and loop inside its sample.
+    fn validate(&mut self) {
+        let samples = &self.samples;
+        for preset in &mut self.presets {
+            preset.zones.retain_mut(|zone| {
+                let Some(sample) = samples.get(zone.sample) else {
+                    return false;
+                };
+                zone.end = zone.end.min(sample.frames());
+                zone.start = zone.start.min(zone.end);
+                if zone.loop_end > zone.end || zone.loop_start >= zone.loop_end {
+                    zone.loop_start = zone.start;
+                    zone.loop_end = zone.end;
+                }
+                zone.start < zone.end
+            });
+        }
+    }
+
+    fn seconds(&self) -> f32 {
+        let zones = self.presets.iter().flat_map(|p| &p.zones);
+        // a pool shared by zones of different rates has no single length
+        let rate = zones.map(|z| z.sample_rate).next().unwrap_or(44100.0);
+        self.samples.iter().map(|s| s.frames()).sum::<usize>() as f32 / rate
+    }
+}
+
+#[derive(Default)]
+struct Handoff {
+    ready: Option<Arc<Program>>,
+    /// The program replaced last, freed here rather than on the audio thread.
+    retired: Option<Arc<Program>>,
+}
+
+/// The instrument file of a sampler, shared between the host and the audio thread.
+#[derive(Default)]
+pub struct SampleBank {
+    /// File the sampler should play, empty for none.
+    path: Mutex<String>,
+    program: Mutex<Option<Arc<Program>>>,
+    handoff: Mutex<Handoff>,
+}
+
+impl SampleBank {
+    /// Parses `path` and decodes its samples on the calling thread.
+    pub fn load(&self, path: &str) -> anyhow::Result<()> {
+        let program = Arc::new(Program::load(path)?);
+        *self.path.lock().unwrap() = path.to_owned();
+        *self.program.lock().unwrap() = Some(program.clone());
+        let mut handoff = self.handoff.lock().unwrap();
+        handoff.ready = Some(program);
+        handoff.retired = None;
+        Ok(())
+    }
+
+    /// For restored states, which arrive while the mixer is locked.
+    fn load_in_background(self: &Arc<Self>, path: String) {
+        *self.path.lock().unwrap() = path.clone();
+        let bank = self.clone();
+        let spawned = thread::Builder::new()
+            .name("muek-sample-loader".to_owned())
+            .spawn(move || {
+                if let Err(e) = bank.load(&path) {
+                    eprintln!("Failed to load instrument {}: {}", path, e);
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("Failed to start sample loader: {}", e);
+        }
+    }
+
+    pub fn path(&self) -> String {
+        self.path.lock().unwrap().clone()
+    }
+
+    /// Presets, zones and seconds of audio of the loaded file.
+    pub fn info(&self) -> Option<(usize, usize, f32)> {
+        let program = self.program.lock().unwrap();
+        program.as_ref().map(|p| {
+            let zones = p.presets.iter().map(|p| p.zones.len()).sum();
+            (p.presets.len(), zones, p.seconds())
+        })
+    }
+
+    /// Bank, program number and name of each preset, in the order the Preset parameter counts them.
+    pub fn presets(&self) -> Vec<(u16, u8, String)> {
+        let program = self.program.lock().unwrap();
+        program
+            .iter()
+            .flat_map(|p| &p.presets)
+            .map(|p| (p.bank, p.program, p.name.clone()))
+            .collect()
+    }
+}
+
+/// Catmull-Rom interpolation between `y1` and `y2`.
+#[inline]
+pub(super) fn hermite(y0: f32, y1: f32, y2: f32, y3: f32, t: f32) -> f32 {
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * t + c2) * t + c1) * t + y1
+}
+
+/// Left and right gains for `pan` from -1 to 1. A stereo sample is balanced
+/// rather than panned.
+pub(super) fn pan_gains(pan: f32, stereo: bool) -> [f32; 2] {
+    if pan == 0.0 {
+        [1.0, 1.0]
+    } else if stereo {
+        [(1.0 - pan).min(1.0), (1.0 + pan).min(1.0)]
+    } else {
+        let angle = (pan + 1.0) * FRAC_PI_4;
+        [angle.cos() * SQRT_2, angle.sin() * SQRT_2]
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Voice {
+    note: u8,
+    preset: usize,
+    zone: usize,
+    /// Position in the sample, in frames.
+    pos: f64,
+    /// Frames advanced per output frame, before pitch bend.
+    step: f64,
+    gains: [f32; 2],
+    env: Envelope,
+    adsr: Adsr,
+    /// Order of the note-on, the oldest voice is stolen first.
+    age: u64,
+    held: bool,
+    sustained: bool,
+    looping: bool,
+    off_by: u32,
+}
+
+impl Voice {
+    fn release(&mut self, zone: &Zone) {
+        self.held = false;
+        self.sustained = false;
+        if zone.loop_mode == LoopMode::OneShot {
+            return;
+        }
+        self.looping = zone.loop_mode == LoopMode::Continuous;
+        self.env.release();
+    }
+
+    /// Adds the voice to `left` and `right` until they end or the voice does.
+    fn render(&mut self, zone: &Zone, sample: &Sample, bend: f64, out: [&mut [f32]; 2]) {
+        let [left, right] = out;
+        let step = self.step * bend;
+        let loop_len = (zone.loop_end - zone.loop_start) as f64;
+        let stereo = sample.channels.len() > 1;
+        let looping = self.looping;
+        let read = |data: &[f32], idx: usize| -> f32 {
+            if looping && idx >= zone.loop_end {
+                data[zone.loop_start + (idx - zone.loop_end) % (zone.loop_end - zone.loop_start)]
+            } else if idx < zone.end {
+                data[idx]
+            } else {
+                0.0
+            }
+        };
+
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            let at = |data: &[f32]| {
+                let before = if i > zone.start {
+                    read(data, i - 1)
+                } else {
+                    data[i]
+                };
+                hermite(
+                    before,
+                    read(data, i),
+                    read(data, i + 1),
+                    read(data, i + 2),
+                    t,
+                )
+            };
+            let level = self.env.next(&self.adsr);
+            if stereo {
+                *l += at(&sample.channels[0]) * self.gains[0] * level;
+                *r += at(&sample.channels[1]) * self.gains[1] * level;
+            } else {
+                let s = at(&sample.channels[0]) * level;
+                *l += s * self.gains[0];
+                *r += s * self.gains[1];
+            }
+
+            self.pos += step;
+            if self.looping && self.pos >= zone.loop_end as f64 {
+                self.pos -= loop_len;
+            } else if !self.looping && self.pos >= zone.end as f64 {
+                self.env.kill();
+            }
+            if !self.env.is_active() {
+                break;
+            }
+        }
+    }
+}
+
+pub struct Sampler {
+    bank: Arc<SampleBank>,
+    program: Option<Arc<Program>>,
+    preset: usize,
+    volume: f32,
+    transpose: f32,
+    tune: f32,
+    custom_envelope: bool,
+    /// Attack, decay and release in seconds, sustain as a level.
+    envelope: [f32; 4],
+    velocity: f32,
+    max_voices: usize,
+    bend_range: f32,
+    release_triggers: bool,
+    sample_rate: f32,
+    voices: [Voice; MAX_VOICES],
+    next_age: u64,
+    /// Pitch wheel, -1 to 1.
+    bend: f32,
+    sustain_pedal: bool,
+    /// Per key: down, velocity and frame of the note-on, for release triggers.
+    keys: [(bool, u8, u64); 128],
+    /// Note-ons per key, for round robins.
+    round_robin: [u32; 128],
+    /// Frames processed, the clock of `keys`.
+    frame: u64,
+    random: u32,
+    mix: [Vec<f32>; 2],
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self {
+            bank: Arc::new(SampleBank::default()),
+            program: None,
+            preset: 0,
+            volume: 1.0,
+            transpose: 0.0,
+            tune: 0.0,
+            custom_envelope: false,
+            envelope: [0.001, 0.5, 1.0, 0.3],
+            velocity: 1.0,
+            max_voices: 64,
+            bend_range: 2.0,
+            release_triggers: true,
+            sample_rate: 44100.0,
+            voices: [Voice::default(); MAX_VOICES],
+            next_age: 0,
+            bend: 0.0,
+            sustain_pedal: false,
+            keys: [(false, 0, 0); 128],
+            round_robin: [0; 128],
+            frame: 0,
+            random: 0x9e37_79b9,
+            mix: [Vec::new(), Vec::new()],
+        }
+    }
+}
+
+impl Sampler {
+    /// Uniform in 0..1, xorshift.
+    fn next_random(&mut self) -> f32 {
+        let mut x = self.random;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random = x;
+        (x >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::NoteOn { note, velocity } => self.note_on(note, velocity),
+            Message::NoteOff { note } => self.note_off(note),
+            Message::Controller { number, value } => match number {
+                64 => {
+                    self.sustain_pedal = value >= 64;
+                    if !self.sustain_pedal {
+                        self.release_voices(|v| v.sustained);
+                    }
+                }
+                120 => self.voices.iter_mut().for_each(|v| v.env.kill()),
+                123 => self.release_voices(|v| v.held || v.sustained),
+                _ => {}
+            },
+            Message::ProgramChange(number) => {
+                let Some(program) = &self.program else {
+                    return;
+                };
+                let bank = program.presets.get(self.preset).map(|p| p.bank);
+                let found = program
+                    .presets
+                    .iter()
+                    .position(|p| Some(p.bank) == bank && p.program == number)
+                    .or_else(|| program.presets.iter().position(|p| p.program == number));
+                if let Some(idx) = found {
+                    self.preset = idx;
+                }
+            }
+            Message::PitchBend(bend) => self.bend = bend,
+            Message::Other => {}
+        }
+    }
+
+    fn release_voices(&mut self, filter: impl Fn(&Voice) -> bool) {
+        let Some(program) = &self.program else {
+            return;
+        };
+        for voice in self.voices.iter_mut().filter(|v| filter(v)) {
+            voice.release(&program.presets[voice.preset].zones[voice.zone]);
+        }
+    }
+
+    fn note_on(&mut self, note: u8, velocity: u8) {
+        let Some(program) = self.program.clone() else {
+            return;
+        };
+        let Some(preset) = program.presets.get(self.preset) else {
+            return;
+        };
+        let key = note as usize & 127;
+        let first = !self.keys.iter().any(|k| k.0);
+        self.keys[key] = (true, velocity, self.frame);
+        let round = self.round_robin[key];
+        self.round_robin[key] = round.wrapping_add(1);
+        let random = self.next_random();
+        self.next_age += 1;
+
+        for (idx, zone) in preset.zones.iter().enumerate() {
+            let triggered = match zone.trigger {
+                Trigger::Attack => true,
+                Trigger::First => first,
+                Trigger::Legato => !first,
+                Trigger::Release => false,
+            };
+            if triggered
+                && zone.matches(note, velocity)
+                && (zone.seq_length <= 1 || round % zone.seq_length + 1 == zone.seq_position)
+                && (zone.random.0..zone.random.1).contains(&random)
+            {
+                self.start_voice(zone, idx, note, velocity, 1.0);
+            }
+        }
+    }
+
+    fn note_off(&mut self, note: u8) {
+        let key = note as usize & 127;
+        let (down, velocity, since) = self.keys[key];
+        if !down {
+            return;
+        }
+        self.keys[key].0 = false;
+
+        let Some(program) = self.program.clone() else {
+            return;
+        };
+        for voice in self.voices.iter_mut().filter(|v| v.held && v.note == note) {
+            if self.sustain_pedal {
+                voice.held = false;
+                voice.sustained = true;
+            } else {
+                voice.release(&program.presets[voice.preset].zones[voice.zone]);
+            }
+        }
+
+        if !self.release_triggers {
+            return;
+        }
+        let Some(preset) = program.presets.get(self.preset) else {
+            return;
+        };
+        let held_secs = (self.frame - since) as f32 / self.sample_rate;
+        self.next_age += 1;
+        for (idx, zone) in preset.zones.iter().enumerate() {
+            if zone.trigger == Trigger::Release && zone.matches(note, velocity) {
+                let gain = db_to_gain(-zone.rt_decay * held_secs);
+                self.start_voice(zone, idx, note, velocity, gain);
+            }
+        }
+    }
+
+    fn start_voice(&mut self, zone: &Zone, idx: usize, note: u8, velocity: u8, gain: f32) {
+        // voices of the same note-on never cut each other, e.g. the halves of a stereo pair
+        if zone.group != 0 {
+            let choke = Adsr::new(0.0, 0.0, 0.0, CHOKE_SECS, self.sample_rate);
+            for voice in self
+                .voices
+                .iter_mut()
+                .filter(|v| v.env.is_active() && v.off_by == zone.group && v.age != self.next_age)
+            {
+                voice.adsr = choke;
+                voice.env.release();
+            }
+        }
+
+        let voices = &mut self.voices[..self.max_voices];
+        let slot = voices
+            .iter()
+            .position(|v| !v.env.is_active())
+            .unwrap_or_else(|| {
+                // steal, releasing voices first, then the oldest
+                (0..voices.len())
+                    .min_by_key(|&i| (!voices[i].env.is_released(), voices[i].age))
+                    .unwrap_or(0)
+            });
+
+        let semitones = (note as f32 - zone.root) * zone.key_track / 100.0
+            + (zone.tune + self.tune) / 100.0
+            + self.transpose;
+        let v = velocity as f32 / 127.0;
+        let track = zone.vel_track * self.velocity;
+        let level = zone.gain * (1.0 - track + track * v * v) * gain;
+        let stereo = self
+            .program
+            .as_ref()
+            .is_some_and(|p| p.samples[zone.sample].channels.len() > 1);
+        let gains = pan_gains(zone.pan, stereo);
+        let [attack, decay, sustain, release] = if self.custom_envelope {
+            self.envelope
+        } else {
+            zone.envelope
+        };
+
+        let voice = &mut voices[slot];
+        *voice = Voice {
+            note,
+            preset: self.preset,
+            zone: idx,
+            pos: zone.start as f64,
+            step: 2f64.powf(semitones as f64 / 12.0) * zone.sample_rate as f64
+                / self.sample_rate as f64,
+            gains: gains.map(|g| g * level),
+            env: Envelope::default(),
+            adsr: Adsr::new(attack, decay, sustain, release, self.sample_rate),
+            age: self.next_age,
+            held: zone.trigger != Trigger::Release,
+            sustained: false,
+            looping: zone.loops(),
+            off_by: zone.off_by,
+        };
+        voice.env.trigger();
+    }
+
+    fn render(&mut self, start: usize, end: usize) {
+        let Some(program) = &self.program else {
+            return;
+        };
+        let bend = 2f64.powf((self.bend * self.bend_range) as f64 / 12.0);
+        let [left, right] = &mut self.mix;
+        for voice in self.voices.iter_mut().filter(|v| v.env.is_active()) {
+            let zone = &program.presets[voice.preset].zones[voice.zone];
+            let sample = &program.samples[zone.sample];
+            voice.render(
+                zone,
+                sample,
+                bend,
+                [&mut left[start..end], &mut right[start..end]],
+            );
+        }
+    }
+}
+
+impl Effect for Sampler {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        SAMPLER_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => self.preset = value.round() as usize,
+            1 => self.volume = db_to_gain(value),
+            2 => self.transpose = value.round(),
+            3 => self.tune = value,
+            4 => self.custom_envelope = value >= 0.5,
+            5 | 6 | 8 => self.envelope[idx - 5] = value / 1000.0,
+            7 => self.envelope[2] = value / 100.0,
+            9 => self.velocity = value / 100.0,
+            10 => self.max_voices = (value.round() as usize).clamp(1, MAX_VOICES),
+            11 => self.bend_range = value,
+            12 => self.release_triggers = value >= 0.5,
+            _ => {}
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.voices.iter_mut().for_each(|v| v.env.kill());
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, _ctx: &BlockContext) {
+        if let Ok(mut handoff) = self.bank.handoff.try_lock()
+            && let Some(ready) = handoff.ready.take()
+        {
+            self.voices.iter_mut().for_each(|v| v.env.kill());
+            handoff.retired = self.program.replace(ready);
+        }
+        if let Some(program) = &self.program
+            && self.preset >= program.presets.len()
+        {
+            self.preset = program.presets.len().saturating_sub(1);
+        }
+
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        for mix in &mut self.mix {
+            mix.clear();
+            mix.resize(frames, 0.0);
+        }
+
+        // events split the block so each lands on its frame
+        let mut events = buses.midi_in.iter().peekable();
+        let mut f = 0;
+        while f < frames {
+            while let Some(event) = events.next_if(|e| e.delta_frames as usize <= f) {
+                self.handle(event.message());
+            }
+            let end = events
+                .peek()
+                .map_or(frames, |e| (e.delta_frames as usize).min(frames));
+            self.render(f, end);
+            self.frame += (end - f) as u64;
+            f = end;
+        }
+        for event in events {
+            self.handle(event.message());
+        }
+
+        let [left, right] = &self.mix;
+        if buffer.len() == 1 {
+            for ((out, l), r) in buffer[0].iter_mut().zip(left).zip(right) {
+                *out += (l + r) * 0.5 * self.volume;
+            }
+        } else {
+            for (ch, channel) in buffer.iter_mut().enumerate() {
+                let side = if ch == 0 { left } else { right };
+                for (out, s) in channel.iter_mut().zip(side) {
+                    *out += s * self.volume;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.voices = [Voice::default(); MAX_VOICES];
+        self.keys = [(false, 0, 0); 128];
+        self.bend = 0.0;
+        self.sustain_pedal = false;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.bank.path().into_bytes()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let path = String::from_utf8(data.to_vec())?;
+        if !path.is_empty() && path != self.bank.path() {
+            self.bank.load_in_background(path);
+        }
+        Ok(())
+    }
+
+    fn sample_bank(&self) -> Option<Arc<SampleBank>> {
+        Some(self.bank.clone())
+    }
+}
@@ -0,0 +1,494 @@
+// SoundFont 2 banks. The whole sample pool is decoded once and every zone
+// points into it. Generators of a preset zone are added to those of the
+// instrument zones it covers, and key and velocity ranges intersect, as the
+// specification asks; modulators are not supported.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, anyhow, bail};
+
+use super::{LoopMode, Preset, Program, Sample, Zone};
+use crate::decode;
+
+const GENERATORS: usize = 61;
+
+const START_OFFSET: usize = 0;
+const END_OFFSET: usize = 1;
+const LOOP_START_OFFSET: usize = 2;
+const LOOP_END_OFFSET: usize = 3;
+const START_COARSE_OFFSET: usize = 4;
+const END_COARSE_OFFSET: usize = 12;
+const PAN: usize = 17;
+const ATTACK_VOL_ENV: usize = 34;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VEL_RANGE: usize = 44;
+const LOOP_START_COARSE_OFFSET: usize = 45;
+const INITIAL_ATTENUATION: usize = 48;
+const LOOP_END_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const SCALE_TUNING: usize = 56;
+const EXCLUSIVE_CLASS: usize = 57;
+const OVERRIDING_ROOT_KEY: usize = 58;
+
+/// Generators a preset zone may not change.
+const INSTRUMENT_ONLY: &[usize] = &[
+    START_OFFSET,
+    END_OFFSET,
+    LOOP_START_OFFSET,
+    LOOP_END_OFFSET,
+    START_COARSE_OFFSET,
+    END_COARSE_OFFSET,
+    LOOP_START_COARSE_OFFSET,
+    LOOP_END_COARSE_OFFSET,
+    46, // keynum
+    47, // velocity
+    SAMPLE_MODES,
+    EXCLUSIVE_CLASS,
+    OVERRIDING_ROOT_KEY,
+];
+
+/// Raw amounts by generator.
+type Generators = [Option<[u8; 2]>; GENERATORS];
+
+fn amount(generators: &Generators, op: usize) -> Option<i32> {
+    generators[op].map(|a| i16::from_le_bytes(a) as i32)
+}
+
+fn range(generators: &Generators, op: usize) -> (u8, u8) {
+    generators[op].map(|[lo, hi]| (lo, hi)).unwrap_or((0, 127))
+}
+
+fn default_amount(op: usize) -> i32 {
+    match op {
+        // about a millisecond, in timecents
+        ATTACK_VOL_ENV | DECAY_VOL_ENV | RELEASE_VOL_ENV => -12000,
+        SCALE_TUNING => 100,
+        OVERRIDING_ROOT_KEY => -1,
+        _ => 0,
+    }
+}
+
+fn timecents(tc: i32) -> f32 {
+    2f32.powf(tc as f32 / 1200.0)
+}
+
+/// Attenuation in centibels to a gain.
+fn centibels(cb: i32) -> f32 {
+    10f32.powf(-(cb.clamp(0, 1440) as f32) / 200.0)
+}
+
+struct Chunk<'a> {
+    id: [u8; 4],
+    data: &'a [u8],
+}
+
+/// The chunks of a RIFF list body.
+fn chunks(mut data: &[u8]) -> impl Iterator<Item = Chunk<'_>> {
+    std::iter::from_fn(move || {
+        if data.len() < 8 {
+            return None;
+        }
+        let id = data[..4].try_into().unwrap();
+        let size = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let body = &data[8..];
+        let size = size.min(body.len());
+        let chunk = Chunk {
+            id,
+            data: &body[..size],
+        };
+        // chunks are padded to an even size
+        data = &body[(size + (size & 1)).min(body.len())..];
+        Some(chunk)
+    })
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn name(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_owned()
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    correction: i8,
+    kind: u16,
+}
+
+/// Zones as lists of generators, the global zone already merged into each.
+fn zones(
+    bags: &[&[u8]],
+    gens: &[&[u8]],
+    from: usize,
+    to: usize,
+    last_op: usize,
+) -> Vec<Generators> {
+    let mut global: Generators = [None; GENERATORS];
+    let mut zones = Vec::new();
+    for bag in from..to.min(bags.len().saturating_sub(1)) {
+        let first = u16_at(bags[bag], 0) as usize;
+        let last = (u16_at(bags[bag + 1], 0) as usize).min(gens.len());
+        let mut zone = global;
+        let mut terminated = false;
+        for generator in gens.get(first..last).unwrap_or(&[]) {
+            let op = u16_at(generator, 0) as usize;
+            if op < GENERATORS {
+                zone[op] = Some([generator[2], generator[3]]);
+                terminated = op == last_op;
+            }
+        }
+        if terminated {
+            zones.push(zone);
+        } else if bag == from {
+            // the first zone without an instrument or sample holds the defaults of the others
+            global = zone;
+        }
+    }
+    zones
+}
+
+pub fn load(path: &str) -> anyhow::Result<Program> {
+    let bytes = std::fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    parse(&bytes)
+}
+
+fn parse(bytes: &[u8]) -> anyhow::Result<Program> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+        bail!("not a SoundFont 2 bank");
+    }
+
+    let mut smpl = None;
+    let mut sm24 = None;
+    let mut pdta = HashMap::new();
+    for list in chunks(&bytes[12..]).filter(|c| &c.id == b"LIST" && c.data.len() >= 4) {
+        let kind = &list.data[..4];
+        for chunk in chunks(&list.data[4..]) {
+            match (kind, &chunk.id) {
+                (b"sdta", b"smpl") => smpl = Some(chunk.data),
+                (b"sdta", b"sm24") => sm24 = Some(chunk.data),
+                (b"pdta", id) => {
+                    pdta.insert(*id, chunk.data);
+                }
+                _ => {}
+            }
+        }
+    }
+    let smpl = smpl.ok_or_else(|| anyhow!("bank has no sample data"))?;
+    let records = |id: &[u8; 4], size: usize| -> anyhow::Result<Vec<&[u8]>> {
+        let data = pdta
+            .get(id)
+            .ok_or_else(|| anyhow!("bank has no {} chunk", String::from_utf8_lossy(id)))?;
+        Ok(data.chunks_exact(size).collect())
+    };
+    let phdr = records(b"phdr", 38)?;
+    let pbag = records(b"pbag", 4)?;
+    let pgen = records(b"pgen", 4)?;
+    let inst = records(b"inst", 22)?;
+    let ibag = records(b"ibag", 4)?;
+    let igen = records(b"igen", 4)?;
+    let shdr: Vec<SampleHeader> = records(b"shdr", 46)?
+        .iter()
+        .map(|r| SampleHeader {
+            start: u32_at(r, 20),
+            end: u32_at(r, 24),
+            loop_start: u32_at(r, 28),
+            loop_end: u32_at(r, 32),
+            sample_rate: u32_at(r, 36),
+            original_pitch: r[40],
+            correction: r[41] as i8,
+            kind: u16_at(r, 44),
+        })
+        .collect();
+
+    // the last record of each list only terminates it
+    let instruments: Vec<Vec<Generators>> = inst
+        .windows(2)
+        .map(|w| {
+            let from = u16_at(w[0], 20) as usize;
+            let to = u16_at(w[1], 20) as usize;
+            zones(&ibag, &igen, from, to, SAMPLE_ID)
+        })
+        .collect();
+
+    let mut presets = Vec::new();
+    for w in phdr.windows(2) {
+        let from = u16_at(w[0], 24) as usize;
+        let to = u16_at(w[1], 24) as usize;
+        let mut zones_out = Vec::new();
+        for preset_zone in zones(&pbag, &pgen, from, to, INSTRUMENT) {
+            let Some(instrument) =
+                amount(&preset_zone, INSTRUMENT).and_then(|i| instruments.get(i as u16 as usize))
+            else {
+                continue;
+            };
+            for inst_zone in instrument {
+                if let Some(zone) = combine(inst_zone, &preset_zone, &shdr) {
+                    zones_out.push(zone);
+                }
+            }
+        }
+        presets.push(Preset {
+            name: name(&w[0][..20]),
+            program: u16_at(w[0], 20).min(127) as u8,
+            bank: u16_at(w[0], 22),
+            zones: zones_out,
+        });
+    }
+    presets.sort_by_key(|p| (p.bank, p.program));
+
+    Ok(Program {
+        presets,
+        samples: vec![Sample {
+            channels: vec![decode::pcm16_decode(smpl, sm24)],
+        }],
+    })
+}
+
+/// One playable zone from an instrument zone and the preset zone above it.
+fn combine(inst: &Generators, preset: &Generators, shdr: &[SampleHeader]) -> Option<Zone> {
+    let header = shdr.get(amount(inst, SAMPLE_ID)? as u16 as usize)?;
+    // samples in ROM are not part of the file
+    if header.kind & 0x8000 != 0 {
+        return None;
+    }
+    let value = |op: usize| {
+        let own = amount(inst, op).unwrap_or_else(|| default_amount(op));
+        if INSTRUMENT_ONLY.contains(&op) {
+            own
+        } else {
+            own + amount(preset, op).unwrap_or(0)
+        }
+    };
+    let intersect = |op: usize| {
+        let (a, b) = (range(inst, op), range(preset, op));
+        (a.0.max(b.0), a.1.min(b.1))
+    };
+    let keys = intersect(KEY_RANGE);
+    let velocities = intersect(VEL_RANGE);
+    if keys.0 > keys.1 || velocities.0 > velocities.1 {
+        return None;
+    }
+
+    let offset = |base: u32, fine: usize, coarse: usize| {
+        (base as i64 + value(fine) as i64 + value(coarse) as i64 * 32768).max(0) as usize
+    };
+    let root = match value(OVERRIDING_ROOT_KEY) {
+        key @ 0..=127 => key as f32,
+        _ if header.original_pitch <= 127 => header.original_pitch as f32,
+        _ => 60.0,
+    };
+    let loop_mode = match value(SAMPLE_MODES) & 3 {
+        1 => LoopMode::Continuous,
+        3 => LoopMode::Sustain,
+        _ => LoopMode::NoLoop,
+    };
+    let class = value(EXCLUSIVE_CLASS).max(0) as u32;
+
+    Some(Zone {
+        sample: 0,
+        sample_rate: header.sample_rate.max(1) as f32,
+        keys,
+        velocities,
+        root,
+        tune: (value(COARSE_TUNE) * 100 + value(FINE_TUNE) + header.correction as i32) as f32,
+        key_track: value(SCALE_TUNING) as f32,
+        gain: centibels(value(INITIAL_ATTENUATION)),
+        pan: (value(PAN) as f32 / 500.0).clamp(-1.0, 1.0),
+        start: offset(header.start, START_OFFSET, START_COARSE_OFFSET),
+        end: offset(header.end, END_OFFSET, END_COARSE_OFFSET),
+        loop_mode,
+        loop_start: offset(
+            header.loop_start,
+            LOOP_START_OFFSET,
+            LOOP_START_COARSE_OFFSET,
+        ),
+        loop_end: offset(header.loop_end, LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET),
+        envelope: [
+            timecents(value(ATTACK_VOL_ENV)),
+            timecents(value(DECAY_VOL_ENV)),
+            centibels(value(SUSTAIN_VOL_ENV)),
+            timecents(value(RELEASE_VOL_ENV)),
+        ],
+        group: class,
+        off_by: class,
+        ..Zone::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        chunks.iter().for_each(|c| data.extend_from_slice(c));
+        chunk(b"LIST", &data)
+    }
+
+    fn name(name: &str) -> [u8; 20] {
+        let mut raw = [0u8; 20];
+        raw[..name.len()].copy_from_slice(name.as_bytes());
+        raw
+    }
+
+    fn preset(title: &str, program: u16, bag: u16) -> Vec<u8> {
+        let mut out = name(title).to_vec();
+        for v in [program, 0, bag] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&[0; 12]);
+        out
+    }
+
+    fn instrument(title: &str, bag: u16) -> Vec<u8> {
+        let mut out = name(title).to_vec();
+        out.extend_from_slice(&bag.to_le_bytes());
+        out
+    }
+
+    fn sample_header(title: &str, start: u32, end: u32, loops: (u32, u32)) -> Vec<u8> {
+        let mut out = name(title).to_vec();
+        for v in [start, end, loops.0, loops.1, 22050] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        // original pitch, correction, link and a mono sample
+        out.extend_from_slice(&[60, 0, 0, 0, 1, 0]);
+        out
+    }
+
+    /// Records of 4 bytes made of two u16, e.g. bags, or a generator and its amount.
+    fn pairs(pairs: &[(u16, [u8; 2])]) -> Vec<u8> {
+        pairs
+            .iter()
+            .flat_map(|(a, b)| {
+                let mut record = a.to_le_bytes().to_vec();
+                record.extend_from_slice(b);
+                record
+            })
+            .collect()
+    }
+
+    /// One preset of one instrument of one looping sample on keys 36 to 72.
+    fn bank() -> Vec<u8> {
+        let smpl: Vec<u8> = (0..16i16).flat_map(|s| (s * 1000).to_le_bytes()).collect();
+        let pdta = list(
+            b"pdta",
+            &[
+                chunk(
+                    b"phdr",
+                    &[preset("Piano", 5, 0), preset("EOP", 0, 1)].concat(),
+                ),
+                chunk(b"pbag", &pairs(&[(0, [0, 0]), (1, [0, 0])])),
+                chunk(b"pgen", &pairs(&[(INSTRUMENT as u16, [0, 0]), (0, [0, 0])])),
+                chunk(
+                    b"inst",
+                    &[instrument("Keys", 0), instrument("EOI", 1)].concat(),
+                ),
+                chunk(b"ibag", &pairs(&[(0, [0, 0]), (3, [0, 0])])),
+                chunk(
+                    b"igen",
+                    &pairs(&[
+                        (KEY_RANGE as u16, [36, 72]),
+                        (SAMPLE_MODES as u16, [1, 0]),
+                        (SAMPLE_ID as u16, [0, 0]),
+                        (0, [0, 0]),
+                    ]),
+                ),
+                chunk(
+                    b"shdr",
+                    &[
+                        sample_header("Tone", 0, 12, (4, 10)),
+                        sample_header("EOS", 0, 0, (0, 0)),
+                    ]
+                    .concat(),
+                ),
+            ],
+        );
+        let sdta = list(b"sdta", &[chunk(b"smpl", &smpl)]);
+        let body = [b"sfbk".to_vec(), sdta, pdta].concat();
+        [chunk(b"RIFF", &body)].concat()
+    }
+
+    #[test]
+    fn reads_a_bank() {
+        let program = parse(&bank()).unwrap();
+
+        assert_eq!(program.samples.len(), 1);
+        assert_eq!(program.samples[0].frames(), 16);
+        assert_eq!(program.samples[0].channels[0][1], 1000.0 / 32768.0);
+
+        assert_eq!(program.presets.len(), 1);
+        let preset = &program.presets[0];
+        assert_eq!(preset.name, "Piano");
+        assert_eq!(preset.program, 5);
+        assert_eq!(preset.zones.len(), 1);
+        let zone = &preset.zones[0];
+        assert_eq!(zone.keys, (36, 72));
+        assert_eq!(zone.velocities, (0, 127));
+        assert_eq!(zone.root, 60.0);
+        assert_eq!(zone.sample_rate, 22050.0);
+        assert_eq!((zone.start, zone.end), (0, 12));
+        assert_eq!((zone.loop_start, zone.loop_end), (4, 10));
+        assert_eq!(zone.loop_mode, LoopMode::Continuous);
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        assert!(parse(b"").is_err());
+        assert!(parse(b"RIFF\0\0\0\0WAVE").is_err());
+        // a bank without its preset data
+        let body = [b"sfbk".to_vec(), list(b"sdta", &[chunk(b"smpl", &[0; 4])])].concat();
+        assert!(parse(&chunk(b"RIFF", &body)).is_err());
+    }
+
+    #[test]
+    fn cut_or_damaged_banks_never_panic() {
+        let bank = bank();
+        for len in 0..bank.len() {
+            let _ = parse(&bank[..len]);
+        }
+        // every index and size in the bank pointing somewhere it shouldn't
+        for at in 0..bank.len() {
+            for value in [0x00, 0x7f, 0xff] {
+                let mut damaged = bank.clone();
+                damaged[at] = value;
+                if let Ok(mut program) = parse(&damaged) {
+                    program.validate();
+                    for zone in program.presets.iter().flat_map(|p| &p.zones) {
+                        let frames = program.samples[zone.sample].frames();
+                        assert!(zone.start < zone.end && zone.end <= frames);
+                        assert!(zone.loop_start < zone.loop_end && zone.loop_end <= zone.end);
+                    }
+                }
+            }
+        }
+    }
+}
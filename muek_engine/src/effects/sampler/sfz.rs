@@ -0,0 +1,467 @@
+// SFZ instruments: a text file of <global>, <master>, <group> and <region>
+// headers whose opcodes apply to the regions under them, with the samples in
+// files of their own. Each sample file is decoded once, however many regions
+// use it. Unknown opcodes are ignored.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, anyhow};
+
+use super::{LoopMode, Preset, Program, Sample, Trigger, Zone};
+use crate::{decode, effects::utility::db_to_gain};
+
+pub fn load(path: &str) -> anyhow::Result<Program> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path))?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let name = Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    parse(&text, &name, |sample| {
+        let file = dir.join(sample);
+        let file = file.to_string_lossy();
+        let (samples, channels, sample_rate) =
+            decode::auto_decode(&file).ok_or_else(|| anyhow!("cannot decode {}", file))?;
+        Ok((
+            Sample::from_interleaved(&samples, channels),
+            sample_rate as f32,
+        ))
+    })
+}
+
+/// Strips comments and expands `#define`d variables.
+fn preprocess(text: &str) -> String {
+    let mut defines: Vec<(String, String)> = Vec::new();
+    let mut out = String::new();
+    let mut in_block = false;
+    for line in text.lines() {
+        let mut line = line.to_owned();
+        if in_block {
+            match line.find("*/") {
+                Some(end) => {
+                    line = line[end + 2..].to_owned();
+                    in_block = false;
+                }
+                None => continue,
+            }
+        }
+        while let Some(start) = line.find("/*") {
+            match line[start..].find("*/") {
+                Some(end) => line.replace_range(start..start + end + 2, " "),
+                None => {
+                    line.truncate(start);
+                    in_block = true;
+                }
+            }
+        }
+        if let Some(start) = line.find("//") {
+            line.truncate(start);
+        }
+
+        let trimmed = line.trim();
+        if let Some(define) = trimmed.strip_prefix("#define") {
+            let mut parts = define.split_whitespace();
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                defines.push((name.to_owned(), value.to_owned()));
+                // longest first, so $NOTE is not replaced inside $NOTE2
+                defines.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+            }
+            continue;
+        }
+        if trimmed.starts_with('#') {
+            eprintln!("Ignoring unsupported SFZ directive: {}", trimmed);
+            continue;
+        }
+        for (name, value) in &defines {
+            line = line.replace(name, value);
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Where the value starting `s` ends: before the next opcode or header.
+fn value_end(s: &str) -> usize {
+    for (i, _) in s.char_indices().filter(|(_, c)| c.is_whitespace()) {
+        let next = s[i..].trim_start();
+        if next.starts_with('<') {
+            return i;
+        }
+        if let Some(eq) = next.find('=') {
+            let word = &next[..eq];
+            if !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return i;
+            }
+        }
+    }
+    s.len()
+}
+
+/// A key as a number or a name such as c#4, where c4 is 60.
+fn parse_key(value: &str) -> Option<u8> {
+    if let Ok(key) = value.parse::<i32>() {
+        return u8::try_from(key).ok().filter(|k| *k <= 127);
+    }
+    let value = value.to_ascii_lowercase();
+    let mut chars = value.chars();
+    let mut key = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let octave = if let Some(rest) = rest.strip_prefix('#') {
+        key += 1;
+        rest
+    } else if let Some(rest) = rest.strip_prefix('b').filter(|r| !r.is_empty()) {
+        key -= 1;
+        rest
+    } else {
+        rest
+    };
+    let key = (octave.parse::<i32>().ok()? + 1) * 12 + key;
+    u8::try_from(key).ok().filter(|k| *k <= 127)
+}
+
+/// Applies one opcode other than `sample`.
+fn apply(zone: &mut Zone, key: &str, value: &str) {
+    let number = || value.parse::<f32>().ok();
+    let frames = || value.parse::<usize>().ok();
+    match key {
+        "lokey" => zone.keys.0 = parse_key(value).unwrap_or(zone.keys.0),
+        "hikey" => zone.keys.1 = parse_key(value).unwrap_or(zone.keys.1),
+        "key" => {
+            if let Some(key) = parse_key(value) {
+                zone.keys = (key, key);
+                zone.root = key as f32;
+            }
+        }
+        "lovel" => zone.velocities.0 = value.parse().unwrap_or(zone.velocities.0),
+        "hivel" => zone.velocities.1 = value.parse().unwrap_or(zone.velocities.1),
+        "pitch_keycenter" => zone.root = parse_key(value).map_or(zone.root, |k| k as f32),
+        "pitch_keytrack" => zone.key_track = number().unwrap_or(zone.key_track),
+        "tune" => zone.tune += number().unwrap_or(0.0),
+        "transpose" => zone.tune += number().unwrap_or(0.0) * 100.0,
+        "volume" => zone.gain *= db_to_gain(number().unwrap_or(0.0)),
+        "amplitude" => zone.gain *= number().unwrap_or(100.0) / 100.0,
+        "pan" => zone.pan = (number().unwrap_or(0.0) / 100.0).clamp(-1.0, 1.0),
+        "offset" => zone.start = frames().unwrap_or(zone.start),
+        // the end and loop end are inclusive in SFZ
+        "end" => zone.end = frames().map_or(zone.end, |f| f + 1),
+        "loop_mode" | "loopmode" => {
+            zone.loop_mode = match value {
+                "one_shot" => LoopMode::OneShot,
+                "loop_continuous" => LoopMode::Continuous,
+                "loop_sustain" => LoopMode::Sustain,
+                _ => LoopMode::NoLoop,
+            }
+        }
+        "loop_start" | "loopstart" => zone.loop_start = frames().unwrap_or(zone.loop_start),
+        "loop_end" | "loopend" => zone.loop_end = frames().map_or(zone.loop_end, |f| f + 1),
+        "ampeg_attack" => zone.envelope[0] = number().unwrap_or(zone.envelope[0]),
+        "ampeg_decay" => zone.envelope[1] = number().unwrap_or(zone.envelope[1]),
+        "ampeg_sustain" => zone.envelope[2] = number().map_or(zone.envelope[2], |s| s / 100.0),
+        "ampeg_release" => zone.envelope[3] = number().unwrap_or(zone.envelope[3]),
+        "trigger" => {
+            zone.trigger = match value {
+                "release" => Trigger::Release,
+                "first" => Trigger::First,
+                "legato" => Trigger::Legato,
+                _ => Trigger::Attack,
+            }
+        }
+        "rt_decay" => zone.rt_decay = number().unwrap_or(0.0),
+        "seq_length" => zone.seq_length = value.parse().unwrap_or(1),
+        "seq_position" => zone.seq_position = value.parse().unwrap_or(1),
+        "lorand" => zone.random.0 = number().unwrap_or(0.0),
+        "hirand" => zone.random.1 = number().unwrap_or(1.0),
+        "group" => zone.group = value.parse().unwrap_or(0),
+        "off_by" => zone.off_by = value.parse().unwrap_or(0),
+        "amp_veltrack" => zone.vel_track = number().map_or(zone.vel_track, |v| v / 100.0),
+        _ => {}
+    }
+}
+
+#[derive(Default)]
+struct Scope {
+    opcodes: Vec<(String, String)>,
+}
+
+/// `decode` turns a sample path, relative to the file, into audio and its rate.
+fn parse(
+    text: &str,
+    name: &str,
+    mut decode: impl FnMut(&str) -> anyhow::Result<(Sample, f32)>,
+) -> anyhow::Result<Program> {
+    let text = preprocess(text);
+
+    let mut default_path = String::new();
+    // global, master, group and the region being read
+    let mut scopes: [Scope; 4] = Default::default();
+    let mut header = String::new();
+    let mut regions: Vec<Vec<(String, String)>> = Vec::new();
+    let mut in_region = false;
+
+    let mut close_region = |scopes: &[Scope; 4], in_region: &mut bool| {
+        if *in_region {
+            regions.push(scopes.iter().flat_map(|s| s.opcodes.clone()).collect());
+            *in_region = false;
+        }
+    };
+
+    for line in text.lines() {
+        let mut rest = line.trim();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('<') {
+                let Some(end) = after.find('>') else {
+                    break;
+                };
+                close_region(&scopes, &mut in_region);
+                header = after[..end].trim().to_owned();
+                let level = match header.as_str() {
+                    "global" => Some(0),
+                    "master" => Some(1),
+                    "group" => Some(2),
+                    "region" => Some(3),
+                    _ => None,
+                };
+                if let Some(level) = level {
+                    scopes[level..].iter_mut().for_each(|s| s.opcodes.clear());
+                    in_region = level == 3;
+                }
+                rest = after[end + 1..].trim_start();
+                continue;
+            }
+
+            let Some(eq) = rest.find('=') else {
+                break;
+            };
+            let key = rest[..eq].trim().to_owned();
+            let after = &rest[eq + 1..];
+            let end = value_end(after);
+            let value = after[..end].trim().to_owned();
+            rest = after[end..].trim_start();
+
+            match header.as_str() {
+                "control" if key == "default_path" => default_path = value,
+                "global" => scopes[0].opcodes.push((key, value)),
+                "master" => scopes[1].opcodes.push((key, value)),
+                "group" => scopes[2].opcodes.push((key, value)),
+                "region" => scopes[3].opcodes.push((key, value)),
+                _ => {}
+            }
+        }
+    }
+    close_region(&scopes, &mut in_region);
+
+    let mut samples = Vec::new();
+    let mut loaded: HashMap<String, Option<(usize, f32)>> = HashMap::new();
+    let mut zones = Vec::new();
+    for opcodes in regions {
+        let mut zone = Zone::default();
+        let mut sample = None;
+        for (key, value) in &opcodes {
+            if key == "sample" {
+                sample = Some(format!("{}{}", default_path, value).replace('\\', "/"));
+            } else {
+                apply(&mut zone, key, value);
+            }
+        }
+        let Some(sample) = sample else {
+            continue;
+        };
+
+        let entry = loaded
+            .entry(sample.clone())
+            .or_insert_with(|| match decode(&sample) {
+                Ok((decoded, rate)) => {
+                    samples.push(decoded);
+                    Some((samples.len() - 1, rate))
+                }
+                Err(e) => {
+                    eprintln!("Skipping SFZ region: {}", e);
+                    None
+                }
+            });
+        let Some((idx, rate)) = *entry else {
+            continue;
+        };
+        zone.sample = idx;
+        zone.sample_rate = rate;
+        zones.push(zone);
+    }
+
+    Ok(Program {
+        presets: vec![Preset {
+            name: name.to_owned(),
+            bank: 0,
+            program: 0,
+            zones,
+        }],
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses with every sample 100 frames of silence at 48 kHz, except `bad.wav`.
+    fn read(text: &str) -> (Program, Vec<String>) {
+        let mut decoded = Vec::new();
+        let program = parse(text, "Test", |path| {
+            decoded.push(path.to_owned());
+            if path.ends_with("bad.wav") {
+                return Err(anyhow!("cannot decode {}", path));
+            }
+            Ok((Sample::from_interleaved(&[0.0; 100], 1), 48000.0))
+        })
+        .unwrap();
+        (program, decoded)
+    }
+
+    #[test]
+    fn regions_take_the_opcodes_of_their_scopes() {
+        let (program, _) = read(
+            "<global> volume=-6 lokey=10
+             <group> hikey=20 loop_mode=loop_continuous
+             <region> sample=a.wav
+             <region> sample=b.wav hikey=30 tune=5
+             <group> trigger=release
+             <region> sample=c.wav",
+        );
+        let zones = &program.presets[0].zones;
+        assert_eq!(program.presets[0].name, "Test");
+        assert_eq!(zones.len(), 3);
+
+        assert_eq!(zones[0].keys, (10, 20));
+        assert_eq!(zones[0].loop_mode, LoopMode::Continuous);
+        assert!((zones[0].gain - db_to_gain(-6.0)).abs() < 1e-6);
+        assert_eq!(zones[0].sample_rate, 48000.0);
+
+        assert_eq!(zones[1].keys, (10, 30));
+        assert_eq!(zones[1].tune, 5.0);
+
+        // a new group drops the opcodes of the last one, not the global ones
+        assert_eq!(zones[2].keys, (10, 127));
+        assert_eq!(zones[2].loop_mode, LoopMode::NoLoop);
+        assert_eq!(zones[2].trigger, Trigger::Release);
+    }
+
+    #[test]
+    fn reads_key_names() {
+        assert_eq!(parse_key("60"), Some(60));
+        assert_eq!(parse_key("c4"), Some(60));
+        assert_eq!(parse_key("C#4"), Some(61));
+        assert_eq!(parse_key("db4"), Some(61));
+        assert_eq!(parse_key("b3"), Some(59));
+        assert_eq!(parse_key("c-1"), Some(0));
+        assert_eq!(parse_key("g9"), Some(127));
+        assert_eq!(parse_key("a9"), None);
+        assert_eq!(parse_key("128"), None);
+        assert_eq!(parse_key("-1"), None);
+        assert_eq!(parse_key("h4"), None);
+        assert_eq!(parse_key(""), None);
+    }
+
+    #[test]
+    fn expands_defines_and_strips_comments() {
+        let (program, decoded) = read(
+            "#define $KEY 40
+             #define $KEY2 50
+             // <region> sample=commented.wav
+             /* <region>
+                sample=blocked.wav */ <region> sample=piano/a.wav key=$KEY
+             <region> sample=piano\\b.wav /* inline */ lokey=$KEY2 // trailing",
+        );
+        let zones = &program.presets[0].zones;
+        assert_eq!(decoded, ["piano/a.wav", "piano/b.wav"]);
+        assert_eq!(zones[0].keys, (40, 40));
+        assert_eq!(zones[0].root, 40.0);
+        assert_eq!(zones[1].keys.0, 50);
+    }
+
+    #[test]
+    fn sample_paths_may_have_spaces() {
+        let (_, decoded) = read(
+            "<control> default_path=Grand Piano/
+             <region> sample=C 4 soft.wav lokey=60 <region> sample=x.wav",
+        );
+        assert_eq!(decoded, ["Grand Piano/C 4 soft.wav", "Grand Piano/x.wav"]);
+    }
+
+    #[test]
+    fn ends_are_inclusive() {
+        let (mut program, _) =
+            read("<region> sample=a.wav offset=10 end=49 loop_start=20 loop_end=29");
+        program.validate();
+        let zone = &program.presets[0].zones[0];
+        assert_eq!((zone.start, zone.end), (10, 50));
+        assert_eq!((zone.loop_start, zone.loop_end), (20, 30));
+    }
+
+    #[test]
+    fn samples_are_decoded_once_and_failures_skipped() {
+        let (program, decoded) = read(
+            "<region> sample=a.wav
+             <region> sample=bad.wav
+             <region> sample=a.wav key=70
+             <region> sample=bad.wav
+             <region> lokey=5",
+        );
+        assert_eq!(decoded, ["a.wav", "bad.wav"]);
+        assert_eq!(program.samples.len(), 1);
+        let zones = &program.presets[0].zones;
+        assert_eq!(zones.len(), 2);
+        assert!(zones.iter().all(|z| z.sample == 0));
+    }
+
+    #[test]
+    fn validate_keeps_zones_inside_their_sample() {
+        let (mut program, _) = read(
+            "<region> sample=a.wav end=499 loop_start=50 loop_end=300
+             <region> sample=a.wav offset=200
+             <region> sample=a.wav loop_start=80 loop_end=40",
+        );
+        program.validate();
+        let zones = &program.presets[0].zones;
+        // the zone past the end is dropped
+        assert_eq!(zones.len(), 2);
+        assert_eq!((zones[0].start, zones[0].end), (0, 100));
+        assert_eq!((zones[0].loop_start, zones[0].loop_end), (0, 100));
+        assert_eq!((zones[1].loop_start, zones[1].loop_end), (0, 100));
+    }
+
+    #[test]
+    fn malformed_text_never_panics() {
+        let text = "<global> volume=-3 <group> lokey=c#4 hikey=g9
+            <region> sample=a.wav offset=5 end=90 loop_mode=loop_sustain /* note */ key=60 // x
+            #define $V 10
+            <region> sample=b.wav lovel=$V";
+        for end in text.char_indices().map(|(i, _)| i) {
+            read(&text[..end]).0.validate();
+        }
+        for junk in [
+            "<",
+            "<region",
+            "=",
+            "==",
+            "<region> =x",
+            "<region> sample=",
+            "/*",
+            "*/ <region>",
+            "#define",
+            "#define $X",
+            "<region> sample=a.wav key=é lokey=999 end=-1 pan=nan",
+            "\u{feff}<region>",
+        ] {
+            read(junk).0.validate();
+        }
+    }
+}
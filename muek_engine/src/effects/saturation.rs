@@ -0,0 +1,238 @@
+// Waveshaping distortion with a choice of curves, run oversampled so the
+// harmonics it creates above Nyquist are filtered instead of folding back.
+
+use super::{Effect, ParamInfo, oversample::Oversampler, utility::db_to_gain};
+use crate::mixer::{BlockContext, Buses, DelayLine};
+
+const OVERSAMPLING_LABELS: &[&str] = &["Off", "2x", "4x", "8x", "16x"];
+
+const SATURATION_PARAMS: &[ParamInfo] = &[
+    ParamInfo::choice(
+        "Model",
+        &["Tape", "Tube", "Hard Clip", "Foldback", "Bitcrush"],
+        0,
+    ),
+    ParamInfo::new("Drive", "dB", 0.0, 36.0, 6.0),
+    ParamInfo::new("Bias", "%", -50.0, 50.0, 0.0),
+    ParamInfo::new("Bits", "", 1.0, 24.0, 8.0),
+    ParamInfo::new("Downsample", "x", 1.0, 32.0, 1.0),
+    ParamInfo::choice("Oversampling", OVERSAMPLING_LABELS, 2),
+    ParamInfo::new("Output", "dB", -24.0, 12.0, 0.0),
+    ParamInfo::new("Mix", "%", 0.0, 100.0, 100.0),
+];
+
+#[derive(Clone, Copy)]
+enum Model {
+    Tape,
+    Tube,
+    HardClip,
+    Foldback,
+    Bitcrush,
+}
+
+/// Sample and hold state of the bitcrusher, per channel.
+#[derive(Clone, Copy, Default)]
+struct Hold {
+    value: f32,
+    left: usize,
+}
+
+pub struct Saturation {
+    model: Model,
+    drive: f32,
+    bias: f32,
+    bits: f32,
+    downsample: usize,
+    factor: usize,
+    output: f32,
+    mix: f32,
+    oversampler: Oversampler,
+    holds: Vec<Hold>,
+    dry: Vec<Vec<f32>>,
+    dry_delay: DelayLine,
+}
+
+impl Default for Saturation {
+    fn default() -> Self {
+        Self {
+            model: Model::Tape,
+            drive: db_to_gain(6.0),
+            bias: 0.0,
+            bits: 8.0,
+            downsample: 1,
+            factor: 4,
+            output: 1.0,
+            mix: 1.0,
+            oversampler: Oversampler::new(4, 0),
+            holds: Vec::new(),
+            dry: Vec::new(),
+            dry_delay: DelayLine::default(),
+        }
+    }
+}
+
+/// Triangle folding that leaves -1..1 untouched.
+fn fold(x: f32) -> f32 {
+    ((x - 1.0).rem_euclid(4.0) - 2.0).abs() - 1.0
+}
+
+impl Effect for Saturation {
+    fn name(&self) -> &'static str {
+        "Saturation"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        SATURATION_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        match idx {
+            0 => {
+                self.model = match value as usize {
+                    0 => Model::Tape,
+                    1 => Model::Tube,
+                    2 => Model::HardClip,
+                    3 => Model::Foldback,
+                    _ => Model::Bitcrush,
+                }
+            }
+            1 => self.drive = db_to_gain(value),
+            2 => self.bias = value / 100.0,
+            3 => self.bits = value,
+            4 => self.downsample = value.round().max(1.0) as usize,
+            5 => {
+                self.factor = 1 << (value as usize).min(OVERSAMPLING_LABELS.len() - 1);
+                self.oversampler = Oversampler::new(self.factor, self.oversampler.channels());
+            }
+            6 => self.output = db_to_gain(value),
+            7 => self.mix = value / 100.0,
+            _ => {}
+        }
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        let channels = buffer.len();
+        if self.oversampler.channels() != channels {
+            self.oversampler = Oversampler::new(self.factor, channels);
+            self.holds = vec![Hold::default(); channels];
+        }
+
+        let dry_needed = self.mix < 1.0;
+        if dry_needed {
+            self.dry.resize(channels, Vec::new());
+            for (dry, channel) in self.dry.iter_mut().zip(buffer.iter()) {
+                dry.clear();
+                dry.extend_from_slice(channel);
+            }
+        }
+
+        let (model, drive, bias) = (self.model, self.drive, self.bias);
+        let tube_offset = bias.tanh();
+        let step = 2.0 / 2f32.powf(self.bits);
+        // held at the base rate whatever the oversampling
+        let hold_len = self.downsample * self.oversampler.factor();
+        let holds = &mut self.holds;
+        self.oversampler.process(buffer, |ch, x| {
+            let x = x * drive;
+            match model {
+                Model::Tape => {
+                    std::f32::consts::FRAC_2_PI * (std::f32::consts::FRAC_PI_2 * x).atan()
+                }
+                Model::Tube => (x + bias).tanh() - tube_offset,
+                Model::HardClip => x.clamp(-1.0, 1.0),
+                Model::Foldback => fold(x),
+                Model::Bitcrush => {
+                    let hold = &mut holds[ch];
+                    if hold.left == 0 {
+                        hold.value = ((x / step).round() * step).clamp(-1.0, 1.0);
+                        hold.left = hold_len;
+                    }
+                    hold.left -= 1;
+                    hold.value
+                }
+            }
+        });
+
+        let latency = self.oversampler.latency();
+        if dry_needed {
+            self.dry_delay.set_delay(channels, latency);
+            self.dry_delay.process(&mut self.dry);
+        }
+        for (ch, channel) in buffer.iter_mut().enumerate() {
+            for (f, sample) in channel.iter_mut().enumerate() {
+                let wet = *sample * self.output;
+                *sample = if dry_needed {
+                    self.dry[ch][f] * (1.0 - self.mix) + wet * self.mix
+                } else {
+                    wet
+                };
+            }
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.oversampler.latency()
+    }
+
+    fn reset(&mut self) {
+        self.oversampler.reset();
+        self.holds.fill(Hold::default());
+        self.dry_delay.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::tests::{ctx, run};
+
+    /// No drive or oversampling, so each model's curve shows directly.
+    fn curve(model: usize, values: &[(usize, f32)], inputs: &[f32]) -> Vec<f32> {
+        let mut saturation = Saturation::default();
+        for &(idx, value) in [(0, model as f32), (1, 0.0), (5, 0.0)].iter().chain(values) {
+            saturation.set_param(idx, value);
+        }
+        let mut buffer = vec![inputs.to_vec()];
+        run(&mut saturation, &mut buffer, &[], &ctx(48000.0, 120.0));
+        buffer.remove(0)
+    }
+
+    fn assert_near(actual: &[f32], expected: &[f32]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn tape_curve() {
+        let output = curve(0, &[], &[0.0, 0.25, 1.0, -4.0]);
+        assert_near(&output, &[0.0, 0.238221, 0.639093, -0.899522]);
+        // 12 dB of drive reaches the same point from a quarter of the level
+        let driven = curve(0, &[(1, 20.0 * 4f32.log10())], &[0.25]);
+        assert_near(&driven, &[0.639093]);
+    }
+
+    #[test]
+    fn tube_curve_stays_centred_with_bias() {
+        assert_near(&curve(1, &[], &[0.0, 0.5]), &[0.0, 0.462117]);
+        let biased = curve(1, &[(2, 20.0)], &[0.0, 0.5, -0.5]);
+        assert_near(&biased, &[0.0, 0.406992, -0.488688]);
+    }
+
+    #[test]
+    fn hard_clip_and_foldback_curves() {
+        let inputs = [0.3, -0.9, 1.5, -2.5, 3.5];
+        assert_near(&curve(2, &[], &inputs), &[0.3, -0.9, 1.0, -1.0, 1.0]);
+        assert_near(&curve(3, &[], &inputs), &[0.3, -0.9, 0.5, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn bitcrush_quantizes_and_holds() {
+        // two bits: steps of a half
+        let output = curve(4, &[(3, 2.0)], &[0.3, 0.2, -0.6, 0.9, 2.0]);
+        assert_near(&output, &[0.5, 0.0, -0.5, 1.0, 1.0]);
+
+        let held = curve(4, &[(3, 24.0), (4, 2.0)], &[0.1, 0.2, 0.3, 0.4]);
+        assert_near(&held, &[0.1, 0.1, 0.3, 0.3]);
+    }
+}
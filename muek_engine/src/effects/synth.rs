@@ -0,0 +1,741 @@
+// Polyphonic subtractive synth played by the MIDI reaching its track. Each
+// voice has two band-limited oscillators with unison copies, a state variable
+// filter with its own envelope and an amp envelope; two free running LFOs
+// modulate pitch, cutoff and level. Once every voice is busy the oldest is
+// stolen, preferring voices that are already releasing.
+
+use std::f32::consts::{FRAC_PI_4, PI, SQRT_2, TAU};
+
+use super::{
+    Effect, ParamInfo,
+    envelope::{Adsr, Envelope},
+    utility::db_to_gain,
+};
+use crate::{
+    midi::Message,
+    mixer::{BlockContext, Buses},
+};
+
+const WAVES: &[&str] = &["Sine", "Saw", "Square", "Triangle"];
+const LFO_SHAPES: &[&str] = &["Sine", "Triangle", "Square", "Saw"];
+
+const SYNTH_PARAMS: &[ParamInfo] = &[
+    ParamInfo::choice("Osc 1 Wave", WAVES, 1),
+    ParamInfo::choice("Osc 2 Wave", WAVES, 2),
+    ParamInfo::new("Osc 2 Pitch", "st", -24.0, 24.0, 0.0),
+    ParamInfo::new("Osc 2 Detune", "ct", -100.0, 100.0, 7.0),
+    ParamInfo::new("Osc Mix", "%", 0.0, 100.0, 50.0),
+    ParamInfo::new("Unison", "", 1.0, MAX_UNISON as f32, 1.0),
+    ParamInfo::new("Unison Detune", "ct", 0.0, 100.0, 20.0),
+    ParamInfo::new("Unison Spread", "%", 0.0, 100.0, 50.0),
+    ParamInfo::choice(
+        "Filter",
+        &["Low Pass", "High Pass", "Band Pass", "Notch"],
+        0,
+    ),
+    ParamInfo::new("Cutoff", "Hz", 20.0, 20000.0, 4000.0),
+    ParamInfo::new("Resonance", "%", 0.0, 100.0, 20.0),
+    ParamInfo::new("Filter Env", "oct", -8.0, 8.0, 2.0),
+    ParamInfo::new("Key Track", "%", 0.0, 100.0, 50.0),
+    ParamInfo::new("Amp Attack", "ms", 0.0, 5000.0, 5.0),
+    ParamInfo::new("Amp Decay", "ms", 1.0, 5000.0, 300.0),
+    ParamInfo::new("Amp Sustain", "%", 0.0, 100.0, 80.0),
+    ParamInfo::new("Amp Release", "ms", 1.0, 10000.0, 250.0),
+    ParamInfo::new("Filter Attack", "ms", 0.0, 5000.0, 5.0),
+    ParamInfo::new("Filter Decay", "ms", 1.0, 5000.0, 400.0),
+    ParamInfo::new("Filter Sustain", "%", 0.0, 100.0, 30.0),
+    ParamInfo::new("Filter Release", "ms", 1.0, 10000.0, 300.0),
+    ParamInfo::choice("LFO 1 Shape", LFO_SHAPES, 0),
+    ParamInfo::new("LFO 1 Rate", "Hz", 0.05, 20.0, 5.0),
+    ParamInfo::new("Vibrato", "ct", 0.0, 100.0, 0.0),
+    ParamInfo::choice("LFO 2 Shape", LFO_SHAPES, 1),
+    ParamInfo::new("LFO 2 Rate", "Hz", 0.05, 20.0, 0.5),
+    ParamInfo::new("Filter Mod", "oct", 0.0, 4.0, 0.0),
+    ParamInfo::new("Tremolo", "%", 0.0, 100.0, 0.0),
+    ParamInfo::new("Voices", "", 1.0, MAX_VOICES as f32, 16.0),
+    ParamInfo::new("Bend Range", "st", 0.0, 24.0, 2.0),
+    ParamInfo::new("Volume", "dB", -60.0, 6.0, -6.0),
+];
+
+const MAX_VOICES: usize = 32;
+const MAX_UNISON: usize = 7;
+/// Frames between updates of the pitch, the filter coefficients and the LFOs.
+const CONTROL_FRAMES: usize = 16;
+/// Vibrato added by the mod wheel at full throw.
+const MOD_WHEEL_CENTS: f32 = 50.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Wave {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+impl Wave {
+    fn from_index(idx: usize) -> Self {
+        match idx {
+            0 => Wave::Sine,
+            1 => Wave::Saw,
+            2 => Wave::Square,
+            _ => Wave::Triangle,
+        }
+    }
+}
+
+/// Correction that rounds off a unit step at phase 0 over a sample on each side.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// The same for a kink, the integral of `poly_blep`.
+fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt - 1.0;
+        -t * t * t / 3.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt + 1.0;
+        t * t * t / 3.0
+    } else {
+        0.0
+    }
+}
+
+/// One sample at phase `t` with `dt` cycles per sample.
+fn oscillator(wave: Wave, t: f32, dt: f32) -> f32 {
+    match wave {
+        Wave::Sine => (TAU * t).sin(),
+        Wave::Saw => 2.0 * t - 1.0 - poly_blep(t, dt),
+        Wave::Square => {
+            let naive = if t < 0.5 { 1.0 } else { -1.0 };
+            naive + poly_blep(t, dt) - poly_blep((t + 0.5).fract(), dt)
+        }
+        Wave::Triangle => {
+            let y = 4.0 * t;
+            let naive = if y >= 3.0 {
+                y - 4.0
+            } else if y > 1.0 {
+                2.0 - y
+            } else {
+                y
+            };
+            // the valley at 0.75 and the peak at 0.25
+            let kinks = poly_blamp((t + 0.25).fract(), dt) - poly_blamp((t + 0.75).fract(), dt);
+            naive + 4.0 * dt * kinks
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Lfo {
+    shape: usize,
+    rate: f32,
+    phase: f32,
+}
+
+impl Lfo {
+    fn value(&self) -> f32 {
+        let p = self.phase;
+        match self.shape {
+            0 => (TAU * p).sin(),
+            1 => 1.0 - 4.0 * (p - 0.5).abs(),
+            2 => {
+                if p < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            _ => 2.0 * p - 1.0,
+        }
+    }
+
+    fn advance(&mut self, frames: usize, sample_rate: f32) {
+        self.phase = (self.phase + self.rate * frames as f32 / sample_rate).fract();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+/// Coefficients of a trapezoidal state variable filter.
+#[derive(Clone, Copy, Default)]
+struct SvfCoeffs {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+}
+
+impl SvfCoeffs {
+    /// `resonance` runs from 0 to 1, just short of self-oscillation.
+    fn new(cutoff: f32, resonance: f32, sample_rate: f32) -> Self {
+        let g = (PI * cutoff / sample_rate).tan();
+        let k = 2.0 - 1.96 * resonance;
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        Self {
+            k,
+            a1,
+            a2,
+            a3: g * a2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Svf {
+    ic1: f32,
+    ic2: f32,
+}
+
+impl Svf {
+    #[inline]
+    fn process(&mut self, x: f32, c: &SvfCoeffs, mode: FilterMode) -> f32 {
+        let v3 = x - self.ic2;
+        let v1 = c.a1 * self.ic1 + c.a2 * v3;
+        let v2 = self.ic2 + c.a2 * self.ic1 + c.a3 * v3;
+        self.ic1 = 2.0 * v1 - self.ic1;
+        self.ic2 = 2.0 * v2 - self.ic2;
+        match mode {
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => x - c.k * v1 - v2,
+            FilterMode::BandPass => v1,
+            FilterMode::Notch => x - c.k * v1,
+        }
+    }
+}
+
+/// Everything the parameters set, shared by all voices.
+struct Patch {
+    waves: [Wave; 2],
+    osc2_pitch: f32,
+    osc2_detune: f32,
+    osc_mix: f32,
+    unison: usize,
+    unison_detune: f32,
+    unison_spread: f32,
+    filter_mode: FilterMode,
+    cutoff: f32,
+    resonance: f32,
+    filter_env: f32,
+    key_track: f32,
+    /// Attack, decay, sustain and release, times in seconds.
+    amp_times: [f32; 4],
+    filter_times: [f32; 4],
+    vibrato: f32,
+    filter_mod: f32,
+    tremolo: f32,
+    max_voices: usize,
+    bend_range: f32,
+    volume: f32,
+    amp_adsr: Adsr,
+    filter_adsr: Adsr,
+    /// Frequency ratio and left/right gains of each unison copy.
+    unison_ratios: [f32; MAX_UNISON],
+    unison_pans: [[f32; 2]; MAX_UNISON],
+}
+
+impl Patch {
+    fn update(&mut self, sample_rate: f32) {
+        let [a, d, s, r] = self.amp_times;
+        self.amp_adsr = Adsr::new(a, d, s, r, sample_rate);
+        let [a, d, s, r] = self.filter_times;
+        self.filter_adsr = Adsr::new(a, d, s, r, sample_rate);
+
+        let n = self.unison;
+        for u in 0..MAX_UNISON {
+            // -1 to 1 across the copies
+            let spread = if n > 1 {
+                2.0 * u as f32 / (n - 1) as f32 - 1.0
+            } else {
+                0.0
+            };
+            self.unison_ratios[u] = 2f32.powf(spread * self.unison_detune / 1200.0);
+            let angle = (spread * self.unison_spread + 1.0) * FRAC_PI_4;
+            // a centred copy keeps its level on both sides
+            self.unison_pans[u] = [angle.cos() * SQRT_2, angle.sin() * SQRT_2];
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Voice {
+    note: u8,
+    velocity: f32,
+    /// Order of the note-on, the oldest voice is stolen first.
+    age: u64,
+    /// The key is down.
+    held: bool,
+    /// The key is up but the sustain pedal keeps the note.
+    sustained: bool,
+    amp: Envelope,
+    filter_env: Envelope,
+    phases: [[f32; MAX_UNISON]; 2],
+    /// Cycles per sample of each oscillator during the current control period.
+    steps: [[f32; MAX_UNISON]; 2],
+    filters: [Svf; 2],
+    coeffs: SvfCoeffs,
+}
+
+impl Voice {
+    fn release(&mut self) {
+        self.held = false;
+        self.sustained = false;
+        self.amp.release();
+        self.filter_env.release();
+    }
+
+    /// Sets the pitch and filter for the next control period.
+    fn control(&mut self, patch: &Patch, pitch_mod: f32, cutoff_mod: f32, sample_rate: f32) {
+        let semitones = self.note as f32 - 69.0 + pitch_mod;
+        let base = 440.0 * 2f32.powf(semitones / 12.0) / sample_rate;
+        let osc2 = 2f32.powf((patch.osc2_pitch + patch.osc2_detune / 100.0) / 12.0);
+        for (steps, ratio) in self.steps.iter_mut().zip([1.0, osc2]) {
+            for (step, unison) in steps.iter_mut().zip(patch.unison_ratios) {
+                *step = base * ratio * unison;
+            }
+        }
+
+        let octaves = patch.filter_env * self.filter_env.level()
+            + patch.key_track * (self.note as f32 - 60.0) / 12.0
+            + cutoff_mod;
+        let cutoff = (patch.cutoff * 2f32.powf(octaves)).clamp(20.0, sample_rate * 0.49);
+        self.coeffs = SvfCoeffs::new(cutoff, patch.resonance, sample_rate);
+    }
+
+    /// Adds `out.len()` frames of the voice to the stereo `out`.
+    fn render(&mut self, patch: &Patch, gain: f32, out: [&mut [f32]; 2]) {
+        let [left, right] = out;
+        let weights = [1.0 - patch.osc_mix, patch.osc_mix];
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let mut sides = [0.0; 2];
+            for u in 0..patch.unison {
+                let mut s = 0.0;
+                let oscs = self.phases.iter_mut().zip(&self.steps);
+                for ((phases, steps), (&wave, weight)) in oscs.zip(patch.waves.iter().zip(weights))
+                {
+                    let (t, dt) = (phases[u], steps[u]);
+                    // nothing of it would be left below Nyquist
+                    if weight > 0.0 && dt < 0.5 {
+                        s += weight * oscillator(wave, t, dt);
+                    }
+                    phases[u] = (t + dt).fract();
+                }
+                sides[0] += s * patch.unison_pans[u][0];
+                sides[1] += s * patch.unison_pans[u][1];
+            }
+
+            let level = self.amp.next(&patch.amp_adsr) * self.velocity * gain;
+            self.filter_env.next(&patch.filter_adsr);
+            *l += self.filters[0].process(sides[0], &self.coeffs, patch.filter_mode) * level;
+            *r += self.filters[1].process(sides[1], &self.coeffs, patch.filter_mode) * level;
+        }
+    }
+}
+
+pub struct Synth {
+    patch: Patch,
+    lfos: [Lfo; 2],
+    voices: [Voice; MAX_VOICES],
+    next_age: u64,
+    sample_rate: f32,
+    /// Pitch wheel, -1 to 1.
+    bend: f32,
+    mod_wheel: f32,
+    sustain_pedal: bool,
+    mix: [Vec<f32>; 2],
+}
+
+impl Default for Synth {
+    fn default() -> Self {
+        let mut synth = Self {
+            patch: Patch {
+                waves: [Wave::Saw, Wave::Square],
+                osc2_pitch: 0.0,
+                osc2_detune: 7.0,
+                osc_mix: 0.5,
+                unison: 1,
+                unison_detune: 20.0,
+                unison_spread: 0.5,
+                filter_mode: FilterMode::LowPass,
+                cutoff: 4000.0,
+                resonance: 0.2,
+                filter_env: 2.0,
+                key_track: 0.5,
+                amp_times: [0.005, 0.3, 0.8, 0.25],
+                filter_times: [0.005, 0.4, 0.3, 0.3],
+                vibrato: 0.0,
+                filter_mod: 0.0,
+                tremolo: 0.0,
+                max_voices: 16,
+                bend_range: 2.0,
+                volume: db_to_gain(-6.0),
+                amp_adsr: Adsr::default(),
+                filter_adsr: Adsr::default(),
+                unison_ratios: [1.0; MAX_UNISON],
+                unison_pans: [[1.0; 2]; MAX_UNISON],
+            },
+            lfos: [
+                Lfo {
+                    shape: 0,
+                    rate: 5.0,
+                    phase: 0.0,
+                },
+                Lfo {
+                    shape: 1,
+                    rate: 0.5,
+                    phase: 0.0,
+                },
+            ],
+            voices: [Voice::default(); MAX_VOICES],
+            next_age: 0,
+            sample_rate: 44100.0,
+            bend: 0.0,
+            mod_wheel: 0.0,
+            sustain_pedal: false,
+            mix: [Vec::new(), Vec::new()],
+        };
+        synth.patch.update(synth.sample_rate);
+        synth
+    }
+}
+
+impl Synth {
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::NoteOn { note, velocity } => self.note_on(note, velocity as f32 / 127.0),
+            Message::NoteOff { note } => self.note_off(note),
+            Message::Controller { number, value } => match number {
+                1 => self.mod_wheel = value as f32 / 127.0,
+                64 => {
+                    self.sustain_pedal = value >= 64;
+                    if !self.sustain_pedal {
+                        self.voices
+                            .iter_mut()
+                            .filter(|v| v.sustained)
+                            .for_each(Voice::release);
+                    }
+                }
+                // all sound off
+                120 => self.voices.iter_mut().for_each(|v| {
+                    v.amp.kill();
+                    v.filter_env.kill();
+                }),
+                // all notes off
+                123 => self.voices.iter_mut().for_each(Voice::release),
+                _ => {}
+            },
+            Message::PitchBend(bend) => self.bend = bend,
+            Message::ProgramChange(_) | Message::Other => {}
+        }
+    }
+
+    fn note_on(&mut self, note: u8, velocity: f32) {
+        let voices = &mut self.voices[..self.patch.max_voices];
+        let idx = voices
+            .iter()
+            .position(|v| v.amp.is_active() && v.note == note)
+            .or_else(|| voices.iter().position(|v| !v.amp.is_active()))
+            .unwrap_or_else(|| {
+                // steal, releasing voices first, then the oldest
+                (0..voices.len())
+                    .min_by_key(|&i| (!voices[i].amp.is_released(), voices[i].age))
+                    .unwrap_or(0)
+            });
+
+        let voice = &mut voices[idx];
+        if !voice.amp.is_active() {
+            voice.filters = [Svf::default(); 2];
+            // spread the unison copies so they don't start in phase
+            for phases in &mut voice.phases {
+                for (u, phase) in phases.iter_mut().enumerate() {
+                    *phase = (u as f32 * 0.382).fract();
+                }
+            }
+        }
+        voice.note = note;
+        voice.velocity = velocity;
+        voice.age = self.next_age;
+        voice.held = true;
+        voice.sustained = false;
+        voice.amp.trigger();
+        voice.filter_env.trigger();
+        self.next_age += 1;
+    }
+
+    fn note_off(&mut self, note: u8) {
+        for voice in self.voices.iter_mut().filter(|v| v.held && v.note == note) {
+            if self.sustain_pedal {
+                voice.held = false;
+                voice.sustained = true;
+            } else {
+                voice.release();
+            }
+        }
+    }
+
+    /// Adds the voices to the mix from frame `start` to `end`, which is at most a control period.
+    fn render(&mut self, start: usize, end: usize) {
+        let lfo = self.lfos.map(|l| l.value());
+        for l in &mut self.lfos {
+            l.advance(end - start, self.sample_rate);
+        }
+
+        let patch = &self.patch;
+        let vibrato = patch.vibrato + self.mod_wheel * MOD_WHEEL_CENTS;
+        let pitch_mod = self.bend * patch.bend_range + lfo[0] * vibrato / 100.0;
+        let cutoff_mod = lfo[1] * patch.filter_mod;
+        let tremolo = 1.0 - patch.tremolo * 0.5 * (1.0 - lfo[1]);
+        let gain = patch.volume * tremolo / (patch.unison as f32).sqrt();
+
+        let [left, right] = &mut self.mix;
+        for voice in self.voices.iter_mut().filter(|v| v.amp.is_active()) {
+            voice.control(patch, pitch_mod, cutoff_mod, self.sample_rate);
+            voice.render(patch, gain, [&mut left[start..end], &mut right[start..end]]);
+        }
+    }
+}
+
+impl Effect for Synth {
+    fn name(&self) -> &'static str {
+        "Synth"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        SYNTH_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        let p = &mut self.patch;
+        match idx {
+            0 | 1 => p.waves[idx] = Wave::from_index(value as usize),
+            2 => p.osc2_pitch = value.round(),
+            3 => p.osc2_detune = value,
+            4 => p.osc_mix = value / 100.0,
+            5 => p.unison = (value.round() as usize).clamp(1, MAX_UNISON),
+            6 => p.unison_detune = value,
+            7 => p.unison_spread = value / 100.0,
+            8 => {
+                p.filter_mode = match value as usize {
+                    0 => FilterMode::LowPass,
+                    1 => FilterMode::HighPass,
+                    2 => FilterMode::BandPass,
+                    _ => FilterMode::Notch,
+                }
+            }
+            9 => p.cutoff = value,
+            10 => p.resonance = value / 100.0,
+            11 => p.filter_env = value,
+            12 => p.key_track = value / 100.0,
+            13..=16 => {
+                let stage = idx - 13;
+                p.amp_times[stage] = if stage == 2 {
+                    value / 100.0
+                } else {
+                    value / 1000.0
+                };
+            }
+            17..=20 => {
+                let stage = idx - 17;
+                p.filter_times[stage] = if stage == 2 {
+                    value / 100.0
+                } else {
+                    value / 1000.0
+                };
+            }
+            21 => self.lfos[0].shape = value as usize,
+            22 => self.lfos[0].rate = value,
+            23 => p.vibrato = value,
+            24 => self.lfos[1].shape = value as usize,
+            25 => self.lfos[1].rate = value,
+            26 => p.filter_mod = value,
+            27 => p.tremolo = value / 100.0,
+            28 => p.max_voices = (value.round() as usize).clamp(1, MAX_VOICES),
+            29 => p.bend_range = value,
+            30 => p.volume = db_to_gain(value),
+            _ => return,
+        }
+        self.patch.update(self.sample_rate);
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.patch.update(sample_rate);
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, _ctx: &BlockContext) {
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        for mix in &mut self.mix {
+            mix.clear();
+            mix.resize(frames, 0.0);
+        }
+
+        // events split the block so each lands on its frame
+        let mut events = buses.midi_in.iter().peekable();
+        let mut f = 0;
+        while f < frames {
+            while let Some(event) = events.next_if(|e| e.delta_frames as usize <= f) {
+                self.handle(event.message());
+            }
+            let next = events
+                .peek()
+                .map_or(frames, |e| (e.delta_frames as usize).min(frames));
+            let end = next.min(f + CONTROL_FRAMES);
+            self.render(f, end);
+            f = end;
+        }
+        for event in events {
+            self.handle(event.message());
+        }
+
+        // the instrument plays on top of whatever the track already holds
+        let [left, right] = &self.mix;
+        if buffer.len() == 1 {
+            for ((out, l), r) in buffer[0].iter_mut().zip(left).zip(right) {
+                *out += (l + r) * 0.5;
+            }
+        } else {
+            for (ch, channel) in buffer.iter_mut().enumerate() {
+                let side = if ch == 0 { left } else { right };
+                for (out, s) in channel.iter_mut().zip(side) {
+                    *out += s;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for voice in &mut self.voices {
+            *voice = Voice::default();
+        }
+        self.bend = 0.0;
+        self.mod_wheel = 0.0;
+        self.sustain_pedal = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        effects::tests::{ctx, run},
+        midi::MidiEvent,
+    };
+
+    const RATE: f32 = 48000.0;
+
+    fn synth(values: &[(usize, f32)]) -> Synth {
+        let mut synth = Synth::default();
+        synth.set_sample_rate(RATE);
+        for &(idx, value) in values {
+            synth.set_param(idx, value);
+        }
+        synth
+    }
+
+    /// Plays `events` at the start of a stereo block of `frames`, returning the left channel.
+    fn play(synth: &mut Synth, events: &[[u8; 3]], frames: usize) -> Vec<f32> {
+        let events: Vec<MidiEvent> = events.iter().map(|&data| MidiEvent::new(0, data)).collect();
+        let mut buffer = vec![vec![0.0; frames]; 2];
+        run(synth, &mut buffer, &events, &ctx(RATE, 120.0));
+        buffer.remove(0)
+    }
+
+    fn sounding(synth: &Synth) -> Vec<u8> {
+        let mut notes: Vec<u8> = synth
+            .voices
+            .iter()
+            .filter(|v| v.amp.is_active())
+            .map(|v| v.note)
+            .collect();
+        notes.sort();
+        notes
+    }
+
+    /// Level at `freq` in dB, from a Hann windowed single bin DFT.
+    fn level_at(samples: &[f32], freq: f32) -> f32 {
+        let len = samples.len() as f32;
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (n, &x) in samples.iter().enumerate() {
+            let window = 0.5 - 0.5 * (TAU * n as f32 / len).cos();
+            let phase = TAU as f64 * (freq as f64 * n as f64 / RATE as f64).fract();
+            re += (x * window) as f64 * phase.cos();
+            im -= (x * window) as f64 * phase.sin();
+        }
+        10.0 * ((re * re + im * im) as f32).max(1e-30).log10()
+    }
+
+    #[test]
+    fn steals_the_oldest_voice_when_all_are_busy() {
+        let mut synth = synth(&[(28, 2.0)]);
+        play(&mut synth, &[[0x90, 60, 100], [0x90, 62, 100]], 64);
+        play(&mut synth, &[[0x90, 64, 100]], 64);
+        assert_eq!(sounding(&synth), [62, 64]);
+    }
+
+    #[test]
+    fn steals_a_releasing_voice_first() {
+        let mut synth = synth(&[(28, 2.0), (16, 5000.0)]);
+        play(&mut synth, &[[0x90, 60, 100], [0x90, 62, 100]], 64);
+        play(&mut synth, &[[0x80, 62, 0]], 64);
+        play(&mut synth, &[[0x90, 64, 100]], 64);
+        assert_eq!(sounding(&synth), [60, 64]);
+    }
+
+    #[test]
+    fn note_off_releases_the_voice() {
+        let mut synth = synth(&[(16, 10.0)]);
+        play(&mut synth, &[[0x90, 60, 100]], 4800);
+        let voice = synth.voices.iter().find(|v| v.note == 60).unwrap();
+        assert!(voice.held && !voice.amp.is_released());
+
+        let output = play(&mut synth, &[[0x80, 60, 0]], 4800);
+        let voice = synth.voices.iter().find(|v| v.note == 60).unwrap();
+        assert!(!voice.held);
+        assert!(output[..64].iter().any(|&x| x != 0.0));
+        // ten milliseconds of release leave nothing after a tenth of a second
+        assert!(!voice.amp.is_active());
+        assert!(output[4000..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn oscillators_keep_their_partials_below_nyquist() {
+        // a lone saw through a wide open filter
+        let open = [(4, 0.0), (9, 20000.0), (10, 0.0), (11, 0.0), (12, 0.0)];
+        let mut synth = synth(&open);
+        let output = play(&mut synth, &[[0x90, 100, 127]], 48000);
+
+        let f0 = 440.0 * 2f32.powf((100.0 - 69.0) / 12.0);
+        let fundamental = level_at(&output, f0);
+        // partials past Nyquist would fold back between the harmonics
+        let worst_alias = (10..40)
+            .map(|k| {
+                let folded = (k as f32 * f0) % RATE;
+                level_at(&output, folded.min(RATE - folded))
+            })
+            .fold(f32::MIN, f32::max);
+        // a naive saw folds its eleventh harmonic back at about -21 dB
+        let rejection = fundamental - worst_alias;
+        assert!(rejection > 30.0, "{}", rejection);
+
+        // a fundamental above Nyquist is not played at all
+        let mut high = self::synth(&[(4, 0.0), (9, 20000.0)]);
+        high.set_sample_rate(22050.0);
+        let output = play(&mut high, &[[0x90, 127, 127]], 4800);
+        assert!(output.iter().all(|&x| x == 0.0));
+    }
+}
@@ -0,0 +1,220 @@
+// Gain staging and channel utilities.
+
+use std::f32::consts::TAU;
+
+use super::{Effect, ParamInfo};
+use crate::mixer::{BlockContext, Buses};
+
+/// Converts decibels to a linear factor.
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Applies a gain that moves from `from` to `to` over the block, avoiding zipper noise.
+fn ramp_gain(buffer: &mut [Vec<f32>], from: f32, to: f32) {
+    for channel in buffer.iter_mut() {
+        let frames = channel.len().max(1) as f32;
+        let step = (to - from) / frames;
+        for (f, sample) in channel.iter_mut().enumerate() {
+            *sample *= from + step * f as f32;
+        }
+    }
+}
+
+const GAIN_PARAMS: &[ParamInfo] = &[ParamInfo::new("Gain", "dB", -60.0, 24.0, 0.0)];
+
+pub struct Gain {
+    target: f32,
+    current: f32,
+}
+
+impl Default for Gain {
+    fn default() -> Self {
+        Self {
+            target: 1.0,
+            current: 1.0,
+        }
+    }
+}
+
+impl Effect for Gain {
+    fn name(&self) -> &'static str {
+        "Gain"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        GAIN_PARAMS
+    }
+
+    fn set_param(&mut self, _idx: usize, value: f32) {
+        // the bottom of the range is silence
+        self.target = if value <= GAIN_PARAMS[0].min {
+            0.0
+        } else {
+            db_to_gain(value)
+        };
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        ramp_gain(buffer, self.current, self.target);
+        self.current = self.target;
+    }
+
+    fn reset(&mut self) {
+        self.current = self.target;
+    }
+}
+
+const POLARITY_PARAMS: &[ParamInfo] = &[
+    ParamInfo::switch("Invert Left", false),
+    ParamInfo::switch("Invert Right", false),
+];
+
+/// Flips the sign of the left and/or right channel.
+#[derive(Default)]
+pub struct Polarity {
+    invert: [bool; 2],
+}
+
+impl Effect for Polarity {
+    fn name(&self) -> &'static str {
+        "Polarity"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        POLARITY_PARAMS
+    }
+
+    fn set_param(&mut self, idx: usize, value: f32) {
+        if let Some(invert) = self.invert.get_mut(idx) {
+            *invert = value >= 0.5;
+        }
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        for (ch, channel) in buffer.iter_mut().enumerate() {
+            // channels past the second follow the right one
+            if self.invert[ch.min(1)] {
+                for sample in channel.iter_mut() {
+                    *sample = -*sample;
+                }
+            }
+        }
+    }
+}
+
+const WIDTH_PARAMS: &[ParamInfo] = &[ParamInfo::new("Width", "%", 0.0, 200.0, 100.0)];
+
+/// Scales the side signal: 0% folds to mono, 200% doubles the stereo image.
+pub struct StereoWidth {
+    target: f32,
+    current: f32,
+}
+
+impl Default for StereoWidth {
+    fn default() -> Self {
+        Self {
+            target: 1.0,
+            current: 1.0,
+        }
+    }
+}
+
+impl Effect for StereoWidth {
+    fn name(&self) -> &'static str {
+        "Stereo Width"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        WIDTH_PARAMS
+    }
+
+    fn set_param(&mut self, _idx: usize, value: f32) {
+        self.target = value / 100.0;
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        let [left, right, ..] = buffer else {
+            return;
+        };
+
+        let frames = left.len().max(1) as f32;
+        let step = (self.target - self.current) / frames;
+        for (f, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            let width = self.current + step * f as f32;
+            let mid = (*l + *r) * 0.5;
+            let side = (*l - *r) * 0.5 * width;
+            *l = mid + side;
+            *r = mid - side;
+        }
+        self.current = self.target;
+    }
+
+    fn reset(&mut self) {
+        self.current = self.target;
+    }
+}
+
+const DC_BLOCKER_PARAMS: &[ParamInfo] = &[ParamInfo::new("Cutoff", "Hz", 2.0, 40.0, 10.0)];
+
+/// One pole high-pass that removes DC offset.
+pub struct DcBlocker {
+    cutoff: f32,
+    sample_rate: f32,
+    coeff: f32,
+    /// Last input and output per channel.
+    state: Vec<(f32, f32)>,
+}
+
+impl Default for DcBlocker {
+    fn default() -> Self {
+        Self {
+            cutoff: DC_BLOCKER_PARAMS[0].default,
+            sample_rate: 44100.0,
+            coeff: 0.0,
+            state: Vec::new(),
+        }
+    }
+}
+
+impl DcBlocker {
+    fn update_coeff(&mut self) {
+        self.coeff = (-TAU * self.cutoff / self.sample_rate).exp();
+    }
+}
+
+impl Effect for DcBlocker {
+    fn name(&self) -> &'static str {
+        "DC Blocker"
+    }
+
+    fn params(&self) -> &'static [ParamInfo] {
+        DC_BLOCKER_PARAMS
+    }
+
+    fn set_param(&mut self, _idx: usize, value: f32) {
+        self.cutoff = value;
+        self.update_coeff();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_coeff();
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], _buses: &mut Buses, _ctx: &BlockContext) {
+        self.state.resize(buffer.len(), (0.0, 0.0));
+        for (channel, (x1, y1)) in buffer.iter_mut().zip(self.state.iter_mut()) {
+            for sample in channel.iter_mut() {
+                let y = *sample - *x1 + self.coeff * *y1;
+                *x1 = *sample;
+                *y1 = y;
+                *sample = y;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.fill((0.0, 0.0));
+    }
+}
@@ -0,0 +1,376 @@
+use anyhow::{anyhow, bail};
+
+use crate::byte_reader::{ByteReader, Endian};
+
+const CHUNK_MAGIC: &[u8; 4] = b"CcnK";
+const PROGRAM_PARAMS_MAGIC: &[u8; 4] = b"FxCk";
+const PROGRAM_CHUNK_MAGIC: &[u8; 4] = b"FPCh";
+const BANK_PROGRAMS_MAGIC: &[u8; 4] = b"FxBk";
+const BANK_CHUNK_MAGIC: &[u8; 4] = b"FBCh";
+
+const PROGRAM_NAME_LEN: usize = 28;
+const BANK_FUTURE_LEN: usize = 124;
+const TRUNCATED: &str = "preset file truncated";
+
+pub enum FxProgramData {
+    Params(Vec<f32>),
+    Chunk(Vec<u8>),
+}
+
+pub struct FxProgram {
+    pub fx_id: i32,
+    pub fx_version: i32,
+    /// number of parameters of the plugin, also written for chunk programs
+    pub num_params: i32,
+    pub name: String,
+    pub data: FxProgramData,
+}
+
+pub enum FxBankData {
+    Programs(Vec<FxProgram>),
+    Chunk(Vec<u8>),
+}
+
+pub struct FxBank {
+    pub fx_id: i32,
+    pub fx_version: i32,
+    pub num_programs: i32,
+    pub current_program: i32,
+    pub data: FxBankData,
+}
+
+/// A standard VST 2 `.fxp` (program) or `.fxb` (bank) file, all fields big-endian.
+pub enum FxPreset {
+    Program(FxProgram),
+    Bank(FxBank),
+}
+
+impl FxPreset {
+    pub fn fx_id(&self) -> i32 {
+        match self {
+            FxPreset::Program(program) => program.fx_id,
+            FxPreset::Bank(bank) => bank.fx_id,
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = ByteReader::new(bytes, Endian::Big, TRUNCATED);
+        let magic = peek_fx_magic(bytes)?;
+
+        if magic == PROGRAM_PARAMS_MAGIC || magic == PROGRAM_CHUNK_MAGIC {
+            Ok(FxPreset::Program(FxProgram::read(&mut reader)?))
+        } else if magic == BANK_PROGRAMS_MAGIC || magic == BANK_CHUNK_MAGIC {
+            Ok(FxPreset::Bank(FxBank::read(&mut reader)?))
+        } else {
+            bail!("unknown preset type {:?}", String::from_utf8_lossy(magic))
+        }
+    }
+}
+
+impl FxProgram {
+    fn read(reader: &mut ByteReader) -> anyhow::Result<Self> {
+        let body = read_header(reader)?;
+        let mut reader = ByteReader::new(body, Endian::Big, TRUNCATED);
+
+        let magic = reader.take(4)?;
+        let _version = reader.i32()?;
+        let fx_id = reader.i32()?;
+        let fx_version = reader.i32()?;
+        let num_params = reader.i32()?;
+        let name = read_name(reader.take(PROGRAM_NAME_LEN)?);
+
+        let data = if magic == PROGRAM_CHUNK_MAGIC {
+            let size = reader.size()?;
+            FxProgramData::Chunk(reader.take(size)?.to_vec())
+        } else {
+            let count = usize::try_from(num_params).map_err(|_| anyhow!("negative param count"))?;
+            FxProgramData::Params(reader.f32s(count)?)
+        };
+
+        Ok(Self {
+            fx_id,
+            fx_version,
+            num_params,
+            name,
+            data,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match &self.data {
+            FxProgramData::Params(_) => body.extend_from_slice(PROGRAM_PARAMS_MAGIC),
+            FxProgramData::Chunk(_) => body.extend_from_slice(PROGRAM_CHUNK_MAGIC),
+        }
+        body.extend_from_slice(&1i32.to_be_bytes());
+        body.extend_from_slice(&self.fx_id.to_be_bytes());
+        body.extend_from_slice(&self.fx_version.to_be_bytes());
+
+        match &self.data {
+            FxProgramData::Params(values) => {
+                body.extend_from_slice(&(values.len() as i32).to_be_bytes());
+                write_name(&mut body, &self.name);
+                for v in values {
+                    body.extend_from_slice(&v.to_be_bytes());
+                }
+            }
+            FxProgramData::Chunk(chunk) => {
+                body.extend_from_slice(&self.num_params.to_be_bytes());
+                write_name(&mut body, &self.name);
+                body.extend_from_slice(&(chunk.len() as i32).to_be_bytes());
+                body.extend_from_slice(chunk);
+            }
+        }
+
+        with_header(body)
+    }
+}
+
+impl FxBank {
+    fn read(reader: &mut ByteReader) -> anyhow::Result<Self> {
+        let body = read_header(reader)?;
+        let mut reader = ByteReader::new(body, Endian::Big, TRUNCATED);
+
+        let magic = reader.take(4)?;
+        let version = reader.i32()?;
+        let fx_id = reader.i32()?;
+        let fx_version = reader.i32()?;
+        let num_programs = reader.i32()?;
+
+        // version 1 banks have no current program, only reserved bytes
+        let current_program = if version >= 2 { reader.i32()? } else { 0 };
+        let future_len = if version >= 2 {
+            BANK_FUTURE_LEN
+        } else {
+            BANK_FUTURE_LEN + 4
+        };
+        reader.take(future_len)?;
+
+        let data = if magic == BANK_CHUNK_MAGIC {
+            let size = reader.size()?;
+            FxBankData::Chunk(reader.take(size)?.to_vec())
+        } else {
+            let mut programs = Vec::new();
+            for _ in 0..num_programs.max(0) {
+                let program = FxProgram::read(&mut reader)?;
+                if program.fx_id != fx_id {
+                    bail!("bank contains a program of another plugin");
+                }
+                programs.push(program);
+            }
+            FxBankData::Programs(programs)
+        };
+
+        Ok(Self {
+            fx_id,
+            fx_version,
+            num_programs,
+            current_program,
+            data,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match &self.data {
+            FxBankData::Programs(_) => body.extend_from_slice(BANK_PROGRAMS_MAGIC),
+            FxBankData::Chunk(_) => body.extend_from_slice(BANK_CHUNK_MAGIC),
+        }
+        body.extend_from_slice(&2i32.to_be_bytes());
+        body.extend_from_slice(&self.fx_id.to_be_bytes());
+        body.extend_from_slice(&self.fx_version.to_be_bytes());
+
+        let num_programs = match &self.data {
+            FxBankData::Programs(programs) => programs.len() as i32,
+            FxBankData::Chunk(_) => self.num_programs,
+        };
+        body.extend_from_slice(&num_programs.to_be_bytes());
+        body.extend_from_slice(&self.current_program.to_be_bytes());
+        body.extend_from_slice(&[0u8; BANK_FUTURE_LEN]);
+
+        match &self.data {
+            FxBankData::Programs(programs) => {
+                for program in programs {
+                    body.extend_from_slice(&program.to_bytes());
+                }
+            }
+            FxBankData::Chunk(chunk) => {
+                body.extend_from_slice(&(chunk.len() as i32).to_be_bytes());
+                body.extend_from_slice(chunk);
+            }
+        }
+
+        with_header(body)
+    }
+}
+
+fn peek_fx_magic(bytes: &[u8]) -> anyhow::Result<&[u8]> {
+    if bytes.len() < 12 || &bytes[0..4] != CHUNK_MAGIC {
+        bail!("not an fxp/fxb file");
+    }
+    Ok(&bytes[8..12])
+}
+
+/// Reads `CcnK` + byte size and returns the body. Some hosts write a wrong
+/// size for the last block, so it is clamped to what is left.
+fn read_header<'a>(reader: &mut ByteReader<'a>) -> anyhow::Result<&'a [u8]> {
+    if reader.take(4)? != CHUNK_MAGIC {
+        bail!("missing CcnK header");
+    }
+    let size = reader.size()?;
+    let remaining = reader.remaining();
+    reader.take(size.min(remaining))
+}
+
+fn with_header(body: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(body.len() + 8);
+    bytes.extend_from_slice(CHUNK_MAGIC);
+    bytes.extend_from_slice(&(body.len() as i32).to_be_bytes());
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+fn read_name(raw: &[u8]) -> String {
+    let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).into_owned()
+}
+
+/// Names are null terminated inside a fixed 28 byte field.
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    let mut raw = [0u8; PROGRAM_NAME_LEN];
+    let name = name.as_bytes();
+    let len = name.len().min(PROGRAM_NAME_LEN - 1);
+    raw[..len].copy_from_slice(&name[..len]);
+    bytes.extend_from_slice(&raw);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params_program(name: &str, values: Vec<f32>) -> FxProgram {
+        FxProgram {
+            fx_id: 0x4d75_656b,
+            fx_version: 2,
+            num_params: values.len() as i32,
+            name: name.to_string(),
+            data: FxProgramData::Params(values),
+        }
+    }
+
+    fn program(bytes: &[u8]) -> FxProgram {
+        match FxPreset::parse(bytes).unwrap() {
+            FxPreset::Program(program) => program,
+            FxPreset::Bank(_) => panic!("expected a program"),
+        }
+    }
+
+    fn bank(bytes: &[u8]) -> FxBank {
+        match FxPreset::parse(bytes).unwrap() {
+            FxPreset::Bank(bank) => bank,
+            FxPreset::Program(_) => panic!("expected a bank"),
+        }
+    }
+
+    #[test]
+    fn params_program_round_trips() {
+        let read = program(&params_program("Lead", vec![0.0, 0.5, 1.0]).to_bytes());
+
+        assert_eq!(read.fx_id, 0x4d75_656b);
+        assert_eq!(read.fx_version, 2);
+        assert_eq!(read.name, "Lead");
+        match read.data {
+            FxProgramData::Params(values) => assert_eq!(values, [0.0, 0.5, 1.0]),
+            FxProgramData::Chunk(_) => panic!("expected parameter values"),
+        }
+    }
+
+    #[test]
+    fn chunk_program_round_trips() {
+        let written = FxProgram {
+            num_params: 12,
+            data: FxProgramData::Chunk(vec![9, 8, 7]),
+            ..params_program("Pad", vec![])
+        };
+        let read = program(&written.to_bytes());
+
+        assert_eq!(read.num_params, 12);
+        match read.data {
+            FxProgramData::Chunk(chunk) => assert_eq!(chunk, [9, 8, 7]),
+            FxProgramData::Params(_) => panic!("expected a chunk"),
+        }
+    }
+
+    #[test]
+    fn long_names_are_cut_to_the_field() {
+        let read = program(&params_program(&"x".repeat(40), vec![]).to_bytes());
+        assert_eq!(read.name.len(), PROGRAM_NAME_LEN - 1);
+    }
+
+    #[test]
+    fn banks_round_trip() {
+        let written = FxBank {
+            fx_id: 0x4d75_656b,
+            fx_version: 2,
+            num_programs: 2,
+            current_program: 1,
+            data: FxBankData::Programs(vec![
+                params_program("A", vec![0.25]),
+                params_program("B", vec![0.75]),
+            ]),
+        };
+        let read = bank(&written.to_bytes());
+
+        assert_eq!(read.current_program, 1);
+        match read.data {
+            FxBankData::Programs(programs) => {
+                assert_eq!(programs.len(), 2);
+                assert_eq!(programs[1].name, "B");
+            }
+            FxBankData::Chunk(_) => panic!("expected programs"),
+        }
+
+        let written = FxBank {
+            data: FxBankData::Chunk(vec![1, 2]),
+            ..written
+        };
+        match bank(&written.to_bytes()).data {
+            FxBankData::Chunk(chunk) => assert_eq!(chunk, [1, 2]),
+            FxBankData::Programs(_) => panic!("expected a chunk"),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let bytes = params_program("Lead", vec![0.5, 0.5]).to_bytes();
+
+        assert!(FxPreset::parse(b"CcnK").is_err());
+
+        let mut magic = bytes.clone();
+        magic[8..12].copy_from_slice(b"Nope");
+        assert!(FxPreset::parse(&magic).is_err());
+
+        // the params are cut off, the header size is clamped to what's left
+        assert!(FxPreset::parse(&bytes[..bytes.len() - 2]).is_err());
+
+        let mut negative = bytes.clone();
+        negative[24..28].copy_from_slice(&(-1i32).to_be_bytes());
+        assert!(FxPreset::parse(&negative).is_err());
+
+        let mut huge = bytes;
+        huge[24..28].copy_from_slice(&i32::MAX.to_be_bytes());
+        assert!(FxPreset::parse(&huge).is_err());
+    }
+
+    #[test]
+    fn rejects_programs_of_another_plugin_in_a_bank() {
+        let written = FxBank {
+            fx_id: 1,
+            fx_version: 1,
+            num_programs: 1,
+            current_program: 0,
+            data: FxBankData::Programs(vec![params_program("A", vec![])]),
+        };
+        assert!(FxPreset::parse(&written.to_bytes()).is_err());
+    }
+}
@@ -0,0 +1,643 @@
+// JACK output for Linux studio machines, used by `AudioEngine::spawn` whenever
+// a JACK server, or PipeWire's JACK, is running. The mix goes out of
+// `main_out_N` ports wired to the system playback ports, and tracks can get
+// their own post-fader direct outs. The transport can follow JACK's, or also
+// lead it as timebase master with bars and beats from the tempo map.
+
+use std::{
+    ffi::c_void,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU8, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use jack::{
+    AsyncClient, AudioOut, Client, ClientOptions, Control, Frames, NotificationHandler, Port,
+    PortFlags, ProcessHandler, ProcessScope, TransportState, jack_sys as j,
+};
+
+use crate::{
+    audio::{AudioConfig, BlockProcessor, EngineState},
+    tempo_map::TempoMap,
+};
+
+const CLIENT_NAME: &str = "Muek";
+/// Ticks per beat of the bars and beats published as timebase master.
+const TICKS_PER_BEAT: f64 = 1920.0;
+/// Tempo changes from the timebase master smaller than this, in bpm, are ignored.
+const TEMPO_TOLERANCE: f64 = 0.01;
+/// How long removing a direct out waits for the process callback to let go of it.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JackTransportMode {
+    /// The transport runs on its own.
+    Off,
+    /// Plays, stops and locates with the JACK transport and takes the tempo of
+    /// its timebase master. Playing and stopping here drive the JACK transport.
+    Follower,
+    /// A follower that is also timebase master.
+    Master,
+}
+
+impl JackTransportMode {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Self::Follower,
+            2 => Self::Master,
+            _ => Self::Off,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Self::Off => 0,
+            Self::Follower => 1,
+            Self::Master => 2,
+        }
+    }
+}
+
+/// Ports carrying one track's post-fader signal, a port per channel.
+struct DirectOut {
+    track_id: String,
+    ports: Vec<Port<AudioOut>>,
+}
+
+/// The direct outs the UI thread set, on their way to the process callback.
+struct Handoff<T> {
+    ready: Option<Vec<T>>,
+    /// The list replaced last, freed here rather than on the audio thread.
+    retired: Option<Vec<T>>,
+}
+
+impl<T> Default for Handoff<T> {
+    fn default() -> Self {
+        Self {
+            ready: None,
+            retired: None,
+        }
+    }
+}
+
+impl<T> Handoff<T> {
+    /// Queues a new list for the callback. Returns the list it retired since,
+    /// for the caller to free.
+    fn offer(&mut self, list: Vec<T>) -> Option<Vec<T>> {
+        self.ready = Some(list);
+        self.retired.take()
+    }
+
+    /// Called by the callback: swaps in the list waiting, if any, and keeps the
+    /// one it had for the UI thread to free.
+    fn take(&mut self, current: &mut Vec<T>) {
+        if let Some(ready) = self.ready.take() {
+            self.retired = Some(std::mem::replace(current, ready));
+        }
+    }
+
+    fn is_taken(&self) -> bool {
+        self.ready.is_none()
+    }
+}
+
+/// Takes out of `removed` what nobody else holds anymore, leaving the rest.
+fn release_unshared<T>(removed: &mut Vec<Arc<T>>) -> Vec<T> {
+    let mut released = Vec::new();
+    let mut held = Vec::new();
+    for item in removed.drain(..) {
+        match Arc::try_unwrap(item) {
+            Ok(item) => released.push(item),
+            Err(item) => held.push(item),
+        }
+    }
+    *removed = held;
+    released
+}
+
+/// Interleaved sample index at a JACK transport frame.
+fn pos_idx_at(frame: Frames, channels: usize) -> u64 {
+    frame as u64 * channels.max(1) as u64
+}
+
+/// JACK transport frame at an interleaved sample index.
+fn frame_at(pos_idx: u64, channels: usize) -> Frames {
+    (pos_idx / channels.max(1) as u64) as Frames
+}
+
+/// The tempo a follower takes on from the timebase master, unless the song
+/// already plays it.
+fn tempo_to_follow(tempo_map: &TempoMap, bpm: f64) -> Option<f64> {
+    let differs =
+        tempo_map.tempos().len() > 1 || (tempo_map.bpm_at(0.0) - bpm).abs() > TEMPO_TOLERANCE;
+    differs.then_some(bpm)
+}
+
+/// What the process callback shares with the UI thread.
+struct Shared {
+    mode: AtomicU8,
+    /// Taken only briefly by the UI thread, the callback never waits for it.
+    handoff: Mutex<Handoff<Arc<DirectOut>>>,
+}
+
+struct Process {
+    processor: BlockProcessor,
+    state: Arc<EngineState>,
+    shared: Arc<Shared>,
+    main: Vec<Port<AudioOut>>,
+    /// Written every cycle, whatever the UI thread is doing.
+    direct_outs: Vec<Arc<DirectOut>>,
+    /// The block interleaved, as the engine mixes it.
+    interleaved: Vec<f32>,
+}
+
+impl Process {
+    fn follow_transport(&mut self, client: &Client) {
+        let Ok(query) = client.transport().query() else {
+            return;
+        };
+        let channels = self.main.len().max(1);
+        let rolling = query.state == TransportState::Rolling;
+        self.state
+            .pos_idx
+            .store(pos_idx_at(query.pos.frame(), channels), Ordering::SeqCst);
+        self.state.is_playing.store(rolling, Ordering::SeqCst);
+
+        // a master of our own already plays the tempo map
+        if self.shared.mode.load(Ordering::Relaxed) == JackTransportMode::Follower.code()
+            && let Some(bbt) = query.pos.bbt()
+            && let Ok(tempo_map) = self.state.tempo_map.try_lock()
+            && let Some(bpm) = tempo_to_follow(&tempo_map, bbt.bpm)
+        {
+            drop(tempo_map);
+            self.state
+                .request_tempo(bpm, client.sample_rate() as u32, channels as u16);
+        }
+    }
+}
+
+impl ProcessHandler for Process {
+    fn process(&mut self, client: &Client, scope: &ProcessScope) -> Control {
+        if self.shared.mode.load(Ordering::Relaxed) != JackTransportMode::Off.code() {
+            self.follow_transport(client);
+        }
+
+        let channels = self.main.len();
+        let frames = scope.n_frames() as usize;
+        // sized by `buffer_size` beforehand
+        self.interleaved.resize(frames * channels, 0.0);
+        self.processor.process(&mut self.interleaved);
+        for (ch, port) in self.main.iter_mut().enumerate() {
+            let out = port.as_mut_slice(scope);
+            for (f, sample) in out.iter_mut().enumerate() {
+                *sample = self.interleaved[f * channels + ch];
+            }
+        }
+
+        if let Ok(mut handoff) = self.shared.handoff.try_lock() {
+            handoff.take(&mut self.direct_outs);
+        }
+        // silent while the mixer is busy rather than left unwritten
+        let mixer = self.state.mixer.try_lock().ok();
+        for direct_out in &self.direct_outs {
+            let block = mixer
+                .as_ref()
+                .and_then(|mixer| mixer.track(&direct_out.track_id))
+                .map(|track| track.block());
+            for (ch, port) in direct_out.ports.iter().enumerate() {
+                // the port is registered until the UI thread gets it back, and
+                // its buffer is only written here, for this cycle's frames
+                let out = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        port.buffer(frames as Frames) as *mut f32,
+                        frames,
+                    )
+                };
+                match block.and_then(|block| block.get(ch)) {
+                    Some(src) if src.len() == out.len() => out.copy_from_slice(src),
+                    _ => out.fill(0.0),
+                }
+            }
+        }
+        Control::Continue
+    }
+
+    fn buffer_size(&mut self, _: &Client, size: Frames) -> Control {
+        self.interleaved.reserve(size as usize * self.main.len());
+        Control::Continue
+    }
+}
+
+struct Notifications;
+
+impl NotificationHandler for Notifications {
+    fn shutdown(&mut self, _status: jack::ClientStatus, reason: &str) {
+        eprintln!("JACK server shut down: {}", reason);
+    }
+}
+
+/// Fills in bars and beats for the JACK transport while we're timebase master.
+struct Timebase {
+    state: Arc<EngineState>,
+}
+
+impl Timebase {
+    fn fill(&self, pos: &mut j::jack_position_t) {
+        // no bars and beats this cycle rather than waiting on the UI thread
+        let Ok(tempo_map) = self.state.tempo_map.try_lock() else {
+            return;
+        };
+        fill_bbt(&tempo_map, pos);
+    }
+}
+
+/// Bars and beats at a JACK position, beats counted in the time signature's
+/// note value.
+fn fill_bbt(tempo_map: &TempoMap, pos: &mut j::jack_position_t) {
+    let seconds = pos.frame as f64 / pos.frame_rate.max(1) as f64;
+    let beat = tempo_map.beat_at(seconds);
+    let bar = tempo_map.bar_at(beat);
+    let in_bar = (beat - bar.start) * bar.denominator.max(1) as f64;
+
+    pos.valid |= j::JackPositionBBT;
+    pos.bar = bar.index as i32 + 1;
+    pos.beat = in_bar as i32 + 1;
+    pos.tick = (in_bar.fract() * TICKS_PER_BEAT) as i32;
+    pos.bar_start_tick = bar.start * bar.denominator.max(1) as f64 * TICKS_PER_BEAT;
+    pos.beats_per_bar = bar.numerator as f32;
+    pos.beat_type = bar.denominator as f32;
+    pos.ticks_per_beat = TICKS_PER_BEAT;
+    pos.beats_per_minute = tempo_map.bpm_at(beat);
+}
+
+unsafe extern "C" fn timebase_callback(
+    _state: j::jack_transport_state_t,
+    _frames: j::jack_nframes_t,
+    pos: *mut j::jack_position_t,
+    _new_pos: i32,
+    arg: *mut c_void,
+) {
+    let timebase = unsafe { &*(arg as *const Timebase) };
+    timebase.fill(unsafe { &mut *pos });
+}
+
+pub struct JackBackend {
+    client: AsyncClient<Notifications, Process>,
+    shared: Arc<Shared>,
+    state: Arc<EngineState>,
+    channels: usize,
+    /// The direct outs as last handed to the process callback.
+    direct_outs: Mutex<Vec<Arc<DirectOut>>>,
+    /// Direct outs taken away, unregistered once the callback lets go of them.
+    removed: Mutex<Vec<Arc<DirectOut>>>,
+    /// Read by the timebase callback while we're master.
+    timebase: Option<Box<Timebase>>,
+}
+
+impl JackBackend {
+    /// A client on a running server, never starting one. The engine takes
+    /// over its rate and block size before it's opened.
+    pub fn connect() -> anyhow::Result<Client> {
+        let (client, _) = Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER)?;
+        Ok(client)
+    }
+
+    /// Activates a connected client and wires the main outs to the system
+    /// playback ports.
+    pub fn open(
+        client: Client,
+        config: &AudioConfig,
+        state: Arc<EngineState>,
+        processor: BlockProcessor,
+    ) -> anyhow::Result<Self> {
+        if client.sample_rate() != config.sample_rate as usize {
+            anyhow::bail!(
+                "JACK runs at {} Hz, the engine at {} Hz",
+                client.sample_rate(),
+                config.sample_rate
+            );
+        }
+
+        let channels = config.channels.max(1) as usize;
+        let main = (1..=channels)
+            .map(|n| client.register_port(&format!("main_out_{}", n), AudioOut))
+            .collect::<Result<Vec<_>, _>>()?;
+        let main_names: Vec<String> = main.iter().filter_map(|p| p.name().ok()).collect();
+        let buffer_size = client.buffer_size() as usize;
+
+        let shared = Arc::new(Shared {
+            mode: AtomicU8::new(JackTransportMode::Off.code()),
+            handoff: Mutex::new(Handoff::default()),
+        });
+        let process = Process {
+            processor,
+            state: state.clone(),
+            shared: shared.clone(),
+            main,
+            direct_outs: Vec::new(),
+            interleaved: Vec::with_capacity(buffer_size * channels),
+        };
+        let client = client.activate_async(Notifications, process)?;
+
+        let playback = client.as_client().ports(
+            None,
+            Some(jack::jack_sys::FLOAT_MONO_AUDIO),
+            PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL,
+        );
+        for (ours, theirs) in main_names.iter().zip(&playback) {
+            if let Err(e) = client.as_client().connect_ports_by_name(ours, theirs) {
+                eprintln!("Failed to connect {} to {}: {}", ours, theirs, e);
+            }
+        }
+        println!(
+            "[start_output] JACK client {} SR-{}",
+            client.as_client().name(),
+            client.as_client().sample_rate()
+        );
+
+        Ok(Self {
+            client,
+            shared,
+            state,
+            channels,
+            direct_outs: Mutex::new(Vec::new()),
+            removed: Mutex::new(Vec::new()),
+            timebase: None,
+        })
+    }
+
+    pub fn transport_mode(&self) -> JackTransportMode {
+        JackTransportMode::from_code(self.shared.mode.load(Ordering::Relaxed))
+    }
+
+    pub fn set_transport_mode(&mut self, mode: JackTransportMode) -> anyhow::Result<()> {
+        let raw = self.client.as_client().raw();
+        if mode == JackTransportMode::Master && self.timebase.is_none() {
+            let timebase = Box::new(Timebase {
+                state: self.state.clone(),
+            });
+            let arg = &*timebase as *const Timebase as *mut c_void;
+            // unconditionally, taking over from another master
+            let result =
+                unsafe { j::jack_set_timebase_callback(raw, 0, Some(timebase_callback), arg) };
+            if result != 0 {
+                anyhow::bail!("cannot become JACK timebase master");
+            }
+            self.timebase = Some(timebase);
+        } else if mode != JackTransportMode::Master {
+            self.release_timebase();
+        }
+        self.shared.mode.store(mode.code(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn release_timebase(&mut self) {
+        if self.timebase.is_some() {
+            unsafe { j::jack_release_timebase(self.client.as_client().raw()) };
+            self.timebase = None;
+        }
+    }
+
+    /// Starts the JACK transport from an interleaved sample index. False when
+    /// the transport runs on its own.
+    pub fn start(&self, pos_idx: u64) -> bool {
+        if self.transport_mode() == JackTransportMode::Off {
+            return false;
+        }
+        let transport = self.client.as_client().transport();
+        let frame = frame_at(pos_idx, self.channels);
+        if let Err(e) = transport.locate(frame).and_then(|_| transport.start()) {
+            eprintln!("Failed to start the JACK transport: {}", e);
+        }
+        true
+    }
+
+    /// Stops the JACK transport, back at the start if it was rolling. False
+    /// when the transport runs on its own; otherwise whether it was rolling.
+    pub fn stop(&self) -> Option<bool> {
+        if self.transport_mode() == JackTransportMode::Off {
+            return None;
+        }
+        let transport = self.client.as_client().transport();
+        let rolling = transport
+            .query_state()
+            .is_ok_and(|state| state != TransportState::Stopped);
+        let result = transport.stop().and_then(|_| match rolling {
+            true => transport.locate(0),
+            false => Ok(()),
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to stop the JACK transport: {}", e);
+        }
+        Some(rolling)
+    }
+
+    /// Gives a track direct outs named `<name>_N`, or takes them away when
+    /// `name` is empty.
+    pub fn set_direct_out(&self, track_id: &str, name: &str) -> anyhow::Result<()> {
+        let client = self.client.as_client();
+        let ports = match name.is_empty() {
+            true => Vec::new(),
+            false => (1..=self.channels)
+                .map(|n| client.register_port(&format!("{}_{}", name, n), AudioOut))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
+        let mut direct_outs = self.direct_outs.lock().unwrap();
+        if let Some(idx) = direct_outs
+            .iter()
+            .position(|direct_out| direct_out.track_id == track_id)
+        {
+            self.removed.lock().unwrap().push(direct_outs.remove(idx));
+        }
+        if !ports.is_empty() {
+            direct_outs.push(Arc::new(DirectOut {
+                track_id: track_id.to_owned(),
+                ports,
+            }));
+        }
+        let retired = self
+            .shared
+            .handoff
+            .lock()
+            .unwrap()
+            .offer(direct_outs.clone());
+        drop(retired);
+        drop(direct_outs);
+
+        self.release_removed();
+        Ok(())
+    }
+
+    /// Unregisters the removed direct outs once the process callback has
+    /// taken the list without them, those it still holds after a while are
+    /// tried again next time.
+    fn release_removed(&self) {
+        let started = Instant::now();
+        loop {
+            let taken = self.shared.handoff.lock().unwrap().is_taken();
+            if taken || started.elapsed() > RELEASE_TIMEOUT {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let retired = self.shared.handoff.lock().unwrap().retired.take();
+        drop(retired);
+
+        let client = self.client.as_client();
+        let released = release_unshared(&mut self.removed.lock().unwrap());
+        for direct_out in released {
+            for port in direct_out.ports {
+                if let Err(e) = client.unregister_port(port) {
+                    eprintln!(
+                        "Failed to remove a direct out of {}: {}",
+                        direct_out.track_id, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Drop for JackBackend {
+    fn drop(&mut self) {
+        self.release_timebase();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempo_map::{TempoChange, TimeSignature};
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Fields of the packed position are read by copy, `{ pos.field }`.
+    fn position(seconds: f64) -> j::jack_position_t {
+        let mut pos: j::jack_position_t = unsafe { std::mem::zeroed() };
+        pos.frame_rate = SAMPLE_RATE;
+        pos.frame = (seconds * SAMPLE_RATE as f64) as j::jack_nframes_t;
+        pos
+    }
+
+    #[test]
+    fn bars_and_beats_count_quarter_notes_in_four_four() {
+        let tempo_map = TempoMap::new(120.0);
+        // ten and a half quarter notes in
+        let mut pos = position(5.25);
+        fill_bbt(&tempo_map, &mut pos);
+
+        assert_ne!(pos.valid & j::JackPositionBBT, 0);
+        assert_eq!((pos.bar, pos.beat, pos.tick), (3, 3, 960));
+        assert_eq!({ pos.bar_start_tick }, 8.0 * TICKS_PER_BEAT);
+        assert_eq!((pos.beats_per_bar, pos.beat_type), (4.0, 4.0));
+        assert_eq!({ pos.beats_per_minute }, 120.0);
+    }
+
+    #[test]
+    fn bars_and_beats_follow_the_meter_and_tempo() {
+        let mut tempo_map = TempoMap::new(120.0);
+        tempo_map.time_signatures.push(TimeSignature {
+            beat: 0.0,
+            numerator: 6,
+            denominator: 8,
+        });
+        tempo_map.set_tempos(vec![
+            TempoChange {
+                beat: 0.0,
+                bpm: 120.0,
+            },
+            TempoChange {
+                beat: 1.5,
+                bpm: 60.0,
+            },
+        ]);
+
+        // the third eighth of the second bar
+        let mut pos = position(2.0);
+        fill_bbt(&tempo_map, &mut pos);
+        assert_eq!((pos.bar, pos.beat, pos.tick), (2, 3, 0));
+        assert_eq!({ pos.bar_start_tick }, 6.0 * TICKS_PER_BEAT);
+        assert_eq!((pos.beats_per_bar, pos.beat_type), (6.0, 8.0));
+        assert_eq!({ pos.beats_per_minute }, 120.0);
+
+        // the third bar starts at 3 s, its eighths last half a second from then
+        let mut pos = position(3.75);
+        fill_bbt(&tempo_map, &mut pos);
+        assert_eq!((pos.bar, pos.beat, pos.tick), (3, 2, 960));
+        assert_eq!({ pos.beats_per_minute }, 60.0);
+    }
+
+    #[test]
+    fn followers_take_the_masters_tempo_once() {
+        let mut master = TempoMap::new(93.0);
+        let mut pos = position(1.0);
+        fill_bbt(&master, &mut pos);
+
+        let follower = TempoMap::new(120.0);
+        assert_eq!(tempo_to_follow(&follower, pos.beats_per_minute), Some(93.0));
+        assert_eq!(
+            tempo_to_follow(&TempoMap::new(93.0), pos.beats_per_minute),
+            None
+        );
+        // a song with tempo changes is flattened to the master's
+        master.set_tempos(vec![
+            TempoChange {
+                beat: 0.0,
+                bpm: 93.0,
+            },
+            TempoChange {
+                beat: 4.0,
+                bpm: 140.0,
+            },
+        ]);
+        assert_eq!(tempo_to_follow(&master, 93.0), Some(93.0));
+    }
+
+    #[test]
+    fn positions_convert_by_channel_count() {
+        assert_eq!(pos_idx_at(48000, 2), 96000);
+        assert_eq!(frame_at(96001, 2), 48000);
+        assert_eq!(frame_at(pos_idx_at(12345, 6), 6), 12345);
+        // no channels count as one
+        assert_eq!(pos_idx_at(7, 0), 7);
+        assert_eq!(frame_at(7, 0), 7);
+    }
+
+    #[test]
+    fn handoff_swaps_on_the_callback_and_retires_on_the_caller() {
+        let mut handoff = Handoff::default();
+        let mut current = vec![Arc::new("old")];
+
+        assert!(handoff.offer(vec![Arc::new("new")]).is_none());
+        assert!(!handoff.is_taken());
+        handoff.take(&mut current);
+        assert!(handoff.is_taken());
+        assert_eq!(*current[0], "new");
+
+        // nothing waiting leaves the callback's list alone
+        handoff.take(&mut current);
+        assert_eq!(*current[0], "new");
+
+        // the next offer hands back the list retired by the callback
+        let retired = handoff.offer(Vec::new()).unwrap();
+        assert_eq!(*retired[0], "old");
+    }
+
+    #[test]
+    fn only_unshared_removals_are_released() {
+        let held = Arc::new(1);
+        let mut removed = vec![Arc::new(0), held.clone(), Arc::new(2)];
+
+        assert_eq!(release_unshared(&mut removed), [0, 2]);
+        assert_eq!(removed.len(), 1);
+
+        // once the callback lets go of it
+        drop(held);
+        assert_eq!(release_unshared(&mut removed), [1]);
+        assert!(removed.is_empty());
+    }
+}
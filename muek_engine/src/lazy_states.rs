@@ -1,13 +1,28 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex, RwLock, mpsc::Sender},
+    sync::{Arc, Mutex, RwLock, atomic::AtomicU32, mpsc::Sender},
 };
 
 use once_cell::sync::Lazy;
 
-use crate::{audio::{AudioConfig, AudioEngine}, protos::tracks_proto::ClipProto};
+use crate::{
+    audio::{AudioConfig, AudioEngine},
+    automation::{AutomationStore, GestureQueue},
+    effects::{
+        EffectInsert, EffectParams, Meters, analyzer::Spectrum, convolution::Impulse,
+        drums::DrumKit, sampler::SampleBank,
+    },
+    link::Link,
+    midi_input::MidiInputs,
+    midi_sync::MidiSync,
+    plugin_bridge::BridgedPlugin,
+    protos::tracks_proto::ClipProto,
+};
+
+#[cfg(target_os = "windows")]
+use crate::vst_box::{self, PluginHandle};
 
-pub static EVENT_LOOP_SENDER: Lazy<Arc<Mutex<Option<Sender<String>>>>> =
+pub static EVENT_LOOP_SENDER: Lazy<Arc<Mutex<Option<Sender<(u32, String)>>>>> =
     Lazy::new(|| Arc::new(Mutex::new(None)));
 
 pub static CLIP_CACHES: Lazy<Arc<RwLock<HashMap<String, Vec<f32>>>>> =
@@ -23,3 +38,70 @@ pub static AUDIO_ENGINE: Lazy<Arc<Mutex<AudioEngine>>> = Lazy::new(|| {
     let engine = AudioEngine::new(&config);
     Arc::new(Mutex::new(engine))
 });
+
+pub static AUTOMATION: Lazy<Arc<Mutex<AutomationStore>>> =
+    Lazy::new(|| Arc::new(Mutex::new(AutomationStore::default())));
+
+/// Plugin gestures for `AUTOMATION`, applied by the engine's housekeeper.
+pub static AUTOMATION_GESTURES: Lazy<GestureQueue> = Lazy::new(GestureQueue::default);
+
+pub static NEXT_PLUGIN_ID: AtomicU32 = AtomicU32::new(1);
+
+#[cfg(target_os = "windows")]
+pub static PLUGINS: Lazy<Arc<Mutex<HashMap<u32, PluginHandle>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// In-process instances, shared between their editor window and an insert chain.
+#[cfg(target_os = "windows")]
+pub static LOADED_PLUGINS: Lazy<Arc<Mutex<HashMap<u32, Arc<Mutex<vst_box::Box>>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Serialized states waiting for their plugin instance to be created.
+pub static PENDING_PLUGIN_STATES: Lazy<Arc<Mutex<HashMap<u32, Vec<u8>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+pub static BRIDGED_PLUGINS: Lazy<Arc<Mutex<HashMap<u32, BridgedPlugin>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Ids of bridged plugins whose helper crashed, drained by the host.
+pub static CRASHED_PLUGINS: Lazy<Arc<Mutex<Vec<u32>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
+
+/// Built-in effects created but not yet placed in an insert chain.
+pub static NEW_EFFECTS: Lazy<Arc<Mutex<HashMap<u32, EffectInsert>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Parameters of every built-in effect, shared with the audio thread.
+pub static EFFECT_PARAMS: Lazy<Arc<Mutex<HashMap<u32, Arc<EffectParams>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Spectrum feeds of the built-in effects that have one.
+pub static EFFECT_SPECTRA: Lazy<Arc<Mutex<HashMap<u32, Arc<Spectrum>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Gain reduction and other readouts of the built-in effects that have them.
+pub static EFFECT_METERS: Lazy<Arc<Mutex<HashMap<u32, Arc<Meters>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Impulse responses of the convolution effects.
+pub static EFFECT_IMPULSES: Lazy<Arc<Mutex<HashMap<u32, Arc<Impulse>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Pads and step grids of the drum machines.
+pub static EFFECT_DRUM_KITS: Lazy<Arc<Mutex<HashMap<u32, Arc<DrumKit>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// Instrument files of the samplers.
+pub static EFFECT_SAMPLE_BANKS: Lazy<Arc<Mutex<HashMap<u32, Arc<SampleBank>>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// MIDI input ports, the open ones feeding the audio callback.
+pub static MIDI_INPUTS: Lazy<Arc<Mutex<MidiInputs>>> =
+    Lazy::new(|| Arc::new(Mutex::new(MidiInputs::default())));
+
+/// Clock and time code sent from the transport, or chased by it.
+pub static MIDI_SYNC: Lazy<Arc<Mutex<MidiSync>>> =
+    Lazy::new(|| Arc::new(Mutex::new(MidiSync::default())));
+
+/// The Ableton Link peer the transport follows while enabled.
+pub static LINK: Lazy<Arc<Mutex<Link>>> = Lazy::new(|| Arc::new(Mutex::new(Link::default())));
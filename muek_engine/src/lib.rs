@@ -1,6 +1,9 @@
 use std::{
+    collections::HashMap,
     env,
     ffi::{CString, c_char},
+    path::PathBuf,
+    sync::atomic::Ordering,
     thread,
 };
 
@@ -10,11 +13,21 @@ use winit::platform::windows::EventLoopBuilderExtWindows;
 
 use crate::{
     audio::RenderedClip,
-    lazy_states::{AUDIO_ENGINE, CLIP_CACHES, EVENT_LOOP_SENDER},
+    automation::{AutomationMode, AutomationPoint},
+    lazy_states::{
+        AUDIO_ENGINE, AUTOMATION, BRIDGED_PLUGINS, CLIP_CACHES, CRASHED_PLUGINS, EFFECT_DRUM_KITS,
+        EFFECT_IMPULSES, EFFECT_METERS, EFFECT_PARAMS, EFFECT_SAMPLE_BANKS, EFFECT_SPECTRA,
+        EVENT_LOOP_SENDER, LINK, MIDI_INPUTS, MIDI_SYNC, NEW_EFFECTS, NEXT_PLUGIN_ID,
+        PENDING_PLUGIN_STATES,
+    },
+    midi::RecordedMidiEvent,
+    midi_clip::MidiClip,
+    midi_sync::{ChaseSource, MtcRate},
+    mixer::{BusLayout, Insert},
     muek_event::MuekEvent,
     protos::{
         byte_buffer::ByteBuffer,
-        tracks_proto::{ClipProto, TrackProto},
+        tracks_proto::{ClipProto, MidiClipProto, TrackProto},
     },
 };
 
@@ -22,14 +35,33 @@ use crate::{
 use winit_app::App;
 
 mod audio;
+mod automation;
+mod byte_reader;
 mod decode;
+mod effects;
+mod fx_preset;
+#[cfg(target_os = "linux")]
+mod jack_backend;
 mod lazy_states;
+mod link;
+mod midi;
+mod midi_clip;
+mod midi_input;
+mod midi_sync;
+mod mixer;
 mod muek_event;
+mod plugin_bridge;
+mod plugin_scanner;
+mod plugin_state;
 mod protos;
+mod shm_bridge;
+mod smf;
+mod tempo_map;
 #[cfg(target_os = "windows")]
 mod vst_box;
 #[cfg(target_os = "windows")]
 mod winit_app;
+mod worker_pool;
 
 #[repr(C)]
 pub struct MyClassRepr {
@@ -79,9 +111,18 @@ pub unsafe extern "C" fn free_c_string(str: *mut c_char) {
     unsafe { CString::from_raw(str) };
 }
 
+/// A string passed in from C#. Invalid UTF-16 is reported as `what` and refused,
+/// since panicking across the FFI boundary would abort the host.
+unsafe fn utf16_arg(ptr: *const u16, len: i32, what: &str) -> Option<String> {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    String::from_utf16(slice)
+        .inspect_err(|e| eprintln!("Failed to read {}: {}", what, e))
+        .ok()
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn init_vst_box() {
-    let (tx, rx) = std::sync::mpsc::channel::<String>();
+    let (tx, rx) = std::sync::mpsc::channel::<(u32, String)>();
 
     #[cfg(target_os = "windows")]
     thread::spawn(move || {
@@ -96,10 +137,10 @@ pub unsafe extern "C" fn init_vst_box() {
         let event_loop_proxy = event_loop.create_proxy();
 
         thread::spawn(move || {
-            while let Ok(msg) = rx.recv() {
+            while let Ok((id, msg)) = rx.recv() {
                 println!("Main receiver got: {}", msg);
                 event_loop_proxy
-                    .send_event(MuekEvent::CreateNewPlugin(msg))
+                    .send_event(MuekEvent::CreateNewPlugin(id, msg))
                     .ok();
             }
         });
@@ -111,37 +152,60 @@ pub unsafe extern "C" fn init_vst_box() {
     *EVENT_LOOP_SENDER.lock().unwrap() = Some(tx);
 }
 
+/// Returns the id of the new plugin instance, used to address its automation lanes.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn run_vst_instance_by_path(utf16_str: *const u16, utf16_len: i32) {
+pub unsafe extern "C" fn run_vst_instance_by_path(utf16_str: *const u16, utf16_len: i32) -> u32 {
     let slice = unsafe { std::slice::from_raw_parts(utf16_str, utf16_len as usize) };
     let path = String::from_utf16(slice).unwrap();
     println!("Loading VST: {}", path);
 
+    let id = NEXT_PLUGIN_ID.fetch_add(1, Ordering::SeqCst);
+
     let m = EVENT_LOOP_SENDER.lock().unwrap();
     let tx = m.as_ref().unwrap();
-    tx.send(path).unwrap();
+    tx.send((id, path)).unwrap();
+
+    id
 }
 
+/// Returns the id of the new plugin instance, used to save and restore its state.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn run_vst_instance_by_path_with_handle(
     utf16_str: *const u16,
     utf16_len: i32,
     hwnd: usize,
-) {
+) -> u32 {
     let slice = unsafe { std::slice::from_raw_parts(utf16_str, utf16_len as usize) };
     let path = String::from_utf16(slice).unwrap();
 
+    let id = NEXT_PLUGIN_ID.fetch_add(1, Ordering::SeqCst);
+
     #[cfg(target_os = "windows")]
     std::thread::spawn(move || {
         let hwnd_ptr = hwnd as *mut std::ffi::c_void;
-        let mut vst = vst_box::Box::from_path(&path);
-        vst.init(48000.0, 48000 / 100);
-        vst.show_editor_with_handle(hwnd_ptr);
+        let mut vst = vst_box::Box::from_path_with_id(id, &path);
+        let (sample_rate, block_size) = {
+            let engine_lock = AUDIO_ENGINE.lock().unwrap();
+            (
+                engine_lock.config.sample_rate as f32,
+                engine_lock.config.buffer_size as i64,
+            )
+        };
+        vst.init(sample_rate, block_size);
+
+        let vst = std::sync::Arc::new(std::sync::Mutex::new(vst));
+        lazy_states::LOADED_PLUGINS
+            .lock()
+            .unwrap()
+            .insert(id, vst.clone());
+        vst_box::Box::show_editor_with_handle(vst, hwnd_ptr);
     });
+
+    id
 }
 
+/// Probes the plugin in a separate process, so a broken plugin cannot crash the host.
 #[unsafe(no_mangle)]
-#[allow(unused)]
 pub unsafe extern "C" fn verify_vst_instance_by_path(
     utf16_str: *const u16,
     utf16_len: i32,
@@ -149,15 +213,21 @@ pub unsafe extern "C" fn verify_vst_instance_by_path(
     let slice = unsafe { std::slice::from_raw_parts(utf16_str, utf16_len as usize) };
     let str = String::from_utf16(slice).unwrap();
 
-    #[cfg(target_os = "windows")]
-    {
-        let name = vst_box::verify_vst(&str).unwrap_or("MUEK_ERR".to_owned());
-        let buf = ByteBuffer::from_vec(name.into_bytes());
-        Box::into_raw(Box::new(buf))
-    }
-
-    #[cfg(not(target_os = "windows"))]
-    Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])))
+    let name = plugin_scanner::probe_plugin(
+        &plugin_scanner::default_probe_path(),
+        &str,
+        plugin_scanner::PROBE_TIMEOUT,
+    )
+    .and_then(|outcome| match outcome {
+        plugin_scanner::ProbeOutcome::Found(record) => Ok(record.name),
+        plugin_scanner::ProbeOutcome::Rejected(reason) => Err(anyhow::anyhow!(reason)),
+    })
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to verify {}: {}", str, e);
+        "MUEK_ERR".to_owned()
+    });
+    let buf = ByteBuffer::from_vec(name.into_bytes());
+    Box::into_raw(Box::new(buf))
 }
 
 #[unsafe(no_mangle)]
@@ -182,9 +252,10 @@ pub unsafe extern "C" fn cache_clip_data(
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn sync_all_clips(ptr: *const ClipProto, len: i32) {
     let slice = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
-    let mut engine_lock = AUDIO_ENGINE.lock().unwrap();
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
     let clip_caches_lock = CLIP_CACHES.read().unwrap();
-    engine_lock.rendered_clips.clear();
+    let mut rendered_clips = state.rendered_clips.lock().unwrap();
+    rendered_clips.clear();
 
     for item in slice {
         println!(
@@ -196,30 +267,41 @@ pub unsafe extern "C" fn sync_all_clips(ptr: *const ClipProto, len: i32) {
 
         let samples = clip_caches_lock.get(&str).unwrap();
 
+        let track_id = if item.track_id.is_null() {
+            String::new()
+        } else {
+            // an unreadable id plays the clip on the master
+            unsafe { utf16_arg(item.track_id, item.track_id_len, "clip track id") }
+                .unwrap_or_default()
+        };
+
         let rendered_clip = RenderedClip {
-            start_sample_idx: audio::beat_to_sample_idx(
-                item.start_time,
-                engine_lock.config.bpm,
-                engine_lock.config.sample_rate,
-                4,
-                engine_lock.config.channels.try_into().unwrap(),
-            ),
-            end_sample_idx: audio::beat_to_sample_idx(
-                item.end_time,
-                engine_lock.config.bpm,
-                engine_lock.config.sample_rate,
-                4,
-                engine_lock.config.channels.try_into().unwrap(),
-            ),
+            track_id,
+            start_beat: item.start_time as f64,
+            end_beat: item.end_time as f64,
             samples: samples.to_vec(),
         };
-        engine_lock.rendered_clips.push(rendered_clip);
+        rendered_clips.push(rendered_clip);
     }
 }
 
+/// Replaces the MIDI clips of every track, played to the track's inserts.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sync_midi_clips(ptr: *const MidiClipProto, len: i32) {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    let mut clips: HashMap<String, Vec<MidiClip>> = HashMap::new();
+    for item in slice {
+        let (track_id, clip) = unsafe { item.to_clip() };
+        clips.entry(track_id).or_default().push(clip);
+    }
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state.mixer.lock().unwrap().set_midi_clips(clips);
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn spawn_audio_thread() {
-    let engine_lock = AUDIO_ENGINE.lock().unwrap();
+    let mut engine_lock = AUDIO_ENGINE.lock().unwrap();
     engine_lock.spawn();
 }
 
@@ -246,3 +328,1588 @@ pub unsafe extern "C" fn set_position_beat(beat: f32) {
     let mut engine_lock = AUDIO_ENGINE.lock().unwrap();
     engine_lock.set_pos_beat(beat);
 }
+
+/// 0 = off, 1 = read, 2 = write, 3 = touch, 4 = latch
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_automation_mode(mode: i32) {
+    let beat = AUDIO_ENGINE.lock().unwrap().get_position_beat();
+    AUTOMATION
+        .lock()
+        .unwrap()
+        .set_mode(AutomationMode::from_i32(mode), beat);
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_automation_mode() -> i32 {
+    AUTOMATION.lock().unwrap().mode.as_i32()
+}
+
+/// Returns the points of one lane as `AutomationPoint` structs.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_automation_lane(plugin_id: u32, param_index: i32) -> *mut ByteBuffer {
+    let automation = AUTOMATION.lock().unwrap();
+    let points = automation
+        .lane((plugin_id, param_index))
+        .map(|lane| lane.points.clone())
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec_struct(points)))
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_automation_lane(
+    plugin_id: u32,
+    param_index: i32,
+    ptr: *const AutomationPoint,
+    len: i32,
+) {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    AUTOMATION
+        .lock()
+        .unwrap()
+        .set_lane((plugin_id, param_index), slice.to_vec());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clear_automation_lane(plugin_id: u32, param_index: i32) {
+    AUTOMATION
+        .lock()
+        .unwrap()
+        .clear_lane((plugin_id, param_index));
+}
+
+/// Serializes the plugin's chunk (or parameter values) for the project file.
+/// Returns an empty buffer when the plugin is not loaded.
+#[unsafe(no_mangle)]
+#[allow(unused)]
+pub unsafe extern "C" fn save_plugin_state(plugin_id: u32) -> *mut ByteBuffer {
+    #[cfg(target_os = "windows")]
+    {
+        let handle = lazy_states::PLUGINS
+            .lock()
+            .unwrap()
+            .get(&plugin_id)
+            .cloned();
+        let bytes = handle
+            .map(|h| h.save_state().to_bytes())
+            .unwrap_or_default();
+        Box::into_raw(Box::new(ByteBuffer::from_vec(bytes)))
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])))
+}
+
+/// Restores a state from `save_plugin_state`. If the instance is still being
+/// created, the state is applied once it is initialized.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn load_plugin_state(plugin_id: u32, data_ptr: *const u8, len: i32) -> bool {
+    if data_ptr.is_null() || len <= 0 {
+        return false;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(data_ptr, len as usize) };
+
+    #[cfg(target_os = "windows")]
+    {
+        let handle = lazy_states::PLUGINS
+            .lock()
+            .unwrap()
+            .get(&plugin_id)
+            .cloned();
+        if let Some(handle) = handle {
+            let result = plugin_state::PluginState::from_bytes(bytes)
+                .and_then(|state| handle.load_state(&state));
+            return match result {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Failed to restore plugin state: {}", e);
+                    false
+                }
+            };
+        }
+    }
+
+    if let Err(e) = plugin_state::PluginState::from_bytes(bytes) {
+        eprintln!("Invalid plugin state: {}", e);
+        return false;
+    }
+
+    PENDING_PLUGIN_STATES
+        .lock()
+        .unwrap()
+        .insert(plugin_id, bytes.to_vec());
+    true
+}
+
+/// Loads an `.fxp` or `.fxb` file into a plugin. Fails if the file was made by another plugin.
+#[unsafe(no_mangle)]
+#[allow(unused)]
+pub unsafe extern "C" fn load_fx_preset_file(
+    plugin_id: u32,
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> bool {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "preset path") }) else {
+        return false;
+    };
+
+    #[cfg(target_os = "windows")]
+    {
+        let handle = lazy_states::PLUGINS
+            .lock()
+            .unwrap()
+            .get(&plugin_id)
+            .cloned();
+        let Some(handle) = handle else {
+            return false;
+        };
+
+        let result = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| fx_preset::FxPreset::parse(&bytes))
+            .and_then(|preset| handle.load_fx_preset(&preset));
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to load preset {}: {}", path, e);
+                false
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    false
+}
+
+/// Writes the current program (`as_bank = false`, `.fxp`) or all programs (`.fxb`).
+#[unsafe(no_mangle)]
+#[allow(unused)]
+pub unsafe extern "C" fn save_fx_preset_file(
+    plugin_id: u32,
+    utf16_str: *const u16,
+    utf16_len: i32,
+    as_bank: bool,
+) -> bool {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "preset path") }) else {
+        return false;
+    };
+
+    #[cfg(target_os = "windows")]
+    {
+        let handle = lazy_states::PLUGINS
+            .lock()
+            .unwrap()
+            .get(&plugin_id)
+            .cloned();
+        let Some(handle) = handle else {
+            return false;
+        };
+
+        let bytes = if as_bank {
+            handle.save_fxb().to_bytes()
+        } else {
+            handle.save_fxp().to_bytes()
+        };
+        match std::fs::write(&path, bytes) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to save preset {}: {}", path, e);
+                false
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    false
+}
+
+/// Scans the `;` separated directories, updating the database at `db_path`.
+/// Returns the database text: `plugin` and `blacklist` records, one per line,
+/// fields separated by tabs.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn scan_plugins(
+    dirs_utf16: *const u16,
+    dirs_len: i32,
+    db_utf16: *const u16,
+    db_len: i32,
+) -> *mut ByteBuffer {
+    let Some(dirs) = (unsafe { utf16_arg(dirs_utf16, dirs_len, "plugin directories") }) else {
+        return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+    };
+    let dirs: Vec<PathBuf> = dirs
+        .split(';')
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    let Some(db_path) = (unsafe { utf16_arg(db_utf16, db_len, "plugin database path") }) else {
+        return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+    };
+    let db_path = PathBuf::from(db_path);
+
+    let mut db = plugin_scanner::PluginDatabase::load(&db_path);
+    match db.scan(&dirs, &plugin_scanner::default_probe_path()) {
+        Ok(report) => println!(
+            "[scan_plugins] scanned={} skipped={} failed={} removed={}",
+            report.scanned, report.skipped, report.failed, report.removed
+        ),
+        Err(e) => eprintln!("Failed to scan plugins: {}", e),
+    }
+    if let Err(e) = db.save(&db_path) {
+        eprintln!("Failed to save plugin database: {}", e);
+    }
+
+    Box::into_raw(Box::new(ByteBuffer::from_vec(db.to_text().into_bytes())))
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_plugin_database(db_utf16: *const u16, db_len: i32) -> *mut ByteBuffer {
+    let Some(db_path) = (unsafe { utf16_arg(db_utf16, db_len, "plugin database path") }) else {
+        return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+    };
+    let db_path = PathBuf::from(db_path);
+
+    let db = plugin_scanner::PluginDatabase::load(&db_path);
+    Box::into_raw(Box::new(ByteBuffer::from_vec(db.to_text().into_bytes())))
+}
+
+/// Removes a plugin from the blacklist so the next scan probes it again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn unblacklist_plugin(
+    db_utf16: *const u16,
+    db_len: i32,
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> bool {
+    let Some(db_path) = (unsafe { utf16_arg(db_utf16, db_len, "plugin database path") }) else {
+        return false;
+    };
+    let db_path = PathBuf::from(db_path);
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "plugin path") }) else {
+        return false;
+    };
+
+    let mut db = plugin_scanner::PluginDatabase::load(&db_path);
+    if db.blacklist.remove(&path).is_none() {
+        return false;
+    }
+    db.save(&db_path).is_ok()
+}
+
+/// Loads the plugin in a `muek_plugin_bridge` helper process. Loading happens in the
+/// background; if it fails the id shows up in `take_crashed_plugins`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn run_vst_instance_bridged(utf16_str: *const u16, utf16_len: i32) -> u32 {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "plugin path") }) else {
+        return 0;
+    };
+    println!("Loading bridged VST: {}", path);
+
+    let id = NEXT_PLUGIN_ID.fetch_add(1, Ordering::SeqCst);
+    let (sample_rate, block_size) = {
+        let engine_lock = AUDIO_ENGINE.lock().unwrap();
+        (
+            engine_lock.config.sample_rate as f32,
+            engine_lock.config.buffer_size,
+        )
+    };
+
+    thread::spawn(move || {
+        match plugin_bridge::BridgedPlugin::spawn(id, &path, sample_rate, block_size) {
+            Ok(plugin) => {
+                BRIDGED_PLUGINS.lock().unwrap().insert(id, plugin);
+            }
+            Err(e) => {
+                eprintln!("Failed to bridge {}: {}", path, e);
+                CRASHED_PLUGINS.lock().unwrap().push(id);
+            }
+        }
+    });
+
+    id
+}
+
+/// Also removes the plugin from the insert chain it sits in.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn close_bridged_plugin(plugin_id: u32) {
+    BRIDGED_PLUGINS.lock().unwrap().remove(&plugin_id);
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state.mixer.lock().unwrap().remove_insert(plugin_id);
+}
+
+/// Ids (u32) of bridged plugins that crashed or failed to load since the last call.
+/// Their inserts are bypassed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn take_crashed_plugins() -> *mut ByteBuffer {
+    let ids = std::mem::take(&mut *CRASHED_PLUGINS.lock().unwrap());
+    Box::into_raw(Box::new(ByteBuffer::from_vec_struct(ids)))
+}
+
+/// Appends a loaded plugin or built-in effect to the insert chain of a track.
+/// Bridged plugins and effects move into the chain; in-process plugins keep
+/// their editor window.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn add_track_insert(
+    utf16_str: *const u16, // track id
+    utf16_len: i32,
+    plugin_id: u32,
+) -> bool {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return false;
+    };
+
+    let insert: Option<Box<dyn Insert>> = BRIDGED_PLUGINS
+        .lock()
+        .unwrap()
+        .remove(&plugin_id)
+        .map(|p| Box::new(p) as Box<dyn Insert>)
+        .or_else(|| {
+            NEW_EFFECTS
+                .lock()
+                .unwrap()
+                .remove(&plugin_id)
+                .map(|e| Box::new(e) as Box<dyn Insert>)
+        });
+
+    #[cfg(target_os = "windows")]
+    let insert = insert.or_else(|| {
+        lazy_states::LOADED_PLUGINS
+            .lock()
+            .unwrap()
+            .get(&plugin_id)
+            .cloned()
+            .map(|p| Box::new(vst_box::PluginInsert::new(p)) as Box<dyn Insert>)
+    });
+
+    let Some(insert) = insert else {
+        eprintln!("No loaded plugin with id {}", plugin_id);
+        return false;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let mut mixer = state.mixer.lock().unwrap();
+    mixer.add_insert(&track_id, insert);
+    println!(
+        "[add_track_insert] plugin {} on track {}, total latency {} samples",
+        plugin_id,
+        track_id,
+        mixer.total_latency()
+    );
+    true
+}
+
+/// Takes the plugin out of its insert chain. A bridged plugin or built-in effect is closed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn remove_track_insert(plugin_id: u32) -> bool {
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let removed = state.mixer.lock().unwrap().remove_insert(plugin_id);
+    forget_effect(plugin_id);
+    removed.is_some()
+}
+
+/// Closes a built-in effect that was created but never added to an insert chain.
+/// Effects in a chain are closed with `remove_track_insert`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn destroy_effect(effect_id: u32) -> bool {
+    let removed = NEW_EFFECTS.lock().unwrap().remove(&effect_id);
+    if removed.is_some() {
+        forget_effect(effect_id);
+    }
+    removed.is_some()
+}
+
+/// Drops what the host side keeps of a built-in effect.
+fn forget_effect(effect_id: u32) {
+    EFFECT_PARAMS.lock().unwrap().remove(&effect_id);
+    EFFECT_SPECTRA.lock().unwrap().remove(&effect_id);
+    EFFECT_METERS.lock().unwrap().remove(&effect_id);
+    EFFECT_IMPULSES.lock().unwrap().remove(&effect_id);
+    EFFECT_SAMPLE_BANKS.lock().unwrap().remove(&effect_id);
+    EFFECT_DRUM_KITS.lock().unwrap().remove(&effect_id);
+}
+
+/// Bus layout of a plugin from its reported input and output counts.
+/// All zero when the plugin is not loaded.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_plugin_bus_layout(plugin_id: u32) -> BusLayout {
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let mixer = state.mixer.lock().unwrap();
+    if let Some(layout) = mixer.insert_layout(plugin_id) {
+        return layout;
+    }
+
+    if let Some(plugin) = BRIDGED_PLUGINS.lock().unwrap().get(&plugin_id) {
+        return BusLayout::new(plugin.in_channels, plugin.out_channels, mixer.channels);
+    }
+
+    #[cfg(target_os = "windows")]
+    if let Some(handle) = lazy_states::PLUGINS.lock().unwrap().get(&plugin_id) {
+        return BusLayout::new(
+            handle.info.inputs.max(0) as usize,
+            handle.info.outputs.max(0) as usize,
+            mixer.channels,
+        );
+    }
+
+    BusLayout::default()
+}
+
+/// Feeds a track from aux output bus `bus` (1 based) of a plugin in an insert chain,
+/// e.g. one drum of a multi-output sampler. Fails if the routing would loop.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn route_plugin_output(
+    plugin_id: u32,
+    bus: i32,
+    utf16_str: *const u16, // track id
+    utf16_len: i32,
+) -> bool {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return false;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let result = state
+        .mixer
+        .lock()
+        .unwrap()
+        .set_track_source(&track_id, Some((plugin_id, bus.max(0) as usize)));
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to route plugin {} output: {}", plugin_id, e);
+            false
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clear_track_source(utf16_str: *const u16, utf16_len: i32) {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state
+        .mixer
+        .lock()
+        .unwrap()
+        .set_track_source(&track_id, None)
+        .ok();
+}
+
+/// Feeds the plugin's extra inputs from another track. An empty track id clears it.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_plugin_sidechain(
+    plugin_id: u32,
+    utf16_str: *const u16, // source track id
+    utf16_len: i32,
+) -> bool {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return false;
+    };
+    let source = (!track_id.is_empty()).then_some(track_id.as_str());
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let result = state.mixer.lock().unwrap().set_sidechain(plugin_id, source);
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to set sidechain of plugin {}: {}", plugin_id, e);
+            false
+        }
+    }
+}
+
+/// Plays the track's inserts with the MIDI a plugin emits, e.g. an arpeggiator
+/// driving a synth on another track. Plugin id 0 disconnects.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_track_midi_source(
+    utf16_str: *const u16, // track id
+    utf16_len: i32,
+    plugin_id: u32,
+) -> bool {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return false;
+    };
+    let source = (plugin_id != 0).then_some(plugin_id);
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let result = state
+        .mixer
+        .lock()
+        .unwrap()
+        .set_midi_source(&track_id, source);
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to route MIDI of plugin {}: {}", plugin_id, e);
+            false
+        }
+    }
+}
+
+/// Plays a MIDI message on the track's inserts at the next block, playing or not.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn send_track_midi(
+    utf16_str: *const u16, // track id
+    utf16_len: i32,
+    status: u8,
+    data1: u8,
+    data2: u8,
+) -> bool {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return false;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let result = state
+        .mixer
+        .lock()
+        .unwrap()
+        .send_midi(&track_id, [status, data1, data2]);
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to send MIDI to track {}: {}", track_id, e);
+            false
+        }
+    }
+}
+
+/// While armed, MIDI the plugin emits during playback is recorded.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arm_plugin_midi_recording(plugin_id: u32, armed: bool) {
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state
+        .mixer
+        .lock()
+        .unwrap()
+        .arm_midi_recording(plugin_id, armed);
+}
+
+/// MIDI recorded from the plugin since the last call, as `RecordedMidiEvent` structs.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn take_recorded_plugin_midi(plugin_id: u32) -> *mut ByteBuffer {
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let events = state.mixer.lock().unwrap().take_recorded_midi(plugin_id);
+    Box::into_raw(Box::new(ByteBuffer::from_vec_struct(events)))
+}
+
+/// MIDI input ports, one per line as id, name and whether it is open, separated by tabs.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_midi_input_ports() -> *mut ByteBuffer {
+    let inputs = MIDI_INPUTS.lock().unwrap();
+    let text = inputs
+        .ports()
+        .into_iter()
+        .map(|p| format!("{}\t{}\t{}\n", p.id, p.name, inputs.is_open(&p.id)))
+        .collect::<String>();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Starts reading a MIDI input port into the armed or input track.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn open_midi_input(utf16_str: *const u16, utf16_len: i32) -> bool {
+    let Some(port_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "port id") }) else {
+        return false;
+    };
+
+    match MIDI_INPUTS.lock().unwrap().open(&port_id) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to open MIDI input {}: {}", port_id, e);
+            false
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn close_midi_input(utf16_str: *const u16, utf16_len: i32) -> bool {
+    let Some(port_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "port id") }) else {
+        return false;
+    };
+
+    MIDI_INPUTS.lock().unwrap().close(&port_id)
+}
+
+/// Adds an input port fed by `send_virtual_midi_input`. Returns its id.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn create_virtual_midi_input(
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> *mut ByteBuffer {
+    let Some(name) = (unsafe { utf16_arg(utf16_str, utf16_len, "port name") }) else {
+        return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+    };
+
+    let id = MIDI_INPUTS.lock().unwrap().create_virtual(&name);
+    Box::into_raw(Box::new(ByteBuffer::from_vec(id.into_bytes())))
+}
+
+/// Delivers a message on an open virtual port, as a device would.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn send_virtual_midi_input(
+    utf16_str: *const u16, // port id
+    utf16_len: i32,
+    status: u8,
+    data1: u8,
+    data2: u8,
+) -> bool {
+    let Some(port_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "port id") }) else {
+        return false;
+    };
+
+    MIDI_INPUTS
+        .lock()
+        .unwrap()
+        .send_virtual(&port_id, [status, data1, data2])
+}
+
+/// Plays the MIDI inputs on this track while no track is armed. An empty id plays none.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_midi_input_track(utf16_str: *const u16, utf16_len: i32) {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state
+        .mixer
+        .lock()
+        .unwrap()
+        .set_input_track(Some(track_id.as_str()).filter(|id| !id.is_empty()));
+}
+
+/// Armed tracks play the MIDI inputs and record them during playback.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arm_track_midi_recording(
+    utf16_str: *const u16,
+    utf16_len: i32,
+    armed: bool,
+) {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state
+        .mixer
+        .lock()
+        .unwrap()
+        .arm_input_recording(&track_id, armed);
+}
+
+/// MIDI input recorded on the track since the last call, as `RecordedMidiEvent` structs.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn take_recorded_track_midi(
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> *mut ByteBuffer {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let events = state.mixer.lock().unwrap().take_recorded_input(&track_id);
+    Box::into_raw(Box::new(ByteBuffer::from_vec_struct(events)))
+}
+
+/// MIDI output ports as lines of `id\tname\tselected`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_midi_output_ports() -> *mut ByteBuffer {
+    let sync = MIDI_SYNC.lock().unwrap();
+    let text = sync
+        .ports()
+        .into_iter()
+        .map(|p| format!("{}\t{}\t{}\n", p.id, p.name, sync.output() == Some(&p.id)))
+        .collect::<String>();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Sends clock and time code to a MIDI output port. An empty id sends them nowhere.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_midi_sync_output(utf16_str: *const u16, utf16_len: i32) -> bool {
+    let Some(port_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "port id") }) else {
+        return false;
+    };
+
+    let port = (!port_id.is_empty()).then_some(port_id.as_str());
+    match MIDI_SYNC.lock().unwrap().set_output(port) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to open MIDI output {}: {}", port_id, e);
+            false
+        }
+    }
+}
+
+/// Adds an output port whose messages are taken with `take_virtual_midi_output`. Returns its id.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn create_virtual_midi_output(
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> *mut ByteBuffer {
+    let Some(name) = (unsafe { utf16_arg(utf16_str, utf16_len, "port name") }) else {
+        return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+    };
+
+    let id = MIDI_SYNC.lock().unwrap().create_virtual(&name);
+    Box::into_raw(Box::new(ByteBuffer::from_vec(id.into_bytes())))
+}
+
+/// Raw bytes sent to virtual output ports since the last call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn take_virtual_midi_output() -> *mut ByteBuffer {
+    let bytes = MIDI_SYNC.lock().unwrap().take_virtual_sent();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(bytes)))
+}
+
+/// Sends MIDI Beat Clock with start, stop, continue and song position.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_midi_clock_output(enabled: bool) {
+    MIDI_SYNC.lock().unwrap().link().set_clock(enabled);
+}
+
+/// Sends MIDI Time Code at a rate: 0 is 24 fps, 1 is 25, 2 is 29.97 drop frame,
+/// 3 is 30. Any other rate sends none.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_mtc_output(rate: i32) {
+    let rate = (0..4)
+        .contains(&rate)
+        .then(|| MtcRate::from_code(rate as u8));
+    MIDI_SYNC.lock().unwrap().link().set_time_code(rate);
+}
+
+/// Follows the open MIDI inputs: 1 chases clock, tempo included, 2 chases
+/// time code, 0 stops chasing. The transport plays the clips as last synced.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_midi_chase(source: u32) {
+    let source = ChaseSource::from_code(source.min(u8::MAX as u32) as u8);
+    MIDI_SYNC.lock().unwrap().link().set_chase(source);
+}
+
+/// Joins Ableton Link sessions on the local network, starting one at the
+/// engine's tempo, or leaves them. The transport then follows the session
+/// tempo and starts on its bar.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_link_enabled(enabled: bool) -> bool {
+    if !enabled {
+        LINK.lock().unwrap().disable();
+        return true;
+    }
+    let bpm = AUDIO_ENGINE.lock().unwrap().bpm();
+    match LINK.lock().unwrap().enable(bpm) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to enable Link: {}", e);
+            false
+        }
+    }
+}
+
+/// Other peers in the Link session.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_link_peers() -> u32 {
+    LINK.lock().unwrap().state().peers() as u32
+}
+
+/// Sets a single tempo for the song, or for the whole Link session when
+/// enabled; a song of a single tempo follows the session.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_tempo(bpm: f64) {
+    let link = LINK.lock().unwrap().state();
+    let engine = AUDIO_ENGINE.lock().unwrap();
+    let several = engine.state.tempo_map.lock().unwrap().tempos().len() > 1;
+    // a song with tempo changes would otherwise propose them right back
+    if !link.is_enabled() || several {
+        engine.set_bpm(bpm);
+    }
+    if link.is_enabled() {
+        link.propose_tempo(bpm);
+    }
+}
+
+/// How the transport goes with JACK's: 0 on its own, 1 following it, 2 also
+/// as timebase master. False when not running on JACK.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_jack_transport_mode(mode: u32) -> bool {
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = mode;
+        false
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let mode = jack_backend::JackTransportMode::from_code(mode.min(u8::MAX as u32) as u8);
+        let engine = AUDIO_ENGINE.lock().unwrap();
+        let mut jack = engine.jack.lock().unwrap();
+        let Some(jack) = jack.as_mut() else {
+            return false;
+        };
+        match jack.set_transport_mode(mode) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to set the JACK transport mode: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Gives a track post-fader JACK ports, `name_1` and on, one per channel, or
+/// takes them away when the name is empty. False when not running on JACK.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_jack_direct_out(
+    track_utf16: *const u16,
+    track_len: i32,
+    name_utf16: *const u16,
+    name_len: i32,
+) -> bool {
+    let Some(track_id) = (unsafe { utf16_arg(track_utf16, track_len, "track id") }) else {
+        return false;
+    };
+    let Some(name) = (unsafe { utf16_arg(name_utf16, name_len, "port name") }) else {
+        return false;
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (track_id, name);
+        false
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let engine = AUDIO_ENGINE.lock().unwrap();
+        let jack = engine.jack.lock().unwrap();
+        let Some(jack) = jack.as_ref() else {
+            return false;
+        };
+        match jack.set_direct_out(&track_id, &name) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to set the direct out of {}: {}", track_id, e);
+                false
+            }
+        }
+    }
+}
+
+/// Tempo at the play position, which may have come from a Link session.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_tempo() -> f64 {
+    AUDIO_ENGINE.lock().unwrap().bpm()
+}
+
+/// Adds a bus to the routing graph, routed to the master. Returns false if the id is taken.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn create_mixer_bus(utf16_str: *const u16, utf16_len: i32) -> bool {
+    let Some(bus_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "bus id") }) else {
+        return false;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let mut mixer = state.mixer.lock().unwrap();
+    if mixer.tracks.iter().any(|t| t.id == bus_id) {
+        return false;
+    }
+    mixer.track_mut(&bus_id);
+    true
+}
+
+/// Removes a track or bus with its inserts. Whatever was routed to it goes to the master.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn remove_mixer_node(utf16_str: *const u16, utf16_len: i32) -> bool {
+    let Some(node_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "node id") }) else {
+        return false;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let Some(removed) = state.mixer.lock().unwrap().remove_track(&node_id) else {
+        return false;
+    };
+    for insert in &removed.inserts {
+        forget_effect(insert.id());
+    }
+    // inserts are dropped here, bridged ones may take a while to shut down
+    true
+}
+
+/// Routes the main output of a track or bus to a bus. An empty target means the master.
+/// Fails if the routing would loop.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_mixer_output(
+    node_utf16: *const u16,
+    node_len: i32,
+    target_utf16: *const u16,
+    target_len: i32,
+) -> bool {
+    let Some(node_id) = (unsafe { utf16_arg(node_utf16, node_len, "node id") }) else {
+        return false;
+    };
+    let Some(target) = (unsafe { utf16_arg(target_utf16, target_len, "target id") }) else {
+        return false;
+    };
+    let target = (!target.is_empty()).then_some(target.as_str());
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let result = state.mixer.lock().unwrap().set_output(&node_id, target);
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to route {}: {}", node_id, e);
+            false
+        }
+    }
+}
+
+/// Adds or updates an aux send. `level` is linear gain.
+/// Fails if the routing would loop.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_mixer_send(
+    node_utf16: *const u16,
+    node_len: i32,
+    target_utf16: *const u16,
+    target_len: i32,
+    level: f32,
+    pre_fader: bool,
+) -> bool {
+    let Some(node_id) = (unsafe { utf16_arg(node_utf16, node_len, "node id") }) else {
+        return false;
+    };
+    let Some(target) = (unsafe { utf16_arg(target_utf16, target_len, "target id") }) else {
+        return false;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let result = state
+        .mixer
+        .lock()
+        .unwrap()
+        .set_send(&node_id, &target, level, pre_fader);
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to send {} to {}: {}", node_id, target, e);
+            false
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn remove_mixer_send(
+    node_utf16: *const u16,
+    node_len: i32,
+    target_utf16: *const u16,
+    target_len: i32,
+) -> bool {
+    let Some(node_id) = (unsafe { utf16_arg(node_utf16, node_len, "node id") }) else {
+        return false;
+    };
+    let Some(target) = (unsafe { utf16_arg(target_utf16, target_len, "target id") }) else {
+        return false;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state.mixer.lock().unwrap().remove_send(&node_id, &target)
+}
+
+/// Fader of a track or bus, linear gain.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_mixer_volume(utf16_str: *const u16, utf16_len: i32, volume: f32) {
+    let Some(node_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "node id") }) else {
+        return;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    state.mixer.lock().unwrap().track_mut(&node_id).volume = volume.max(0.0);
+}
+
+/// Delay between the engine position and what is heard, in samples per channel.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_output_latency() -> u32 {
+    let engine_lock = AUDIO_ENGINE.lock().unwrap();
+    engine_lock.latency() as u32
+}
+
+/// Time spent processing a block relative to its duration, smoothed. 1.0 or more means dropouts.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_dsp_load() -> f32 {
+    let engine_lock = AUDIO_ENGINE.lock().unwrap();
+    engine_lock.dsp_load()
+}
+
+/// The position currently heard, i.e. the engine position minus the plugin latency.
+/// Used for the playhead.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_audible_position_beat() -> f32 {
+    let engine_lock = AUDIO_ENGINE.lock().unwrap();
+    let latency = engine_lock.latency() as u64 * engine_lock.config.channels as u64;
+    let pos_idx = engine_lock.state.pos_idx.load(Ordering::SeqCst);
+    engine_lock.beat_at_sample(pos_idx.saturating_sub(latency))
+}
+
+/// Names of the built-in effects, one per line.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_builtin_effects() -> *mut ByteBuffer {
+    let names = effects::BUILTIN_EFFECTS.join("\n");
+    Box::into_raw(Box::new(ByteBuffer::from_vec(names.into_bytes())))
+}
+
+/// Creates a built-in effect, ready for `add_track_insert`. Returns 0 for an unknown name.
+/// Effects share their ids with plugins.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn create_effect(utf16_str: *const u16, utf16_len: i32) -> u32 {
+    let Some(name) = (unsafe { utf16_arg(utf16_str, utf16_len, "effect name") }) else {
+        return 0;
+    };
+    new_effect(&name)
+}
+
+fn new_effect(name: &str) -> u32 {
+    let Some(effect) = effects::create(name) else {
+        eprintln!("No built-in effect named {}", name);
+        return 0;
+    };
+
+    let id = NEXT_PLUGIN_ID.fetch_add(1, Ordering::SeqCst);
+    let channels = AUDIO_ENGINE.lock().unwrap().config.channels as usize;
+    let insert = effects::EffectInsert::new(id, channels, effect);
+    EFFECT_PARAMS.lock().unwrap().insert(id, insert.params());
+    if let Some(spectrum) = insert.spectrum() {
+        EFFECT_SPECTRA.lock().unwrap().insert(id, spectrum);
+    }
+    if let Some(meters) = insert.meters() {
+        EFFECT_METERS.lock().unwrap().insert(id, meters);
+    }
+    if let Some(impulse) = insert.impulse() {
+        EFFECT_IMPULSES.lock().unwrap().insert(id, impulse);
+    }
+    if let Some(bank) = insert.sample_bank() {
+        EFFECT_SAMPLE_BANKS.lock().unwrap().insert(id, bank);
+    }
+    if let Some(kit) = insert.drum_kit() {
+        EFFECT_DRUM_KITS.lock().unwrap().insert(id, kit);
+    }
+    NEW_EFFECTS.lock().unwrap().insert(id, insert);
+    id
+}
+
+/// Puts the built-in synth on a track whose insert chain is empty, so MIDI played on it
+/// sounds before any instrument is loaded. Returns the id of the first insert of the
+/// track, 0 on failure.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ensure_track_instrument(utf16_str: *const u16, utf16_len: i32) -> u32 {
+    let Some(track_id) = (unsafe { utf16_arg(utf16_str, utf16_len, "track id") }) else {
+        return 0;
+    };
+
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let first_insert = |mixer: &mixer::Mixer| {
+        mixer
+            .track(&track_id)
+            .and_then(|t| t.inserts.first())
+            .map(|i| i.id())
+    };
+    if let Some(id) = first_insert(&state.mixer.lock().unwrap()) {
+        return id;
+    }
+
+    // created outside the mixer lock, which must not be held while taking the engine's
+    let id = new_effect(effects::DEFAULT_INSTRUMENT);
+    let Some(insert) = NEW_EFFECTS.lock().unwrap().remove(&id) else {
+        return 0;
+    };
+
+    let mut mixer = state.mixer.lock().unwrap();
+    if let Some(existing) = first_insert(&mixer) {
+        drop(mixer);
+        drop(insert);
+        forget_effect(id);
+        return existing;
+    }
+    mixer.add_insert(&track_id, Box::new(insert));
+    id
+}
+
+/// Parameters of a built-in effect, one per line: name, unit, min, max, default,
+/// steps (0 when continuous) and the `|` separated step labels, separated by tabs.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_effect_params(effect_id: u32) -> *mut ByteBuffer {
+    let params = EFFECT_PARAMS.lock().unwrap().get(&effect_id).cloned();
+    let text = params
+        .map(|params| {
+            params
+                .info()
+                .iter()
+                .map(|p| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        p.name,
+                        p.unit,
+                        p.min,
+                        p.max,
+                        p.default,
+                        p.steps,
+                        p.labels.join("|")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Value of a parameter in its own unit, NaN if there is no such effect or parameter.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_effect_param(effect_id: u32, param_index: i32) -> f32 {
+    EFFECT_PARAMS
+        .lock()
+        .unwrap()
+        .get(&effect_id)
+        .and_then(|params| params.get(param_index as usize))
+        .unwrap_or(f32::NAN)
+}
+
+/// Sets a parameter in its own unit, clamped to its range. Never waits for the audio thread.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_effect_param(effect_id: u32, param_index: i32, value: f32) -> bool {
+    EFFECT_PARAMS
+        .lock()
+        .unwrap()
+        .get(&effect_id)
+        .is_some_and(|params| params.set(param_index as usize, value))
+}
+
+/// Serializes a built-in effect for the project file. Empty if there is no such effect.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn save_effect_state(effect_id: u32) -> *mut ByteBuffer {
+    let mut bytes = NEW_EFFECTS
+        .lock()
+        .unwrap()
+        .get(&effect_id)
+        .map(|e| e.save_state());
+    if bytes.is_none() {
+        let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+        let mut mixer = state.mixer.lock().unwrap();
+        bytes = mixer.effect_mut(effect_id).map(|e| e.save_state());
+    }
+    Box::into_raw(Box::new(ByteBuffer::from_vec(bytes.unwrap_or_default())))
+}
+
+/// Restores a state from `save_effect_state`. Fails if it belongs to another effect.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn load_effect_state(effect_id: u32, data_ptr: *const u8, len: i32) -> bool {
+    if data_ptr.is_null() || len <= 0 {
+        return false;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(data_ptr, len as usize) };
+
+    let result = {
+        let mut new_effects = NEW_EFFECTS.lock().unwrap();
+        match new_effects.get_mut(&effect_id) {
+            Some(effect) => Some(effect.load_state(bytes)),
+            None => {
+                let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+                let mut mixer = state.mixer.lock().unwrap();
+                mixer.effect_mut(effect_id).map(|e| e.load_state(bytes))
+            }
+        }
+    };
+    match result {
+        Some(Ok(())) => true,
+        Some(Err(e)) => {
+            eprintln!("Failed to restore effect state: {}", e);
+            false
+        }
+        None => {
+            eprintln!("No effect with id {}", effect_id);
+            false
+        }
+    }
+}
+
+/// Level of the effect's output in dBFS (f32) at `points` frequencies spaced
+/// logarithmically from 20 Hz to 20 kHz. Empty if the effect has no analyzer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_effect_spectrum(effect_id: u32, points: i32) -> *mut ByteBuffer {
+    let spectrum = EFFECT_SPECTRA.lock().unwrap().get(&effect_id).cloned();
+    let levels = spectrum
+        .map(|spectrum| {
+            let sample_rate = AUDIO_ENGINE.lock().unwrap().config.sample_rate as f32;
+            let freqs = effects::analyzer::log_frequencies(points.max(0) as usize);
+            spectrum.magnitudes(sample_rate, &freqs)
+        })
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec_struct(levels)))
+}
+
+/// Names of the effect's meters, one per line. Empty if it has none.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_effect_meter_names(effect_id: u32) -> *mut ByteBuffer {
+    let meters = EFFECT_METERS.lock().unwrap().get(&effect_id).cloned();
+    let names = meters.map(|m| m.names.join("\n")).unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(names.into_bytes())))
+}
+
+/// Current meter values (f32) in the order of `get_effect_meter_names`.
+/// Gain reduction is in positive dB, the largest of the last block.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_effect_meters(effect_id: u32) -> *mut ByteBuffer {
+    let meters = EFFECT_METERS.lock().unwrap().get(&effect_id).cloned();
+    let values = meters.map(|m| m.values()).unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec_struct(values)))
+}
+
+/// Loads an impulse response file into a convolution effect. The file is decoded
+/// before returning; preparing it for the engine continues in the background.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn load_effect_ir(
+    effect_id: u32,
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> bool {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "impulse response path") }) else {
+        return false;
+    };
+
+    let Some(impulse) = EFFECT_IMPULSES.lock().unwrap().get(&effect_id).cloned() else {
+        eprintln!("No convolution effect with id {}", effect_id);
+        return false;
+    };
+    match impulse.load(&path) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to load impulse response: {}", e);
+            false
+        }
+    }
+}
+
+/// The loaded impulse response as path, channels and length in seconds, separated by tabs.
+/// Empty if nothing is loaded.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_effect_ir(effect_id: u32) -> *mut ByteBuffer {
+    let impulse = EFFECT_IMPULSES.lock().unwrap().get(&effect_id).cloned();
+    let text = impulse
+        .and_then(|impulse| {
+            let (channels, secs) = impulse.info()?;
+            Some(format!("{}\t{}\t{}", impulse.path(), channels, secs))
+        })
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Loads an SF2 bank or SFZ instrument into a sampler. Samples are decoded before
+/// returning.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn load_sampler_instrument(
+    effect_id: u32,
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> bool {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "sampler instrument path") }) else {
+        return false;
+    };
+
+    let Some(bank) = EFFECT_SAMPLE_BANKS.lock().unwrap().get(&effect_id).cloned() else {
+        eprintln!("No sampler with id {}", effect_id);
+        return false;
+    };
+    match bank.load(&path) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to load sampler instrument: {}", e);
+            false
+        }
+    }
+}
+
+/// The loaded instrument as path, presets, zones and sample length in seconds, separated
+/// by tabs. Empty if nothing is loaded.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_sampler_instrument(effect_id: u32) -> *mut ByteBuffer {
+    let bank = EFFECT_SAMPLE_BANKS.lock().unwrap().get(&effect_id).cloned();
+    let text = bank
+        .and_then(|bank| {
+            let (presets, zones, secs) = bank.info()?;
+            Some(format!("{}\t{}\t{}\t{}", bank.path(), presets, zones, secs))
+        })
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Presets of the loaded instrument, one per line as bank, program and name separated by
+/// tabs, in the order the Preset parameter counts them.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_sampler_presets(effect_id: u32) -> *mut ByteBuffer {
+    let bank = EFFECT_SAMPLE_BANKS.lock().unwrap().get(&effect_id).cloned();
+    let text = bank
+        .map(|bank| {
+            bank.presets()
+                .into_iter()
+                .map(|(bank, program, name)| format!("{}\t{}\t{}\n", bank, program, name))
+                .collect::<String>()
+        })
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Loads a sample into a pad of a drum machine, or clears the pad for an empty path.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn load_drum_pad(
+    effect_id: u32,
+    pad: u32,
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> bool {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "drum pad path") }) else {
+        return false;
+    };
+
+    let Some(kit) = EFFECT_DRUM_KITS.lock().unwrap().get(&effect_id).cloned() else {
+        eprintln!("No drum machine with id {}", effect_id);
+        return false;
+    };
+    match kit.load_pad(pad as usize, &path) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to load drum pad: {}", e);
+            false
+        }
+    }
+}
+
+/// One line per pad with the sample path and its length in seconds, separated by a tab.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_drum_pads(effect_id: u32) -> *mut ByteBuffer {
+    let kit = EFFECT_DRUM_KITS.lock().unwrap().get(&effect_id).cloned();
+    let text = kit
+        .map(|kit| {
+            kit.pads()
+                .into_iter()
+                .map(|(path, secs)| format!("{}\t{}\n", path, secs))
+                .collect::<String>()
+        })
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Sets a step of the grid. Velocity 0 makes it a rest; probability is in percent.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_drum_step(
+    effect_id: u32,
+    pad: u32,
+    step: u32,
+    velocity: u8,
+    probability: u8,
+) -> bool {
+    let Some(kit) = EFFECT_DRUM_KITS.lock().unwrap().get(&effect_id).cloned() else {
+        eprintln!("No drum machine with id {}", effect_id);
+        return false;
+    };
+    kit.set_step(pad as usize, step as usize, velocity, probability)
+}
+
+/// Velocity and probability bytes of every step, 32 steps for each of the 16 pads.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_drum_steps(effect_id: u32) -> *mut ByteBuffer {
+    let kit = EFFECT_DRUM_KITS.lock().unwrap().get(&effect_id).cloned();
+    let steps = kit.map(|kit| kit.steps()).unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec(steps)))
+}
+
+/// Response of a parametric EQ in dB (f32) at the same frequencies as `get_effect_spectrum`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_eq_curve(effect_id: u32, points: i32) -> *mut ByteBuffer {
+    let params = EFFECT_PARAMS.lock().unwrap().get(&effect_id).cloned();
+    let curve = params
+        .filter(|params| params.name == effects::eq::NAME)
+        .map(|params| {
+            let sample_rate = AUDIO_ENGINE.lock().unwrap().config.sample_rate as f32;
+            let freqs = effects::analyzer::log_frequencies(points.max(0) as usize);
+            effects::eq::curve(&params, sample_rate, &freqs)
+        })
+        .unwrap_or_default();
+    Box::into_raw(Box::new(ByteBuffer::from_vec_struct(curve)))
+}
+
+/// Reads a Standard MIDI File and makes its tempo map the engine's.
+/// Returns its tracks with channel messages as lines of tab-separated fields,
+/// by kind: `track name length`, then `event beat status data1 data2` for each
+/// message of that track. Empty if the file can't be read.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn import_midi_file(
+    utf16_str: *const u16,
+    utf16_len: i32,
+) -> *mut ByteBuffer {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "MIDI file path") }) else {
+        return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+    };
+
+    let smf = match smf::load(&path) {
+        Ok(smf) => smf,
+        Err(e) => {
+            eprintln!("Failed to import MIDI file: {}", e);
+            return Box::into_raw(Box::new(ByteBuffer::from_vec(vec![])));
+        }
+    };
+    AUDIO_ENGINE.lock().unwrap().set_tempo_map(smf.tempo_map);
+
+    let mut text = String::new();
+    for track in &smf.tracks {
+        let length = track.events.iter().map(|e| e.beat).fold(0.0, f32::max);
+        let name = track.name.replace(['\t', '\n'], " ");
+        text += &format!("track\t{}\t{}\n", name, length);
+        for e in &track.events {
+            text += &format!(
+                "event\t{}\t{}\t{}\t{}\n",
+                e.beat, e.status, e.data1, e.data2
+            );
+        }
+    }
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+/// Writes MIDI clips to a Standard MIDI File with the engine's tempo map, a
+/// track per track id, named after it. Clips play as they would in the engine.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn export_midi_file(
+    utf16_str: *const u16,
+    utf16_len: i32,
+    ptr: *const MidiClipProto,
+    len: i32,
+) -> bool {
+    let Some(path) = (unsafe { utf16_arg(utf16_str, utf16_len, "MIDI file path") }) else {
+        return false;
+    };
+
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+    let mut clips: Vec<(String, Vec<MidiClip>)> = Vec::new();
+    for item in slice {
+        let (track_id, clip) = unsafe { item.to_clip() };
+        match clips.iter_mut().find(|(id, _)| *id == track_id) {
+            Some((_, track)) => track.push(clip),
+            None => clips.push((track_id, vec![clip])),
+        }
+    }
+
+    let ticks_per_beat = smf::PPQ as u32 * 4;
+    let tracks = clips
+        .into_iter()
+        .map(|(name, clips)| {
+            let events = midi_clip::render(&clips, ticks_per_beat)
+                .into_iter()
+                .map(|e| RecordedMidiEvent {
+                    beat: e.delta_frames as f32 / ticks_per_beat as f32,
+                    status: e.data[0],
+                    data1: e.data[1],
+                    data2: e.data[2],
+                })
+                .collect();
+            smf::SmfTrack { name, events }
+        })
+        .collect();
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let tempo_map = state.tempo_map.lock().unwrap().clone();
+
+    match smf::save(&path, &smf::Smf { tracks, tempo_map }) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to export MIDI file: {}", e);
+            false
+        }
+    }
+}
+
+/// The engine's tempo map as lines of tab-separated fields, by kind:
+/// `tempo beat bpm`, `time beat numerator denominator`, `key beat sharps minor`
+/// and `marker beat name`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_tempo_map() -> *mut ByteBuffer {
+    let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+    let map = state.tempo_map.lock().unwrap();
+
+    let mut text = String::new();
+    for t in map.tempos() {
+        text += &format!("tempo\t{}\t{}\n", t.beat, t.bpm);
+    }
+    for t in &map.time_signatures {
+        text += &format!("time\t{}\t{}\t{}\n", t.beat, t.numerator, t.denominator);
+    }
+    for k in &map.key_signatures {
+        text += &format!("key\t{}\t{}\t{}\n", k.beat, k.sharps, k.minor as u8);
+    }
+    for m in &map.markers {
+        let name = m.name.replace(['\t', '\n'], " ");
+        text += &format!("marker\t{}\t{}\n", m.beat, name);
+    }
+    Box::into_raw(Box::new(ByteBuffer::from_vec(text.into_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn destroying_an_unused_effect_drops_its_tables() {
+        let name = utf16("Gain");
+        let id = unsafe { create_effect(name.as_ptr(), name.len() as i32) };
+        assert_ne!(id, 0);
+        assert!(EFFECT_PARAMS.lock().unwrap().contains_key(&id));
+
+        assert!(unsafe { destroy_effect(id) });
+        assert!(!NEW_EFFECTS.lock().unwrap().contains_key(&id));
+        assert!(!EFFECT_PARAMS.lock().unwrap().contains_key(&id));
+
+        // already gone
+        assert!(!unsafe { destroy_effect(id) });
+    }
+
+    #[test]
+    fn invalid_utf16_is_refused() {
+        // an unpaired surrogate
+        let bad = [0xd800u16];
+        assert_eq!(unsafe { create_effect(bad.as_ptr(), 1) }, 0);
+        assert!(!unsafe { load_effect_ir(0, bad.as_ptr(), 1) });
+        assert!(!unsafe { load_sampler_instrument(0, bad.as_ptr(), 1) });
+        assert!(!unsafe { load_drum_pad(0, 0, bad.as_ptr(), 1) });
+        assert!(!unsafe { create_mixer_bus(bad.as_ptr(), 1) });
+        assert!(!unsafe { remove_mixer_node(bad.as_ptr(), 1) });
+        assert!(!unsafe { set_mixer_send(bad.as_ptr(), 1, bad.as_ptr(), 1, 1.0, false) });
+        assert!(!unsafe { open_midi_input(bad.as_ptr(), 1) });
+        let imported = unsafe { Box::from_raw(import_midi_file(bad.as_ptr(), 1)) };
+        assert_eq!(imported.len(), 0);
+    }
+
+    #[test]
+    fn tracks_without_inserts_get_the_synth_once() {
+        let track = utf16("instrument track");
+        let id = unsafe { ensure_track_instrument(track.as_ptr(), track.len() as i32) };
+        assert_ne!(id, 0);
+        assert!(EFFECT_PARAMS.lock().unwrap().contains_key(&id));
+
+        let again = unsafe { ensure_track_instrument(track.as_ptr(), track.len() as i32) };
+        assert_eq!(again, id);
+
+        let state = AUDIO_ENGINE.lock().unwrap().state.clone();
+        let mixer = state.mixer.lock().unwrap();
+        assert_eq!(mixer.track("instrument track").unwrap().inserts.len(), 1);
+    }
+
+    #[test]
+    fn removing_a_node_drops_its_effects_tables() {
+        let track = utf16("removed track");
+        let id = unsafe { ensure_track_instrument(track.as_ptr(), track.len() as i32) };
+        assert!(EFFECT_PARAMS.lock().unwrap().contains_key(&id));
+
+        assert!(unsafe { remove_mixer_node(track.as_ptr(), track.len() as i32) });
+        assert!(!EFFECT_PARAMS.lock().unwrap().contains_key(&id));
+        assert!(!EFFECT_METERS.lock().unwrap().contains_key(&id));
+
+        // already gone
+        assert!(!unsafe { remove_mixer_node(track.as_ptr(), track.len() as i32) });
+    }
+}
@@ -0,0 +1,850 @@
+// Ableton Link. Peers on the local network find each other by UDP multicast
+// and gather into sessions sharing one tempo and beat timeline; when two
+// sessions meet, everyone moves to the older one. A peer joining a session
+// measures the session's ghost clock against its own host clock by pinging a
+// member, so that a beat falls at the same moment on every peer. The engine
+// takes the session tempo, or gives the session its own when the song changes
+// tempo, keeps its bars in phase with the session's and holds a start back
+// until the session comes round to the same place in the bar.
+
+use std::{
+    collections::{HashMap, HashSet, hash_map::RandomState},
+    hash::BuildHasher,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    midi_sync::{Follow, correct_drift},
+    tempo_map::TempoMap,
+};
+
+const MULTICAST: Ipv4Addr = Ipv4Addr::new(224, 76, 78, 75);
+const PORT: u16 = 20808;
+
+const DISCOVERY_HEADER: &[u8] = b"_asdp_v\x01";
+const ALIVE: u8 = 1;
+const RESPONSE: u8 = 2;
+const BYEBYE: u8 = 3;
+/// Seconds a peer is remembered without hearing from it.
+const TTL: u8 = 5;
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(250);
+
+const MEASUREMENT_HEADER: &[u8] = b"_link_v\x01";
+const PING: u8 = 1;
+const PONG: u8 = 2;
+/// Offsets a measurement takes the median of.
+const MEASUREMENT_POINTS: usize = 100;
+const PING_TIMEOUT: Duration = Duration::from_millis(50);
+const PING_RETRIES: u32 = 5;
+
+/// Sessions whose ghost clocks started less than this many microseconds
+/// apart are as old as each other, and the lower id wins.
+const SESSION_EPSILON: i64 = 500_000;
+/// Session tempo changes smaller than this, in bpm, are ignored.
+const TEMPO_TOLERANCE: f64 = 0.01;
+/// Session beats are quarter notes, four to the engine beat.
+const QUARTERS_PER_BEAT: f64 = 4.0;
+/// Quarter notes to the bar whose phase is shared.
+const QUANTUM: f64 = 4.0;
+/// How often the network thread looks for messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_MESSAGE: usize = 512;
+
+const TIMELINE: u32 = u32::from_be_bytes(*b"tmln");
+const SESSION: u32 = u32::from_be_bytes(*b"sess");
+const ENDPOINT: u32 = u32::from_be_bytes(*b"mep4");
+const HOST_TIME: u32 = u32::from_be_bytes(*b"__ht");
+const GHOST_TIME: u32 = u32::from_be_bytes(*b"__gt");
+const PREV_GHOST_TIME: u32 = u32::from_be_bytes(*b"_pgt");
+
+type NodeId = [u8; 8];
+
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Microseconds of the host clock.
+fn host_micros(at: Instant) -> i64 {
+    at.saturating_duration_since(*EPOCH).as_micros() as i64
+}
+
+/// Eight random letters and digits, as Link ids are printable.
+fn random_id() -> NodeId {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut bits = RandomState::new().hash_one(Instant::now());
+    std::array::from_fn(|_| {
+        let c = CHARS[(bits % CHARS.len() as u64) as usize];
+        bits /= CHARS.len() as u64;
+        c
+    })
+}
+
+/// Session beats, in quarter notes, along ghost time in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Timeline {
+    bpm: f64,
+    beat_origin: f64,
+    time_origin: i64,
+}
+
+impl Timeline {
+    /// A timeline at a tempo that survives the wire's whole microseconds per beat.
+    fn new(bpm: f64, beat_origin: f64, time_origin: i64) -> Self {
+        let micros_per_beat = (60_000_000.0 / bpm.clamp(20.0, 999.0)).round();
+        Self {
+            bpm: 60_000_000.0 / micros_per_beat,
+            beat_origin,
+            time_origin,
+        }
+    }
+
+    fn beat_at(&self, ghost: i64) -> f64 {
+        self.beat_origin + (ghost - self.time_origin) as f64 * self.bpm / 60_000_000.0
+    }
+
+    fn time_at(&self, beat: f64) -> i64 {
+        self.time_origin + ((beat - self.beat_origin) * 60_000_000.0 / self.bpm).round() as i64
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let micros_per_beat = (60_000_000.0 / self.bpm).round() as i64;
+        let micro_beats = (self.beat_origin * 1e6).round() as i64;
+        [micros_per_beat, micro_beats, self.time_origin]
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect()
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let int = |i: usize| Some(i64::from_be_bytes(data.get(i..i + 8)?.try_into().ok()?));
+        let micros_per_beat = int(0)?;
+        (micros_per_beat > 0).then_some(Self {
+            bpm: 60_000_000.0 / micros_per_beat as f64,
+            beat_origin: int(8)? as f64 / 1e6,
+            time_origin: int(16)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Session {
+    id: NodeId,
+    timeline: Timeline,
+    /// Ghost time less host time, in microseconds.
+    intercept: i64,
+}
+
+impl Session {
+    fn ghost(&self, host: i64) -> i64 {
+        host + self.intercept
+    }
+}
+
+/// The entries of a message a peer may send, each keyed by four letters.
+#[derive(Default)]
+struct Payload {
+    timeline: Option<Timeline>,
+    session: Option<NodeId>,
+    endpoint: Option<SocketAddrV4>,
+    host_time: Option<i64>,
+    ghost_time: Option<i64>,
+    prev_ghost_time: Option<i64>,
+}
+
+impl Payload {
+    fn read(mut data: &[u8]) -> Self {
+        let mut payload = Self::default();
+        while data.len() >= 8 {
+            let key = u32::from_be_bytes(data[..4].try_into().unwrap());
+            let size = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+            let Some(value) = data.get(8..8 + size) else {
+                break;
+            };
+            let int = || Some(i64::from_be_bytes(value.try_into().ok()?));
+            match key {
+                TIMELINE => payload.timeline = Timeline::decode(value),
+                SESSION => payload.session = value.try_into().ok(),
+                ENDPOINT if size == 6 => {
+                    let ip = Ipv4Addr::new(value[0], value[1], value[2], value[3]);
+                    let port = u16::from_be_bytes([value[4], value[5]]);
+                    payload.endpoint = Some(SocketAddrV4::new(ip, port));
+                }
+                HOST_TIME => payload.host_time = int(),
+                GHOST_TIME => payload.ghost_time = int(),
+                PREV_GHOST_TIME => payload.prev_ghost_time = int(),
+                // start/stop state and entries of later versions
+                _ => {}
+            }
+            data = &data[8 + size..];
+        }
+        payload
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut entry = |key: u32, value: &[u8]| {
+            out.extend_from_slice(&key.to_be_bytes());
+            out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            out.extend_from_slice(value);
+        };
+        if let Some(timeline) = &self.timeline {
+            entry(TIMELINE, &timeline.encode());
+        }
+        if let Some(session) = &self.session {
+            entry(SESSION, session);
+        }
+        if let Some(endpoint) = &self.endpoint {
+            let mut value = endpoint.ip().octets().to_vec();
+            value.extend_from_slice(&endpoint.port().to_be_bytes());
+            entry(ENDPOINT, &value);
+        }
+        for (key, value) in [
+            (HOST_TIME, self.host_time),
+            (GHOST_TIME, self.ghost_time),
+            (PREV_GHOST_TIME, self.prev_ghost_time),
+        ] {
+            if let Some(value) = value {
+                entry(key, &value.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Header, kind, time to live, group and sender, then the payload.
+fn discovery_message(kind: u8, node: &NodeId, payload: &Payload) -> Vec<u8> {
+    let mut message = DISCOVERY_HEADER.to_vec();
+    message.extend_from_slice(&[kind, TTL, 0, 0]);
+    message.extend_from_slice(node);
+    payload.write(&mut message);
+    message
+}
+
+fn read_discovery(data: &[u8]) -> Option<(u8, u8, NodeId, Payload)> {
+    if data.len() < 20 || !data.starts_with(DISCOVERY_HEADER) {
+        return None;
+    }
+    let node = data[12..20].try_into().unwrap();
+    Some((data[8], data[9], node, Payload::read(&data[20..])))
+}
+
+fn measurement_message(kind: u8, payload: &Payload) -> Vec<u8> {
+    let mut message = MEASUREMENT_HEADER.to_vec();
+    message.push(kind);
+    payload.write(&mut message);
+    message
+}
+
+/// The session as the engine and the network thread share it.
+pub struct LinkState {
+    enabled: AtomicBool,
+    /// Other peers in our session.
+    peers: AtomicUsize,
+    session: Mutex<Session>,
+    /// A start waiting for its bar, as (engine beat, host microseconds).
+    start: Mutex<Option<(f64, i64)>>,
+    /// A tempo set here for the session to take.
+    proposed: Mutex<Option<f64>>,
+}
+
+impl Default for LinkState {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            peers: AtomicUsize::new(0),
+            session: Mutex::new(Session {
+                id: [0; 8],
+                timeline: Timeline::new(120.0, 0.0, 0),
+                intercept: 0,
+            }),
+            start: Mutex::new(None),
+            proposed: Mutex::new(None),
+        }
+    }
+}
+
+impl LinkState {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn peers(&self) -> usize {
+        self.peers.load(Ordering::Relaxed)
+    }
+
+    pub fn propose_tempo(&self, bpm: f64) {
+        *self.proposed.lock().unwrap() = Some(bpm);
+    }
+
+    /// Holds a start at an engine beat back until the session next reaches
+    /// the same place in its bar. False when Link is off.
+    pub fn request_start(&self, beat: f64, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let session = *self.session.lock().unwrap();
+        let phase = (beat * QUARTERS_PER_BEAT).rem_euclid(QUANTUM);
+        let current = session.timeline.beat_at(session.ghost(host_micros(now)));
+        let next = ((current - phase) / QUANTUM).ceil() * QUANTUM + phase;
+        let at = session.timeline.time_at(next) - session.intercept;
+        *self.start.lock().unwrap() = Some((beat, at));
+        true
+    }
+
+    /// Drops a waiting start, returning whether there was one.
+    pub fn cancel_start(&self) -> bool {
+        self.start.lock().unwrap().take().is_some()
+    }
+
+    /// What the transport should do for a block of `frames` about to start
+    /// at `pos_idx`. A song of a single tempo takes the session's, while one
+    /// with tempo changes proposes the tempo it's at to the session.
+    #[allow(clippy::too_many_arguments)]
+    pub fn follow(
+        &self,
+        now: Instant,
+        is_playing: bool,
+        pos_idx: u64,
+        frames: usize,
+        tempo_map: &TempoMap,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Follow {
+        let channels_u = channels.max(1) as u64;
+        let sample_rate_f = sample_rate as f64;
+        let start = self.start.try_lock().ok().and_then(|start| *start);
+        if !self.is_enabled() {
+            // switched off while a start was waiting
+            let transport = start.and_then(|(beat, _)| {
+                self.start.try_lock().ok()?.take();
+                Some((true, tempo_map.sample_at_beat(beat, sample_rate, channels)))
+            });
+            return Follow {
+                bpm: None,
+                transport,
+            };
+        }
+        let Ok(session) = self.session.try_lock().map(|session| *session) else {
+            return Follow::default();
+        };
+
+        let bpm = if tempo_map.tempos().len() > 1 {
+            // a song that changes tempo leads the session instead of losing its changes
+            let beat = tempo_map.beat_at_sample(pos_idx, sample_rate, channels);
+            let song = Timeline::new(tempo_map.bpm_at(beat), 0.0, 0).bpm;
+            if (song - session.timeline.bpm).abs() > TEMPO_TOLERANCE
+                && let Ok(mut proposed) = self.proposed.try_lock()
+            {
+                *proposed = Some(song);
+            }
+            None
+        } else {
+            Some(session.timeline.bpm)
+                .filter(|bpm| (tempo_map.bpm_at(0.0) - bpm).abs() > TEMPO_TOLERANCE)
+        };
+        // the block is heard a block from now
+        let heard = host_micros(now) + (frames as f64 * 1e6 / sample_rate_f) as i64;
+
+        if !is_playing {
+            if let Some((beat, at)) = start
+                && heard >= at
+                && let Ok(mut start) = self.start.try_lock()
+            {
+                start.take();
+                // starting a little into the song rather than off the bar
+                let late = (heard - at) as f64 * sample_rate_f / 1e6;
+                let frame = tempo_map.seconds_at(beat) * sample_rate_f + late;
+                return Follow {
+                    bpm,
+                    transport: Some((true, frame.round() as u64 * channels_u)),
+                };
+            }
+            return Follow {
+                bpm,
+                transport: None,
+            };
+        }
+
+        // the song's bar keeps in phase with the session's
+        let beat = session.timeline.beat_at(session.ghost(heard));
+        let quarter = tempo_map.beat_at_sample(pos_idx, sample_rate, channels) * QUARTERS_PER_BEAT;
+        let mut offset = (beat - quarter).rem_euclid(QUANTUM);
+        if offset > QUANTUM / 2.0 {
+            offset -= QUANTUM;
+        }
+        let target = tempo_map.seconds_at((quarter + offset) / QUARTERS_PER_BEAT) * sample_rate_f;
+        let frame = correct_drift((pos_idx / channels_u) as f64, target, frames, sample_rate_f);
+        let pos = frame.round().max(0.0) as u64 * channels_u;
+        Follow {
+            bpm,
+            transport: (pos != pos_idx).then_some((true, pos)),
+        }
+    }
+}
+
+struct Peer {
+    session: NodeId,
+    timeline: Timeline,
+    expires: Instant,
+}
+
+/// Pings a member of another session for its ghost time.
+struct Measurement {
+    session: NodeId,
+    endpoint: SocketAddrV4,
+    /// Ghost time less host time, once per ping and once per pair of pongs.
+    offsets: Vec<f64>,
+    prev_ghost: Option<i64>,
+    sent: Instant,
+    retries: u32,
+}
+
+/// The network side of a peer, run on its own thread.
+struct Node {
+    id: NodeId,
+    state: Arc<LinkState>,
+    discovery: UdpSocket,
+    unicast: UdpSocket,
+    endpoint: SocketAddrV4,
+    peers: HashMap<NodeId, Peer>,
+    /// Other sessions measured and found younger than ours.
+    younger: HashSet<NodeId>,
+    measurement: Option<Measurement>,
+}
+
+/// The address the network is reached by, from the route to the group.
+fn default_interface() -> Ipv4Addr {
+    let addr = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|probe| {
+        probe.connect((MULTICAST, PORT))?;
+        probe.local_addr()
+    });
+    match addr {
+        Ok(SocketAddr::V4(addr)) => *addr.ip(),
+        _ => Ipv4Addr::LOCALHOST,
+    }
+}
+
+impl Node {
+    fn open(state: Arc<LinkState>, interface: Ipv4Addr) -> anyhow::Result<Self> {
+        // every peer on this machine listens on the one port
+        let discovery = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        discovery.set_reuse_address(true)?;
+        discovery.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+        discovery
+            .join_multicast_v4(&MULTICAST, &interface)
+            .context("cannot join the Link multicast group")?;
+        discovery.set_nonblocking(true)?;
+
+        let unicast = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        unicast.bind(&SocketAddr::from((interface, 0)).into())?;
+        unicast.set_multicast_if_v4(&interface)?;
+        unicast.set_multicast_loop_v4(true)?;
+        unicast.set_multicast_ttl_v4(1)?;
+        unicast.set_nonblocking(true)?;
+        let unicast: UdpSocket = unicast.into();
+        let endpoint = match unicast.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        let id = state.session.lock().unwrap().id;
+        Ok(Self {
+            id,
+            state,
+            discovery: discovery.into(),
+            unicast,
+            endpoint,
+            peers: HashMap::new(),
+            younger: HashSet::new(),
+            measurement: None,
+        })
+    }
+
+    fn run(mut self, stop: &AtomicBool) {
+        let mut buf = [0u8; MAX_MESSAGE];
+        let mut next_broadcast = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            let proposed = self.state.proposed.lock().unwrap().take();
+            if let Some(bpm) = proposed {
+                let mut session = self.state.session.lock().unwrap();
+                let ghost = session.ghost(host_micros(now));
+                let beat = session.timeline.beat_at(ghost);
+                session.timeline = Timeline::new(bpm, beat, ghost);
+                next_broadcast = now;
+            }
+
+            while let Ok((len, from)) = self.discovery.recv_from(&mut buf) {
+                self.on_discovery(&buf[..len], from, now);
+            }
+            while let Ok((len, from)) = self.unicast.recv_from(&mut buf) {
+                let data = &buf[..len];
+                if data.starts_with(MEASUREMENT_HEADER) {
+                    if self.on_measurement(data, from, now) {
+                        next_broadcast = now;
+                    }
+                } else {
+                    self.on_discovery(data, from, now);
+                }
+            }
+
+            if let Some(measurement) = &mut self.measurement
+                && now.duration_since(measurement.sent) > PING_TIMEOUT
+            {
+                measurement.retries += 1;
+                if measurement.retries > PING_RETRIES {
+                    // tried again when the session is next seen
+                    self.measurement = None;
+                } else {
+                    self.ping(now);
+                }
+            }
+
+            if now >= next_broadcast {
+                self.prune(now);
+                self.send_state(ALIVE, SocketAddr::from((MULTICAST, PORT)));
+                next_broadcast = now + BROADCAST_INTERVAL;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        let _ = self.unicast.send_to(
+            &discovery_message(BYEBYE, &self.id, &Payload::default()),
+            (MULTICAST, PORT),
+        );
+    }
+
+    fn send_state(&self, kind: u8, to: SocketAddr) {
+        let session = *self.state.session.lock().unwrap();
+        let payload = Payload {
+            timeline: Some(session.timeline),
+            session: Some(session.id),
+            endpoint: Some(self.endpoint),
+            ..Default::default()
+        };
+        if let Err(e) = self
+            .unicast
+            .send_to(&discovery_message(kind, &self.id, &payload), to)
+        {
+            eprintln!("Failed to send Link state: {}", e);
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.peers.retain(|_, peer| peer.expires > now);
+        let peers = &self.peers;
+        self.younger
+            .retain(|session| peers.values().any(|peer| peer.session == *session));
+        let session = self.state.session.lock().unwrap().id;
+        let count = peers
+            .values()
+            .filter(|peer| peer.session == session)
+            .count();
+        self.state.peers.store(count, Ordering::Relaxed);
+    }
+
+    fn on_discovery(&mut self, data: &[u8], from: SocketAddr, now: Instant) {
+        let Some((kind, ttl, node, payload)) = read_discovery(data) else {
+            return;
+        };
+        if node == self.id {
+            return;
+        }
+        match kind {
+            BYEBYE => {
+                self.peers.remove(&node);
+                return;
+            }
+            ALIVE => self.send_state(RESPONSE, from),
+            RESPONSE => {}
+            _ => return,
+        }
+        let (Some(session), Some(timeline), Some(endpoint)) =
+            (payload.session, payload.timeline, payload.endpoint)
+        else {
+            return;
+        };
+        self.peers.insert(
+            node,
+            Peer {
+                session,
+                timeline,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+
+        let mut ours = self.state.session.lock().unwrap();
+        if session == ours.id {
+            // the latest change of tempo anyone made
+            if timeline.time_origin > ours.timeline.time_origin {
+                ours.timeline = timeline;
+            }
+        } else if self.measurement.is_none() && !self.younger.contains(&session) {
+            drop(ours);
+            self.measurement = Some(Measurement {
+                session,
+                endpoint,
+                offsets: Vec::with_capacity(MEASUREMENT_POINTS + 1),
+                prev_ghost: None,
+                sent: now,
+                retries: 0,
+            });
+            self.ping(now);
+        }
+    }
+
+    fn ping(&mut self, now: Instant) {
+        let Some(measurement) = &mut self.measurement else {
+            return;
+        };
+        let payload = Payload {
+            host_time: Some(host_micros(now)),
+            prev_ghost_time: measurement.prev_ghost,
+            ..Default::default()
+        };
+        measurement.sent = now;
+        let message = measurement_message(PING, &payload);
+        if let Err(e) = self.unicast.send_to(&message, measurement.endpoint) {
+            eprintln!("Failed to send Link ping: {}", e);
+        }
+    }
+
+    /// Answers pings and measures with pongs. True when we joined the session
+    /// measured.
+    fn on_measurement(&mut self, data: &[u8], from: SocketAddr, now: Instant) -> bool {
+        let Some(&kind) = data.get(MEASUREMENT_HEADER.len()) else {
+            return false;
+        };
+        let payload = Payload::read(&data[MEASUREMENT_HEADER.len() + 1..]);
+        let host = host_micros(now);
+        match kind {
+            PING => {
+                let session = *self.state.session.lock().unwrap();
+                let pong = Payload {
+                    session: Some(session.id),
+                    ghost_time: Some(session.ghost(host)),
+                    host_time: payload.host_time,
+                    prev_ghost_time: payload.prev_ghost_time,
+                    ..Default::default()
+                };
+                let _ = self
+                    .unicast
+                    .send_to(&measurement_message(PONG, &pong), from);
+                false
+            }
+            PONG => {
+                let Some(measurement) = &mut self.measurement else {
+                    return false;
+                };
+                if from != SocketAddr::V4(measurement.endpoint)
+                    || payload.session != Some(measurement.session)
+                {
+                    return false;
+                }
+                let (Some(ghost), Some(sent)) = (payload.ghost_time, payload.host_time) else {
+                    return false;
+                };
+                // the pong left halfway between sending the ping and now
+                measurement
+                    .offsets
+                    .push(ghost as f64 - (sent + host) as f64 / 2.0);
+                // and the ping arrived halfway between two pongs
+                if let Some(prev) = payload.prev_ghost_time {
+                    measurement
+                        .offsets
+                        .push((ghost + prev) as f64 / 2.0 - sent as f64);
+                }
+                measurement.prev_ghost = Some(ghost);
+                measurement.retries = 0;
+                if measurement.offsets.len() < MEASUREMENT_POINTS {
+                    self.ping(now);
+                    return false;
+                }
+                let mut measurement = self.measurement.take().unwrap();
+                measurement.offsets.sort_by(f64::total_cmp);
+                let intercept = measurement.offsets[measurement.offsets.len() / 2].round() as i64;
+                self.join(measurement.session, intercept)
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves to a measured session if it's older than ours.
+    fn join(&mut self, id: NodeId, intercept: i64) -> bool {
+        let mut ours = self.state.session.lock().unwrap();
+        let age = intercept - ours.intercept;
+        if !(age > SESSION_EPSILON || (age.abs() <= SESSION_EPSILON && id < ours.id)) {
+            self.younger.insert(id);
+            return false;
+        }
+        let Some(timeline) = self
+            .peers
+            .values()
+            .filter(|peer| peer.session == id)
+            .map(|peer| peer.timeline)
+            .max_by_key(|timeline| timeline.time_origin)
+        else {
+            return false;
+        };
+        *ours = Session {
+            id,
+            timeline,
+            intercept,
+        };
+        self.younger.clear();
+        true
+    }
+}
+
+/// A peer of Link sessions, taking part while enabled.
+#[derive(Default)]
+pub struct Link {
+    state: Arc<LinkState>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Link {
+    pub fn state(&self) -> Arc<LinkState> {
+        self.state.clone()
+    }
+
+    /// Starts a session of our own at a tempo, which the first older session
+    /// we meet takes over.
+    pub fn enable(&mut self, bpm: f64) -> anyhow::Result<()> {
+        self.enable_on(bpm, default_interface())
+    }
+
+    fn enable_on(&mut self, bpm: f64, interface: Ipv4Addr) -> anyhow::Result<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+        let id = random_id();
+        *self.state.session.lock().unwrap() = Session {
+            id,
+            timeline: Timeline::new(bpm, 0.0, 0),
+            // ghost time starts now
+            intercept: -host_micros(Instant::now()),
+        };
+        self.state.proposed.lock().unwrap().take();
+        let node = Node::open(self.state.clone(), interface)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        self.thread = Some(
+            thread::Builder::new()
+                .name("link".into())
+                .spawn(move || node.run(&flag))?,
+        );
+        self.stop = stop;
+        self.state.enabled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn disable(&mut self) {
+        self.state.enabled.store(false, Ordering::Relaxed);
+        self.state.peers.store(0, Ordering::Relaxed);
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempo_map::TempoChange;
+
+    /// Waits up to 10 seconds for a condition the network threads bring about.
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(10), "no {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn session(link: &Link) -> Session {
+        *link.state.session.lock().unwrap()
+    }
+
+    #[test]
+    fn peers_on_loopback_share_one_session() {
+        let mut first = Link::default();
+        first.enable_on(120.0, Ipv4Addr::LOCALHOST).unwrap();
+        // older by more than sessions count as the same age
+        thread::sleep(Duration::from_micros(SESSION_EPSILON as u64 + 100_000));
+        let mut second = Link::default();
+        second.enable_on(90.0, Ipv4Addr::LOCALHOST).unwrap();
+
+        wait_for("joined session", || {
+            session(&second).id == session(&first).id
+        });
+        assert!((session(&second).timeline.bpm - 120.0).abs() < TEMPO_TOLERANCE);
+        wait_for("peers", || {
+            first.state.peers() == 1 && second.state.peers() == 1
+        });
+
+        // the younger peer changes the tempo for everyone
+        second.state.propose_tempo(100.0);
+        wait_for("new tempo", || {
+            (session(&first).timeline.bpm - 100.0).abs() < TEMPO_TOLERANCE
+        });
+
+        // a start on the second quarter of a bar waits for the session's second quarter
+        let now = Instant::now();
+        assert!(second.state.request_start(0.25, now));
+        let (_, at) = second.state.start.lock().unwrap().unwrap();
+        assert!(at >= host_micros(now));
+        let shared = session(&first);
+        let phase = shared
+            .timeline
+            .beat_at(shared.ghost(at))
+            .rem_euclid(QUANTUM);
+        assert!(
+            (phase - 1.0).abs() < 0.01,
+            "started at quarter {} of the bar",
+            phase
+        );
+    }
+
+    #[test]
+    fn songs_with_tempo_changes_lead_the_session() {
+        let state = LinkState::default();
+        state.enabled.store(true, Ordering::Relaxed);
+        let now = Instant::now();
+        // at 1000 Hz and 240 bpm an engine beat is 1000 frames
+        let mut tempo_map = TempoMap::new(240.0);
+
+        // a single tempo takes the session's
+        let follow = state.follow(now, false, 0, 100, &tempo_map, 1000, 1);
+        assert_eq!(follow.bpm, Some(120.0));
+        assert_eq!(*state.proposed.lock().unwrap(), None);
+
+        tempo_map.set_tempos(vec![
+            TempoChange {
+                beat: 0.0,
+                bpm: 240.0,
+            },
+            TempoChange {
+                beat: 1.0,
+                bpm: 150.0,
+            },
+        ]);
+        let follow = state.follow(now, false, 1500, 100, &tempo_map, 1000, 1);
+        assert_eq!(follow.bpm, None);
+        assert_eq!(*state.proposed.lock().unwrap(), Some(150.0));
+    }
+}
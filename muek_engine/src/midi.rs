@@ -0,0 +1,75 @@
+/// A short MIDI message at a frame offset inside the current block.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MidiEvent {
+    pub delta_frames: u32,
+    pub data: [u8; 3],
+}
+
+impl MidiEvent {
+    pub fn new(delta_frames: u32, data: [u8; 3]) -> Self {
+        Self { delta_frames, data }
+    }
+
+    pub fn message(&self) -> Message {
+        let [status, data1, data2] = self.data;
+        match status & 0xf0 {
+            0x90 if data2 > 0 => Message::NoteOn {
+                note: data1,
+                velocity: data2,
+            },
+            0x80 | 0x90 => Message::NoteOff { note: data1 },
+            0xb0 => Message::Controller {
+                number: data1,
+                value: data2,
+            },
+            0xc0 => Message::ProgramChange(data1),
+            0xe0 => {
+                let value = ((data2 as i32) << 7 | data1 as i32) - 8192;
+                Message::PitchBend(value as f32 / 8192.0)
+            }
+            _ => Message::Other,
+        }
+    }
+}
+
+/// Orders events by frame, keeping the order of those on the same frame.
+/// Blocks hold few events, so an insertion sort that never allocates is enough.
+pub fn sort_events(events: &mut [MidiEvent]) {
+    for i in 1..events.len() {
+        let mut j = i;
+        while j > 0 && events[j - 1].delta_frames > events[j].delta_frames {
+            events.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+}
+
+/// A MIDI message at a beat, as recorded for the host or placed in a MIDI clip.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RecordedMidiEvent {
+    pub beat: f32,
+    pub status: u8,
+    pub data1: u8,
+    pub data2: u8,
+}
+
+/// The channel messages instruments react to, whatever the channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Message {
+    NoteOn {
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        note: u8,
+    },
+    Controller {
+        number: u8,
+        value: u8,
+    },
+    ProgramChange(u8),
+    /// -1 to 1.
+    PitchBend(f32),
+    Other,
+}
@@ -0,0 +1,468 @@
+// MIDI clips: channel messages placed in musical time inside a clip's content,
+// which may repeat through the clip. Each block the clips of a track become
+// frame-accurate events for its inserts. Notes a clip starts are remembered
+// until they end, so they are released where the clip or a loop cycle ends,
+// and when the playhead jumps or stops, instead of hanging.
+
+use crate::{
+    midi::{MidiEvent, RecordedMidiEvent},
+    mixer::BlockContext,
+};
+
+/// Notes a track holds from its clips at once; further note-ons are dropped.
+/// Also the room past its limit a block keeps for releasing them.
+pub const MAX_SOUNDING: usize = 256;
+/// How far the beat carried over from the last block may drift from the
+/// engine's, e.g. after a tempo change, before the engine's is taken.
+const RESYNC_FRAMES: f64 = 64.0;
+/// Shorter loops play once, rather than wrap many times a block.
+const MIN_LOOP_LENGTH: f64 = 1.0 / 256.0;
+
+pub struct MidiClip {
+    /// Timeline beats.
+    pub start: f64,
+    pub end: f64,
+    /// Content beat at the start of the clip.
+    pub offset: f64,
+    /// Length of the content repeated through the clip, 0 plays it once.
+    pub loop_length: f64,
+    /// Content messages by beat, note-offs before note-ons on the same beat.
+    events: Vec<RecordedMidiEvent>,
+}
+
+impl MidiClip {
+    /// `events` are placed in beats from the start of the content; any order will do.
+    pub fn new(
+        start: f64,
+        end: f64,
+        offset: f64,
+        loop_length: f64,
+        mut events: Vec<RecordedMidiEvent>,
+    ) -> Self {
+        events.retain(|e| (0x80..0xf0).contains(&e.status));
+        events.sort_by(|a, b| {
+            a.beat
+                .total_cmp(&b.beat)
+                .then(is_note_on(a).cmp(&is_note_on(b)))
+        });
+        Self {
+            start,
+            end,
+            offset: offset.max(0.0),
+            loop_length: if loop_length >= MIN_LOOP_LENGTH {
+                loop_length
+            } else {
+                0.0
+            },
+            events,
+        }
+    }
+}
+
+fn is_note_on(e: &RecordedMidiEvent) -> bool {
+    e.status & 0xf0 == 0x90 && e.data2 > 0
+}
+
+fn is_note_off(e: &RecordedMidiEvent) -> bool {
+    e.status & 0xf0 == 0x80 || (e.status & 0xf0 == 0x90 && e.data2 == 0)
+}
+
+#[derive(Clone, Copy)]
+struct Sounding {
+    clip: usize,
+    channel: u8,
+    note: u8,
+}
+
+/// Plays the clips of one track.
+pub struct ClipPlayer {
+    sounding: Vec<Sounding>,
+    /// Frame and beat the last block ended at.
+    next: Option<(u64, f64)>,
+}
+
+impl Default for ClipPlayer {
+    fn default() -> Self {
+        Self {
+            sounding: Vec::with_capacity(MAX_SOUNDING),
+            next: None,
+        }
+    }
+}
+
+impl ClipPlayer {
+    /// The next block releases every note, as after a jump. Used when the clips change.
+    pub fn interrupt(&mut self) {
+        self.next = None;
+    }
+
+    /// Adds the events of the block starting at `start_frame` to `out`,
+    /// unsorted. Past `limit` events in `out` only note-offs are added, up to
+    /// `MAX_SOUNDING` more; notes that can't be released yet keep sounding
+    /// until a later block has room.
+    pub fn play(
+        &mut self,
+        clips: &[MidiClip],
+        start_frame: u64,
+        frames: usize,
+        ctx: &BlockContext,
+        out: &mut Vec<MidiEvent>,
+        limit: usize,
+    ) {
+        let resumed = self
+            .next
+            .filter(|&(frame, _)| frame == start_frame)
+            .map(|(_, beat)| beat);
+        if !ctx.is_playing || resumed.is_none() {
+            self.release(|_| true, 0, out, limit);
+        }
+        if !ctx.is_playing || frames == 0 || ctx.bpm <= 0.0 {
+            self.next = None;
+            return;
+        }
+
+        // one engine beat spans 4 quarter notes
+        let frames_per_beat = ctx.sample_rate as f64 * 240.0 / ctx.bpm as f64;
+        // a block that continues the last one starts at the exact beat that one
+        // ended at, so nothing on the boundary plays twice or not at all
+        let from = resumed
+            .filter(|beat| (beat - ctx.start_beat as f64).abs() * frames_per_beat < RESYNC_FRAMES)
+            .unwrap_or(ctx.start_beat as f64);
+        let to = from + frames as f64 / frames_per_beat;
+        self.next = Some((start_frame + frames as u64, to));
+        let last = frames as i64 - 1;
+        let frame_of =
+            |beat: f64| (((beat - from) * frames_per_beat).round() as i64).clamp(0, last) as u32;
+        // the block takes what rounds to one of its frames, so an event on the
+        // boundary between two blocks lands in exactly one of them
+        let half_frame = 0.5 / frames_per_beat;
+        let (from, to) = (from - half_frame, to - half_frame);
+
+        for (idx, clip) in clips.iter().enumerate() {
+            let begin = from.max(clip.start);
+            let end = to.min(clip.end);
+            if begin < end {
+                self.play_clip(idx, clip, begin, end, &frame_of, out, limit);
+            }
+            if from <= clip.end && clip.end < to {
+                self.release(|s| s.clip == idx, frame_of(clip.end), out, limit);
+            }
+        }
+    }
+
+    /// Plays the timeline beats `begin..end` of a clip, cycle by cycle.
+    #[allow(clippy::too_many_arguments)]
+    fn play_clip(
+        &mut self,
+        idx: usize,
+        clip: &MidiClip,
+        begin: f64,
+        end: f64,
+        frame_of: &impl Fn(f64) -> u32,
+        out: &mut Vec<MidiEvent>,
+        limit: usize,
+    ) {
+        // cycles are placed from where the first one would have started, so
+        // every block computes the same boundaries
+        let (first, length) = if clip.loop_length > 0.0 {
+            let length = clip.loop_length;
+            (clip.start - clip.offset.rem_euclid(length), length)
+        } else {
+            (clip.start - clip.offset, f64::INFINITY)
+        };
+        let mut cycle = if length.is_finite() {
+            ((begin - first) / length).floor()
+        } else {
+            0.0
+        };
+        loop {
+            // a clip that doesn't loop has its one cycle at `first`
+            let cycle_start = if length.is_finite() {
+                first + cycle * length
+            } else {
+                first
+            };
+            if cycle_start >= end {
+                break;
+            }
+            if cycle_start > clip.start && cycle_start >= begin {
+                // the last cycle's notes end where this one starts
+                self.release(|s| s.clip == idx, frame_of(cycle_start), out, limit);
+            }
+            let from = begin.max(cycle_start) - cycle_start;
+            let to = end.min(cycle_start + length) - cycle_start;
+            let skip = clip.events.partition_point(|e| (e.beat as f64) < from);
+            for e in &clip.events[skip..] {
+                if e.beat as f64 >= to {
+                    break;
+                }
+                self.emit(idx, e, frame_of(cycle_start + e.beat as f64), out, limit);
+            }
+            if !length.is_finite() {
+                break;
+            }
+            cycle += 1.0;
+        }
+    }
+
+    fn emit(
+        &mut self,
+        clip: usize,
+        e: &RecordedMidiEvent,
+        frame: u32,
+        out: &mut Vec<MidiEvent>,
+        limit: usize,
+    ) {
+        let channel = e.status & 0x0f;
+        let room = if is_note_off(e) {
+            limit.saturating_add(MAX_SOUNDING)
+        } else {
+            limit
+        };
+        if out.len() >= room {
+            return;
+        }
+        if is_note_on(e) {
+            if self.sounding.len() >= MAX_SOUNDING {
+                return;
+            }
+            self.sounding.push(Sounding {
+                clip,
+                channel,
+                note: e.data1,
+            });
+        } else if is_note_off(e) {
+            // notes the playhead started past have nothing to release
+            let Some(pos) = self
+                .sounding
+                .iter()
+                .position(|s| s.clip == clip && s.channel == channel && s.note == e.data1)
+            else {
+                return;
+            };
+            self.sounding.swap_remove(pos);
+        }
+        out.push(MidiEvent::new(frame, [e.status, e.data1, e.data2]));
+    }
+
+    fn release(
+        &mut self,
+        which: impl Fn(&Sounding) -> bool,
+        frame: u32,
+        out: &mut Vec<MidiEvent>,
+        limit: usize,
+    ) {
+        let room = limit.saturating_add(MAX_SOUNDING);
+        self.sounding.retain(|s| {
+            if !which(s) || out.len() >= room {
+                return true;
+            }
+            out.push(MidiEvent::new(frame, [0x80 | s.channel, s.note, 0]));
+            false
+        });
+    }
+}
+
+/// The messages clips play, laid out on one tick per frame at `ticks_per_beat`,
+/// the way a block would play them, loops and releases included.
+pub fn render(clips: &[MidiClip], ticks_per_beat: u32) -> Vec<MidiEvent> {
+    let end = clips.iter().map(|c| c.end).fold(0.0, f64::max);
+    // at 240 quarter notes a minute an engine beat lasts a second
+    let ctx = BlockContext {
+        sample_rate: ticks_per_beat as f32,
+        bpm: 240.0,
+        start_beat: 0.0,
+        beats_per_sample: 1.0 / ticks_per_beat as f32,
+        is_playing: true,
+    };
+    let frames = (end * ticks_per_beat as f64).ceil() as usize + 1;
+    let mut out = Vec::new();
+    ClipPlayer::default().play(clips, 0, frames, &ctx, &mut out, usize::MAX);
+    out.sort_by_key(|e| e.delta_frames);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One engine beat lasts 1000 frames.
+    const SAMPLE_RATE: f32 = 1000.0;
+    const BPM: f32 = 240.0;
+
+    fn ctx(start_frame: u64, is_playing: bool) -> BlockContext {
+        BlockContext {
+            sample_rate: SAMPLE_RATE,
+            bpm: BPM,
+            start_beat: start_frame as f32 / SAMPLE_RATE,
+            beats_per_sample: 1.0 / SAMPLE_RATE,
+            is_playing,
+        }
+    }
+
+    fn event(beat: f32, status: u8, note: u8) -> RecordedMidiEvent {
+        RecordedMidiEvent {
+            beat,
+            status,
+            data1: note,
+            data2: if status == 0x90 { 100 } else { 0 },
+        }
+    }
+
+    fn note(start: f32, end: f32, key: u8) -> [RecordedMidiEvent; 2] {
+        [event(start, 0x90, key), event(end, 0x80, key)]
+    }
+
+    /// Plays `frames` from `start_frame` in blocks of `block`, as (frame, message).
+    fn play(
+        player: &mut ClipPlayer,
+        clips: &[MidiClip],
+        start_frame: u64,
+        frames: u64,
+        block: u64,
+    ) -> Vec<(u64, [u8; 3])> {
+        let mut played = Vec::new();
+        let mut out = Vec::new();
+        let mut frame = start_frame;
+        while frame < start_frame + frames {
+            let len = block.min(start_frame + frames - frame);
+            out.clear();
+            player.play(
+                clips,
+                frame,
+                len as usize,
+                &ctx(frame, true),
+                &mut out,
+                usize::MAX,
+            );
+            played.extend(out.iter().map(|e| (frame + e.delta_frames as u64, e.data)));
+            frame += len;
+        }
+        played.sort_by_key(|&(frame, data)| (frame, data[0] & 0xf0 == 0x90));
+        played
+    }
+
+    #[test]
+    fn clip_end_releases_its_notes() {
+        // the note would last past the clip
+        let clips = [MidiClip::new(0.0, 1.0, 0.0, 0.0, note(0.0, 3.0, 60).into())];
+        let played = play(&mut ClipPlayer::default(), &clips, 0, 2000, 128);
+        assert_eq!(played, [(0, [0x90, 60, 100]), (1000, [0x80, 60, 0])]);
+    }
+
+    #[test]
+    fn loop_wrap_releases_and_replays() {
+        // held through the cycle, released where the next one starts
+        let clips = [MidiClip::new(
+            0.0,
+            1.5,
+            0.0,
+            0.5,
+            vec![event(0.25, 0x90, 60)],
+        )];
+        let played = play(&mut ClipPlayer::default(), &clips, 0, 2000, 100);
+        assert_eq!(
+            played,
+            [
+                (250, [0x90, 60, 100]),
+                (500, [0x80, 60, 0]),
+                (750, [0x90, 60, 100]),
+                (1000, [0x80, 60, 0]),
+                (1250, [0x90, 60, 100]),
+                (1500, [0x80, 60, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn jump_releases_sounding_notes() {
+        let clips = [MidiClip::new(0.0, 4.0, 0.0, 0.0, note(0.0, 1.0, 60).into())];
+        let mut player = ClipPlayer::default();
+        assert_eq!(
+            play(&mut player, &clips, 0, 256, 128),
+            [(0, [0x90, 60, 100])]
+        );
+
+        // the playhead moves to beat 2, past the note's own end
+        let played = play(&mut player, &clips, 2000, 128, 128);
+        assert_eq!(played, [(2000, [0x80, 60, 0])]);
+        assert!(play(&mut player, &clips, 2128, 1000, 128).is_empty());
+    }
+
+    #[test]
+    fn stop_releases_sounding_notes() {
+        let clips = [MidiClip::new(0.0, 4.0, 0.0, 0.0, note(0.0, 1.0, 60).into())];
+        let mut player = ClipPlayer::default();
+        play(&mut player, &clips, 0, 256, 128);
+
+        let mut out = Vec::new();
+        player.play(&clips, 256, 128, &ctx(256, false), &mut out, usize::MAX);
+        assert_eq!(out, [MidiEvent::new(0, [0x80, 60, 0])]);
+
+        // nothing is left to release
+        out.clear();
+        player.play(&clips, 256, 128, &ctx(256, false), &mut out, usize::MAX);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn block_boundaries_neither_repeat_nor_drop() {
+        // notes on every tenth of a beat, many of them on block boundaries
+        let events: Vec<RecordedMidiEvent> = (0..10)
+            .flat_map(|i| note(i as f32 * 0.1, i as f32 * 0.1 + 0.05, 40 + i as u8))
+            .collect();
+        let clips = [MidiClip::new(0.0, 3.0, 0.0, 1.0, events)];
+        let whole = play(&mut ClipPlayer::default(), &clips, 0, 3000, 3000);
+        assert_eq!(whole.len(), 3 * 10 * 2);
+
+        for block in [1, 50, 64, 100, 128, 333] {
+            let played = play(&mut ClipPlayer::default(), &clips, 0, 3000, block);
+            assert_eq!(played, whole, "blocks of {}", block);
+        }
+    }
+
+    #[test]
+    fn render_matches_playing_in_blocks() {
+        let clips = [MidiClip::new(
+            1.0,
+            3.0,
+            0.5,
+            1.0,
+            note(0.0, 0.75, 60).into(),
+        )];
+        let rendered: Vec<(u64, [u8; 3])> = render(&clips, 1000)
+            .iter()
+            .map(|e| (e.delta_frames as u64, e.data))
+            .collect();
+        let played = play(&mut ClipPlayer::default(), &clips, 0, 3001, 256);
+        assert_eq!(rendered, played);
+    }
+
+    #[test]
+    fn full_blocks_stay_in_their_room_and_release_later() {
+        // four notes at once, for a block with room for two
+        let events: Vec<RecordedMidiEvent> = (0..4).flat_map(|i| note(0.0, 0.5, 60 + i)).collect();
+        let clips = [MidiClip::new(0.0, 1.0, 0.0, 0.0, events)];
+        let mut player = ClipPlayer::default();
+        let mut out = Vec::new();
+        player.play(&clips, 0, 100, &ctx(0, true), &mut out, 2);
+        assert_eq!(out.len(), 2);
+        assert!(out.iter().all(|e| e.data[0] == 0x90));
+
+        // the block of the note-offs is already full but still releases them
+        out = vec![MidiEvent::new(0, [0xb0, 1, 0]); 2];
+        player.play(&clips, 500, 100, &ctx(500, true), &mut out, 2);
+        assert_eq!(out.len(), 4);
+        assert!(out[2..].iter().all(|e| e.data[0] == 0x80));
+
+        // with no room for a release, the note keeps sounding until there is
+        player.play(&clips, 0, 100, &ctx(0, true), &mut Vec::new(), usize::MAX);
+        let mut full = vec![MidiEvent::new(0, [0xb0, 1, 0]); 2 + MAX_SOUNDING];
+        player.play(&clips, 500, 100, &ctx(500, true), &mut full, 2);
+        assert_eq!(full.len(), 2 + MAX_SOUNDING);
+        out.clear();
+        player.play(&clips, 600, 100, &ctx(600, false), &mut out, 2);
+        assert_eq!(out.len(), 4);
+        assert!(out.iter().all(|e| e.data[0] == 0x80));
+    }
+}
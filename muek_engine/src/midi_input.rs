@@ -0,0 +1,497 @@
+// Live MIDI input. Each open port is read on a thread of its own, which stamps
+// every message with its arrival time and queues it. The audio callback then
+// places the queued messages in its block by those times, one block late, so
+// their spacing survives the block size. Clock and time code are queued apart
+// for the sync to chase. ALSA sequencer ports are read on Linux; virtual ports
+// are fed through `MidiInputs::send_virtual` and work everywhere.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::bail;
+
+use crate::{midi::MidiEvent, midi_sync::SyncMessage};
+
+/// Messages held between two blocks before the newest are dropped.
+pub const QUEUE_CAPACITY: usize = 1024;
+const VIRTUAL_PREFIX: &str = "virtual:";
+
+pub struct MidiInputPort {
+    /// Stable for as long as the device stays connected.
+    pub id: String,
+    pub name: String,
+}
+
+/// Messages read from the open ports, waiting for the next block.
+pub struct InputQueue {
+    events: Mutex<Vec<(Instant, [u8; 3])>>,
+    sync: Mutex<Vec<(Instant, SyncMessage)>>,
+}
+
+impl Default for InputQueue {
+    fn default() -> Self {
+        Self {
+            events: Mutex::new(Vec::with_capacity(QUEUE_CAPACITY)),
+            sync: Mutex::new(Vec::with_capacity(QUEUE_CAPACITY)),
+        }
+    }
+}
+
+impl InputQueue {
+    /// Keeps channel messages only; clock and time code go through `push_sync`.
+    pub fn push(&self, at: Instant, data: [u8; 3]) {
+        if !(0x80..0xf0).contains(&data[0]) {
+            return;
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() < QUEUE_CAPACITY {
+            events.push((at, data));
+        }
+    }
+
+    /// Keeps clock, transport and time code messages, dropping other system messages.
+    pub fn push_sync(&self, at: Instant, bytes: &[u8]) {
+        let Some(message) = SyncMessage::new(bytes) else {
+            return;
+        };
+        let mut sync = self.sync.lock().unwrap();
+        if sync.len() < QUEUE_CAPACITY {
+            sync.push((at, message));
+        }
+    }
+
+    /// Moves the queued sync messages into `out`, with their arrival times. Like
+    /// `take_block`, leaves them for the next block rather than wait.
+    pub fn take_sync(&self, out: &mut Vec<(Instant, SyncMessage)>) {
+        out.clear();
+        if let Ok(mut sync) = self.sync.try_lock() {
+            out.append(&mut sync);
+        }
+    }
+
+    /// Moves the queued messages into `out` for a block of `frames` starting
+    /// now. A message lands as far into the block as it arrived into the
+    /// block's length of time before now. Called on the audio thread, so it
+    /// leaves the queue for the next block rather than wait for a reader.
+    pub fn take_block(
+        &self,
+        now: Instant,
+        frames: usize,
+        sample_rate: f32,
+        out: &mut Vec<MidiEvent>,
+    ) {
+        out.clear();
+        let Ok(mut events) = self.events.try_lock() else {
+            return;
+        };
+        let block_secs = frames as f32 / sample_rate;
+        let last = frames.saturating_sub(1);
+        for (at, data) in events.drain(..) {
+            let late = now.saturating_duration_since(at).as_secs_f32();
+            let frame = ((block_secs - late).max(0.0) * sample_rate).round() as usize;
+            out.push(MidiEvent::new(frame.min(last) as u32, data));
+        }
+    }
+}
+
+enum OpenPort {
+    Virtual,
+    /// Reads until dropped.
+    #[cfg(target_os = "linux")]
+    Alsa {
+        _reader: alsa_seq::Reader,
+    },
+}
+
+/// The input ports and which of them are open.
+#[derive(Default)]
+pub struct MidiInputs {
+    queue: Arc<InputQueue>,
+    virtual_ports: Vec<String>,
+    open: HashMap<String, OpenPort>,
+}
+
+impl MidiInputs {
+    pub fn queue(&self) -> Arc<InputQueue> {
+        self.queue.clone()
+    }
+
+    pub fn ports(&self) -> Vec<MidiInputPort> {
+        let mut ports: Vec<MidiInputPort> = self
+            .virtual_ports
+            .iter()
+            .map(|name| MidiInputPort {
+                id: format!("{}{}", VIRTUAL_PREFIX, name),
+                name: name.clone(),
+            })
+            .collect();
+        #[cfg(target_os = "linux")]
+        match alsa_seq::ports() {
+            Ok(found) => ports.extend(found),
+            Err(e) => eprintln!("Failed to list ALSA MIDI ports: {}", e),
+        }
+        ports
+    }
+
+    pub fn is_open(&self, id: &str) -> bool {
+        self.open.contains_key(id)
+    }
+
+    pub fn open(&mut self, id: &str) -> anyhow::Result<()> {
+        if self.open.contains_key(id) {
+            return Ok(());
+        }
+        let port = if let Some(name) = id.strip_prefix(VIRTUAL_PREFIX) {
+            if !self.virtual_ports.iter().any(|p| p == name) {
+                bail!("no virtual MIDI port {}", name);
+            }
+            OpenPort::Virtual
+        } else {
+            self.open_device(id)?
+        };
+        self.open.insert(id.to_owned(), port);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_device(&self, id: &str) -> anyhow::Result<OpenPort> {
+        let reader = alsa_seq::Reader::open(id, self.queue.clone())?;
+        Ok(OpenPort::Alsa { _reader: reader })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open_device(&self, id: &str) -> anyhow::Result<OpenPort> {
+        bail!("no MIDI input port {}", id)
+    }
+
+    /// Stops reading the port. False if it wasn't open.
+    pub fn close(&mut self, id: &str) -> bool {
+        self.open.remove(id).is_some()
+    }
+
+    /// Adds a port the host feeds itself. Returns its id.
+    pub fn create_virtual(&mut self, name: &str) -> String {
+        if !self.virtual_ports.iter().any(|p| p == name) {
+            self.virtual_ports.push(name.to_owned());
+        }
+        format!("{}{}", VIRTUAL_PREFIX, name)
+    }
+
+    /// Delivers a message as if it arrived on a virtual port now. False unless the port is open.
+    pub fn send_virtual(&self, id: &str, data: [u8; 3]) -> bool {
+        if !id.starts_with(VIRTUAL_PREFIX) || !self.open.contains_key(id) {
+            return false;
+        }
+        match SyncMessage::from_short(data) {
+            Some(message) => self.queue.push_sync(Instant::now(), message.bytes()),
+            None => self.queue.push(Instant::now(), data),
+        }
+        true
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod alsa_seq {
+    use std::{
+        ffi::CString,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread::{self, JoinHandle},
+        time::Instant,
+    };
+
+    use alsa::{
+        Direction, PollDescriptors,
+        seq::{
+            Addr, ClientIter, MidiEvent, PortCap, PortInfo, PortIter, PortSubscribe, PortType, Seq,
+        },
+    };
+    use anyhow::{Context, anyhow};
+
+    use super::{InputQueue, MidiInputPort};
+
+    const PREFIX: &str = "alsa:";
+    /// How often a reader looks at its stop flag.
+    const POLL_MS: i32 = 100;
+
+    fn open_seq() -> anyhow::Result<Seq> {
+        let seq = Seq::open(None, Some(Direction::Capture), true)?;
+        seq.set_client_name(&CString::new("Muek")?)?;
+        Ok(seq)
+    }
+
+    /// Ports other clients can be read from, as `alsa:<client>:<port>`.
+    pub fn ports() -> anyhow::Result<Vec<MidiInputPort>> {
+        let seq = open_seq()?;
+        let own = seq.client_id()?;
+        let readable = PortCap::READ | PortCap::SUBS_READ;
+        let mut ports = Vec::new();
+        for client in ClientIter::new(&seq) {
+            let id = client.get_client();
+            // client 0 is the system timer and announcements
+            if id == 0 || id == own {
+                continue;
+            }
+            let client_name = client.get_name().unwrap_or("").to_owned();
+            for port in PortIter::new(&seq, id) {
+                if !port.get_capability().contains(readable) {
+                    continue;
+                }
+                ports.push(MidiInputPort {
+                    id: format!("{}{}:{}", PREFIX, id, port.get_port()),
+                    name: format!("{}: {}", client_name, port.get_name().unwrap_or("")),
+                });
+            }
+        }
+        Ok(ports)
+    }
+
+    /// A subscription to one port, read until dropped.
+    pub struct Reader {
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Reader {
+        pub fn open(id: &str, queue: Arc<InputQueue>) -> anyhow::Result<Self> {
+            let sender: Addr = id
+                .strip_prefix(PREFIX)
+                .and_then(|addr| addr.parse().ok())
+                .ok_or_else(|| anyhow!("no MIDI input port {}", id))?;
+
+            let seq = open_seq()?;
+            let mut info = PortInfo::empty()?;
+            info.set_capability(PortCap::WRITE | PortCap::SUBS_WRITE);
+            info.set_type(PortType::MIDI_GENERIC | PortType::APPLICATION);
+            info.set_name(&CString::new("Input")?);
+            seq.create_port(&info)?;
+            let subscription = PortSubscribe::empty()?;
+            subscription.set_sender(sender);
+            subscription.set_dest(Addr {
+                client: seq.client_id()?,
+                port: info.get_port(),
+            });
+            seq.subscribe_port(&subscription)
+                .with_context(|| format!("cannot connect to {}", id))?;
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let flag = stop.clone();
+            let thread = thread::Builder::new()
+                .name("muek-midi-input".to_owned())
+                .spawn(move || {
+                    if let Err(e) = read(&seq, &queue, &flag) {
+                        eprintln!("MIDI input stopped: {}", e);
+                    }
+                })?;
+            Ok(Self {
+                stop,
+                thread: Some(thread),
+            })
+        }
+    }
+
+    impl Drop for Reader {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+
+    fn read(seq: &Seq, queue: &InputQueue, stop: &AtomicBool) -> anyhow::Result<()> {
+        let decoder = MidiEvent::new(16)?;
+        decoder.enable_running_status(false);
+        let mut fds = (seq, Some(Direction::Capture)).get()?;
+        let mut input = seq.input();
+        let mut bytes = [0u8; 16];
+        while !stop.load(Ordering::Relaxed) {
+            if alsa::poll::poll(&mut fds, POLL_MS)? == 0 {
+                continue;
+            }
+            while input.event_input_pending(true)? > 0 {
+                let mut event = input.event_input()?;
+                let at = Instant::now();
+                let len = decoder.decode(&mut bytes, &mut event).unwrap_or(0);
+                if len > 0 && bytes[0] >= 0xf0 {
+                    queue.push_sync(at, &bytes[..len]);
+                } else if (1..=3).contains(&len) {
+                    let mut data = [0u8; 3];
+                    data[..len].copy_from_slice(&bytes[..len]);
+                    queue.push(at, data);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        midi::RecordedMidiEvent,
+        mixer::{BlockContext, Buses, Insert, Mixer},
+    };
+
+    const FRAMES: usize = 1024;
+    const SAMPLE_RATE: f32 = 48000.0;
+    const NOTE_ON: [u8; 3] = [0x90, 60, 100];
+
+    /// Keeps the MIDI its track hands it.
+    struct Listener(Arc<Mutex<Vec<MidiEvent>>>);
+
+    impl Insert for Listener {
+        fn id(&self) -> u32 {
+            1
+        }
+
+        fn io(&self) -> (usize, usize) {
+            (2, 2)
+        }
+
+        fn process(&mut self, _: &mut [Vec<f32>], buses: &mut Buses, _: &BlockContext) {
+            self.0.lock().unwrap().extend_from_slice(buses.midi_in);
+        }
+
+        fn latency(&self) -> usize {
+            0
+        }
+    }
+
+    fn ctx(is_playing: bool) -> BlockContext {
+        BlockContext {
+            sample_rate: SAMPLE_RATE,
+            bpm: 120.0,
+            start_beat: 1.0,
+            beats_per_sample: 120.0 / 60.0 / SAMPLE_RATE / 4.0,
+            is_playing,
+        }
+    }
+
+    fn open_virtual() -> (MidiInputs, String) {
+        let mut inputs = MidiInputs::default();
+        let id = inputs.create_virtual("keys");
+        inputs.open(&id).unwrap();
+        (inputs, id)
+    }
+
+    /// Runs one block of the queued input through `mixer`.
+    fn play_block(inputs: &MidiInputs, mixer: &mut Mixer, ctx: &BlockContext) {
+        let mut events = Vec::new();
+        inputs
+            .queue()
+            .take_block(Instant::now(), FRAMES, SAMPLE_RATE, &mut events);
+        mixer.push_input_midi(&events, ctx);
+        let mut output = vec![0.0; FRAMES * 2];
+        mixer.process(0, &mut output, ctx);
+    }
+
+    #[test]
+    fn closed_ports_take_nothing() {
+        let mut inputs = MidiInputs::default();
+        let id = inputs.create_virtual("keys");
+        assert!(!inputs.send_virtual(&id, NOTE_ON));
+
+        inputs.open(&id).unwrap();
+        assert!(inputs.send_virtual(&id, NOTE_ON));
+        assert!(inputs.close(&id));
+        assert!(!inputs.send_virtual(&id, NOTE_ON));
+    }
+
+    #[test]
+    fn messages_land_where_they_arrived_in_the_block() {
+        let (inputs, id) = open_virtual();
+        assert!(inputs.send_virtual(&id, NOTE_ON));
+
+        // a quarter of the block has passed since the message arrived
+        let block = Duration::from_secs_f32(FRAMES as f32 / SAMPLE_RATE);
+        let mut events = Vec::new();
+        inputs
+            .queue()
+            .take_block(Instant::now() + block / 4, FRAMES, SAMPLE_RATE, &mut events);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, NOTE_ON);
+        let frame = events[0].delta_frames as usize;
+        assert!((FRAMES / 2..=FRAMES * 3 / 4).contains(&frame), "{}", frame);
+
+        // taken once only
+        inputs
+            .queue()
+            .take_block(Instant::now(), FRAMES, SAMPLE_RATE, &mut events);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn clock_goes_to_the_sync_queue() {
+        let (inputs, id) = open_virtual();
+        assert!(inputs.send_virtual(&id, [0xf8, 0, 0]));
+
+        let mut events = Vec::new();
+        inputs
+            .queue()
+            .take_block(Instant::now(), FRAMES, SAMPLE_RATE, &mut events);
+        assert!(events.is_empty());
+
+        let mut sync = Vec::new();
+        inputs.queue().take_sync(&mut sync);
+        assert_eq!(sync.len(), 1);
+        assert_eq!(sync[0].1.bytes(), &[0xf8]);
+    }
+
+    #[test]
+    fn input_track_plays_the_port() {
+        let (inputs, id) = open_virtual();
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let mut mixer = Mixer::new(2);
+        mixer.add_insert("keys", Box::new(Listener(heard.clone())));
+        mixer.track_mut("other");
+        mixer.set_input_track(Some("keys"));
+
+        inputs.send_virtual(&id, NOTE_ON);
+        play_block(&inputs, &mut mixer, &ctx(false));
+
+        let heard = heard.lock().unwrap();
+        assert_eq!(heard.len(), 1);
+        assert_eq!(heard[0].data, NOTE_ON);
+    }
+
+    #[test]
+    fn armed_track_records_while_playing() {
+        let (inputs, id) = open_virtual();
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let mut mixer = Mixer::new(2);
+        mixer.add_insert("keys", Box::new(Listener(heard.clone())));
+        // the armed track wins over the input track
+        mixer.track_mut("input");
+        mixer.set_input_track(Some("input"));
+        mixer.arm_input_recording("keys", true);
+
+        inputs.send_virtual(&id, NOTE_ON);
+        play_block(&inputs, &mut mixer, &ctx(false));
+        assert_eq!(heard.lock().unwrap().len(), 1);
+        assert!(mixer.take_recorded_input("keys").is_empty());
+
+        inputs.send_virtual(&id, NOTE_ON);
+        let playing = ctx(true);
+        play_block(&inputs, &mut mixer, &playing);
+        assert_eq!(heard.lock().unwrap().len(), 2);
+
+        let recorded: Vec<RecordedMidiEvent> = mixer.take_recorded_input("keys");
+        assert_eq!(recorded.len(), 1);
+        let event = recorded[0];
+        assert_eq!((event.status, event.data1, event.data2), (0x90, 60, 100));
+        let block_beats = FRAMES as f32 * playing.beats_per_sample;
+        assert!(event.beat >= playing.start_beat);
+        assert!(event.beat < playing.start_beat + block_beats);
+        assert!(mixer.take_recorded_input("input").is_empty());
+    }
+}
@@ -0,0 +1,1315 @@
+// Syncing other MIDI devices to the transport, or the transport to them. The
+// audio callback turns each block into MIDI Beat Clock and MIDI Time Code
+// messages stamped one block late, like the input, and a thread sends them to
+// the chosen output at those times. Chasing goes the other way: clock or time
+// code from the inputs is turned back into where the master is, and each block
+// the engine's position and tempo are moved towards it, with a jump when it is
+// far off and small nudges otherwise.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use crate::{midi_input::QUEUE_CAPACITY, mixer::BlockContext, tempo_map::TempoMap};
+
+const CLOCK: u8 = 0xf8;
+const START: u8 = 0xfa;
+const CONTINUE: u8 = 0xfb;
+const STOP: u8 = 0xfc;
+const SONG_POSITION: u8 = 0xf2;
+const QUARTER_FRAME: u8 = 0xf1;
+
+/// Clocks per engine beat, 24 to the quarter note.
+const CLOCKS_PER_BEAT: f64 = 96.0;
+/// Clocks the chased tempo is measured over.
+const CLOCK_WINDOW: usize = 24;
+/// How fast the chased tempo follows each new measurement.
+const TEMPO_SMOOTHING: f64 = 0.1;
+/// Seconds without clock or time code before the master counts as stopped.
+const CLOCK_TIMEOUT: f64 = 0.5;
+const MTC_TIMEOUT: f64 = 0.2;
+/// Chased tempo changes smaller than this, in bpm, are ignored.
+const TEMPO_TOLERANCE: f64 = 0.05;
+/// Drift beyond this many seconds is corrected with a jump.
+const JUMP_SECONDS: f64 = 0.02;
+/// Share of the drift corrected each block, and at most which share of the block.
+const DRIFT_GAIN: f64 = 0.1;
+const MAX_NUDGE: f64 = 0.01;
+/// How often the sender looks for due messages.
+const SEND_INTERVAL: Duration = Duration::from_millis(1);
+/// Bytes kept for the host from virtual outputs before the oldest are dropped.
+const VIRTUAL_CAPACITY: usize = 1 << 16;
+const VIRTUAL_PREFIX: &str = "virtual:";
+
+/// A clock, transport or time code message, the longest being an MTC full frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyncMessage {
+    len: u8,
+    data: [u8; 10],
+}
+
+impl SyncMessage {
+    /// None unless the bytes are one of the messages sync is made of.
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let is_sync = matches!(
+            bytes,
+            [QUARTER_FRAME, _]
+                | [SONG_POSITION, _, _]
+                | [CLOCK | START | CONTINUE | STOP]
+                | [0xf0, 0x7f, _, 0x01, 0x01, _, _, _, _, 0xf7]
+        );
+        if !is_sync {
+            return None;
+        }
+        let mut data = [0; 10];
+        data[..bytes.len()].copy_from_slice(bytes);
+        Some(Self {
+            len: bytes.len() as u8,
+            data,
+        })
+    }
+
+    /// A message given as three bytes, as short messages are, of which the status tells how many count.
+    pub fn from_short(data: [u8; 3]) -> Option<Self> {
+        let len = match data[0] {
+            QUARTER_FRAME | 0xf3 => 2,
+            0xf8..=0xff => 1,
+            _ => 3,
+        };
+        Self::new(&data[..len])
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    fn song_position(sixteenths: u64) -> Self {
+        let sixteenths = sixteenths.min(0x3fff) as u16;
+        Self::new(&[
+            SONG_POSITION,
+            (sixteenths & 0x7f) as u8,
+            (sixteenths >> 7) as u8,
+        ])
+        .unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MtcRate {
+    Fps24,
+    Fps25,
+    Fps30Drop,
+    Fps30,
+}
+
+impl MtcRate {
+    /// The rate with the code MTC gives it, 0 to 3.
+    pub fn from_code(code: u8) -> Self {
+        match code & 3 {
+            0 => MtcRate::Fps24,
+            1 => MtcRate::Fps25,
+            2 => MtcRate::Fps30Drop,
+            _ => MtcRate::Fps30,
+        }
+    }
+
+    fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Frames in a second of real time.
+    fn fps(self) -> f64 {
+        match self {
+            MtcRate::Fps24 => 24.0,
+            MtcRate::Fps25 => 25.0,
+            MtcRate::Fps30Drop => 30000.0 / 1001.0,
+            MtcRate::Fps30 => 30.0,
+        }
+    }
+
+    /// Frames a second counts to.
+    fn nominal(self) -> u64 {
+        match self {
+            MtcRate::Fps24 => 24,
+            MtcRate::Fps25 => 25,
+            MtcRate::Fps30Drop | MtcRate::Fps30 => 30,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TimeCode {
+    hours: u8,
+    minutes: u8,
+    seconds: u8,
+    frames: u8,
+}
+
+impl TimeCode {
+    /// The time code of the frame so many frames in. Drop frame skips frame
+    /// numbers 0 and 1 of each minute but every tenth.
+    fn at_frame(frame: u64, rate: MtcRate) -> Self {
+        let mut frame = frame;
+        if rate == MtcRate::Fps30Drop {
+            // 17982 frames in ten minutes, 1798 in each minute after the first
+            let (tens, rest) = (frame / 17982, frame % 17982);
+            frame += 18 * tens
+                + if rest >= 2 {
+                    2 * ((rest - 2) / 1798)
+                } else {
+                    0
+                };
+        }
+        let fps = rate.nominal();
+        Self {
+            hours: (frame / fps / 3600 % 24) as u8,
+            minutes: (frame / fps / 60 % 60) as u8,
+            seconds: (frame / fps % 60) as u8,
+            frames: (frame % fps) as u8,
+        }
+    }
+
+    fn frame(&self, rate: MtcRate) -> u64 {
+        let minutes = self.hours as u64 * 60 + self.minutes as u64;
+        let frame = (minutes * 60 + self.seconds as u64) * rate.nominal() + self.frames as u64;
+        if rate == MtcRate::Fps30Drop {
+            frame.saturating_sub(2 * (minutes - minutes / 10))
+        } else {
+            frame
+        }
+    }
+
+    fn seconds(&self, rate: MtcRate) -> f64 {
+        self.frame(rate) as f64 / rate.fps()
+    }
+
+    fn full_frame(&self, rate: MtcRate) -> SyncMessage {
+        let hours = self.hours | rate.code() << 5;
+        let data = [
+            0xf0,
+            0x7f,
+            0x7f,
+            0x01,
+            0x01,
+            hours,
+            self.minutes,
+            self.seconds,
+            self.frames,
+            0xf7,
+        ];
+        SyncMessage::new(&data).unwrap()
+    }
+
+    /// Piece `piece` of the eight quarter frames that carry the time code.
+    fn quarter_frame(&self, piece: u8, rate: MtcRate) -> SyncMessage {
+        let value = match piece {
+            0 => self.frames & 0x0f,
+            1 => self.frames >> 4,
+            2 => self.seconds & 0x0f,
+            3 => self.seconds >> 4,
+            4 => self.minutes & 0x0f,
+            5 => self.minutes >> 4,
+            6 => self.hours & 0x0f,
+            _ => self.hours >> 4 | rate.code() << 1,
+        };
+        SyncMessage::new(&[QUARTER_FRAME, piece << 4 | value]).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChaseSource {
+    Off,
+    Clock,
+    TimeCode,
+}
+
+impl ChaseSource {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => ChaseSource::Clock,
+            2 => ChaseSource::TimeCode,
+            _ => ChaseSource::Off,
+        }
+    }
+}
+
+/// Settings and outgoing messages, shared with the audio callback.
+pub struct SyncLink {
+    has_output: AtomicBool,
+    clock: AtomicBool,
+    /// The rate code plus 1, 0 sends no time code.
+    time_code: AtomicU8,
+    chase: AtomicU8,
+    outgoing: Mutex<Vec<(Instant, SyncMessage)>>,
+}
+
+impl Default for SyncLink {
+    fn default() -> Self {
+        Self {
+            has_output: AtomicBool::new(false),
+            clock: AtomicBool::new(false),
+            time_code: AtomicU8::new(0),
+            chase: AtomicU8::new(ChaseSource::Off as u8),
+            outgoing: Mutex::new(Vec::with_capacity(QUEUE_CAPACITY)),
+        }
+    }
+}
+
+impl SyncLink {
+    pub fn set_clock(&self, on: bool) {
+        self.clock.store(on, Ordering::Relaxed);
+    }
+
+    pub fn set_time_code(&self, rate: Option<MtcRate>) {
+        let code = rate.map_or(0, |rate| rate.code() + 1);
+        self.time_code.store(code, Ordering::Relaxed);
+    }
+
+    fn time_code(&self) -> Option<MtcRate> {
+        match self.time_code.load(Ordering::Relaxed) {
+            0 => None,
+            code => Some(MtcRate::from_code(code - 1)),
+        }
+    }
+
+    pub fn set_chase(&self, source: ChaseSource) {
+        self.chase.store(source as u8, Ordering::Relaxed);
+    }
+
+    pub fn chase(&self) -> ChaseSource {
+        ChaseSource::from_code(self.chase.load(Ordering::Relaxed))
+    }
+}
+
+/// Sends the transport as clock and time code, block by block.
+pub struct Transmitter {
+    /// Frame the last block ended at, while playing.
+    next_frame: Option<u64>,
+    /// Clock due next, counted from the start of the song.
+    next_clock: u64,
+    /// Quarter frame due next, counted from the start of the song.
+    next_quarter: u64,
+    /// Stopped frame last announced with a song position or full frame.
+    announced: Option<u64>,
+    /// Messages waiting for the sender to take them.
+    pending: Vec<(Instant, SyncMessage)>,
+}
+
+impl Default for Transmitter {
+    fn default() -> Self {
+        Self {
+            next_frame: None,
+            next_clock: 0,
+            next_quarter: 0,
+            announced: None,
+            pending: Vec::with_capacity(QUEUE_CAPACITY),
+        }
+    }
+}
+
+impl Transmitter {
+    /// Queues the messages of the block starting at `start_frame`, computed at `started`.
+    pub fn block(
+        &mut self,
+        link: &SyncLink,
+        started: Instant,
+        start_frame: u64,
+        frames: usize,
+        ctx: &BlockContext,
+    ) {
+        let clock = link.clock.load(Ordering::Relaxed);
+        let time_code = link.time_code();
+        if !link.has_output.load(Ordering::Relaxed) || (!clock && time_code.is_none()) {
+            self.next_frame = None;
+            self.announced = None;
+            self.pending.clear();
+            return;
+        }
+
+        let sample_rate = ctx.sample_rate as f64;
+        let pending = &mut self.pending;
+        let mut send = |frame: f64, message: SyncMessage| {
+            // while the sender is stuck the oldest are dropped, they are late anyway
+            if pending.len() >= QUEUE_CAPACITY {
+                pending.remove(0);
+            }
+            let delay = (frames as f64 + frame.max(0.0)) / sample_rate;
+            pending.push((started + Duration::from_secs_f64(delay), message));
+        };
+        let start_seconds = start_frame as f64 / sample_rate;
+        let start_beat = ctx.start_beat as f64;
+
+        if !ctx.is_playing || ctx.bpm <= 0.0 || frames == 0 {
+            if self.next_frame.take().is_some() {
+                send(0.0, SyncMessage::new(&[STOP]).unwrap());
+            }
+            if self.announced != Some(start_frame) {
+                self.announced = Some(start_frame);
+                if clock {
+                    let sixteenths = (start_beat * 16.0).round().max(0.0) as u64;
+                    send(0.0, SyncMessage::song_position(sixteenths));
+                }
+                if let Some(rate) = time_code {
+                    let frame = (start_seconds * rate.fps()) as u64;
+                    send(0.0, TimeCode::at_frame(frame, rate).full_frame(rate));
+                }
+            }
+            self.flush(link);
+            return;
+        }
+        self.announced = None;
+
+        let frames_per_beat = sample_rate * 240.0 / ctx.bpm as f64;
+        let end_beat = start_beat + frames as f64 / frames_per_beat;
+        let end_seconds = start_seconds + frames as f64 / sample_rate;
+        if self.next_frame != Some(start_frame) {
+            // a jump while playing stops the devices, moves them and continues
+            if self.next_frame.is_some() {
+                send(0.0, SyncMessage::new(&[STOP]).unwrap());
+            }
+            if clock {
+                // the devices pick up on the next sixteenth note
+                let sixteenths = ((start_beat * 16.0 - 1e-6).ceil().max(0.0) as u64).min(0x3fff);
+                if sixteenths == 0 {
+                    send(0.0, SyncMessage::new(&[START]).unwrap());
+                } else {
+                    send(0.0, SyncMessage::song_position(sixteenths));
+                    send(0.0, SyncMessage::new(&[CONTINUE]).unwrap());
+                }
+                self.next_clock = sixteenths * 6;
+            }
+            if let Some(rate) = time_code {
+                let frame = (start_seconds * rate.fps()) as u64;
+                send(0.0, TimeCode::at_frame(frame, rate).full_frame(rate));
+                self.next_quarter = (start_seconds * rate.fps() * 4.0 - 1e-6).ceil() as u64;
+            }
+        }
+        self.next_frame = Some(start_frame + frames as u64);
+
+        if clock {
+            // past the song positions a device can be sent to, clocks resume where the transport is
+            self.next_clock = self
+                .next_clock
+                .max((start_beat * CLOCKS_PER_BEAT).floor() as u64);
+            loop {
+                let beat = self.next_clock as f64 / CLOCKS_PER_BEAT;
+                if beat >= end_beat {
+                    break;
+                }
+                send(
+                    (beat - start_beat) * frames_per_beat,
+                    SyncMessage::new(&[CLOCK]).unwrap(),
+                );
+                self.next_clock += 1;
+            }
+        }
+        if let Some(rate) = time_code {
+            let quarters_per_second = rate.fps() * 4.0;
+            loop {
+                let seconds = self.next_quarter as f64 / quarters_per_second;
+                if seconds >= end_seconds {
+                    break;
+                }
+                // the eight pieces carry the time code of the frame the first one started
+                let piece = (self.next_quarter % 8) as u8;
+                let time = TimeCode::at_frame((self.next_quarter - piece as u64) / 4, rate);
+                send(
+                    (seconds - start_seconds) * sample_rate,
+                    time.quarter_frame(piece, rate),
+                );
+                self.next_quarter += 1;
+            }
+        }
+        self.flush(link);
+    }
+
+    /// Hands as many pending messages as fit to the sender, unless it is taking
+    /// them right now.
+    fn flush(&mut self, link: &SyncLink) {
+        if let Ok(mut outgoing) = link.outgoing.try_lock() {
+            let room = QUEUE_CAPACITY.saturating_sub(outgoing.len());
+            outgoing.extend(self.pending.drain(..room.min(self.pending.len())));
+        }
+    }
+}
+
+enum Position {
+    Beat(f64),
+    Seconds(f64),
+}
+
+/// Where the master is now, as far as its messages tell.
+struct Master {
+    playing: bool,
+    /// While stopped, only given once after the master moved.
+    position: Option<Position>,
+    bpm: Option<f64>,
+}
+
+struct ClockFollower {
+    /// Clocks into the song at the last clock.
+    clocks: u64,
+    /// Start or continue came, the next clock starts playing.
+    armed: bool,
+    playing: bool,
+    /// The stopped position changed and is yet to be followed.
+    moved: bool,
+    /// Arrival of the last clocks, the oldest at `next`.
+    window: [Option<Instant>; CLOCK_WINDOW],
+    next: usize,
+    /// Smoothed seconds between clocks.
+    interval: f64,
+}
+
+impl Default for ClockFollower {
+    fn default() -> Self {
+        Self {
+            clocks: 0,
+            armed: false,
+            playing: false,
+            moved: false,
+            window: [None; CLOCK_WINDOW],
+            next: 0,
+            interval: 0.0,
+        }
+    }
+}
+
+impl ClockFollower {
+    fn last(&self) -> Option<Instant> {
+        self.window[(self.next + CLOCK_WINDOW - 1) % CLOCK_WINDOW]
+    }
+
+    fn clock(&mut self, at: Instant) {
+        // the tempo is measured over a quarter note, which evens out jitter
+        if let Some(oldest) = self.window[self.next] {
+            let interval = at.saturating_duration_since(oldest).as_secs_f64() / CLOCK_WINDOW as f64;
+            if interval < CLOCK_TIMEOUT {
+                self.interval = if self.interval > 0.0 {
+                    self.interval + (interval - self.interval) * TEMPO_SMOOTHING
+                } else {
+                    interval
+                };
+            }
+        }
+        if self
+            .last()
+            .is_some_and(|last| at.saturating_duration_since(last).as_secs_f64() >= CLOCK_TIMEOUT)
+        {
+            self.window = [None; CLOCK_WINDOW];
+            self.next = 0;
+        }
+        self.window[self.next] = Some(at);
+        self.next = (self.next + 1) % CLOCK_WINDOW;
+
+        if self.armed {
+            self.armed = false;
+            self.playing = true;
+        } else if self.playing {
+            self.clocks += 1;
+        }
+    }
+
+    fn start(&mut self) {
+        self.clocks = 0;
+        self.armed = true;
+        self.playing = false;
+        self.moved = true;
+    }
+
+    fn resume(&mut self) {
+        self.armed = true;
+        self.playing = false;
+    }
+
+    fn stop(&mut self) {
+        self.armed = false;
+        self.playing = false;
+        self.moved = true;
+    }
+
+    fn song_position(&mut self, sixteenths: u64) {
+        if !self.playing {
+            self.clocks = sixteenths * 6;
+            self.moved = true;
+        }
+    }
+
+    fn master(&mut self, now: Instant) -> Master {
+        let since = self
+            .last()
+            .map(|last| now.saturating_duration_since(last).as_secs_f64());
+        let alive = since.is_some_and(|since| since < CLOCK_TIMEOUT);
+        if self.playing && !alive {
+            self.stop();
+        }
+        let bpm = (alive && self.interval > 0.0).then(|| 60.0 / (24.0 * self.interval));
+        if self.playing {
+            // never past the next clock before it came
+            let since = since.unwrap_or(0.0).min(self.interval);
+            let fraction = if self.interval > 0.0 {
+                since / self.interval
+            } else {
+                0.0
+            };
+            let beat = (self.clocks as f64 + fraction) / CLOCKS_PER_BEAT;
+            return Master {
+                playing: true,
+                position: Some(Position::Beat(beat)),
+                bpm,
+            };
+        }
+        let moved = std::mem::take(&mut self.moved);
+        Master {
+            playing: false,
+            position: moved.then(|| Position::Beat(self.clocks as f64 / CLOCKS_PER_BEAT)),
+            bpm,
+        }
+    }
+}
+
+#[derive(Default)]
+struct TimeCodeFollower {
+    nibbles: [u8; 8],
+    /// Piece expected next, 8 after one came out of order.
+    next_piece: u8,
+    last_quarter: Option<Instant>,
+    /// Seconds into the song at an instant.
+    position: Option<(Instant, f64)>,
+    playing: bool,
+    /// Stopped position yet to be followed.
+    located: Option<f64>,
+}
+
+impl TimeCodeFollower {
+    fn quarter_frame(&mut self, at: Instant, data: u8) {
+        self.last_quarter = Some(at);
+        let piece = data >> 4;
+        if piece == 0 {
+            self.next_piece = 0;
+        }
+        if piece != self.next_piece {
+            self.next_piece = 8;
+            return;
+        }
+        self.nibbles[piece as usize] = data & 0x0f;
+        self.next_piece += 1;
+        if piece < 7 {
+            return;
+        }
+
+        let n = self.nibbles;
+        let rate = MtcRate::from_code(n[7] >> 1);
+        let time = TimeCode {
+            hours: n[6] | (n[7] & 1) << 4,
+            minutes: (n[4] | n[5] << 4) & 0x3f,
+            seconds: (n[2] | n[3] << 4) & 0x3f,
+            frames: (n[0] | n[1] << 4) & 0x1f,
+        };
+        // the last piece comes 7 quarter frames after the frame they describe
+        let seconds = time.seconds(rate) + 1.75 / rate.fps();
+        self.position = Some((at, seconds));
+        self.playing = true;
+    }
+
+    fn full_frame(&mut self, at: Instant, hours: u8, minutes: u8, seconds: u8, frames: u8) {
+        let rate = MtcRate::from_code(hours >> 5);
+        let time = TimeCode {
+            hours: hours & 0x1f,
+            minutes,
+            seconds,
+            frames,
+        };
+        let seconds = time.seconds(rate);
+        self.position = Some((at, seconds));
+        self.located = Some(seconds);
+    }
+
+    fn master(&mut self, now: Instant) -> Master {
+        let alive = self
+            .last_quarter
+            .is_some_and(|last| now.saturating_duration_since(last).as_secs_f64() < MTC_TIMEOUT);
+        if self.playing
+            && alive
+            && let Some((at, seconds)) = self.position
+        {
+            let since = now
+                .saturating_duration_since(at)
+                .as_secs_f64()
+                .min(MTC_TIMEOUT);
+            return Master {
+                playing: true,
+                position: Some(Position::Seconds(seconds + since)),
+                bpm: None,
+            };
+        }
+        if self.playing {
+            // stopped where the time code did
+            self.playing = false;
+            self.next_piece = 8;
+            self.located = self.position.map(|(_, seconds)| seconds);
+        }
+        Master {
+            playing: false,
+            position: self.located.take().map(Position::Seconds),
+            bpm: None,
+        }
+    }
+}
+
+/// Follows clock and time code from the inputs.
+#[derive(Default)]
+pub struct Chase {
+    clock: ClockFollower,
+    time_code: TimeCodeFollower,
+}
+
+impl Chase {
+    /// Takes in a message that arrived at `at`.
+    pub fn receive(&mut self, at: Instant, message: &SyncMessage) {
+        match *message.bytes() {
+            [CLOCK] => self.clock.clock(at),
+            [START] => self.clock.start(),
+            [CONTINUE] => self.clock.resume(),
+            [STOP] => self.clock.stop(),
+            [SONG_POSITION, lsb, msb] => self.clock.song_position((msb as u64) << 7 | lsb as u64),
+            [QUARTER_FRAME, data] => self.time_code.quarter_frame(at, data),
+            [
+                0xf0,
+                0x7f,
+                _,
+                0x01,
+                0x01,
+                hours,
+                minutes,
+                seconds,
+                frames,
+                0xf7,
+            ] => self
+                .time_code
+                .full_frame(at, hours, minutes, seconds, frames),
+            _ => {}
+        }
+    }
+
+    /// What the transport should do for a block of `frames` about to start at
+    /// `pos_idx`. Chasing clock also takes over the tempo, as a single tempo
+    /// for the song.
+    #[allow(clippy::too_many_arguments)]
+    pub fn follow(
+        &mut self,
+        source: ChaseSource,
+        now: Instant,
+        is_playing: bool,
+        pos_idx: u64,
+        frames: usize,
+        tempo_map: &TempoMap,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Follow {
+        let master = match source {
+            ChaseSource::Off => return Follow::default(),
+            ChaseSource::Clock => self.clock.master(now),
+            ChaseSource::TimeCode => self.time_code.master(now),
+        };
+        let channels = channels.max(1) as u64;
+        let sample_rate_f = sample_rate as f64;
+
+        let bpm = master.bpm.filter(|bpm| {
+            tempo_map.tempos().len() > 1 || (tempo_map.bpm_at(0.0) - bpm).abs() > TEMPO_TOLERANCE
+        });
+        // until the new tempo is in, the position is chased on the old one
+        let target = master.position.map(|position| match position {
+            Position::Beat(beat) => tempo_map.seconds_at(beat) * sample_rate_f,
+            Position::Seconds(seconds) => seconds * sample_rate_f,
+        });
+
+        let frame = (pos_idx / channels) as f64;
+        let (playing, frame) = match (master.playing, target) {
+            (true, Some(target)) => {
+                // the block is heard a block from now, where the master will be by then
+                let target = target + frames as f64;
+                let frame = match is_playing {
+                    true => correct_drift(frame, target, frames, sample_rate_f),
+                    false => target,
+                };
+                (true, frame)
+            }
+            (true, None) => (is_playing, frame),
+            (false, target) => (false, target.unwrap_or(frame)),
+        };
+        let pos = frame.round().max(0.0) as u64 * channels;
+        Follow {
+            bpm,
+            transport: (playing != is_playing || pos != pos_idx).then_some((playing, pos)),
+        }
+    }
+}
+
+/// What a sync source wants of the transport for the next block.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Follow {
+    /// Single tempo the song should take. Changing the tempo map moves the
+    /// position and the audio clips, so it's left to the engine's housekeeper.
+    pub bpm: Option<f64>,
+    /// (playing, interleaved sample index), if it should change.
+    pub transport: Option<(bool, u64)>,
+}
+
+/// The frame a playing transport at `frame` takes for its next block of
+/// `frames` when it should be at `target`: a jump when far off, else a nudge.
+///
+/// A nudge skips or repeats frames of the song outright rather than
+/// resampling, which can click in sustained audio clips. Drift under a frame
+/// is left alone and a nudge moves at most `MAX_NUDGE` of a block, so against
+/// a steady master they stay rare and small.
+pub fn correct_drift(frame: f64, target: f64, frames: usize, sample_rate: f64) -> f64 {
+    let drift = target - frame;
+    if drift.abs() > JUMP_SECONDS * sample_rate {
+        target
+    } else if drift.abs() >= 1.0 {
+        let most = frames as f64 * MAX_NUDGE;
+        frame + (drift * DRIFT_GAIN).clamp(-most, most)
+    } else {
+        frame
+    }
+}
+
+pub struct MidiOutputPort {
+    pub id: String,
+    pub name: String,
+}
+
+/// Sends due messages until dropped.
+struct Sender {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sender {
+    /// `connect` makes the function that sends a message, on the sending thread.
+    fn spawn<S>(
+        link: Arc<SyncLink>,
+        connect: impl FnOnce() -> anyhow::Result<S> + Send + 'static,
+    ) -> anyhow::Result<Self>
+    where
+        S: FnMut(&[u8]) -> anyhow::Result<()>,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let thread = thread::Builder::new()
+            .name("muek-midi-sync".to_owned())
+            .spawn(move || {
+                let mut send = match connect() {
+                    Ok(send) => send,
+                    Err(e) => {
+                        eprintln!("MIDI sync output stopped: {}", e);
+                        return;
+                    }
+                };
+                let mut due: VecDeque<(Instant, SyncMessage)> =
+                    VecDeque::with_capacity(QUEUE_CAPACITY);
+                while !flag.load(Ordering::Relaxed) {
+                    due.extend(link.outgoing.lock().unwrap().drain(..));
+                    let now = Instant::now();
+                    while let Some((at, message)) = due.front()
+                        && *at <= now
+                    {
+                        if let Err(e) = send(message.bytes()) {
+                            eprintln!("MIDI sync output stopped: {}", e);
+                            return;
+                        }
+                        due.pop_front();
+                    }
+                    thread::sleep(SEND_INTERVAL);
+                }
+            })?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// The output sync is sent to and what is sent or chased.
+#[derive(Default)]
+pub struct MidiSync {
+    link: Arc<SyncLink>,
+    virtual_ports: Vec<String>,
+    /// Bytes sent to virtual outputs, waiting for the host.
+    virtual_sent: Arc<Mutex<Vec<u8>>>,
+    output: Option<(String, Sender)>,
+}
+
+impl MidiSync {
+    pub fn link(&self) -> Arc<SyncLink> {
+        self.link.clone()
+    }
+
+    pub fn ports(&self) -> Vec<MidiOutputPort> {
+        let mut ports: Vec<MidiOutputPort> = self
+            .virtual_ports
+            .iter()
+            .map(|name| MidiOutputPort {
+                id: format!("{}{}", VIRTUAL_PREFIX, name),
+                name: name.clone(),
+            })
+            .collect();
+        #[cfg(target_os = "linux")]
+        match alsa_seq::ports() {
+            Ok(found) => ports.extend(found),
+            Err(e) => eprintln!("Failed to list ALSA MIDI ports: {}", e),
+        }
+        ports
+    }
+
+    pub fn output(&self) -> Option<&str> {
+        self.output.as_ref().map(|(id, _)| id.as_str())
+    }
+
+    /// Sends sync to the port, or nowhere.
+    pub fn set_output(&mut self, id: Option<&str>) -> anyhow::Result<()> {
+        self.link.has_output.store(false, Ordering::Relaxed);
+        self.output = None;
+        self.link.outgoing.lock().unwrap().clear();
+        let Some(id) = id else {
+            return Ok(());
+        };
+
+        let sender = if let Some(name) = id.strip_prefix(VIRTUAL_PREFIX) {
+            if !self.virtual_ports.iter().any(|p| p == name) {
+                bail!("no virtual MIDI port {}", name);
+            }
+            let sent = self.virtual_sent.clone();
+            Sender::spawn(self.link.clone(), move || {
+                Ok(move |bytes: &[u8]| {
+                    let mut sent = sent.lock().unwrap();
+                    if sent.len() + bytes.len() <= VIRTUAL_CAPACITY {
+                        sent.extend_from_slice(bytes);
+                    }
+                    Ok(())
+                })
+            })?
+        } else {
+            self.open_device(id)?
+        };
+        self.output = Some((id.to_owned(), sender));
+        self.link.has_output.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_device(&self, id: &str) -> anyhow::Result<Sender> {
+        let output = alsa_seq::Output::open(id)?;
+        Sender::spawn(self.link.clone(), move || output.sender())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open_device(&self, id: &str) -> anyhow::Result<Sender> {
+        bail!("no MIDI output port {}", id)
+    }
+
+    /// Adds a port whose messages the host takes with `take_virtual_sent`. Returns its id.
+    pub fn create_virtual(&mut self, name: &str) -> String {
+        if !self.virtual_ports.iter().any(|p| p == name) {
+            self.virtual_ports.push(name.to_owned());
+        }
+        format!("{}{}", VIRTUAL_PREFIX, name)
+    }
+
+    /// The bytes sent to virtual outputs since the last call, in order.
+    pub fn take_virtual_sent(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.virtual_sent.lock().unwrap())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod alsa_seq {
+    use std::ffi::CString;
+
+    use alsa::{
+        Direction,
+        seq::{
+            Addr, ClientIter, MidiEvent, PortCap, PortInfo, PortIter, PortSubscribe, PortType, Seq,
+        },
+    };
+    use anyhow::{Context, anyhow};
+
+    use super::MidiOutputPort;
+
+    const PREFIX: &str = "alsa:";
+
+    fn open_seq() -> anyhow::Result<Seq> {
+        let seq = Seq::open(None, Some(Direction::Playback), false)?;
+        seq.set_client_name(&CString::new("Muek")?)?;
+        Ok(seq)
+    }
+
+    /// Ports other clients can be written to, as `alsa:<client>:<port>`.
+    pub fn ports() -> anyhow::Result<Vec<MidiOutputPort>> {
+        let seq = open_seq()?;
+        let own = seq.client_id()?;
+        let writable = PortCap::WRITE | PortCap::SUBS_WRITE;
+        let mut ports = Vec::new();
+        for client in ClientIter::new(&seq) {
+            let id = client.get_client();
+            // client 0 is the system timer and announcements
+            if id == 0 || id == own {
+                continue;
+            }
+            let client_name = client.get_name().unwrap_or("").to_owned();
+            for port in PortIter::new(&seq, id) {
+                if !port.get_capability().contains(writable) {
+                    continue;
+                }
+                ports.push(MidiOutputPort {
+                    id: format!("{}{}:{}", PREFIX, id, port.get_port()),
+                    name: format!("{}: {}", client_name, port.get_name().unwrap_or("")),
+                });
+            }
+        }
+        Ok(ports)
+    }
+
+    /// A port of ours connected to the chosen one.
+    pub struct Output {
+        seq: Seq,
+        port: i32,
+    }
+
+    impl Output {
+        pub fn open(id: &str) -> anyhow::Result<Self> {
+            let dest: Addr = id
+                .strip_prefix(PREFIX)
+                .and_then(|addr| addr.parse().ok())
+                .ok_or_else(|| anyhow!("no MIDI output port {}", id))?;
+
+            let seq = open_seq()?;
+            let mut info = PortInfo::empty()?;
+            info.set_capability(PortCap::READ | PortCap::SUBS_READ);
+            info.set_type(PortType::MIDI_GENERIC | PortType::APPLICATION);
+            info.set_name(&CString::new("Sync")?);
+            seq.create_port(&info)?;
+            let port = info.get_port();
+            let subscription = PortSubscribe::empty()?;
+            subscription.set_sender(Addr {
+                client: seq.client_id()?,
+                port,
+            });
+            subscription.set_dest(dest);
+            seq.subscribe_port(&subscription)
+                .with_context(|| format!("cannot connect to {}", id))?;
+            Ok(Self { seq, port })
+        }
+
+        /// Sends raw messages right away. Made on the sending thread, which the encoder stays on.
+        pub fn sender(self) -> anyhow::Result<impl FnMut(&[u8]) -> anyhow::Result<()>> {
+            let mut encoder = MidiEvent::new(16)?;
+            Ok(move |bytes: &[u8]| {
+                let mut rest = bytes;
+                while !rest.is_empty() {
+                    let (used, event) = encoder.encode(rest)?;
+                    if let Some(mut event) = event {
+                        event.set_source(self.port);
+                        event.set_subs();
+                        event.set_direct();
+                        self.seq.event_output_direct(&mut event)?;
+                    }
+                    if used == 0 {
+                        break;
+                    }
+                    rest = &rest[used..];
+                }
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn link(clock: bool, time_code: Option<MtcRate>) -> SyncLink {
+        let link = SyncLink::default();
+        link.has_output.store(true, Ordering::Relaxed);
+        link.set_clock(clock);
+        link.set_time_code(time_code);
+        link
+    }
+
+    /// A block at `frame` on a single tempo, where a beat is a whole note.
+    fn ctx(frame: u64, bpm: f32, is_playing: bool) -> BlockContext {
+        let beats_per_sample = bpm / 240.0 / SAMPLE_RATE as f32;
+        BlockContext {
+            sample_rate: SAMPLE_RATE as f32,
+            bpm,
+            start_beat: (frame as f64 * bpm as f64 / 240.0 / SAMPLE_RATE) as f32,
+            beats_per_sample,
+            is_playing,
+        }
+    }
+
+    /// Everything queued for the sender, with the seconds after `base` it's due at.
+    fn sent(link: &SyncLink, base: Instant) -> Vec<(f64, Vec<u8>)> {
+        link.outgoing
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|(at, message)| {
+                let due = at.duration_since(base).as_secs_f64();
+                (due, message.bytes().to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sends_24_clocks_a_quarter_note_at_the_tempo() {
+        for bpm in [120.0, 93.5] {
+            let link = link(true, None);
+            let mut transmitter = Transmitter::default();
+            let base = Instant::now();
+            let mut messages = Vec::new();
+            for block in 0..100u64 {
+                let started = base + Duration::from_millis(10 * block);
+                transmitter.block(
+                    &link,
+                    started,
+                    block * 480,
+                    480,
+                    &ctx(block * 480, bpm, true),
+                );
+                messages.extend(sent(&link, base));
+            }
+
+            assert_eq!(messages[0].1, [START]);
+            let clocks: Vec<f64> = messages[1..].iter().map(|(at, _)| *at).collect();
+            assert!(messages[1..].iter().all(|(_, bytes)| bytes == &[CLOCK]));
+            // a second of transport, heard a block late
+            let interval = 60.0 / (bpm as f64 * 24.0);
+            assert!((clocks.len() as f64 - 1.0 / interval).abs() <= 1.0);
+            for (k, at) in clocks.iter().enumerate() {
+                assert!(
+                    (at - 0.01 - k as f64 * interval).abs() < 1e-6,
+                    "{} {}",
+                    bpm,
+                    k
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sends_the_song_position_on_locate() {
+        let link = link(true, None);
+        let mut transmitter = Transmitter::default();
+        let now = Instant::now();
+        let half = (SAMPLE_RATE * 240.0 / 120.0 / 2.0) as u64;
+
+        // stopped on the second half note: eight sixteenths in
+        transmitter.block(&link, now, half, 480, &ctx(half, 120.0, false));
+        transmitter.block(&link, now, half, 480, &ctx(half, 120.0, false));
+        let messages: Vec<Vec<u8>> = sent(&link, now).into_iter().map(|m| m.1).collect();
+        assert_eq!(messages, [vec![SONG_POSITION, 8, 0]]);
+
+        // playing from there continues on it, a jump stops, moves and continues
+        transmitter.block(&link, now, half, 480, &ctx(half, 120.0, true));
+        let far = 300 * half;
+        transmitter.block(&link, now, far, 480, &ctx(far, 120.0, true));
+        let messages: Vec<Vec<u8>> = sent(&link, now)
+            .into_iter()
+            .map(|m| m.1)
+            .filter(|bytes| bytes != &[CLOCK])
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                vec![SONG_POSITION, 8, 0],
+                vec![CONTINUE],
+                vec![STOP],
+                vec![SONG_POSITION, (2400 & 0x7f) as u8, (2400 >> 7) as u8],
+                vec![CONTINUE],
+            ]
+        );
+    }
+
+    #[test]
+    fn quarter_frames_carry_the_time_code() {
+        let link = link(false, Some(MtcRate::Fps25));
+        let mut transmitter = Transmitter::default();
+        let base = Instant::now();
+        // 01:02:03:04
+        let start_seconds = 3723.0 + 4.0 / 25.0;
+        let start = (start_seconds * SAMPLE_RATE) as u64;
+        transmitter.block(&link, base, start, 9600, &ctx(start, 120.0, true));
+        let messages = sent(&link, base);
+
+        let full = TimeCode {
+            hours: 1,
+            minutes: 2,
+            seconds: 3,
+            frames: 4,
+        };
+        assert_eq!(messages[0].1, full.full_frame(MtcRate::Fps25).bytes());
+
+        let quarters = &messages[1..];
+        for (at, bytes) in quarters {
+            // due where the quarter falls in real time, a block late
+            let quarter = ((at - 0.2 + start_seconds) * 100.0).round();
+            assert!((at - 0.2 + start_seconds - quarter / 100.0).abs() < 1e-6);
+            assert_eq!(bytes[1] >> 4, (quarter as u64 % 8) as u8);
+        }
+        let first = quarters
+            .iter()
+            .position(|(_, bytes)| bytes[1] >> 4 == 0)
+            .unwrap();
+        let nibbles: Vec<u8> = quarters[first..first + 8]
+            .iter()
+            .map(|(_, bytes)| bytes[1] & 0x0f)
+            .collect();
+        // the eight pieces from 01:02:03:05, the first frame on a whole piece 0
+        assert_eq!(nibbles, [5, 0, 3, 0, 2, 0, 1, 1 << 1]);
+    }
+
+    #[test]
+    fn drop_frame_skips_two_numbers_a_minute() {
+        let rate = MtcRate::Fps30Drop;
+        let time = TimeCode::at_frame(1800, rate);
+        assert_eq!((time.minutes, time.seconds, time.frames), (1, 0, 2));
+        let time = TimeCode::at_frame(17982, rate);
+        assert_eq!((time.minutes, time.seconds, time.frames), (10, 0, 0));
+        for frame in (0..200_000).step_by(7) {
+            assert_eq!(TimeCode::at_frame(frame, rate).frame(rate), frame);
+        }
+    }
+
+    #[test]
+    fn pending_messages_never_outgrow_the_queue() {
+        let link = link(true, None);
+        let mut transmitter = Transmitter::default();
+        let now = Instant::now();
+        {
+            // a sender that stopped taking messages
+            let _held = link.outgoing.lock().unwrap();
+            for block in 0..2000u64 {
+                let started = now + Duration::from_millis(10 * block);
+                transmitter.block(
+                    &link,
+                    started,
+                    block * 480,
+                    480,
+                    &ctx(block * 480, 300.0, true),
+                );
+                assert!(transmitter.pending.len() <= QUEUE_CAPACITY);
+            }
+        }
+        let started = now + Duration::from_millis(20_000);
+        transmitter.block(
+            &link,
+            started,
+            2000 * 480,
+            480,
+            &ctx(2000 * 480, 300.0, true),
+        );
+        let messages = sent(&link, now);
+        assert_eq!(messages.len(), QUEUE_CAPACITY);
+        // the newest are kept
+        let last = messages.last().unwrap().0;
+        assert!(last > 2000.0 * 0.01);
+    }
+
+    #[test]
+    fn chase_locks_onto_a_jittery_clock() {
+        const MASTER_BPM: f64 = 126.0;
+        const FRAMES: usize = 480;
+        let (sample_rate, channels) = (SAMPLE_RATE as u32, 2u16);
+        let interval = 60.0 / (MASTER_BPM * 24.0);
+        let base = Instant::now();
+
+        // start at 50 ms, then clocks off by up to a millisecond either way
+        let mut random = 0x2545_f491u32;
+        let mut master = vec![(0.05, START)];
+        for k in 0..600 {
+            random ^= random << 13;
+            random ^= random >> 17;
+            random ^= random << 5;
+            let jitter = (random as f64 / u32::MAX as f64 - 0.5) * 0.002;
+            master.push((0.06 + k as f64 * interval + jitter, CLOCK));
+        }
+
+        let mut chase = Chase::default();
+        let mut tempo_map = TempoMap::new(120.0);
+        let (mut pos_idx, mut is_playing) = (0u64, false);
+        let mut next = 0;
+        for block in 0..1000 {
+            let seconds = block as f64 * FRAMES as f64 / SAMPLE_RATE;
+            let now = base + Duration::from_secs_f64(seconds);
+            while let Some(&(at, status)) = master.get(next)
+                && at <= seconds
+            {
+                let at = base + Duration::from_secs_f64(at);
+                chase.receive(at, &SyncMessage::new(&[status]).unwrap());
+                next += 1;
+            }
+
+            let follow = chase.follow(
+                ChaseSource::Clock,
+                now,
+                is_playing,
+                pos_idx,
+                FRAMES,
+                &tempo_map,
+                sample_rate,
+                channels,
+            );
+            if let Some(bpm) = follow.bpm {
+                // as the housekeeper retempoes, keeping the beat
+                let beat = tempo_map.beat_at_sample(pos_idx, sample_rate, channels);
+                tempo_map.set_bpm(bpm);
+                pos_idx = tempo_map.sample_at_beat(beat, sample_rate, channels);
+            }
+            if let Some((playing, pos)) = follow.transport {
+                (is_playing, pos_idx) = (playing, pos);
+            }
+
+            if block >= 900 {
+                assert!(is_playing);
+                assert!((tempo_map.bpm_at(0.0) - MASTER_BPM).abs() < 0.5);
+                // the block is heard one later, when the master has got there
+                let heard = seconds + FRAMES as f64 / SAMPLE_RATE;
+                let master_beat = (heard - 0.06) / interval / CLOCKS_PER_BEAT;
+                let beat = tempo_map.beat_at_sample(pos_idx, sample_rate, channels);
+                let drift = (beat - master_beat) * 240.0 / MASTER_BPM;
+                // the jitter moves it about, but never near a jump
+                assert!(
+                    drift.abs() < JUMP_SECONDS / 2.0,
+                    "{} s off at block {}",
+                    drift,
+                    block
+                );
+            }
+            if is_playing {
+                pos_idx += (FRAMES * channels as usize) as u64;
+            }
+        }
+    }
+}
@@ -0,0 +1,1599 @@
+// The routing graph. Tracks and buses run their insert chains in dependency
+// order and send to buses or the master; every route is delayed so that all
+// signals meeting at a bus or the master line up despite plugin latency.
+// Nodes that don't depend on each other run in parallel on the worker pool.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::bail;
+
+use crate::{
+    effects::EffectInsert,
+    midi::{self, MidiEvent, RecordedMidiEvent},
+    midi_clip::{ClipPlayer, MAX_SOUNDING, MidiClip},
+    worker_pool::WorkerPool,
+};
+
+/// Room reserved for MIDI an insert emits, so blocks don't allocate.
+const MIDI_OUTPUT_CAPACITY: usize = 1024;
+/// Room for the MIDI a track's inserts receive in a block: live input and
+/// clips up to `MIDI_OUTPUT_CAPACITY`, the clips' releases past it, and
+/// routed MIDI in what's left.
+const MIDI_INPUT_CAPACITY: usize = 2 * MIDI_OUTPUT_CAPACITY + MAX_SOUNDING;
+/// Events a MIDI recording holds until the host takes them; later ones are
+/// dropped rather than grow it on the audio thread.
+const RECORDING_CAPACITY: usize = 1 << 16;
+
+/// Timing of the block being processed.
+#[derive(Clone, Copy, Default)]
+pub struct BlockContext {
+    pub sample_rate: f32,
+    pub bpm: f32,
+    /// Engine beat at the first frame of the block.
+    pub start_beat: f32,
+    pub beats_per_sample: f32,
+    pub is_playing: bool,
+}
+
+/// Plugin I/O beside the main bus, which is processed in place.
+pub struct Buses<'a> {
+    /// Fed to the inputs after the main bus.
+    pub sidechain: &'a [Vec<f32>],
+    /// Filled from the outputs after the main bus.
+    pub aux_outputs: &'a mut [Vec<f32>],
+    pub midi_in: &'a [MidiEvent],
+    /// MIDI the plugin emitted during the block.
+    pub midi_out: &'a mut Vec<MidiEvent>,
+}
+
+/// How a plugin's channels split into buses. The first `main` inputs and outputs
+/// are the main bus, extra inputs form the sidechain and extra outputs are
+/// grouped into aux buses of `main` channels.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BusLayout {
+    pub inputs: i32,
+    pub outputs: i32,
+    pub sidechain_channels: i32,
+    pub aux_output_buses: i32,
+}
+
+impl BusLayout {
+    pub fn new(inputs: usize, outputs: usize, main: usize) -> Self {
+        Self {
+            inputs: inputs as i32,
+            outputs: outputs as i32,
+            sidechain_channels: inputs.saturating_sub(main) as i32,
+            aux_output_buses: outputs.saturating_sub(main).div_ceil(main.max(1)) as i32,
+        }
+    }
+}
+
+/// Anything that can sit in an insert chain.
+pub trait Insert: Send {
+    fn id(&self) -> u32;
+
+    /// (inputs, outputs) as reported by the plugin.
+    fn io(&self) -> (usize, usize);
+
+    /// Processes `buffer` in place, one `Vec` per engine channel, all block sized.
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, ctx: &BlockContext);
+
+    /// Samples of delay the insert adds to its path.
+    fn latency(&self) -> usize;
+
+    /// Built-in effects expose themselves for state and parameter access.
+    fn as_effect_mut(&mut self) -> Option<&mut EffectInsert> {
+        None
+    }
+}
+
+/// Channel buffers for running an out-of-place processor in place.
+#[derive(Default)]
+pub struct ChannelScratch {
+    inputs: Vec<Vec<f32>>,
+    outputs: Vec<Vec<f32>>,
+}
+
+impl ChannelScratch {
+    /// Runs `process` with `ins`/`outs` channels in place on `buffer`, feeding extra
+    /// inputs from `sidechain` and extra outputs into `aux_outputs`.
+    /// Missing inputs are silent; a mono output feeds every engine channel.
+    pub fn process_in_place(
+        &mut self,
+        buffer: &mut [Vec<f32>],
+        sidechain: &[Vec<f32>],
+        aux_outputs: &mut [Vec<f32>],
+        ins: usize,
+        outs: usize,
+        process: impl FnOnce(&[Vec<f32>], &mut [Vec<f32>]),
+    ) {
+        let main = buffer.len();
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+
+        self.inputs.resize_with(ins, Vec::new);
+        for (ch, input) in self.inputs.iter_mut().enumerate() {
+            input.clear();
+            let src = if ch < main {
+                buffer.get(ch)
+            } else {
+                sidechain.get(ch - main)
+            };
+            match src {
+                Some(src) if src.len() == frames => input.extend_from_slice(src),
+                _ => input.resize(frames, 0.0),
+            }
+        }
+        clear_block(&mut self.outputs, outs, frames);
+
+        process(&self.inputs, &mut self.outputs);
+
+        let main_outs = outs.min(main);
+        if main_outs > 0 {
+            for (ch, dst) in buffer.iter_mut().enumerate() {
+                dst.copy_from_slice(&self.outputs[ch.min(main_outs - 1)]);
+            }
+        }
+        for (dst, src) in aux_outputs.iter_mut().zip(&self.outputs[main_outs..]) {
+            dst.copy_from_slice(src);
+        }
+    }
+}
+
+/// Fixed per-channel delay.
+#[derive(Default)]
+pub struct DelayLine {
+    lines: Vec<Vec<f32>>,
+    pos: usize,
+}
+
+impl DelayLine {
+    pub fn delay(&self) -> usize {
+        self.lines.first().map(|l| l.len()).unwrap_or(0)
+    }
+
+    /// Clears the line when the delay or channel count changes.
+    pub fn set_delay(&mut self, channels: usize, delay: usize) {
+        if self.delay() == delay && self.lines.len() == channels {
+            return;
+        }
+        self.lines = vec![vec![0.0; delay]; channels];
+        self.pos = 0;
+    }
+
+    pub fn reset(&mut self) {
+        for line in &mut self.lines {
+            line.fill(0.0);
+        }
+        self.pos = 0;
+    }
+
+    pub fn process(&mut self, buffer: &mut [Vec<f32>]) {
+        let delay = self.delay();
+        if delay == 0 {
+            return;
+        }
+
+        let frames = buffer.first().map(|b| b.len()).unwrap_or(0);
+        for (line, channel) in self.lines.iter_mut().zip(buffer.iter_mut()) {
+            let mut pos = self.pos;
+            for sample in channel.iter_mut() {
+                std::mem::swap(&mut line[pos], sample);
+                pos = (pos + 1) % delay;
+            }
+        }
+        self.pos = (self.pos + frames) % delay;
+    }
+}
+
+/// A connection from a track to a bus, or to the master when `target` is `None`.
+pub struct Route {
+    pub target: Option<String>,
+    pub level: f32,
+    /// Taps the signal before the track's volume.
+    pub pre_fader: bool,
+    target_idx: Option<usize>,
+    delay: DelayLine,
+    /// What the route carries during the current block, read by its target.
+    buffer: Vec<Vec<f32>>,
+}
+
+impl Route {
+    pub fn new(target: Option<&str>, level: f32, pre_fader: bool) -> Self {
+        Self {
+            target: target.map(str::to_owned),
+            level,
+            pre_fader,
+            target_idx: None,
+            delay: DelayLine::default(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Fills the route's buffer with `buffer * level`, delayed for compensation.
+    fn feed(&mut self, buffer: &[Vec<f32>]) {
+        self.buffer.resize_with(buffer.len(), Vec::new);
+        for (dst, src) in self.buffer.iter_mut().zip(buffer) {
+            dst.clear();
+            dst.extend(src.iter().map(|x| x * self.level));
+        }
+        self.delay.process(&mut self.buffer);
+    }
+}
+
+impl Default for Route {
+    fn default() -> Self {
+        Self::new(None, 1.0, false)
+    }
+}
+
+/// A node of the graph. Audio tracks play their rendered clips, buses only
+/// hear what is routed to them; otherwise both work the same.
+pub struct MixerTrack {
+    pub id: String,
+    /// Interleaved audio rendered from the track's clips.
+    pub rendered: Vec<f32>,
+    pub inserts: Vec<Box<dyn Insert>>,
+    /// Aux output bus (1 based) of an insert on another track, mixed into this one.
+    pub source: Option<(u32, usize)>,
+    /// Sidechain source track per insert id.
+    pub sidechains: HashMap<u32, String>,
+    /// Insert on another track whose MIDI output plays this track's inserts.
+    pub midi_source: Option<u32>,
+    /// Played to the inserts during playback, set with `Mixer::set_midi_clips`.
+    midi_clips: Vec<MidiClip>,
+    clip_player: ClipPlayer,
+    /// Linear gain of the fader.
+    pub volume: f32,
+    /// Post-fader main output.
+    pub output: Route,
+    pub sends: Vec<Route>,
+    buffer: Vec<Vec<f32>>,
+    /// Lines the track's own clips and aux source up with its routed inputs.
+    direct_delay: DelayLine,
+    midi_in: Vec<MidiEvent>,
+    /// Sent by the host, e.g. notes played on the piano roll, due at the next block.
+    live_midi: Vec<MidiEvent>,
+    /// Extra outputs of the inserts during the current block, by insert id.
+    aux_outputs: HashMap<u32, Vec<Vec<f32>>>,
+    /// MIDI emitted by the inserts during the current block, by insert id.
+    midi_outputs: HashMap<u32, Vec<MidiEvent>>,
+    /// Armed inserts and the MIDI they emitted while playing.
+    midi_recordings: HashMap<u32, Vec<RecordedMidiEvent>>,
+    /// MIDI input received while armed and playing, None unless armed.
+    input_recording: Option<Vec<RecordedMidiEvent>>,
+    /// Latency of the audio arriving at the insert chain.
+    input_latency: usize,
+    /// Latency of the track's own clips and aux source.
+    direct_latency: usize,
+    chain_latency: usize,
+}
+
+impl MixerTrack {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            rendered: Vec::new(),
+            inserts: Vec::new(),
+            source: None,
+            sidechains: HashMap::new(),
+            midi_source: None,
+            midi_clips: Vec::new(),
+            clip_player: ClipPlayer::default(),
+            volume: 1.0,
+            output: Route::default(),
+            sends: Vec::new(),
+            buffer: Vec::new(),
+            direct_delay: DelayLine::default(),
+            midi_in: Vec::with_capacity(MIDI_INPUT_CAPACITY),
+            live_midi: Vec::with_capacity(MIDI_OUTPUT_CAPACITY),
+            aux_outputs: HashMap::new(),
+            midi_outputs: HashMap::new(),
+            midi_recordings: HashMap::new(),
+            input_recording: None,
+            input_latency: 0,
+            direct_latency: 0,
+            chain_latency: 0,
+        }
+    }
+
+    /// Post-fader audio of the last block, a buffer per channel.
+    pub fn block(&self) -> &[Vec<f32>] {
+        &self.buffer
+    }
+
+    /// Latency of the insert chain.
+    pub fn latency(&self) -> usize {
+        self.inserts.iter().map(|i| i.latency()).sum()
+    }
+
+    fn output_latency(&self) -> usize {
+        self.input_latency + self.chain_latency
+    }
+
+    fn routes(&self) -> impl Iterator<Item = &Route> {
+        std::iter::once(&self.output).chain(self.sends.iter())
+    }
+
+    fn routes_mut(&mut self) -> impl Iterator<Item = &mut Route> {
+        std::iter::once(&mut self.output).chain(self.sends.iter_mut())
+    }
+
+    /// Route 0 is the main output, the sends follow.
+    fn route(&self, idx: usize) -> &Route {
+        match idx {
+            0 => &self.output,
+            _ => &self.sends[idx - 1],
+        }
+    }
+}
+
+/// What a node reads from the others, resolved to indices by `update_routing`.
+#[derive(Default)]
+struct Node {
+    /// Nodes that have to wait for this one.
+    readers: Vec<usize>,
+    /// Edges into this node, counted like `readers`.
+    deps: usize,
+    /// Routes ending here, as (node, route).
+    inputs: Vec<(usize, usize)>,
+    source_owner: Option<usize>,
+    midi_source_owner: Option<usize>,
+    /// Sidechain source node per insert id.
+    sidechains: Vec<(u32, usize)>,
+}
+
+/// Hands ready nodes to whichever thread asks first. Every node is queued
+/// exactly once per block, so the queue never wraps.
+#[derive(Default)]
+struct Schedule {
+    pending: Vec<AtomicUsize>,
+    /// Ready node plus one, 0 until the slot is written.
+    ready: Vec<AtomicUsize>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    done: AtomicUsize,
+}
+
+impl Schedule {
+    fn new(nodes: usize) -> Self {
+        Self {
+            pending: (0..nodes).map(|_| AtomicUsize::new(0)).collect(),
+            ready: (0..nodes).map(|_| AtomicUsize::new(0)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn reset(&self, plan: &[Node]) {
+        self.head.store(0, Ordering::Relaxed);
+        self.tail.store(0, Ordering::Relaxed);
+        self.done.store(0, Ordering::Relaxed);
+        for slot in &self.ready {
+            slot.store(0, Ordering::Relaxed);
+        }
+        for (idx, (pending, node)) in self.pending.iter().zip(plan).enumerate() {
+            pending.store(node.deps, Ordering::Relaxed);
+            if node.deps == 0 {
+                self.push(idx);
+            }
+        }
+    }
+
+    fn push(&self, idx: usize) {
+        let slot = self.tail.fetch_add(1, Ordering::AcqRel);
+        self.ready[slot].store(idx + 1, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<usize> {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            if head >= self.tail.load(Ordering::Acquire) {
+                return None;
+            }
+            if self
+                .head
+                .compare_exchange_weak(head, head + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                // the slot is reserved, its writer may not have stored it yet
+                loop {
+                    let idx = self.ready[head].load(Ordering::Acquire);
+                    if idx != 0 {
+                        return Some(idx - 1);
+                    }
+                    std::hint::spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Marks `idx` processed and queues the readers it was the last wait of.
+    fn finish(&self, idx: usize, plan: &[Node]) {
+        for &reader in &plan[idx].readers {
+            if self.pending[reader].fetch_sub(1, Ordering::AcqRel) == 1 {
+                self.push(reader);
+            }
+        }
+        self.done.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// One block of the graph, run by every thread of the pool at once.
+struct BlockJob<'a> {
+    tracks: *mut MixerTrack,
+    plan: &'a [Node],
+    schedule: &'a Schedule,
+    channels: usize,
+    frames: usize,
+    start: usize,
+    ctx: &'a BlockContext,
+}
+
+// a node is only touched by the thread that popped it, and only read by
+// others once `finish` has released it
+unsafe impl Sync for BlockJob<'_> {}
+
+impl BlockJob<'_> {
+    fn run(&self) {
+        let nodes = self.plan.len();
+        while self.schedule.done.load(Ordering::Acquire) < nodes {
+            match self.schedule.pop() {
+                Some(idx) => {
+                    unsafe { self.process_node(idx) };
+                    self.schedule.finish(idx, self.plan);
+                }
+                None => std::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Safety: `idx` must be popped from the schedule and every node it reads finished.
+    unsafe fn process_node(&self, idx: usize) {
+        let (channels, frames, ctx) = (self.channels, self.frames, self.ctx);
+        let node = &self.plan[idx];
+        let other = |i: usize| unsafe { &*self.tracks.add(i) };
+        let MixerTrack {
+            rendered,
+            inserts,
+            source,
+            volume,
+            output,
+            sends,
+            buffer,
+            direct_delay,
+            midi_in,
+            live_midi,
+            aux_outputs,
+            midi_outputs,
+            midi_recordings,
+            midi_source,
+            midi_clips,
+            clip_player,
+            ..
+        } = unsafe { &mut *self.tracks.add(idx) };
+
+        buffer.resize_with(channels, Vec::new);
+        for (ch, buf) in buffer.iter_mut().enumerate() {
+            buf.clear();
+            // while stopped the inserts still run, for tails and live notes
+            buf.extend((0..frames).map(|f| {
+                rendered
+                    .get(self.start + f * channels + ch)
+                    .filter(|_| ctx.is_playing)
+                    .copied()
+                    .unwrap_or(0.0)
+            }));
+        }
+
+        if let Some((insert_id, bus)) = *source
+            && let Some(owner) = node.source_owner
+            && let Some(aux) = other(owner).aux_outputs.get(&insert_id)
+        {
+            // bus 1 starts right after the main outputs
+            let first = (bus - 1) * channels;
+            let last = (first + channels).min(aux.len());
+            if first < last {
+                for (ch, buf) in buffer.iter_mut().enumerate() {
+                    let src = &aux[(first + ch).min(last - 1)];
+                    for (dst, s) in buf.iter_mut().zip(src) {
+                        *dst += *s;
+                    }
+                }
+            }
+        }
+
+        direct_delay.process(buffer);
+        for &(src, route) in &node.inputs {
+            let routed = &other(src).route(route).buffer;
+            for (buf, input) in buffer.iter_mut().zip(routed) {
+                for (dst, s) in buf.iter_mut().zip(input) {
+                    *dst += *s;
+                }
+            }
+        }
+
+        midi_in.clear();
+        midi_in.append(live_midi);
+        let start_frame = (self.start / channels) as u64;
+        clip_player.play(
+            midi_clips,
+            start_frame,
+            frames,
+            ctx,
+            midi_in,
+            MIDI_OUTPUT_CAPACITY,
+        );
+        if let Some(insert_id) = *midi_source
+            && let Some(owner) = node.midi_source_owner
+            && let Some(events) = other(owner).midi_outputs.get(&insert_id)
+        {
+            let room = MIDI_INPUT_CAPACITY.saturating_sub(midi_in.len());
+            midi_in.extend_from_slice(&events[..events.len().min(room)]);
+        }
+        // host events play first among those on the same frame
+        midi::sort_events(midi_in);
+
+        for insert in inserts.iter_mut() {
+            let sidechain = node
+                .sidechains
+                .iter()
+                .find(|(id, _)| *id == insert.id())
+                .map(|&(_, src)| other(src).buffer.as_slice())
+                .unwrap_or(&[]);
+
+            // entries are made by `add_insert`, never on this thread
+            let (Some(aux), Some(midi_out)) = (
+                aux_outputs.get_mut(&insert.id()),
+                midi_outputs.get_mut(&insert.id()),
+            ) else {
+                continue;
+            };
+            for buf in aux.iter_mut() {
+                buf.clear();
+                buf.resize(frames, 0.0);
+            }
+            midi_out.clear();
+
+            let mut buses = Buses {
+                sidechain,
+                aux_outputs: aux,
+                midi_in,
+                midi_out,
+            };
+            insert.process(buffer, &mut buses, ctx);
+
+            if ctx.is_playing
+                && let Some(recording) = midi_recordings.get_mut(&insert.id())
+            {
+                let room = RECORDING_CAPACITY.saturating_sub(recording.len());
+                recording.extend(buses.midi_out.iter().take(room).map(|e| RecordedMidiEvent {
+                    beat: ctx.start_beat + e.delta_frames as f32 * ctx.beats_per_sample,
+                    status: e.data[0],
+                    data1: e.data[1],
+                    data2: e.data[2],
+                }));
+            }
+        }
+
+        for send in sends.iter_mut().filter(|s| s.pre_fader) {
+            send.feed(buffer);
+        }
+
+        for buf in buffer.iter_mut() {
+            for s in buf.iter_mut() {
+                *s *= *volume;
+            }
+        }
+
+        let post_fader = sends.iter_mut().filter(|s| !s.pre_fader);
+        for route in std::iter::once(output).chain(post_fader) {
+            route.feed(buffer);
+        }
+    }
+}
+
+pub struct Mixer {
+    pub channels: usize,
+    pub tracks: Vec<MixerTrack>,
+    /// Track indices, every track after the tracks it reads from.
+    order: Vec<usize>,
+    plan: Vec<Node>,
+    schedule: Schedule,
+    /// Routes into the master, as (node, route).
+    master_inputs: Vec<(usize, usize)>,
+    /// Latency of the slowest path into the master.
+    total_latency: usize,
+    pool: WorkerPool,
+    /// Track played from the MIDI inputs while none is armed, usually the selected one.
+    input_track: Option<String>,
+}
+
+impl Mixer {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            tracks: Vec::new(),
+            order: Vec::new(),
+            plan: Vec::new(),
+            schedule: Schedule::default(),
+            master_inputs: Vec::new(),
+            total_latency: 0,
+            pool: WorkerPool::new(),
+            input_track: None,
+        }
+    }
+
+    /// The track or bus with this id, created (routed to the master) if missing.
+    pub fn track_mut(&mut self, id: &str) -> &mut MixerTrack {
+        let idx = match self.track_index(id) {
+            Some(idx) => idx,
+            None => {
+                self.tracks.push(MixerTrack::new(id));
+                // a new node cannot close a cycle
+                self.update_routing().ok();
+                self.tracks.len() - 1
+            }
+        };
+        &mut self.tracks[idx]
+    }
+
+    pub fn track(&self, id: &str) -> Option<&MixerTrack> {
+        self.tracks.iter().find(|t| t.id == id)
+    }
+
+    fn track_index(&self, id: &str) -> Option<usize> {
+        self.tracks.iter().position(|t| t.id == id)
+    }
+
+    /// Takes a track or bus out of the graph. Whatever was routed to it goes to
+    /// the master instead; the caller drops it, outside the mixer lock.
+    pub fn remove_track(&mut self, id: &str) -> Option<MixerTrack> {
+        let idx = self.track_index(id)?;
+        let removed = self.tracks.remove(idx);
+
+        let insert_ids: Vec<u32> = removed.inserts.iter().map(|i| i.id()).collect();
+        for track in &mut self.tracks {
+            if track.output.target.as_deref() == Some(id) {
+                track.output = Route::default();
+            }
+            track.sends.retain(|s| s.target.as_deref() != Some(id));
+            track.sidechains.retain(|_, source| source != id);
+            if track.source.is_some_and(|(i, _)| insert_ids.contains(&i)) {
+                track.source = None;
+            }
+            if track.midi_source.is_some_and(|i| insert_ids.contains(&i)) {
+                track.midi_source = None;
+            }
+        }
+
+        // removing a node cannot create a cycle
+        self.update_routing().ok();
+        Some(removed)
+    }
+
+    /// Index of the track whose chain holds the insert.
+    fn owner_of(&self, insert_id: u32) -> Option<usize> {
+        self.tracks
+            .iter()
+            .position(|t| t.inserts.iter().any(|i| i.id() == insert_id))
+    }
+
+    pub fn insert_layout(&self, insert_id: u32) -> Option<BusLayout> {
+        self.tracks
+            .iter()
+            .flat_map(|t| t.inserts.iter())
+            .find(|i| i.id() == insert_id)
+            .map(|i| {
+                let (ins, outs) = i.io();
+                BusLayout::new(ins, outs, self.channels)
+            })
+    }
+
+    pub fn effect_mut(&mut self, insert_id: u32) -> Option<&mut EffectInsert> {
+        self.tracks
+            .iter_mut()
+            .flat_map(|t| t.inserts.iter_mut())
+            .find(|i| i.id() == insert_id)?
+            .as_effect_mut()
+    }
+
+    pub fn add_insert(&mut self, track_id: &str, insert: Box<dyn Insert>) {
+        let channels = self.channels;
+        let id = insert.id();
+        let (_, outs) = insert.io();
+
+        let track = self.track_mut(track_id);
+        track
+            .aux_outputs
+            .insert(id, vec![Vec::new(); outs.saturating_sub(channels)]);
+        track
+            .midi_outputs
+            .insert(id, Vec::with_capacity(MIDI_OUTPUT_CAPACITY));
+        track.inserts.push(insert);
+        self.update_latency();
+    }
+
+    /// Takes the insert out of whichever chain holds it.
+    pub fn remove_insert(&mut self, insert_id: u32) -> Option<Box<dyn Insert>> {
+        let removed = self.tracks.iter_mut().find_map(|track| {
+            let idx = track.inserts.iter().position(|i| i.id() == insert_id)?;
+            track.sidechains.remove(&insert_id);
+            track.aux_outputs.remove(&insert_id);
+            track.midi_outputs.remove(&insert_id);
+            track.midi_recordings.remove(&insert_id);
+            Some(track.inserts.remove(idx))
+        });
+        if removed.is_some() {
+            for track in &mut self.tracks {
+                if track.source.is_some_and(|(id, _)| id == insert_id) {
+                    track.source = None;
+                }
+                if track.midi_source == Some(insert_id) {
+                    track.midi_source = None;
+                }
+            }
+            self.update_routing().ok();
+        }
+        removed
+    }
+
+    /// Routes the track's main output to a bus, or to the master with `None`.
+    pub fn set_output(&mut self, track_id: &str, target: Option<&str>) -> anyhow::Result<()> {
+        let Some(idx) = self.track_index(track_id) else {
+            bail!("no track or bus {}", track_id);
+        };
+        if let Some(target) = target
+            && self.track_index(target).is_none()
+        {
+            bail!("no track or bus {}", target);
+        }
+
+        let output = Route::new(target, 1.0, false);
+        let previous = std::mem::replace(&mut self.tracks[idx].output, output);
+        if let Err(e) = self.update_routing() {
+            self.tracks[idx].output = previous;
+            self.update_routing().ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Adds an aux send to `target`, or updates the existing one.
+    pub fn set_send(
+        &mut self,
+        track_id: &str,
+        target: &str,
+        level: f32,
+        pre_fader: bool,
+    ) -> anyhow::Result<()> {
+        let Some(idx) = self.track_index(track_id) else {
+            bail!("no track or bus {}", track_id);
+        };
+        if self.track_index(target).is_none() {
+            bail!("no track or bus {}", target);
+        }
+
+        let sends = &mut self.tracks[idx].sends;
+        if let Some(send) = sends
+            .iter_mut()
+            .find(|s| s.target.as_deref() == Some(target))
+        {
+            // same edge, no need to check the graph again
+            send.level = level;
+            send.pre_fader = pre_fader;
+            return Ok(());
+        }
+
+        sends.push(Route::new(Some(target), level, pre_fader));
+        if let Err(e) = self.update_routing() {
+            self.tracks[idx].sends.pop();
+            self.update_routing().ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn remove_send(&mut self, track_id: &str, target: &str) -> bool {
+        let Some(idx) = self.track_index(track_id) else {
+            return false;
+        };
+        let sends = &mut self.tracks[idx].sends;
+        let before = sends.len();
+        sends.retain(|s| s.target.as_deref() != Some(target));
+        let removed = sends.len() != before;
+        if removed {
+            self.update_routing().ok();
+        }
+        removed
+    }
+
+    /// Feeds `track_id` from an aux output bus of an insert, or clears its source.
+    pub fn set_track_source(
+        &mut self,
+        track_id: &str,
+        source: Option<(u32, usize)>,
+    ) -> anyhow::Result<()> {
+        if let Some((insert_id, bus)) = source {
+            let Some(layout) = self.insert_layout(insert_id) else {
+                bail!("no insert with id {}", insert_id);
+            };
+            if bus == 0 || bus > layout.aux_output_buses as usize {
+                bail!("plugin {} has no aux output bus {}", insert_id, bus);
+            }
+        }
+
+        let track = self.track_mut(track_id);
+        let previous = std::mem::replace(&mut track.source, source);
+        if let Err(e) = self.update_routing() {
+            self.track_mut(track_id).source = previous;
+            self.update_routing().ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Plays the track's inserts with the MIDI output of an insert, or clears it.
+    pub fn set_midi_source(&mut self, track_id: &str, source: Option<u32>) -> anyhow::Result<()> {
+        if let Some(insert_id) = source
+            && self.owner_of(insert_id).is_none()
+        {
+            bail!("no insert with id {}", insert_id);
+        }
+
+        let track = self.track_mut(track_id);
+        let previous = std::mem::replace(&mut track.midi_source, source);
+        if let Err(e) = self.update_routing() {
+            self.track_mut(track_id).midi_source = previous;
+            self.update_routing().ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Replaces the MIDI clips of every track; tracks missing from `clips` get none.
+    /// Notes still sounding from the old clips are released at the next block.
+    pub fn set_midi_clips(&mut self, mut clips: HashMap<String, Vec<MidiClip>>) {
+        for id in clips.keys() {
+            self.track_mut(id);
+        }
+        for track in &mut self.tracks {
+            track.midi_clips = clips.remove(&track.id).unwrap_or_default();
+            track.clip_player.interrupt();
+        }
+    }
+
+    /// Queues a message for the track's inserts, played at the start of the next block.
+    pub fn send_midi(&mut self, track_id: &str, data: [u8; 3]) -> anyhow::Result<()> {
+        let Some(idx) = self.track_index(track_id) else {
+            bail!("no track {}", track_id);
+        };
+        let queue = &mut self.tracks[idx].live_midi;
+        if queue.len() >= MIDI_OUTPUT_CAPACITY {
+            bail!("MIDI queue of track {} is full", track_id);
+        }
+        queue.push(MidiEvent::new(0, data));
+        Ok(())
+    }
+
+    pub fn set_input_track(&mut self, track_id: Option<&str>) {
+        self.input_track = track_id.map(str::to_owned);
+    }
+
+    /// Armed tracks play the MIDI inputs and keep what they receive during playback for the host.
+    pub fn arm_input_recording(&mut self, track_id: &str, armed: bool) {
+        let track = self.track_mut(track_id);
+        if !armed {
+            track.input_recording = None;
+        } else if track.input_recording.is_none() {
+            track.input_recording = Some(Vec::with_capacity(RECORDING_CAPACITY));
+        }
+    }
+
+    /// Input recorded so far, the track stays armed.
+    pub fn take_recorded_input(&mut self, track_id: &str) -> Vec<RecordedMidiEvent> {
+        self.track_index(track_id)
+            .and_then(|idx| self.tracks[idx].input_recording.as_mut())
+            .map(|r| std::mem::replace(r, Vec::with_capacity(RECORDING_CAPACITY)))
+            .unwrap_or_default()
+    }
+
+    /// Hands a block of MIDI input to the armed tracks, or to the input track
+    /// when none is armed. Called on the audio thread before `process`.
+    pub fn push_input_midi(&mut self, events: &[MidiEvent], ctx: &BlockContext) {
+        if events.is_empty() {
+            return;
+        }
+        let any_armed = self.tracks.iter().any(|t| t.input_recording.is_some());
+        for track in &mut self.tracks {
+            let target = if any_armed {
+                track.input_recording.is_some()
+            } else {
+                self.input_track.as_deref() == Some(track.id.as_str())
+            };
+            if !target {
+                continue;
+            }
+            let room = MIDI_OUTPUT_CAPACITY.saturating_sub(track.live_midi.len());
+            track.live_midi.extend(events.iter().take(room));
+            if ctx.is_playing
+                && let Some(recording) = track.input_recording.as_mut()
+            {
+                let room = RECORDING_CAPACITY.saturating_sub(recording.len());
+                recording.extend(events.iter().take(room).map(|e| RecordedMidiEvent {
+                    beat: ctx.start_beat + e.delta_frames as f32 * ctx.beats_per_sample,
+                    status: e.data[0],
+                    data1: e.data[1],
+                    data2: e.data[2],
+                }));
+            }
+        }
+    }
+
+    /// While armed, MIDI emitted by the insert during playback is kept for the host.
+    pub fn arm_midi_recording(&mut self, insert_id: u32, armed: bool) {
+        let Some(owner) = self.owner_of(insert_id) else {
+            return;
+        };
+        let recordings = &mut self.tracks[owner].midi_recordings;
+        if armed {
+            recordings
+                .entry(insert_id)
+                .or_insert_with(|| Vec::with_capacity(RECORDING_CAPACITY));
+        } else {
+            recordings.remove(&insert_id);
+        }
+    }
+
+    /// Events recorded so far, the insert stays armed.
+    pub fn take_recorded_midi(&mut self, insert_id: u32) -> Vec<RecordedMidiEvent> {
+        self.tracks
+            .iter_mut()
+            .find_map(|t| t.midi_recordings.get_mut(&insert_id))
+            .map(|r| std::mem::replace(r, Vec::with_capacity(RECORDING_CAPACITY)))
+            .unwrap_or_default()
+    }
+
+    /// Feeds the insert's extra inputs from another track, or clears them.
+    pub fn set_sidechain(&mut self, insert_id: u32, source: Option<&str>) -> anyhow::Result<()> {
+        let Some(owner) = self.owner_of(insert_id) else {
+            bail!("no insert with id {}", insert_id);
+        };
+        if let Some(source) = source {
+            self.track_mut(source);
+        }
+
+        let sidechains = &mut self.tracks[owner].sidechains;
+        let previous = match source {
+            Some(source) => sidechains.insert(insert_id, source.to_owned()),
+            None => sidechains.remove(&insert_id),
+        };
+        if let Err(e) = self.update_routing() {
+            let sidechains = &mut self.tracks[owner].sidechains;
+            match previous {
+                Some(previous) => sidechains.insert(insert_id, previous),
+                None => sidechains.remove(&insert_id),
+            };
+            self.update_routing().ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Tracks `idx` reads from: the owners of its audio and MIDI source inserts
+    /// and its sidechain sources. Routes are edges of their own.
+    fn dependencies(&self, idx: usize) -> Vec<usize> {
+        let track = &self.tracks[idx];
+        let mut deps: Vec<usize> = track
+            .sidechains
+            .values()
+            .filter_map(|id| self.track_index(id))
+            .collect();
+        let sources = track.source.map(|(id, _)| id).into_iter();
+        deps.extend(
+            sources
+                .chain(track.midi_source)
+                .filter_map(|id| self.owner_of(id)),
+        );
+        deps
+    }
+
+    /// Recomputes the processing order, failing if the routing has a cycle.
+    pub fn update_routing(&mut self) -> anyhow::Result<()> {
+        for i in 0..self.tracks.len() {
+            let targets: Vec<Option<usize>> = self.tracks[i]
+                .routes()
+                .map(|r| r.target.as_deref().and_then(|t| self.track_index(t)))
+                .collect();
+            for (route, target) in self.tracks[i].routes_mut().zip(targets) {
+                route.target_idx = target;
+            }
+        }
+
+        let n = self.tracks.len();
+        let mut plan: Vec<Node> = (0..n).map(|_| Node::default()).collect();
+        let mut master_inputs = Vec::new();
+        for idx in 0..n {
+            for dep in self.dependencies(idx) {
+                plan[dep].readers.push(idx);
+                plan[idx].deps += 1;
+            }
+            let targets: Vec<Option<usize>> =
+                self.tracks[idx].routes().map(|r| r.target_idx).collect();
+            for (route, target) in targets.into_iter().enumerate() {
+                match target {
+                    Some(target) => {
+                        plan[idx].readers.push(target);
+                        plan[target].deps += 1;
+                        plan[target].inputs.push((idx, route));
+                    }
+                    None => master_inputs.push((idx, route)),
+                }
+            }
+
+            let track = &self.tracks[idx];
+            plan[idx].source_owner = track.source.and_then(|(id, _)| self.owner_of(id));
+            plan[idx].midi_source_owner = track.midi_source.and_then(|id| self.owner_of(id));
+            plan[idx].sidechains = track
+                .sidechains
+                .iter()
+                .filter_map(|(&insert_id, source)| Some((insert_id, self.track_index(source)?)))
+                .collect();
+        }
+
+        let mut pending: Vec<usize> = plan.iter().map(|node| node.deps).collect();
+        let mut queue: VecDeque<usize> = (0..n).filter(|&i| pending[i] == 0).collect();
+        let mut order = Vec::with_capacity(n);
+        while let Some(idx) = queue.pop_front() {
+            order.push(idx);
+            for &reader in &plan[idx].readers {
+                pending[reader] -= 1;
+                if pending[reader] == 0 {
+                    queue.push_back(reader);
+                }
+            }
+        }
+
+        if order.len() != n {
+            bail!("routing would create a cycle");
+        }
+        self.order = order;
+        self.plan = plan;
+        self.schedule = Schedule::new(n);
+        self.master_inputs = master_inputs;
+        self.update_latency();
+        Ok(())
+    }
+
+    pub fn total_latency(&self) -> usize {
+        self.total_latency
+    }
+
+    /// Sums latency along every path and delays each route so that all
+    /// signals arriving at a bus, or at the master, line up.
+    pub fn update_latency(&mut self) {
+        for oi in 0..self.order.len() {
+            let idx = self.order[oi];
+
+            // a track fed by an aux output inherits the latency up to that plugin
+            let direct_latency = self.tracks[idx]
+                .source
+                .and_then(|(insert_id, _)| {
+                    let owner = &self.tracks[self.owner_of(insert_id)?];
+                    let upstream: usize = owner
+                        .inserts
+                        .iter()
+                        .take_while(|i| i.id() != insert_id)
+                        .map(|i| i.latency())
+                        .sum();
+                    let insert = owner.inserts.iter().find(|i| i.id() == insert_id)?;
+                    Some(owner.input_latency + upstream + insert.latency())
+                })
+                .unwrap_or(0);
+
+            // everything routed here comes earlier in the order
+            let routed_latency = self
+                .tracks
+                .iter()
+                .filter(|t| t.routes().any(|r| r.target_idx == Some(idx)))
+                .map(|t| t.output_latency())
+                .max()
+                .unwrap_or(0);
+
+            let track = &mut self.tracks[idx];
+            track.direct_latency = direct_latency;
+            track.input_latency = direct_latency.max(routed_latency);
+            track.chain_latency = track.latency();
+        }
+
+        self.total_latency = self
+            .tracks
+            .iter()
+            .filter(|t| t.output.target_idx.is_none())
+            .map(|t| t.output_latency())
+            .max()
+            .unwrap_or(0);
+
+        let arrivals: Vec<usize> = self.tracks.iter().map(|t| t.input_latency).collect();
+        for track in &mut self.tracks {
+            let channels = self.channels;
+            let direct_delay = track.input_latency - track.direct_latency;
+            track.direct_delay.set_delay(channels, direct_delay);
+
+            let output_latency = track.output_latency();
+            for route in track.routes_mut() {
+                let arrival = route
+                    .target_idx
+                    .map(|t| arrivals[t])
+                    .unwrap_or(self.total_latency);
+                route
+                    .delay
+                    .set_delay(channels, arrival.saturating_sub(output_latency));
+            }
+        }
+    }
+
+    /// Plugins may report a new latency at any time. Checked on the audio thread,
+    /// which leaves `update_latency` to another thread since it reallocates the delays.
+    pub fn latency_changed(&self) -> bool {
+        self.tracks.iter().any(|t| t.chain_latency != t.latency())
+    }
+
+    /// Drops audio still inside the compensation delays, e.g. after a jump.
+    pub fn reset(&mut self) {
+        for track in &mut self.tracks {
+            track.direct_delay.reset();
+            for route in track.routes_mut() {
+                route.delay.reset();
+            }
+        }
+    }
+
+    /// Mixes one interleaved block starting at `pos_idx` into `output`.
+    /// Nodes whose inputs are ready run in parallel on the worker pool.
+    pub fn process(&mut self, pos_idx: u64, output: &mut [f32], ctx: &BlockContext) {
+        let channels = self.channels;
+        let frames = output.len() / channels;
+        output.fill(0.0);
+
+        self.schedule.reset(&self.plan);
+        let job = BlockJob {
+            tracks: self.tracks.as_mut_ptr(),
+            plan: &self.plan,
+            schedule: &self.schedule,
+            channels,
+            frames,
+            start: pos_idx as usize,
+            ctx,
+        };
+        if self.plan.len() > 1 {
+            self.pool.run(&|| job.run());
+        } else {
+            job.run();
+        }
+
+        for &(src, route) in &self.master_inputs {
+            let routed = &self.tracks[src].route(route).buffer;
+            for (ch, buf) in routed.iter().enumerate() {
+                for (f, sample) in buf.iter().enumerate() {
+                    output[f * channels + ch] += *sample;
+                }
+            }
+        }
+    }
+}
+
+/// Resizes `block` to `channels` x `frames` of silence.
+fn clear_block(block: &mut Vec<Vec<f32>>, channels: usize, frames: usize) {
+    block.resize_with(channels, Vec::new);
+    for buf in block.iter_mut() {
+        buf.clear();
+        buf.resize(frames, 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds a constant to the main bus; `io` decides its sidechain and aux buses.
+    struct Dc {
+        id: u32,
+        io: (usize, usize),
+    }
+
+    impl Insert for Dc {
+        fn id(&self) -> u32 {
+            self.id
+        }
+
+        fn io(&self) -> (usize, usize) {
+            self.io
+        }
+
+        fn process(&mut self, buffer: &mut [Vec<f32>], _: &mut Buses, _: &BlockContext) {
+            for channel in buffer {
+                channel.iter_mut().for_each(|s| *s += 1.0);
+            }
+        }
+
+        fn latency(&self) -> usize {
+            0
+        }
+    }
+
+    /// Delays the main bus by its latency.
+    struct Latent {
+        id: u32,
+        line: DelayLine,
+    }
+
+    impl Latent {
+        fn new(id: u32, latency: usize) -> Box<Self> {
+            let mut line = DelayLine::default();
+            line.set_delay(2, latency);
+            Box::new(Self { id, line })
+        }
+    }
+
+    impl Insert for Latent {
+        fn id(&self) -> u32 {
+            self.id
+        }
+
+        fn io(&self) -> (usize, usize) {
+            (2, 2)
+        }
+
+        fn process(&mut self, buffer: &mut [Vec<f32>], _: &mut Buses, _: &BlockContext) {
+            self.line.process(buffer);
+        }
+
+        fn latency(&self) -> usize {
+            self.line.delay()
+        }
+    }
+
+    /// Moves its main bus to its aux bus and plays its sidechain in its place.
+    struct Swap {
+        id: u32,
+    }
+
+    impl Insert for Swap {
+        fn id(&self) -> u32 {
+            self.id
+        }
+
+        fn io(&self) -> (usize, usize) {
+            (4, 4)
+        }
+
+        fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, _: &BlockContext) {
+            for (ch, channel) in buffer.iter_mut().enumerate() {
+                buses.aux_outputs[ch].copy_from_slice(channel);
+                match buses.sidechain.get(ch) {
+                    Some(sidechain) => channel.copy_from_slice(sidechain),
+                    None => channel.fill(0.0),
+                }
+            }
+        }
+
+        fn latency(&self) -> usize {
+            0
+        }
+    }
+
+    /// Emits a note at frame 3 of every block and plays the note-ons it hears
+    /// as their velocity, at their frame.
+    struct Notes {
+        id: u32,
+    }
+
+    impl Insert for Notes {
+        fn id(&self) -> u32 {
+            self.id
+        }
+
+        fn io(&self) -> (usize, usize) {
+            (2, 2)
+        }
+
+        fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, _: &BlockContext) {
+            for event in buses.midi_in.iter().filter(|e| e.data[0] & 0xf0 == 0x90) {
+                for channel in buffer.iter_mut() {
+                    channel[event.delta_frames as usize] += event.data[2] as f32;
+                }
+            }
+            buses.midi_out.push(MidiEvent::new(3, [0x90, 60, 100]));
+        }
+
+        fn latency(&self) -> usize {
+            0
+        }
+    }
+
+    fn ctx() -> BlockContext {
+        BlockContext {
+            sample_rate: 48000.0,
+            bpm: 120.0,
+            start_beat: 0.0,
+            beats_per_sample: 120.0 / 60.0 / 48000.0 / 4.0,
+            is_playing: false,
+        }
+    }
+
+    /// Track `a` carries an insert making DC, routed to `b`.
+    fn mixer() -> Mixer {
+        let mut mixer = Mixer::new(2);
+        mixer.add_insert("a", Box::new(Dc { id: 1, io: (2, 4) }));
+        mixer.track_mut("b");
+        mixer.set_output("a", Some("b")).unwrap();
+        mixer
+    }
+
+    /// Interleaved stereo clip audio, silent but for `amplitude` at `frame`.
+    fn impulse(frames: usize, frame: usize, amplitude: f32) -> Vec<f32> {
+        let mut rendered = vec![0.0; frames * 2];
+        rendered[frame * 2..frame * 2 + 2].fill(amplitude);
+        rendered
+    }
+
+    /// Plays `blocks` blocks of `frames` from the start, the left channel of the master.
+    fn play(mixer: &mut Mixer, blocks: usize, frames: usize) -> Vec<f32> {
+        let mut left = Vec::new();
+        let mut output = vec![0.0; frames * 2];
+        for block in 0..blocks {
+            let ctx = BlockContext {
+                is_playing: true,
+                start_beat: (block * frames) as f32 * ctx().beats_per_sample,
+                ..ctx()
+            };
+            mixer.process((block * frames * 2) as u64, &mut output, &ctx);
+            left.extend(output.iter().step_by(2));
+        }
+        left
+    }
+
+    /// The DC of `a` reaches the master exactly once.
+    fn assert_mixes(mixer: &mut Mixer) {
+        let mut output = vec![0.0; 64];
+        mixer.process(0, &mut output, &ctx());
+        assert!(output.iter().all(|s| *s == 1.0), "{:?}", &output[..4]);
+    }
+
+    #[test]
+    fn routes_through_a_bus() {
+        assert_mixes(&mut mixer());
+    }
+
+    #[test]
+    fn output_loop_is_rejected() {
+        let mut mixer = mixer();
+
+        assert!(mixer.set_output("b", Some("a")).is_err());
+
+        assert_eq!(mixer.track("b").unwrap().output.target, None);
+        assert_eq!(
+            mixer.track("a").unwrap().output.target.as_deref(),
+            Some("b")
+        );
+        assert_mixes(&mut mixer);
+    }
+
+    #[test]
+    fn send_loop_is_rejected() {
+        let mut mixer = mixer();
+
+        assert!(mixer.set_send("b", "a", 0.5, false).is_err());
+
+        assert!(mixer.track("b").unwrap().sends.is_empty());
+        assert_mixes(&mut mixer);
+    }
+
+    #[test]
+    fn aux_output_loop_is_rejected() {
+        let mut mixer = mixer();
+        mixer.track_mut("c");
+        mixer.set_output("c", Some("a")).unwrap();
+
+        // `c` would read the aux output of `a`, which already hears `c`
+        assert!(mixer.set_track_source("c", Some((1, 1))).is_err());
+        assert_eq!(mixer.track("c").unwrap().source, None);
+
+        // and the other way round
+        mixer.set_output("c", None).unwrap();
+        mixer.set_track_source("c", Some((1, 1))).unwrap();
+        assert!(mixer.set_output("c", Some("a")).is_err());
+        assert_eq!(mixer.track("c").unwrap().output.target, None);
+        assert_eq!(mixer.track("c").unwrap().source, Some((1, 1)));
+    }
+
+    #[test]
+    fn sidechain_loop_is_rejected() {
+        let mut mixer = mixer();
+        mixer.add_insert("a", Box::new(Dc { id: 2, io: (4, 2) }));
+        mixer.set_sidechain(2, Some("c")).unwrap();
+
+        // `b` hears `a`, so `a` can't listen to `b`
+        assert!(mixer.set_sidechain(2, Some("b")).is_err());
+
+        let sidechains = &mixer.track("a").unwrap().sidechains;
+        assert_eq!(sidechains.get(&2).map(String::as_str), Some("c"));
+        assert!(mixer.update_routing().is_ok());
+    }
+
+    #[test]
+    fn midi_source_loop_is_rejected() {
+        let mut mixer = mixer();
+        mixer.add_insert("b", Box::new(Dc { id: 3, io: (2, 2) }));
+
+        assert!(mixer.set_midi_source("a", Some(3)).is_err());
+
+        assert_eq!(mixer.track("a").unwrap().midi_source, None);
+        assert!(mixer.update_routing().is_ok());
+    }
+
+    #[test]
+    fn input_recording_stops_at_its_capacity() {
+        let mut mixer = mixer();
+        mixer.arm_input_recording("a", true);
+        let ctx = BlockContext {
+            is_playing: true,
+            ..ctx()
+        };
+        let events = vec![MidiEvent::new(0, [0x90, 60, 100]); MIDI_OUTPUT_CAPACITY];
+        for _ in 0..RECORDING_CAPACITY / MIDI_OUTPUT_CAPACITY + 2 {
+            mixer.push_input_midi(&events, &ctx);
+            mixer.track_mut("a").live_midi.clear();
+        }
+
+        let recording = mixer.track("a").unwrap().input_recording.as_ref().unwrap();
+        assert_eq!(recording.len(), RECORDING_CAPACITY);
+        assert_eq!(recording.capacity(), RECORDING_CAPACITY);
+        assert_eq!(mixer.take_recorded_input("a").len(), RECORDING_CAPACITY);
+    }
+
+    #[test]
+    fn latency_is_compensated_on_every_path() {
+        let mut mixer = Mixer::new(2);
+        // straight to the master, through a bus, and through a pre-fader send
+        // into a bus that adds latency of its own
+        mixer.add_insert("late", Latent::new(1, 100));
+        mixer.track_mut("bus");
+        mixer.track_mut("bused");
+        mixer.set_output("bused", Some("bus")).unwrap();
+        mixer.add_insert("fx", Latent::new(2, 30));
+        mixer.track_mut("sent").volume = 0.0;
+        mixer.set_send("sent", "fx", 1.0, true).unwrap();
+        for (id, amplitude) in [("late", 1.0), ("bused", 2.0), ("sent", 4.0)] {
+            mixer.track_mut(id).rendered = impulse(256, 10, amplitude);
+        }
+        assert_eq!(mixer.total_latency(), 100);
+
+        let output = play(&mut mixer, 4, 64);
+        for (frame, sample) in output.iter().enumerate() {
+            let expected = if frame == 110 { 7.0 } else { 0.0 };
+            assert_eq!(*sample, expected, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn workers_mix_bit_identically() {
+        let mix = |pool: WorkerPool| {
+            let mut mixer = Mixer::new(2);
+            mixer.pool = pool;
+            let mut random = 0x9e37_79b9u32;
+            for t in 0..8u32 {
+                let id = format!("track {}", t);
+                mixer.add_insert(&id, Latent::new(t + 1, 7 * t as usize));
+                mixer.track_mut(&id).volume = 0.5 + t as f32 / 8.0;
+                mixer.track_mut(&id).rendered = (0..2048)
+                    .map(|_| {
+                        random ^= random << 13;
+                        random ^= random >> 17;
+                        random ^= random << 5;
+                        random as f32 / u32::MAX as f32 - 0.5
+                    })
+                    .collect();
+            }
+            mixer.add_insert("drums", Box::new(Dc { id: 20, io: (2, 2) }));
+            mixer.track_mut("keys");
+            mixer.add_insert("reverb", Latent::new(21, 13));
+            mixer.set_output("drums", Some("keys")).unwrap();
+            for t in 0..8 {
+                let id = format!("track {}", t);
+                let bus = if t % 2 == 0 { "drums" } else { "keys" };
+                mixer.set_output(&id, Some(bus)).unwrap();
+                mixer
+                    .set_send(&id, "reverb", 0.1 * t as f32, t % 3 == 0)
+                    .unwrap();
+            }
+            play(&mut mixer, 16, 64)
+        };
+
+        let serial = mix(WorkerPool::with_workers(0));
+        for workers in [1, 3, 7] {
+            for _ in 0..4 {
+                let parallel = mix(WorkerPool::with_workers(workers));
+                assert!(
+                    serial
+                        .iter()
+                        .zip(&parallel)
+                        .all(|(a, b)| a.to_bits() == b.to_bits()),
+                    "{} workers",
+                    workers
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn aux_output_feeds_its_return_track() {
+        let mut mixer = Mixer::new(2);
+        mixer.add_insert("synth", Box::new(Swap { id: 1 }));
+        mixer.track_mut("synth").rendered = impulse(64, 5, 1.0);
+        mixer.set_track_source("out 2", Some((1, 1))).unwrap();
+
+        let output = play(&mut mixer, 1, 64);
+
+        for (frame, sample) in output.iter().enumerate() {
+            assert_eq!(*sample, if frame == 5 { 1.0 } else { 0.0 });
+        }
+        let synth = mixer.track("synth").unwrap().block();
+        assert!(synth.iter().flatten().all(|s| *s == 0.0));
+        let aux_return = mixer.track("out 2").unwrap().block();
+        assert!(aux_return.iter().all(|channel| channel[5] == 1.0));
+    }
+
+    #[test]
+    fn sidechain_reaches_the_extra_inputs() {
+        let mut mixer = Mixer::new(2);
+        mixer.add_insert("bass", Box::new(Swap { id: 1 }));
+        mixer.track_mut("kick").rendered = impulse(64, 20, 2.0);
+        mixer.track_mut("kick").volume = 0.5;
+        mixer.set_sidechain(1, Some("kick")).unwrap();
+
+        play(&mut mixer, 1, 64);
+
+        // post-fader, as the kick sounds
+        let bass = mixer.track("bass").unwrap().block();
+        for channel in bass {
+            for (frame, sample) in channel.iter().enumerate() {
+                assert_eq!(*sample, if frame == 20 { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    #[test]
+    fn plugin_midi_plays_another_track_and_is_recorded() {
+        let mut mixer = Mixer::new(2);
+        mixer.add_insert("arp", Box::new(Notes { id: 1 }));
+        mixer.add_insert("synth", Box::new(Notes { id: 2 }));
+        mixer.set_midi_source("synth", Some(1)).unwrap();
+        mixer.arm_midi_recording(1, true);
+
+        play(&mut mixer, 2, 64);
+
+        let synth = mixer.track("synth").unwrap().block();
+        for channel in synth {
+            for (frame, sample) in channel.iter().enumerate() {
+                assert_eq!(*sample, if frame == 3 { 100.0 } else { 0.0 });
+            }
+        }
+        let arp = mixer.track("arp").unwrap().block();
+        assert!(arp.iter().flatten().all(|s| *s == 0.0));
+
+        let recorded = mixer.take_recorded_midi(1);
+        let beats_per_sample = ctx().beats_per_sample;
+        let beats: Vec<f32> = recorded.iter().map(|e| e.beat).collect();
+        assert_eq!(beats, [3.0 * beats_per_sample, 67.0 * beats_per_sample]);
+        assert!(
+            recorded
+                .iter()
+                .all(|e| (e.status, e.data1, e.data2) == (0x90, 60, 100))
+        );
+        assert!(mixer.take_recorded_midi(2).is_empty());
+    }
+}
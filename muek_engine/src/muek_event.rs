@@ -1,4 +1,4 @@
 pub enum MuekEvent {
     SendAudioBuffer(Vec<f32>, Vec<f32>),
-    CreateNewPlugin(String),
+    CreateNewPlugin(u32, String),
 }
\ No newline at end of file
@@ -0,0 +1,478 @@
+use std::{
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use crate::{
+    lazy_states::CRASHED_PLUGINS,
+    midi::MidiEvent,
+    mixer::{BlockContext, Buses, ChannelScratch, Insert},
+    shm_bridge::{
+        BlockRequest, MAX_BLOCK, MAX_MIDI_EVENTS, MidiEventRecord, READY_FAILED, READY_OK,
+        SharedBridge,
+    },
+};
+
+pub const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(target_os = "windows")]
+const HELPER_EXE: &str = "muek_plugin_bridge.exe";
+#[cfg(not(target_os = "windows"))]
+const HELPER_EXE: &str = "muek_plugin_bridge";
+
+/// Fraction of a block's duration the audio thread waits for the helper's answer.
+const BLOCK_DEADLINE: f32 = 0.5;
+/// How often the watchdog looks after the helper process.
+const WATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A plugin hosted in a `muek_plugin_bridge` helper process. Audio and MIDI go
+/// through shared memory; if the helper dies or stops answering, the insert is
+/// bypassed and its id is queued in `CRASHED_PLUGINS` for the host.
+pub struct BridgedPlugin {
+    pub id: u32,
+    pub in_channels: usize,
+    pub out_channels: usize,
+    pub initial_delay: usize,
+    shm: SharedBridge,
+    shm_path: PathBuf,
+    watch: Arc<Watch>,
+    watchdog: Option<JoinHandle<()>>,
+    crashed: bool,
+    sample_rate: f32,
+    /// how long the helper may stay behind before it counts as hung
+    timeout: Duration,
+    late: Option<Late>,
+    interleaved: Vec<f32>,
+    scratch: ChannelScratch,
+    midi_scratch: Vec<MidiEventRecord>,
+}
+
+/// Shared with the watchdog thread, which owns the helper process so that
+/// killing and reaping it never happens on the audio thread.
+#[derive(Default)]
+struct Watch {
+    crashed: AtomicBool,
+    shutdown: AtomicBool,
+    reason: OnceLock<&'static str>,
+}
+
+/// A block the helper didn't answer before the deadline. Its output is
+/// dropped when it shows up.
+struct Late {
+    samples: usize,
+    since: Instant,
+}
+
+impl BridgedPlugin {
+    pub fn spawn(id: u32, path: &str, sample_rate: f32, block_size: usize) -> anyhow::Result<Self> {
+        let shm_path =
+            std::env::temp_dir().join(format!("muek_bridge_{}_{}.shm", std::process::id(), id));
+        let shm = SharedBridge::open(&shm_path, true)?;
+
+        // the helper exits when its stdin closes, so it never outlives the host
+        let mut child = Command::new(default_helper_path())
+            .arg(path)
+            .arg(&shm_path)
+            .arg(sample_rate.to_string())
+            .arg(block_size.to_string())
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        let started = Instant::now();
+        loop {
+            match shm.ready() {
+                READY_OK => break,
+                READY_FAILED => {
+                    child.wait().ok();
+                    bail!("helper failed to load {}", path);
+                }
+                _ => {}
+            }
+            if let Some(status) = child.try_wait()? {
+                bail!("helper exited while loading ({})", status);
+            }
+            if started.elapsed() > READY_TIMEOUT {
+                child.kill().ok();
+                child.wait().ok();
+                bail!("helper did not load {} in {:?}", path, READY_TIMEOUT);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (in_channels, out_channels, initial_delay) = shm.io();
+        println!(
+            "[plugin_bridge] {} bridged: {} in, {} out",
+            path, in_channels, out_channels
+        );
+
+        // a few blocks of slack, never less than 50 ms
+        let block_secs = block_size as f32 / sample_rate;
+        let timeout = Duration::from_secs_f32(block_secs * 4.0).max(Duration::from_millis(50));
+
+        let watch = Arc::new(Watch::default());
+        let watchdog = {
+            let watch = watch.clone();
+            thread::Builder::new()
+                .name(format!("muek-bridge-watchdog-{}", id))
+                .spawn(move || run_watchdog(id, child, &watch))?
+        };
+
+        Ok(Self {
+            id,
+            in_channels,
+            out_channels,
+            initial_delay,
+            shm,
+            shm_path,
+            watch,
+            watchdog: Some(watchdog),
+            crashed: false,
+            sample_rate,
+            timeout,
+            late: None,
+            interleaved: vec![0.0; MAX_BLOCK * in_channels.max(out_channels).max(1)],
+            scratch: ChannelScratch::default(),
+            midi_scratch: vec![MidiEventRecord::default(); MAX_MIDI_EVENTS],
+        })
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// MIDI emitted by the plugin is appended to `midi_out`. Frames the helper
+    /// doesn't answer in time pass through dry.
+    pub fn process_block(
+        &mut self,
+        inputs: &[Vec<f32>],
+        outputs: &mut [Vec<f32>],
+        midi_in: &[MidiEvent],
+        midi_out: &mut Vec<MidiEvent>,
+    ) {
+        if !self.crashed && self.watch.crashed.load(Ordering::Acquire) {
+            self.crashed = true;
+        }
+
+        let mut done = 0;
+        if !self.crashed {
+            match self.try_process(inputs, outputs, midi_in, midi_out) {
+                Ok(frames) => done = frames,
+                Err(reason) => self.mark_crashed(reason),
+            }
+        }
+
+        for (ch, out) in outputs.iter_mut().enumerate() {
+            let len = out.len();
+            match inputs.get(ch) {
+                Some(input) => out[done..].copy_from_slice(&input[done..len]),
+                None => out[done..].fill(0.0),
+            }
+        }
+    }
+
+    /// Returns how many frames the helper processed.
+    fn try_process(
+        &mut self,
+        inputs: &[Vec<f32>],
+        outputs: &mut [Vec<f32>],
+        midi_in: &[MidiEvent],
+        midi_out: &mut Vec<MidiEvent>,
+    ) -> Result<usize, &'static str> {
+        if !self.catch_up()? {
+            return Ok(0);
+        }
+
+        let frames = outputs.first().map(|o| o.len()).unwrap_or(0);
+
+        let mut offset = 0;
+        while offset < frames {
+            let len = (frames - offset).min(MAX_BLOCK);
+
+            let mut events = 0;
+            for event in midi_in {
+                let delta = event.delta_frames as usize;
+                if delta < offset || delta >= offset + len || events >= MAX_MIDI_EVENTS {
+                    continue;
+                }
+                let record = MidiEventRecord {
+                    delta_frames: (delta - offset) as u32,
+                    data: event.data,
+                    _pad: 0,
+                };
+                if !self.shm.midi_in.push_slice(&[record]) {
+                    return Err("midi ring full");
+                }
+                events += 1;
+            }
+
+            let samples = len * self.in_channels;
+            for f in 0..len {
+                for ch in 0..self.in_channels {
+                    self.interleaved[f * self.in_channels + ch] =
+                        inputs.get(ch).map(|c| c[offset + f]).unwrap_or(0.0);
+                }
+            }
+            if !self.shm.audio_in.push_slice(&self.interleaved[..samples]) {
+                return Err("audio ring full");
+            }
+
+            // the request goes last, so the helper finds its data already queued
+            let request = BlockRequest {
+                frames: len as u32,
+                midi_events: events as u32,
+                sample_rate: self.sample_rate,
+            };
+            if !self.shm.control.push_slice(&[request]) {
+                return Err("control ring full");
+            }
+
+            let samples = len * self.out_channels;
+            if !self.wait_for_output(samples, len) {
+                self.late = Some(Late {
+                    samples,
+                    since: Instant::now(),
+                });
+                return Ok(offset);
+            }
+
+            // the helper queues MIDI before audio, so all of it is here now
+            let events = self.shm.midi_out.available().min(MAX_MIDI_EVENTS);
+            self.shm
+                .midi_out
+                .pop_slice(&mut self.midi_scratch[..events]);
+            midi_out.extend(
+                self.midi_scratch[..events]
+                    .iter()
+                    .map(|record| MidiEvent::new(record.delta_frames + offset as u32, record.data)),
+            );
+
+            self.shm
+                .audio_out
+                .pop_slice(&mut self.interleaved[..samples]);
+
+            for (ch, out) in outputs.iter_mut().enumerate() {
+                for f in 0..len {
+                    out[offset + f] = if ch < self.out_channels {
+                        self.interleaved[f * self.out_channels + ch]
+                    } else {
+                        0.0
+                    };
+                }
+            }
+
+            offset += len;
+        }
+
+        Ok(frames)
+    }
+
+    /// Drops the answer to a late block once it arrives. False while it's still
+    /// outstanding; an error once the helper has been behind for `timeout`.
+    fn catch_up(&mut self) -> Result<bool, &'static str> {
+        let Some(late) = &self.late else {
+            return Ok(true);
+        };
+
+        if self.shm.audio_out.available() < late.samples {
+            if late.since.elapsed() > self.timeout {
+                return Err("helper stopped answering");
+            }
+            return Ok(false);
+        }
+
+        let mut samples = late.samples;
+        while samples > 0 {
+            let len = samples.min(self.interleaved.len());
+            self.shm.audio_out.pop_slice(&mut self.interleaved[..len]);
+            samples -= len;
+        }
+        let events = self.shm.midi_out.available().min(MAX_MIDI_EVENTS);
+        self.shm
+            .midi_out
+            .pop_slice(&mut self.midi_scratch[..events]);
+
+        self.late = None;
+        Ok(true)
+    }
+
+    /// Spins for at most `BLOCK_DEADLINE` of the block's duration.
+    fn wait_for_output(&self, samples: usize, frames: usize) -> bool {
+        let deadline = Duration::from_secs_f32(frames as f32 / self.sample_rate * BLOCK_DEADLINE);
+        let started = Instant::now();
+        let mut spins = 0u32;
+        while self.shm.audio_out.available() < samples {
+            spins += 1;
+            if spins.is_multiple_of(64)
+                && (started.elapsed() > deadline || self.watch.crashed.load(Ordering::Acquire))
+            {
+                return false;
+            }
+            thread::yield_now();
+        }
+        true
+    }
+
+    /// Only flags the crash; the watchdog kills the helper and reports it.
+    fn mark_crashed(&mut self, reason: &'static str) {
+        self.crashed = true;
+        self.watch.reason.set(reason).ok();
+        self.watch.crashed.store(true, Ordering::Release);
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.thread().unpark();
+        }
+    }
+}
+
+/// Reaps the helper when it exits on its own, when the audio thread flags it
+/// as crashed, or when the plugin is closed.
+fn run_watchdog(id: u32, mut child: Child, watch: &Watch) {
+    loop {
+        thread::park_timeout(WATCH_INTERVAL);
+
+        if watch.shutdown.load(Ordering::Acquire) {
+            let started = Instant::now();
+            while matches!(child.try_wait(), Ok(None)) {
+                if started.elapsed() > Duration::from_millis(500) {
+                    child.kill().ok();
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            child.wait().ok();
+            return;
+        }
+
+        let exited = match child.try_wait() {
+            Ok(Some(status)) => Some(status),
+            Ok(None) => None,
+            Err(_) => return,
+        };
+        if exited.is_none() && !watch.crashed.load(Ordering::Acquire) {
+            continue;
+        }
+
+        watch.crashed.store(true, Ordering::Release);
+        child.kill().ok();
+        child.wait().ok();
+        match exited {
+            Some(status) => eprintln!(
+                "[plugin_bridge] plugin {} bypassed: helper exited ({})",
+                id, status
+            ),
+            None => eprintln!(
+                "[plugin_bridge] plugin {} bypassed: {}",
+                id,
+                watch.reason.get().unwrap_or(&"unknown error")
+            ),
+        }
+        CRASHED_PLUGINS.lock().unwrap().push(id);
+        return;
+    }
+}
+
+impl Insert for BridgedPlugin {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn io(&self) -> (usize, usize) {
+        (self.in_channels, self.out_channels)
+    }
+
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, ctx: &BlockContext) {
+        self.sample_rate = ctx.sample_rate;
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.process_in_place(
+            buffer,
+            buses.sidechain,
+            buses.aux_outputs,
+            self.in_channels,
+            self.out_channels,
+            |ins, outs| self.process_block(ins, outs, buses.midi_in, buses.midi_out),
+        );
+        self.scratch = scratch;
+    }
+
+    fn latency(&self) -> usize {
+        // a bypassed insert passes audio straight through
+        if self.crashed { 0 } else { self.initial_delay }
+    }
+}
+
+impl Drop for BridgedPlugin {
+    fn drop(&mut self) {
+        if !self.crashed {
+            self.shm.request_shutdown();
+        }
+        self.watch.shutdown.store(true, Ordering::Release);
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.thread().unpark();
+            watchdog.join().ok();
+        }
+        std::fs::remove_file(&self.shm_path).ok();
+    }
+}
+
+/// The helper executable shipped next to the host executable.
+pub fn default_helper_path() -> PathBuf {
+    std::env::current_exe()
+        .map(|exe| exe.with_file_name(HELPER_EXE))
+        .unwrap_or_else(|_| PathBuf::from(HELPER_EXE))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn watch(id: u32, program: &str, arg: &str) -> (Arc<Watch>, JoinHandle<()>) {
+        let child = Command::new(program).arg(arg).spawn().unwrap();
+        let watch = Arc::new(Watch::default());
+        let watchdog = {
+            let watch = watch.clone();
+            thread::spawn(move || run_watchdog(id, child, &watch))
+        };
+        (watch, watchdog)
+    }
+
+    #[test]
+    fn flagged_helper_is_killed_and_reported() {
+        let (watch, watchdog) = watch(9001, "sleep", "10");
+
+        watch.reason.set("helper stopped answering").ok();
+        watch.crashed.store(true, Ordering::Release);
+        watchdog.thread().unpark();
+
+        let started = Instant::now();
+        watchdog.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(CRASHED_PLUGINS.lock().unwrap().contains(&9001));
+    }
+
+    #[test]
+    fn helper_exiting_on_its_own_is_flagged() {
+        let (watch, watchdog) = watch(9002, "true", "");
+
+        watchdog.join().unwrap();
+        assert!(watch.crashed.load(Ordering::Acquire));
+        assert!(CRASHED_PLUGINS.lock().unwrap().contains(&9002));
+    }
+
+    #[test]
+    fn shutdown_reaps_without_reporting() {
+        let (watch, watchdog) = watch(9003, "sleep", "10");
+
+        watch.shutdown.store(true, Ordering::Release);
+        watchdog.thread().unpark();
+
+        watchdog.join().unwrap();
+        assert!(!CRASHED_PLUGINS.lock().unwrap().contains(&9003));
+    }
+}
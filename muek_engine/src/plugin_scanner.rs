@@ -0,0 +1,386 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(target_os = "windows")]
+const PROBE_EXE: &str = "muek_plugin_probe.exe";
+#[cfg(not(target_os = "windows"))]
+const PROBE_EXE: &str = "muek_plugin_probe";
+
+#[cfg(target_os = "windows")]
+const PLUGIN_EXT: &str = "dll";
+#[cfg(not(target_os = "windows"))]
+const PLUGIN_EXT: &str = "so";
+
+#[derive(Clone, Debug, Default)]
+pub struct PluginRecord {
+    pub path: String,
+    pub modified: u64,
+    pub name: String,
+    pub vendor: String,
+    pub category: String,
+    pub version: i32,
+    pub unique_id: i32,
+    pub is_instrument: bool,
+    pub inputs: i32,
+    pub outputs: i32,
+}
+
+/// What the probe found out about one plugin file.
+pub enum ProbeOutcome {
+    Found(PluginRecord),
+    /// the plugin crashed, hung or refused to load; the reason goes into the blacklist
+    Rejected(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct BlacklistEntry {
+    pub path: String,
+    pub modified: u64,
+    pub reason: String,
+}
+
+#[derive(Default)]
+pub struct ScanReport {
+    pub scanned: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub removed: usize,
+}
+
+/// On-disk plugin database, one tab separated record per line.
+#[derive(Default)]
+pub struct PluginDatabase {
+    pub plugins: HashMap<String, PluginRecord>,
+    pub blacklist: HashMap<String, BlacklistEntry>,
+}
+
+impl PluginDatabase {
+    pub fn load(path: &Path) -> Self {
+        let mut db = PluginDatabase::default();
+        let Ok(text) = fs::read_to_string(path) else {
+            return db;
+        };
+
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                [
+                    "plugin",
+                    path,
+                    modified,
+                    name,
+                    vendor,
+                    category,
+                    version,
+                    unique_id,
+                    is_instrument,
+                    inputs,
+                    outputs,
+                ] => {
+                    let record = PluginRecord {
+                        path: path.to_string(),
+                        modified: modified.parse().unwrap_or(0),
+                        name: name.to_string(),
+                        vendor: vendor.to_string(),
+                        category: category.to_string(),
+                        version: version.parse().unwrap_or(0),
+                        unique_id: unique_id.parse().unwrap_or(0),
+                        is_instrument: *is_instrument == "1",
+                        inputs: inputs.parse().unwrap_or(0),
+                        outputs: outputs.parse().unwrap_or(0),
+                    };
+                    db.plugins.insert(record.path.clone(), record);
+                }
+                ["blacklist", path, modified, reason] => {
+                    let entry = BlacklistEntry {
+                        path: path.to_string(),
+                        modified: modified.parse().unwrap_or(0),
+                        reason: reason.to_string(),
+                    };
+                    db.blacklist.insert(entry.path.clone(), entry);
+                }
+                _ => eprintln!("[plugin_db] skipping bad line: {}", line),
+            }
+        }
+
+        db
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut paths: Vec<&String> = self.plugins.keys().collect();
+        paths.sort();
+
+        let mut text = String::new();
+        for path in paths {
+            let r = &self.plugins[path];
+            text.push_str(&format!(
+                "plugin\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                clean(&r.path),
+                r.modified,
+                clean(&r.name),
+                clean(&r.vendor),
+                clean(&r.category),
+                r.version,
+                r.unique_id,
+                r.is_instrument as u8,
+                r.inputs,
+                r.outputs
+            ));
+        }
+
+        let mut paths: Vec<&String> = self.blacklist.keys().collect();
+        paths.sort();
+        for path in paths {
+            let b = &self.blacklist[path];
+            text.push_str(&format!(
+                "blacklist\t{}\t{}\t{}\n",
+                clean(&b.path),
+                b.modified,
+                clean(&b.reason)
+            ));
+        }
+
+        text
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // write next to the database first so a crash never leaves half a file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_text())?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Probes every new or modified plugin under `dirs`. Unchanged files are
+    /// skipped, including blacklisted ones, until their modification time changes.
+    /// Stops at the first error of the probe itself, e.g. when it can't be started,
+    /// so no plugin gets blacklisted for it; what was scanned so far is kept.
+    pub fn scan(&mut self, dirs: &[PathBuf], probe: &Path) -> anyhow::Result<ScanReport> {
+        let mut report = ScanReport::default();
+
+        let mut files = Vec::new();
+        for dir in dirs {
+            collect_plugin_files(dir, &mut files);
+        }
+
+        let before = self.plugins.len() + self.blacklist.len();
+        self.plugins.retain(|path, _| Path::new(path).exists());
+        self.blacklist.retain(|path, _| Path::new(path).exists());
+        report.removed = before - self.plugins.len() - self.blacklist.len();
+
+        for file in files {
+            let path = file.to_string_lossy().into_owned();
+            let modified = modified_secs(&file);
+
+            let known = self.plugins.get(&path).map(|r| r.modified) == Some(modified)
+                || self.blacklist.get(&path).map(|b| b.modified) == Some(modified);
+            if known {
+                report.skipped += 1;
+                continue;
+            }
+
+            println!("[plugin_scanner] probing {}", path);
+            match probe_plugin(probe, &path, PROBE_TIMEOUT)? {
+                ProbeOutcome::Found(mut record) => {
+                    record.modified = modified;
+                    self.blacklist.remove(&path);
+                    self.plugins.insert(path, record);
+                    report.scanned += 1;
+                }
+                ProbeOutcome::Rejected(reason) => {
+                    eprintln!("[plugin_scanner] {} blacklisted: {}", path, reason);
+                    self.plugins.remove(&path);
+                    self.blacklist.insert(
+                        path.clone(),
+                        BlacklistEntry {
+                            path,
+                            modified,
+                            reason,
+                        },
+                    );
+                    report.failed += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// The probe executable shipped next to the host executable.
+pub fn default_probe_path() -> PathBuf {
+    std::env::current_exe()
+        .map(|exe| exe.with_file_name(PROBE_EXE))
+        .unwrap_or_else(|_| PathBuf::from(PROBE_EXE))
+}
+
+/// Loads the plugin in a child process, killing it after `timeout`.
+/// Errors are about the probe itself, not the plugin.
+pub fn probe_plugin(probe: &Path, path: &str, timeout: Duration) -> anyhow::Result<ProbeOutcome> {
+    let mut child = Command::new(probe)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("failed to start probe {}: {}", probe.display(), e))?;
+
+    // drain both pipes while waiting, a chatty plugin must not block on a full pipe
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            child.kill().ok();
+            child.wait().ok();
+            return Ok(ProbeOutcome::Rejected(format!(
+                "timed out after {:?}",
+                timeout
+            )));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    let stdout = stdout.and_then(|h| h.join().ok()).unwrap_or_default();
+    let stderr = stderr.and_then(|h| h.join().ok()).unwrap_or_default();
+
+    if !status.success() {
+        // the last line is the probe's own error message
+        let reason = match stderr.lines().rev().find(|l| !l.trim().is_empty()) {
+            Some(line) => line.trim().to_owned(),
+            None => format!("probe crashed ({})", status),
+        };
+        return Ok(ProbeOutcome::Rejected(reason));
+    }
+
+    let mut record = PluginRecord {
+        path: path.to_owned(),
+        ..Default::default()
+    };
+    for line in stdout.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key {
+            "name" => record.name = value.to_owned(),
+            "vendor" => record.vendor = value.to_owned(),
+            "category" => record.category = value.to_owned(),
+            "version" => record.version = value.parse().unwrap_or(0),
+            "unique_id" => record.unique_id = value.parse().unwrap_or(0),
+            "is_instrument" => record.is_instrument = value == "1",
+            "inputs" => record.inputs = value.parse().unwrap_or(0),
+            "outputs" => record.outputs = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+
+    if record.name.is_empty() {
+        return Ok(ProbeOutcome::Rejected(
+            "probe returned no plugin info".to_owned(),
+        ));
+    }
+    Ok(ProbeOutcome::Found(record))
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf).ok();
+        String::from_utf8_lossy(&buf).into_owned()
+    })
+}
+
+fn collect_plugin_files(path: &Path, files: &mut Vec<PathBuf>) {
+    if path.is_file() {
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(PLUGIN_EXT))
+        {
+            files.push(path.to_path_buf());
+        }
+        return;
+    }
+
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        collect_plugin_files(&entry.path(), files);
+    }
+}
+
+fn modified_secs(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Tabs and newlines would break the line format.
+fn clean(s: &str) -> String {
+    s.replace(['\t', '\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("muek_scan_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("plugin.{}", PLUGIN_EXT)), b"").unwrap();
+        dir
+    }
+
+    #[test]
+    fn missing_probe_is_an_error_not_a_blacklisting() {
+        let dir = plugin_dir("missing_probe");
+        let mut db = PluginDatabase::default();
+
+        let result = db.scan(
+            std::slice::from_ref(&dir),
+            Path::new("/nonexistent/muek_plugin_probe"),
+        );
+
+        assert!(result.is_err());
+        assert!(db.blacklist.is_empty());
+        assert!(db.plugins.is_empty());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_probe_blacklists_the_plugin() {
+        let dir = plugin_dir("failing_probe");
+        let mut db = PluginDatabase::default();
+
+        // `false` runs and exits non-zero, like a probe whose plugin failed to load
+        let report = db
+            .scan(std::slice::from_ref(&dir), Path::new("false"))
+            .unwrap();
+
+        assert_eq!(report.failed, 1);
+        assert_eq!(db.blacklist.len(), 1);
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::bail;
+
+use crate::byte_reader::{ByteReader, Endian};
+
+const MAGIC: &[u8; 4] = b"MKPS";
+const VERSION: u8 = 1;
+
+const KIND_CHUNK: u8 = 0;
+const KIND_PARAMS: u8 = 1;
+
+pub enum PluginStateData {
+    /// opaque chunk returned by the plugin
+    Chunk(Vec<u8>),
+    /// normalized parameter values, for plugins without chunk support
+    Params(Vec<f32>),
+}
+
+pub struct PluginState {
+    pub unique_id: i32,
+    pub program: i32,
+    pub data: PluginStateData,
+}
+
+impl PluginState {
+    /// magic, version, kind, unique id, program, then the chunk or parameter values
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+
+        match &self.data {
+            PluginStateData::Chunk(_) => bytes.push(KIND_CHUNK),
+            PluginStateData::Params(_) => bytes.push(KIND_PARAMS),
+        }
+
+        bytes.extend_from_slice(&self.unique_id.to_le_bytes());
+        bytes.extend_from_slice(&self.program.to_le_bytes());
+
+        match &self.data {
+            PluginStateData::Chunk(chunk) => {
+                bytes.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(chunk);
+            }
+            PluginStateData::Params(values) => {
+                bytes.extend_from_slice(&(values.len() as u32).to_le_bytes());
+                for v in values {
+                    bytes.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = ByteReader::new(bytes, Endian::Little, "plugin state truncated");
+
+        if reader.take(4)? != MAGIC {
+            bail!("not a plugin state");
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            bail!("unsupported plugin state version {}", version);
+        }
+
+        let kind = reader.take(1)?[0];
+        let unique_id = reader.i32()?;
+        let program = reader.i32()?;
+        let len = reader.u32()? as usize;
+
+        let data = match kind {
+            KIND_CHUNK => PluginStateData::Chunk(reader.take(len)?.to_vec()),
+            KIND_PARAMS => PluginStateData::Params(reader.f32s(len)?),
+            _ => bail!("unknown plugin state kind {}", kind),
+        };
+
+        Ok(Self {
+            unique_id,
+            program,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(data: PluginStateData) -> PluginState {
+        PluginState {
+            unique_id: 0x4d75_656b,
+            program: 3,
+            data,
+        }
+    }
+
+    #[test]
+    fn chunk_round_trips() {
+        let bytes = state(PluginStateData::Chunk(vec![1, 2, 3, 255])).to_bytes();
+        let read = PluginState::from_bytes(&bytes).unwrap();
+
+        assert_eq!(read.unique_id, 0x4d75_656b);
+        assert_eq!(read.program, 3);
+        match read.data {
+            PluginStateData::Chunk(chunk) => assert_eq!(chunk, [1, 2, 3, 255]),
+            PluginStateData::Params(_) => panic!("expected a chunk"),
+        }
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let bytes = state(PluginStateData::Params(vec![0.0, 0.25, 1.0])).to_bytes();
+
+        match PluginState::from_bytes(&bytes).unwrap().data {
+            PluginStateData::Params(values) => assert_eq!(values, [0.0, 0.25, 1.0]),
+            PluginStateData::Chunk(_) => panic!("expected parameter values"),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let bytes = state(PluginStateData::Chunk(vec![1])).to_bytes();
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(PluginState::from_bytes(&magic).is_err());
+
+        let mut version = bytes.clone();
+        version[4] = VERSION + 1;
+        assert!(PluginState::from_bytes(&version).is_err());
+
+        let mut kind = bytes;
+        kind[5] = 7;
+        assert!(PluginState::from_bytes(&kind).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = state(PluginStateData::Params(vec![0.5, 0.5])).to_bytes();
+
+        for len in 0..bytes.len() {
+            assert!(PluginState::from_bytes(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_length() {
+        for kind in [KIND_CHUNK, KIND_PARAMS] {
+            let mut bytes = MAGIC.to_vec();
+            bytes.push(VERSION);
+            bytes.push(kind);
+            bytes.extend_from_slice(&0i32.to_le_bytes());
+            bytes.extend_from_slice(&0i32.to_le_bytes());
+            bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+
+            assert!(PluginState::from_bytes(&bytes).is_err());
+        }
+    }
+}
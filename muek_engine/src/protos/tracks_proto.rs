@@ -1,3 +1,5 @@
+use crate::{midi::RecordedMidiEvent, midi_clip::MidiClip};
+
 #[repr(C)]
 pub struct TrackProto {
     pub track_id: i32,
@@ -9,4 +11,51 @@ pub struct ClipProto {
     pub clip_id_len: i32,
     pub start_time: f32,
     pub end_time: f32,
-}
\ No newline at end of file
+    /// Clips without a track id play on a default track.
+    pub track_id: *const u16,
+    pub track_id_len: i32,
+}
+
+#[repr(C)]
+pub struct MidiClipProto {
+    pub start_time: f32,
+    pub end_time: f32,
+    /// Content beat at the start of the clip.
+    pub offset: f32,
+    /// Length of the content repeated through the clip, 0 plays it once.
+    pub loop_length: f32,
+    pub track_id: *const u16,
+    pub track_id_len: i32,
+    /// Messages in beats from the start of the content.
+    pub events: *const RecordedMidiEvent,
+    pub events_len: i32,
+}
+
+impl MidiClipProto {
+    /// The clip and the id of its track.
+    ///
+    /// # Safety
+    /// The track id and events must point to as many elements as their lengths say, or be null.
+    pub unsafe fn to_clip(&self) -> (String, MidiClip) {
+        let track_id = if self.track_id.is_null() {
+            String::new()
+        } else {
+            let slice =
+                unsafe { std::slice::from_raw_parts(self.track_id, self.track_id_len as usize) };
+            String::from_utf16(slice).unwrap()
+        };
+        let events = if self.events.is_null() {
+            Vec::new()
+        } else {
+            unsafe { std::slice::from_raw_parts(self.events, self.events_len as usize) }.to_vec()
+        };
+        let clip = MidiClip::new(
+            self.start_time as f64,
+            self.end_time as f64,
+            self.offset as f64,
+            self.loop_length as f64,
+            events,
+        );
+        (track_id, clip)
+    }
+}
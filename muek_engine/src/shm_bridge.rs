@@ -0,0 +1,243 @@
+// Shared memory layout used between the engine and `muek_plugin_bridge`.
+// Also compiled into the helper binary, so it must not depend on the rest of the crate.
+
+use std::{
+    fs::OpenOptions,
+    marker::PhantomData,
+    path::Path,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use memmap2::MmapMut;
+
+pub const BRIDGE_MAGIC: u32 = 0x4D4B_4252; // "MKBR"
+
+pub const MAX_BLOCK: usize = 4096;
+pub const MAX_CHANNELS: usize = 16;
+pub const MAX_MIDI_EVENTS: usize = 1024;
+
+/// Helper has not reported yet.
+pub const READY_PENDING: u32 = 0;
+pub const READY_OK: u32 = 1;
+pub const READY_FAILED: u32 = 2;
+
+const HEADER_SIZE: usize = 64;
+const RING_HEADER_SIZE: usize = 64;
+
+const CONTROL_CAPACITY: usize = 64;
+const MIDI_CAPACITY: usize = MAX_MIDI_EVENTS * 2;
+const AUDIO_CAPACITY: usize = MAX_BLOCK * MAX_CHANNELS * 2;
+
+/// One block request from the engine.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct BlockRequest {
+    pub frames: u32,
+    pub midi_events: u32,
+    /// the helper re-initializes the plugin when this changes
+    pub sample_rate: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct MidiEventRecord {
+    pub delta_frames: u32,
+    pub data: [u8; 3],
+    pub _pad: u8,
+}
+
+#[repr(C)]
+struct Header {
+    magic: AtomicU32,
+    ready: AtomicU32,
+    in_channels: AtomicU32,
+    out_channels: AtomicU32,
+    initial_delay: AtomicU32,
+    shutdown: AtomicU32,
+}
+
+#[repr(C)]
+struct RingHeader {
+    write: AtomicU64,
+    read: AtomicU64,
+}
+
+/// Single producer, single consumer ring inside the mapping.
+pub struct ShmRing<T: Copy> {
+    header: *const RingHeader,
+    data: *mut T,
+    capacity: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Copy> Send for ShmRing<T> {}
+
+impl<T: Copy> ShmRing<T> {
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.header }
+    }
+
+    pub fn available(&self) -> usize {
+        let h = self.header();
+        (h.write.load(Ordering::Acquire) - h.read.load(Ordering::Acquire)) as usize
+    }
+
+    /// Pushes all items or nothing.
+    pub fn push_slice(&self, items: &[T]) -> bool {
+        let h = self.header();
+        let write = h.write.load(Ordering::Relaxed);
+        let read = h.read.load(Ordering::Acquire);
+        if (write - read) as usize + items.len() > self.capacity {
+            return false;
+        }
+
+        for (i, item) in items.iter().enumerate() {
+            let idx = (write as usize + i) % self.capacity;
+            unsafe { self.data.add(idx).write(*item) };
+        }
+        h.write.store(write + items.len() as u64, Ordering::Release);
+        true
+    }
+
+    /// Pops exactly `out.len()` items, or nothing if not enough are available.
+    pub fn pop_slice(&self, out: &mut [T]) -> bool {
+        let h = self.header();
+        let read = h.read.load(Ordering::Relaxed);
+        let write = h.write.load(Ordering::Acquire);
+        if ((write - read) as usize) < out.len() {
+            return false;
+        }
+
+        for (i, item) in out.iter_mut().enumerate() {
+            let idx = (read as usize + i) % self.capacity;
+            *item = unsafe { self.data.add(idx).read() };
+        }
+        h.read.store(read + out.len() as u64, Ordering::Release);
+        true
+    }
+}
+
+pub struct SharedBridge {
+    _mmap: MmapMut,
+    header: *const Header,
+    /// engine -> helper
+    pub control: ShmRing<BlockRequest>,
+    pub midi_in: ShmRing<MidiEventRecord>,
+    pub audio_in: ShmRing<f32>,
+    /// helper -> engine
+    pub audio_out: ShmRing<f32>,
+    /// MIDI the plugin emitted, queued before the block's audio.
+    pub midi_out: ShmRing<MidiEventRecord>,
+}
+
+unsafe impl Send for SharedBridge {}
+
+impl SharedBridge {
+    fn size() -> usize {
+        HEADER_SIZE
+            + ring_size::<BlockRequest>(CONTROL_CAPACITY)
+            + ring_size::<MidiEventRecord>(MIDI_CAPACITY) * 2
+            + ring_size::<f32>(AUDIO_CAPACITY) * 2
+    }
+
+    /// The engine creates the file, the helper opens it with `create = false`.
+    pub fn open(path: &Path, create: bool) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(create)
+            .open(path)?;
+        if create {
+            file.set_len(Self::size() as u64)?;
+        }
+
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        if mmap.len() < Self::size() {
+            anyhow::bail!("bridge mapping too small");
+        }
+        if create {
+            mmap.fill(0);
+        }
+
+        let base = mmap.as_mut_ptr();
+        let header = base as *const Header;
+
+        let mut offset = HEADER_SIZE;
+        let control = unsafe { ring_at(base, &mut offset, CONTROL_CAPACITY) };
+        let midi_in = unsafe { ring_at(base, &mut offset, MIDI_CAPACITY) };
+        let audio_in = unsafe { ring_at(base, &mut offset, AUDIO_CAPACITY) };
+        let audio_out = unsafe { ring_at(base, &mut offset, AUDIO_CAPACITY) };
+        let midi_out = unsafe { ring_at(base, &mut offset, MIDI_CAPACITY) };
+
+        let bridge = Self {
+            _mmap: mmap,
+            header,
+            control,
+            midi_in,
+            audio_in,
+            audio_out,
+            midi_out,
+        };
+
+        if create {
+            bridge.header().magic.store(BRIDGE_MAGIC, Ordering::Release);
+        } else if bridge.header().magic.load(Ordering::Acquire) != BRIDGE_MAGIC {
+            anyhow::bail!("not a bridge mapping");
+        }
+
+        Ok(bridge)
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    pub fn ready(&self) -> u32 {
+        self.header().ready.load(Ordering::Acquire)
+    }
+
+    /// Called by the helper once the plugin is loaded (or failed to load).
+    pub fn set_ready(&self, ready: u32, in_channels: u32, out_channels: u32, initial_delay: u32) {
+        let h = self.header();
+        h.in_channels.store(in_channels, Ordering::Relaxed);
+        h.out_channels.store(out_channels, Ordering::Relaxed);
+        h.initial_delay.store(initial_delay, Ordering::Relaxed);
+        h.ready.store(ready, Ordering::Release);
+    }
+
+    /// (inputs, outputs, initial delay) reported by the helper.
+    pub fn io(&self) -> (usize, usize, usize) {
+        let h = self.header();
+        (
+            h.in_channels.load(Ordering::Acquire) as usize,
+            h.out_channels.load(Ordering::Acquire) as usize,
+            h.initial_delay.load(Ordering::Acquire) as usize,
+        )
+    }
+
+    pub fn request_shutdown(&self) {
+        self.header().shutdown.store(1, Ordering::Release);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.header().shutdown.load(Ordering::Acquire) != 0
+    }
+}
+
+fn ring_size<T>(capacity: usize) -> usize {
+    let bytes = RING_HEADER_SIZE + capacity * std::mem::size_of::<T>();
+    bytes.div_ceil(64) * 64
+}
+
+unsafe fn ring_at<T: Copy>(base: *mut u8, offset: &mut usize, capacity: usize) -> ShmRing<T> {
+    let header = unsafe { base.add(*offset) } as *const RingHeader;
+    let data = unsafe { base.add(*offset + RING_HEADER_SIZE) } as *mut T;
+    *offset += ring_size::<T>(capacity);
+    ShmRing {
+        header,
+        data,
+        capacity,
+        _marker: PhantomData,
+    }
+}
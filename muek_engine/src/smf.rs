@@ -0,0 +1,488 @@
+// Standard MIDI Files, formats 0 and 1. Reading keeps the channel messages of
+// every track and gathers tempo, time and key signatures and markers into a
+// tempo map, whichever track they are in. Writing always makes a format 1 file
+// whose first track carries the tempo map. Times are engine beats.
+
+use anyhow::{Context, anyhow, bail};
+
+use crate::{
+    byte_reader::{ByteReader, Endian},
+    midi::RecordedMidiEvent,
+    tempo_map::{KeySignature, Marker, TempoChange, TempoMap, TimeSignature},
+};
+
+/// Ticks per quarter note of written files.
+pub const PPQ: u16 = 480;
+/// Tempo of files without tempo events.
+const DEFAULT_BPM: f64 = 120.0;
+
+const META: u8 = 0xff;
+const TRACK_NAME: u8 = 0x03;
+const MARKER: u8 = 0x06;
+const END_OF_TRACK: u8 = 0x2f;
+const TEMPO: u8 = 0x51;
+const TIME_SIGNATURE: u8 = 0x58;
+const KEY_SIGNATURE: u8 = 0x59;
+
+pub struct SmfTrack {
+    pub name: String,
+    /// Channel messages by beat.
+    pub events: Vec<RecordedMidiEvent>,
+}
+
+pub struct Smf {
+    /// Tracks with channel messages; a conductor track only adds to the tempo map.
+    pub tracks: Vec<SmfTrack>,
+    pub tempo_map: TempoMap,
+}
+
+pub fn load(path: &str) -> anyhow::Result<Smf> {
+    let bytes = std::fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    read(&bytes)
+}
+
+pub fn save(path: &str, smf: &Smf) -> anyhow::Result<()> {
+    std::fs::write(path, write(smf)).with_context(|| format!("cannot write {}", path))
+}
+
+/// A variable-length quantity, 7 bits a byte, at most 4 bytes.
+fn number(reader: &mut ByteReader) -> anyhow::Result<u32> {
+    let mut value = 0u32;
+    for _ in 0..4 {
+        let byte = reader.byte()?;
+        value = value << 7 | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("variable-length number too long")
+}
+
+/// The chunks of the file as (id, body).
+fn chunks(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 8 {
+            return None;
+        }
+        let id = data[..4].try_into().unwrap();
+        let len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        let body = &data[8..(8 + len).min(data.len())];
+        data = &data[8 + body.len()..];
+        Some((id, body))
+    })
+}
+
+pub fn read(bytes: &[u8]) -> anyhow::Result<Smf> {
+    let mut chunks = chunks(bytes);
+    let header = match chunks.next() {
+        Some((id, body)) if &id == b"MThd" && body.len() >= 6 => body,
+        _ => bail!("not a Standard MIDI File"),
+    };
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if format > 1 {
+        bail!("SMF format {} is not supported", format);
+    }
+    if division & 0x8000 != 0 || division == 0 {
+        bail!("SMPTE time division is not supported");
+    }
+    let ticks_per_beat = division as f64 * 4.0;
+
+    let mut tracks = Vec::new();
+    let mut tempos = Vec::new();
+    let mut tempo_map = TempoMap::new(DEFAULT_BPM);
+    for (idx, (_, body)) in chunks.filter(|(id, _)| id == b"MTrk").enumerate() {
+        let mut reader = ByteReader::new(body, Endian::Big, "track ends early");
+        let mut tick = 0u64;
+        let mut running = None;
+        let mut name = None;
+        let mut events = Vec::new();
+        while !reader.is_empty() {
+            tick += number(&mut reader)? as u64;
+            let beat = tick as f64 / ticks_per_beat;
+
+            let status = match reader.peek()? {
+                data if data < 0x80 => running.ok_or_else(|| anyhow!("data without a status"))?,
+                _ => reader.byte()?,
+            };
+            match status {
+                META => {
+                    running = None;
+                    let kind = reader.byte()?;
+                    let len = number(&mut reader)? as usize;
+                    let data = reader.take(len)?;
+                    let text = || String::from_utf8_lossy(data).trim().to_owned();
+                    match (kind, data) {
+                        (END_OF_TRACK, _) => break,
+                        (TRACK_NAME, _) if name.is_none() => name = Some(text()),
+                        (MARKER, _) => tempo_map.markers.push(Marker { beat, name: text() }),
+                        (TEMPO, &[a, b, c]) => {
+                            let micros = u32::from_be_bytes([0, a, b, c]).max(1);
+                            tempos.push(TempoChange {
+                                beat,
+                                bpm: 60_000_000.0 / micros as f64,
+                            });
+                        }
+                        (TIME_SIGNATURE, &[numerator, power, ..]) => {
+                            tempo_map.time_signatures.push(TimeSignature {
+                                beat,
+                                numerator,
+                                denominator: 1u8.checked_shl(power as u32).unwrap_or(4),
+                            })
+                        }
+                        (KEY_SIGNATURE, &[sharps, minor]) => {
+                            tempo_map.key_signatures.push(KeySignature {
+                                beat,
+                                sharps: sharps as i8,
+                                minor: minor == 1,
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+                0xf0 | 0xf7 => {
+                    running = None;
+                    let len = number(&mut reader)? as usize;
+                    reader.take(len)?;
+                }
+                0x80..=0xef => {
+                    running = Some(status);
+                    let data1 = reader.byte()?;
+                    // program change and channel pressure carry one data byte
+                    let data2 = match status & 0xf0 {
+                        0xc0 | 0xd0 => 0,
+                        _ => reader.byte()?,
+                    };
+                    events.push(RecordedMidiEvent {
+                        beat: beat as f32,
+                        status,
+                        data1,
+                        data2,
+                    });
+                }
+                _ => bail!("unexpected status {:#04x} in track {}", status, idx),
+            }
+        }
+        if !events.is_empty() {
+            tracks.push(SmfTrack {
+                name: name.unwrap_or_default(),
+                events,
+            });
+        }
+    }
+
+    tempo_map.set_tempos(tempos);
+    let by_beat = |a: &f64, b: &f64| a.total_cmp(b);
+    tempo_map
+        .time_signatures
+        .sort_by(|a, b| by_beat(&a.beat, &b.beat));
+    tempo_map
+        .key_signatures
+        .sort_by(|a, b| by_beat(&a.beat, &b.beat));
+    tempo_map.markers.sort_by(|a, b| by_beat(&a.beat, &b.beat));
+    Ok(Smf { tracks, tempo_map })
+}
+
+fn write_number(out: &mut Vec<u8>, value: u32) {
+    let mut groups = [0u8; 4];
+    let mut len = 0;
+    let mut rest = value & 0x0fff_ffff;
+    loop {
+        groups[len] = (rest & 0x7f) as u8;
+        len += 1;
+        rest >>= 7;
+        if rest == 0 {
+            break;
+        }
+    }
+    for i in (0..len).rev() {
+        out.push(groups[i] | if i > 0 { 0x80 } else { 0 });
+    }
+}
+
+fn meta(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut event = vec![META, kind];
+    write_number(&mut event, data.len() as u32);
+    event.extend_from_slice(data);
+    event
+}
+
+fn tick(beat: f64) -> u64 {
+    (beat.max(0.0) * 4.0 * PPQ as f64).round() as u64
+}
+
+/// An `MTrk` chunk of events at ticks, in the order given on equal ticks.
+fn track_chunk(mut events: Vec<(u64, Vec<u8>)>) -> Vec<u8> {
+    events.sort_by_key(|(tick, _)| *tick);
+    let mut body = Vec::new();
+    let mut last = 0;
+    for (tick, event) in &events {
+        write_number(&mut body, (tick - last) as u32);
+        body.extend_from_slice(event);
+        last = *tick;
+    }
+    body.extend_from_slice(&[0, META, END_OF_TRACK, 0]);
+
+    let mut chunk = b"MTrk".to_vec();
+    chunk.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    chunk.extend(body);
+    chunk
+}
+
+pub fn write(smf: &Smf) -> Vec<u8> {
+    let map = &smf.tempo_map;
+    let mut conductor = Vec::new();
+    for tempo in map.tempos() {
+        let micros = (60_000_000.0 / tempo.bpm)
+            .round()
+            .clamp(1.0, 0xff_ffff as f64) as u32;
+        conductor.push((tick(tempo.beat), meta(TEMPO, &micros.to_be_bytes()[1..])));
+    }
+    for time in &map.time_signatures {
+        let power = time.denominator.max(1).ilog2() as u8;
+        // a metronome click every quarter note, 8 thirty-seconds to the quarter
+        let data = [time.numerator, power, 24, 8];
+        conductor.push((tick(time.beat), meta(TIME_SIGNATURE, &data)));
+    }
+    for key in &map.key_signatures {
+        let data = [key.sharps as u8, key.minor as u8];
+        conductor.push((tick(key.beat), meta(KEY_SIGNATURE, &data)));
+    }
+    for marker in &map.markers {
+        conductor.push((tick(marker.beat), meta(MARKER, marker.name.as_bytes())));
+    }
+
+    let mut out = b"MThd".to_vec();
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&(smf.tracks.len() as u16 + 1).to_be_bytes());
+    out.extend_from_slice(&PPQ.to_be_bytes());
+    out.extend(track_chunk(conductor));
+
+    for track in &smf.tracks {
+        let mut events = Vec::with_capacity(track.events.len() + 1);
+        if !track.name.is_empty() {
+            events.push((0, meta(TRACK_NAME, track.name.as_bytes())));
+        }
+        let mut messages: Vec<&RecordedMidiEvent> = track
+            .events
+            .iter()
+            .filter(|e| (0x80..0xf0).contains(&e.status))
+            .collect();
+        // a note ending where the next one starts is let go first
+        messages.sort_by_key(|e| {
+            let note_on = e.status & 0xf0 == 0x90 && e.data2 > 0;
+            (tick(e.beat as f64), note_on)
+        });
+        for e in messages {
+            let message = match e.status & 0xf0 {
+                0xc0 | 0xd0 => vec![e.status, e.data1 & 0x7f],
+                _ => vec![e.status, e.data1 & 0x7f, e.data2 & 0x7f],
+            };
+            events.push((tick(e.beat as f64), message));
+        }
+        out.extend(track_chunk(events));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(beat: f32, status: u8, data1: u8, data2: u8) -> RecordedMidiEvent {
+        RecordedMidiEvent {
+            beat,
+            status,
+            data1,
+            data2,
+        }
+    }
+
+    fn file(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend_from_slice(&6u32.to_be_bytes());
+        out.extend_from_slice(&format.to_be_bytes());
+        out.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        out.extend_from_slice(&division.to_be_bytes());
+        for body in tracks {
+            out.extend_from_slice(b"MTrk");
+            out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            out.extend_from_slice(body);
+        }
+        out
+    }
+
+    #[test]
+    fn reads_format_0_with_running_status() {
+        // 96 ticks to the quarter note, so 96 ticks are a quarter of an engine beat
+        #[rustfmt::skip]
+        let body: &[u8] = &[
+            0x00, 0xff, 0x03, 4, b'L', b'e', b'a', b'd',
+            0x00, 0xff, 0x51, 3, 0x07, 0xa1, 0x20,
+            0x00, 0xff, 0x58, 4, 3, 2, 24, 8,
+            0x00, 0xff, 0x59, 2, 0xfe, 1,
+            0x00, 0x90, 60, 100,
+            0x00, 64, 80,
+            0x60, 0xb0, 7, 100,
+            0x00, 0xe0, 0x00, 0x40,
+            0x00, 0xff, 0x06, 5, b'V', b'e', b'r', b's', b'e',
+            0x60, 0x80, 60, 0,
+            0x00, 64, 0,
+            0x00, 0xff, 0x51, 3, 0x0f, 0x42, 0x40,
+            0x00, 0xff, 0x2f, 0,
+        ];
+        let smf = read(&file(0, 96, &[body])).unwrap();
+
+        assert_eq!(smf.tracks.len(), 1);
+        assert_eq!(smf.tracks[0].name, "Lead");
+        assert_eq!(
+            smf.tracks[0].events,
+            [
+                event(0.0, 0x90, 60, 100),
+                event(0.0, 0x90, 64, 80),
+                event(0.25, 0xb0, 7, 100),
+                event(0.25, 0xe0, 0x00, 0x40),
+                event(0.5, 0x80, 60, 0),
+                event(0.5, 0x80, 64, 0),
+            ]
+        );
+
+        let map = &smf.tempo_map;
+        assert_eq!(
+            map.tempos(),
+            [
+                TempoChange {
+                    beat: 0.0,
+                    bpm: 120.0
+                },
+                TempoChange {
+                    beat: 0.5,
+                    bpm: 60.0
+                },
+            ]
+        );
+        assert_eq!(
+            map.time_signatures,
+            [TimeSignature {
+                beat: 0.0,
+                numerator: 3,
+                denominator: 4
+            }]
+        );
+        assert_eq!(
+            map.key_signatures,
+            [KeySignature {
+                beat: 0.0,
+                sharps: -2,
+                minor: true
+            }]
+        );
+        assert_eq!(
+            map.markers,
+            [Marker {
+                beat: 0.25,
+                name: "Verse".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn format_1_round_trips() {
+        let mut tempo_map = TempoMap::new(100.0);
+        tempo_map.set_tempos(vec![
+            TempoChange {
+                beat: 0.0,
+                bpm: 100.0,
+            },
+            TempoChange {
+                beat: 2.0,
+                bpm: 150.0,
+            },
+        ]);
+        tempo_map.time_signatures = vec![
+            TimeSignature {
+                beat: 0.0,
+                numerator: 4,
+                denominator: 4,
+            },
+            TimeSignature {
+                beat: 1.0,
+                numerator: 7,
+                denominator: 8,
+            },
+        ];
+        tempo_map.key_signatures = vec![KeySignature {
+            beat: 0.0,
+            sharps: 3,
+            minor: false,
+        }];
+        tempo_map.markers = vec![Marker {
+            beat: 1.5,
+            name: "Chorus".to_owned(),
+        }];
+        let tracks = vec![
+            SmfTrack {
+                name: "Bass".to_owned(),
+                events: vec![
+                    event(0.0, 0x91, 36, 110),
+                    event(0.125, 0xb1, 64, 127),
+                    event(0.25, 0xe1, 0x7f, 0x7f),
+                    event(0.5, 0x81, 36, 0),
+                    event(0.5, 0xc1, 33, 0),
+                ],
+            },
+            SmfTrack {
+                name: "Pad".to_owned(),
+                events: vec![event(1.0, 0x90, 60, 90), event(3.0, 0x80, 60, 0)],
+            },
+        ];
+
+        let bytes = write(&Smf { tracks, tempo_map });
+        assert_eq!(&bytes[8..10], &1u16.to_be_bytes());
+        let smf = read(&bytes).unwrap();
+
+        assert_eq!(smf.tracks.len(), 2);
+        assert_eq!(smf.tracks[0].name, "Bass");
+        assert_eq!(
+            smf.tracks[0].events,
+            [
+                event(0.0, 0x91, 36, 110),
+                event(0.125, 0xb1, 64, 127),
+                event(0.25, 0xe1, 0x7f, 0x7f),
+                event(0.5, 0x81, 36, 0),
+                event(0.5, 0xc1, 33, 0),
+            ]
+        );
+        assert_eq!(smf.tracks[1].name, "Pad");
+        assert_eq!(smf.tracks[1].events.len(), 2);
+
+        let map = &smf.tempo_map;
+        assert_eq!(map.tempos().len(), 2);
+        assert_eq!(map.tempos()[1].beat, 2.0);
+        assert!((map.tempos()[0].bpm - 100.0).abs() < 1e-3);
+        assert!((map.tempos()[1].bpm - 150.0).abs() < 1e-3);
+        assert_eq!(map.time_signatures[1].numerator, 7);
+        assert_eq!(map.time_signatures[1].denominator, 8);
+        assert_eq!(map.key_signatures[0].sharps, 3);
+        assert!(!map.key_signatures[0].minor);
+        assert_eq!(map.markers[0].name, "Chorus");
+        assert_eq!(map.markers[0].beat, 1.5);
+    }
+
+    #[test]
+    fn refuses_malformed_files() {
+        assert!(read(b"RIFF\0\0\0\0").is_err());
+        assert!(read(&file(2, 96, &[])).is_err());
+        // SMPTE division
+        assert!(read(&file(1, 0xe728, &[])).is_err());
+        // the note-on is cut short
+        assert!(read(&file(0, 96, &[&[0x00, 0x90, 60]])).is_err());
+        // running status without a status before it
+        assert!(read(&file(0, 96, &[&[0x00, 60, 100]])).is_err());
+        // a meta event longer than its track
+        assert!(read(&file(0, 96, &[&[0x00, 0xff, 0x06, 10, b'a']])).is_err());
+        // a delta time of five bytes
+        assert!(read(&file(0, 96, &[&[0x80, 0x80, 0x80, 0x80, 0x00]])).is_err());
+    }
+}
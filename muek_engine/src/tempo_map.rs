@@ -0,0 +1,167 @@
+// The song's tempo and meter. Tempo changes are steps at engine beats, so
+// converting between beats and time adds up the constant stretches before.
+// Tempos are in quarter notes per minute while an engine beat spans 4 quarter
+// notes; meter, key and markers only describe the song and don't affect timing.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoChange {
+    pub beat: f64,
+    pub bpm: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeSignature {
+    pub beat: f64,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeySignature {
+    pub beat: f64,
+    /// Sharps when positive, flats when negative.
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Marker {
+    pub beat: f64,
+    pub name: String,
+}
+
+/// A bar of the song, counted from 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bar {
+    pub index: u32,
+    pub start: f64,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+impl Bar {
+    /// Length in engine beats, which are whole notes.
+    pub fn length(&self) -> f64 {
+        self.numerator.max(1) as f64 / self.denominator.max(1) as f64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TempoMap {
+    /// By beat, the first at beat 0.
+    tempos: Vec<TempoChange>,
+    pub time_signatures: Vec<TimeSignature>,
+    pub key_signatures: Vec<KeySignature>,
+    pub markers: Vec<Marker>,
+}
+
+impl TempoMap {
+    pub fn new(bpm: f64) -> Self {
+        Self {
+            tempos: vec![TempoChange { beat: 0.0, bpm }],
+            time_signatures: Vec::new(),
+            key_signatures: Vec::new(),
+            markers: Vec::new(),
+        }
+    }
+
+    pub fn tempos(&self) -> &[TempoChange] {
+        &self.tempos
+    }
+
+    /// Replaces the tempo changes. The first one holds from beat 0 on; without any the tempo stays.
+    pub fn set_tempos(&mut self, mut tempos: Vec<TempoChange>) {
+        tempos.retain(|t| t.bpm > 0.0 && t.beat.is_finite());
+        if tempos.is_empty() {
+            return;
+        }
+        tempos.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+        // the last of several changes on one beat wins
+        tempos.dedup_by(|later, earlier| {
+            let same = later.beat == earlier.beat;
+            if same {
+                earlier.bpm = later.bpm;
+            }
+            same
+        });
+        tempos[0].beat = 0.0;
+        self.tempos = tempos;
+    }
+
+    /// Makes the tempo constant.
+    pub fn set_bpm(&mut self, bpm: f64) {
+        self.tempos.truncate(1);
+        self.tempos[0].bpm = bpm;
+    }
+
+    pub fn bpm_at(&self, beat: f64) -> f64 {
+        let idx = self.tempos.partition_point(|t| t.beat <= beat);
+        self.tempos[idx.saturating_sub(1)].bpm
+    }
+
+    /// Seconds of each stretch of constant tempo, the last one endless.
+    fn stretches(&self) -> impl Iterator<Item = (&TempoChange, f64)> {
+        self.tempos.iter().enumerate().map(|(i, tempo)| {
+            let length = self.tempos.get(i + 1).map_or(f64::INFINITY, |next| {
+                (next.beat - tempo.beat) * 240.0 / tempo.bpm
+            });
+            (tempo, length)
+        })
+    }
+
+    pub fn seconds_at(&self, beat: f64) -> f64 {
+        let mut start = 0.0;
+        for (tempo, length) in self.stretches() {
+            let seconds = (beat - tempo.beat) * 240.0 / tempo.bpm;
+            if seconds < length {
+                return start + seconds;
+            }
+            start += length;
+        }
+        start
+    }
+
+    pub fn beat_at(&self, seconds: f64) -> f64 {
+        let mut start = 0.0;
+        for (tempo, length) in self.stretches() {
+            if seconds - start < length {
+                return tempo.beat + (seconds - start) * tempo.bpm / 240.0;
+            }
+            start += length;
+        }
+        0.0
+    }
+
+    /// The bar playing at a beat, 4/4 before the first time signature. A
+    /// signature coming in mid-bar starts a new bar.
+    pub fn bar_at(&self, beat: f64) -> Bar {
+        let mut bar = Bar {
+            index: 0,
+            start: 0.0,
+            numerator: 4,
+            denominator: 4,
+        };
+        for time in self.time_signatures.iter().take_while(|t| t.beat <= beat) {
+            bar.index += ((time.beat - bar.start) / bar.length()).ceil().max(0.0) as u32;
+            bar.start = time.beat;
+            bar.numerator = time.numerator;
+            bar.denominator = time.denominator;
+        }
+        let bars = ((beat - bar.start) / bar.length()).floor().max(0.0);
+        bar.index += bars as u32;
+        bar.start += bars * bar.length();
+        bar
+    }
+
+    /// Engine beat at an interleaved sample index.
+    pub fn beat_at_sample(&self, sample_idx: u64, sample_rate: u32, channels: u16) -> f64 {
+        let frame = sample_idx / channels.max(1) as u64;
+        self.beat_at(frame as f64 / sample_rate as f64)
+    }
+
+    /// Interleaved sample index of the frame at an engine beat.
+    pub fn sample_at_beat(&self, beat: f64, sample_rate: u32, channels: u16) -> u64 {
+        let frame = (self.seconds_at(beat.max(0.0)) * sample_rate as f64).round() as u64;
+        frame * channels.max(1) as u64
+    }
+}
@@ -1,11 +1,15 @@
 use std::mem::transmute;
 use std::os::raw::c_void;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
 use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
-use vst::host::{Host, PluginInstance, PluginLoader};
-use vst::plugin::Plugin;
+use vst::api;
+use vst::buffer::SendEventBuffer;
+use vst::event::{Event, MidiEvent as VstMidiEvent};
+use vst::host::{Host, HostBuffer, PluginInstance, PluginLoader};
+use vst::plugin::{Info, Plugin, PluginParameters};
 use windows::Win32::UI::WindowsAndMessaging::{
     DispatchMessageW, GetMessageW, MSG, TranslateMessage,
 };
@@ -13,31 +17,295 @@ use winit::dpi::PhysicalSize;
 use winit::event_loop::EventLoop;
 use winit::window::WindowAttributes;
 
-struct HostHandle;
+use crate::audio::EngineState;
+use crate::automation::{AUTOMATION_SUB_BLOCK, Gesture, LaneFeed, PluginLanes};
+use crate::fx_preset::{FxBank, FxBankData, FxPreset, FxProgram, FxProgramData};
+use crate::lazy_states::{
+    AUDIO_ENGINE, AUTOMATION, AUTOMATION_GESTURES, NEXT_PLUGIN_ID, PENDING_PLUGIN_STATES, PLUGINS,
+};
+use crate::midi::MidiEvent;
+use crate::mixer::{BlockContext, Buses, ChannelScratch, Insert};
+use crate::plugin_state::{PluginState, PluginStateData};
+
+struct HostHandle {
+    plugin_id: u32,
+    state: Arc<EngineState>,
+    /// MIDI sent by the plugin during `process`, offsets relative to that call.
+    /// Shared with the `Box`, so reading it never takes the host lock.
+    midi_out: Arc<Mutex<Vec<MidiEvent>>>,
+    /// Set while automation is played into the plugin, whose echoes aren't recorded.
+    replaying: Arc<AtomicBool>,
+    /// Beat of the sub-block last processed, as `f32` bits, which the
+    /// plugin's gestures are recorded at.
+    beat: Arc<AtomicU32>,
+}
 
 const SAMPLE_RATE: usize = 48000;
+const MAX_MIDI_EVENTS: usize = 1024;
 const BLOCK_SIZE: usize = SAMPLE_RATE / 100;
 
+impl HostHandle {
+    fn new(plugin_id: u32) -> Self {
+        let engine = AUDIO_ENGINE.lock().unwrap();
+        let beat = engine.get_position_beat();
+        Self {
+            plugin_id,
+            state: engine.state.clone(),
+            midi_out: Arc::new(Mutex::new(Vec::with_capacity(MAX_MIDI_EVENTS))),
+            replaying: Arc::new(AtomicBool::new(false)),
+            beat: Arc::new(AtomicU32::new(beat.to_bits())),
+        }
+    }
+
+    /// Called from the plugin, often inside `process`, so never waits on a lock.
+    fn gesture(&self, gesture: Gesture) {
+        if AUTOMATION_GESTURES.push(gesture) {
+            self.state.request_gestures();
+        }
+    }
+
+    fn beat(&self) -> f32 {
+        f32::from_bits(self.beat.load(Ordering::Relaxed))
+    }
+}
+
 impl Host for HostHandle {
     fn automate(&self, index: i32, value: f32) {
-        println!("Parameter {} had its value changed to {}", index, value);
+        if self.replaying.load(Ordering::Relaxed) {
+            return;
+        }
+        self.gesture(Gesture::Automate {
+            key: (self.plugin_id, index),
+            value,
+            beat: self.beat(),
+            is_playing: self.state.is_playing.load(Ordering::SeqCst),
+        });
+    }
+    fn begin_edit(&self, index: i32) {
+        self.gesture(Gesture::Begin((self.plugin_id, index)));
     }
-    fn begin_edit(&self, _index: i32) {
-        println!("update_display")
+    fn end_edit(&self, index: i32) {
+        self.gesture(Gesture::End((self.plugin_id, index), self.beat()));
+    }
+    fn process_events(&self, events: &api::Events) {
+        let mut midi_out = self.midi_out.lock().unwrap();
+        for event in events.events() {
+            // called on the audio thread, so the queue never grows
+            if let Event::Midi(midi) = event
+                && midi_out.len() < MAX_MIDI_EVENTS
+            {
+                midi_out.push(MidiEvent::new(midi.delta_frames.max(0) as u32, midi.data));
+            }
+        }
+    }
+}
+
+/// Thread-safe view of a loaded plugin, kept in `PLUGINS` for the FFI side.
+#[derive(Clone)]
+pub struct PluginHandle {
+    pub info: Info,
+    pub params: Arc<dyn PluginParameters>,
+}
+
+impl PluginHandle {
+    /// Chunk of the current program when the plugin supports it, parameter values otherwise.
+    pub fn save_state(&self) -> PluginState {
+        let data = if self.info.preset_chunks {
+            PluginStateData::Chunk(self.params.get_preset_data())
+        } else {
+            PluginStateData::Params(
+                (0..self.info.parameters)
+                    .map(|i| self.params.get_parameter(i))
+                    .collect(),
+            )
+        };
+
+        PluginState {
+            unique_id: self.info.unique_id,
+            program: self.params.get_preset_num(),
+            data,
+        }
+    }
+
+    pub fn load_state(&self, state: &PluginState) -> anyhow::Result<()> {
+        if state.unique_id != self.info.unique_id {
+            anyhow::bail!(
+                "state belongs to plugin {} but '{}' is {}",
+                state.unique_id,
+                self.info.name,
+                self.info.unique_id
+            );
+        }
+
+        // the chunk belongs to the program, so it's selected first
+        self.params.change_preset(state.program);
+
+        match &state.data {
+            PluginStateData::Chunk(chunk) => self.params.load_preset_data(chunk),
+            PluginStateData::Params(values) => {
+                for (i, v) in values
+                    .iter()
+                    .enumerate()
+                    .take(self.info.parameters as usize)
+                {
+                    self.params.set_parameter(i as i32, *v);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The current program as an `.fxp`.
+    pub fn save_fxp(&self) -> FxProgram {
+        let data = if self.info.preset_chunks {
+            FxProgramData::Chunk(self.params.get_preset_data())
+        } else {
+            FxProgramData::Params(
+                (0..self.info.parameters)
+                    .map(|i| self.params.get_parameter(i))
+                    .collect(),
+            )
+        };
+
+        FxProgram {
+            fx_id: self.info.unique_id,
+            fx_version: self.info.version,
+            num_params: self.info.parameters,
+            name: self.params.get_preset_name(self.params.get_preset_num()),
+            data,
+        }
+    }
+
+    /// All programs as an `.fxb`. Without chunk support every program is
+    /// selected in turn to read its values.
+    pub fn save_fxb(&self) -> FxBank {
+        let current_program = self.params.get_preset_num();
+
+        let data = if self.info.preset_chunks {
+            FxBankData::Chunk(self.params.get_bank_data())
+        } else {
+            let programs = (0..self.info.presets)
+                .map(|i| {
+                    self.params.change_preset(i);
+                    self.save_fxp()
+                })
+                .collect();
+            self.params.change_preset(current_program);
+            FxBankData::Programs(programs)
+        };
+
+        FxBank {
+            fx_id: self.info.unique_id,
+            fx_version: self.info.version,
+            num_programs: self.info.presets,
+            current_program,
+            data,
+        }
+    }
+
+    pub fn load_fx_preset(&self, preset: &FxPreset) -> anyhow::Result<()> {
+        if preset.fx_id() != self.info.unique_id {
+            anyhow::bail!(
+                "preset belongs to plugin {} but '{}' is {}",
+                preset.fx_id(),
+                self.info.name,
+                self.info.unique_id
+            );
+        }
+
+        // an opaque chunk only means something to a plugin that reads chunks;
+        // checked up front, so a bank is never applied halfway
+        let has_chunk = match preset {
+            FxPreset::Program(program) => matches!(program.data, FxProgramData::Chunk(_)),
+            FxPreset::Bank(bank) => match &bank.data {
+                FxBankData::Chunk(_) => true,
+                FxBankData::Programs(programs) => programs
+                    .iter()
+                    .any(|p| matches!(p.data, FxProgramData::Chunk(_))),
+            },
+        };
+        if has_chunk && !self.info.preset_chunks {
+            anyhow::bail!(
+                "preset holds chunk data but '{}' doesn't support chunks",
+                self.info.name
+            );
+        }
+
+        match preset {
+            FxPreset::Program(program) => self.load_fx_program(program),
+            FxPreset::Bank(bank) => match &bank.data {
+                FxBankData::Chunk(chunk) => {
+                    self.params.load_bank_data(chunk);
+                    self.params.change_preset(bank.current_program);
+                }
+                FxBankData::Programs(programs) => {
+                    for (i, program) in programs.iter().enumerate() {
+                        self.params.change_preset(i as i32);
+                        self.load_fx_program(program);
+                    }
+                    self.params.change_preset(bank.current_program);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn load_fx_program(&self, program: &FxProgram) {
+        match &program.data {
+            FxProgramData::Chunk(chunk) => self.params.load_preset_data(chunk),
+            FxProgramData::Params(values) => {
+                for (i, v) in values
+                    .iter()
+                    .enumerate()
+                    .take(self.info.parameters as usize)
+                {
+                    self.params.set_parameter(i as i32, *v);
+                }
+            }
+        }
+        self.params.set_preset_name(program.name.clone());
     }
 }
 
 pub struct Box {
+    pub id: u32,
     pub host: Arc<Mutex<HostHandle>>,
     pub plugin: PluginInstance,
     pub loader: PluginLoader<HostHandle>,
+    pub host_buffer: HostBuffer<f32>,
+    pub info: Info,
+    params: Arc<dyn PluginParameters>,
+    send_events: SendEventBuffer,
+    scratch: ChannelScratch,
+    /// One automation sub-block of each channel, bound to the plugin in turn.
+    sub_inputs: Vec<Vec<f32>>,
+    sub_outputs: Vec<Vec<f32>>,
+    midi_out: Arc<Mutex<Vec<MidiEvent>>>,
+    automation: Arc<LaneFeed>,
+    /// Lanes played back, swapped in from `automation` at the start of a block.
+    lanes: std::boxed::Box<PluginLanes>,
+    replaying: Arc<AtomicBool>,
+    beat: Arc<AtomicU32>,
 }
 
+// the host buffer only holds pointers while a block is bound
+unsafe impl Send for Box {}
+
 impl Box {
     pub fn from_path(path: &str) -> Self {
+        Self::from_path_with_id(NEXT_PLUGIN_ID.fetch_add(1, Ordering::SeqCst), path)
+    }
+
+    pub fn from_path_with_id(id: u32, path: &str) -> Self {
         let path = Path::new(path);
 
-        let host: Arc<Mutex<HostHandle>> = Arc::new(Mutex::new(HostHandle));
+        let host_handle = HostHandle::new(id);
+        let replaying = host_handle.replaying.clone();
+        let beat = host_handle.beat.clone();
+        let midi_out = host_handle.midi_out.clone();
+        let host: Arc<Mutex<HostHandle>> = Arc::new(Mutex::new(host_handle));
 
         println!("Loading {}...", path.to_str().unwrap());
 
@@ -46,7 +314,7 @@ impl Box {
             .unwrap_or_else(|e| panic!("Failed to load plugin: {}", e));
 
         // Create an instance of the plugin
-        let plugin = loader.instance().unwrap();
+        let mut plugin = loader.instance().unwrap();
 
         // Get the plugin information
         let info = plugin.get_info();
@@ -68,13 +336,48 @@ impl Box {
             info.initial_delay
         );
 
+        let host_buffer = HostBuffer::new(info.inputs as usize, info.outputs as usize);
+        let params = plugin.get_parameter_object();
+
+        PLUGINS.lock().unwrap().insert(
+            id,
+            PluginHandle {
+                info: info.clone(),
+                params: params.clone(),
+            },
+        );
+
         Box {
+            id,
             host,
             plugin,
             loader,
+            host_buffer,
+            info,
+            params,
+            send_events: SendEventBuffer::new(MAX_MIDI_EVENTS),
+            scratch: ChannelScratch::default(),
+            sub_inputs: vec![Vec::with_capacity(AUTOMATION_SUB_BLOCK); info.inputs.max(0) as usize],
+            sub_outputs: vec![
+                Vec::with_capacity(AUTOMATION_SUB_BLOCK);
+                info.outputs.max(0) as usize
+            ],
+            midi_out,
+            automation: AUTOMATION.lock().unwrap().feed(id),
+            lanes: std::boxed::Box::default(),
+            replaying,
+            beat,
         }
     }
 
+    /// Switches a running plugin to a new rate and maximum block size.
+    pub fn set_stream(&mut self, sample_rate: f32, block_size: i64) {
+        self.plugin.suspend();
+        self.plugin.set_sample_rate(sample_rate);
+        self.plugin.set_block_size(block_size);
+        self.plugin.resume();
+    }
+
     pub fn init(&mut self, sample_rate: f32, block_size: i64) {
         let plugin = &mut self.plugin;
         plugin.init();
@@ -82,11 +385,103 @@ impl Box {
         plugin.set_sample_rate(sample_rate);
         plugin.set_block_size(block_size);
 
+        // state requested before the instance existed
+        let pending = PENDING_PLUGIN_STATES.lock().unwrap().remove(&self.id);
+        if let Some(bytes) = pending {
+            let handle = PLUGINS.lock().unwrap().get(&self.id).cloned();
+            let result = PluginState::from_bytes(&bytes)
+                .and_then(|state| handle.unwrap().load_state(&state));
+            if let Err(e) = result {
+                eprintln!("Failed to restore plugin state: {}", e);
+            }
+        }
+
         plugin.resume();
 
         println!("Initialized instance!");
     }
 
+    /// Processes one block, replaying automation every `AUTOMATION_SUB_BLOCK` samples.
+    /// MIDI the plugin sends back is appended to `midi_out`.
+    pub fn process_block(
+        &mut self,
+        inputs: &[Vec<f32>],
+        outputs: &mut [Vec<f32>],
+        midi_in: &[MidiEvent],
+        midi_out: &mut Vec<MidiEvent>,
+        start_beat: f32,
+        beats_per_sample: f32,
+    ) {
+        let len = outputs.first().map(|o| o.len()).unwrap_or(0);
+        self.automation.update(&mut self.lanes);
+
+        let mut offset = 0;
+        while offset < len {
+            let end = (offset + AUTOMATION_SUB_BLOCK).min(len);
+            let beat = start_beat + offset as f32 * beats_per_sample;
+            self.beat.store(beat.to_bits(), Ordering::Relaxed);
+
+            // the plugin may echo these back through `automate`
+            self.replaying.store(true, Ordering::Relaxed);
+            for (index, value) in self.lanes.values_at(beat) {
+                self.params.set_parameter(index, value);
+            }
+            self.replaying.store(false, Ordering::Relaxed);
+
+            let events = midi_in.iter().filter(|e| {
+                let delta = e.delta_frames as usize;
+                delta >= offset && delta < end
+            });
+            if events.clone().next().is_some() {
+                self.send_events.send_events_to_plugin(
+                    events.map(|e| VstMidiEvent {
+                        data: e.data,
+                        delta_frames: (e.delta_frames as usize - offset) as i32,
+                        live: true,
+                        note_length: None,
+                        note_offset: None,
+                        detune: 0,
+                        note_off_velocity: 0,
+                    }),
+                    &mut self.plugin,
+                );
+            }
+
+            // sized for a sub-block up front, so none of this allocates
+            for (ch, sub) in self.sub_inputs.iter_mut().enumerate() {
+                sub.clear();
+                match inputs.get(ch) {
+                    Some(input) => sub.extend_from_slice(&input[offset..end]),
+                    None => sub.resize(end - offset, 0.0),
+                }
+            }
+            for sub in &mut self.sub_outputs {
+                sub.clear();
+                sub.resize(end - offset, 0.0);
+            }
+            let mut audio_buffer = self
+                .host_buffer
+                .bind(&self.sub_inputs, &mut self.sub_outputs);
+            self.plugin.process(&mut audio_buffer);
+            for (output, sub) in outputs.iter_mut().zip(&self.sub_outputs) {
+                output[offset..end].copy_from_slice(sub);
+            }
+
+            // held by the plugin's own callback, the events go out with the next sub-block
+            if let Ok(mut emitted) = self.midi_out.try_lock() {
+                let room = midi_out.capacity() - midi_out.len();
+                midi_out.extend(
+                    emitted
+                        .drain(..)
+                        .take(room)
+                        .map(|e| MidiEvent::new(e.delta_frames + offset as u32, e.data)),
+                );
+            }
+
+            offset = end;
+        }
+    }
+
     pub fn show_editor(&mut self, event_loop: &EventLoop<(Vec<f32>, Vec<f32>)>) {
         let plugin = &mut self.plugin;
 
@@ -123,10 +518,14 @@ impl Box {
         println!("Opened editor window!");
     }
 
-    pub fn show_editor_with_handle(&mut self, raw_window_handle: *mut c_void) {
-        let plugin = &mut self.plugin;
-
-        let mut editor_view = plugin.get_editor().unwrap();
+    /// Opens the editor into a window owned by the UI and pumps its messages until it closes.
+    /// The editor is taken out first, so the plugin itself stays free for the audio thread.
+    pub fn show_editor_with_handle(plugin: Arc<Mutex<Self>>, raw_window_handle: *mut c_void) {
+        let editor = plugin.lock().unwrap().plugin.get_editor();
+        let Some(mut editor_view) = editor else {
+            eprintln!("Plugin has no editor");
+            return;
+        };
 
         unsafe {
             editor_view.open(transmute(raw_window_handle));
@@ -142,22 +541,85 @@ impl Box {
     }
 }
 
-pub fn verify_vst(path: &str) -> anyhow::Result<String> {
-    let path = Path::new(&path);
+/// A loaded plugin in an insert chain. The editor keeps its own handle on the
+/// plugin, so the audio thread never waits for it: while the plugin is busy
+/// elsewhere the block passes through dry.
+pub struct PluginInsert {
+    id: u32,
+    io: (usize, usize),
+    latency: usize,
+    plugin: Arc<Mutex<Box>>,
+}
+
+impl PluginInsert {
+    pub fn new(plugin: Arc<Mutex<Box>>) -> Self {
+        let (id, io, latency) = {
+            let plugin = plugin.lock().unwrap();
+            (
+                plugin.id,
+                (
+                    plugin.info.inputs.max(0) as usize,
+                    plugin.info.outputs.max(0) as usize,
+                ),
+                plugin.info.initial_delay.max(0) as usize,
+            )
+        };
+        Self {
+            id,
+            io,
+            latency,
+            plugin,
+        }
+    }
+}
 
-    let host: Arc<Mutex<HostHandle>> = Arc::new(Mutex::new(HostHandle));
+impl Insert for PluginInsert {
+    fn id(&self) -> u32 {
+        self.id
+    }
 
-    println!("Loading {}...", path.to_str().unwrap());
+    fn io(&self) -> (usize, usize) {
+        self.io
+    }
 
-    // Load the plugin
-    let mut loader = PluginLoader::load(path, Arc::clone(&host))
-        .unwrap_or_else(|e| panic!("Failed to load plugin: {}", e));
+    fn process(&mut self, buffer: &mut [Vec<f32>], buses: &mut Buses, ctx: &BlockContext) {
+        let Ok(mut plugin) = self.plugin.try_lock() else {
+            for aux in buses.aux_outputs.iter_mut() {
+                aux.fill(0.0);
+            }
+            return;
+        };
 
-    // Create an instance of the plugin
-    let plugin = loader.instance()?;
+        let (ins, outs) = self.io;
+        let mut scratch = std::mem::take(&mut plugin.scratch);
+        scratch.process_in_place(
+            buffer,
+            buses.sidechain,
+            buses.aux_outputs,
+            ins,
+            outs,
+            |ins, outs| {
+                plugin.process_block(
+                    ins,
+                    outs,
+                    buses.midi_in,
+                    buses.midi_out,
+                    ctx.start_beat,
+                    ctx.beats_per_sample,
+                )
+            },
+        );
+        plugin.scratch = scratch;
+    }
 
-    // Get the plugin information
-    let info = plugin.get_info();
+    fn latency(&self) -> usize {
+        self.latency
+    }
+}
 
-    Ok(info.name)
+impl Drop for Box {
+    fn drop(&mut self) {
+        PLUGINS.lock().unwrap().remove(&self.id);
+        AUTOMATION.lock().unwrap().remove_feed(self.id);
+    }
 }
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::mem::transmute;
+use std::sync::{Arc, Mutex};
 
 use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
 use vst::plugin::Plugin;
@@ -9,13 +10,14 @@ use winit::event::WindowEvent;
 use winit::event_loop::ActiveEventLoop;
 use winit::window::{Window, WindowAttributes, WindowId};
 
+use crate::lazy_states::{AUDIO_ENGINE, LOADED_PLUGINS};
 use crate::muek_event::MuekEvent;
 use crate::vst_box;
 
 #[derive(Default)]
 pub struct App {
     windows: HashMap<WindowId, Window>,
-    plugins: Vec<Box<vst_box::Box>>,
+    plugins: Vec<Arc<Mutex<vst_box::Box>>>,
 }
 
 impl ApplicationHandler<MuekEvent> for App {
@@ -24,16 +26,25 @@ impl ApplicationHandler<MuekEvent> for App {
     fn user_event(&mut self, event_loop: &ActiveEventLoop, event: MuekEvent) {
         match event {
             MuekEvent::SendAudioBuffer(_items, _items1) => {}
-            MuekEvent::CreateNewPlugin(path) => {
-                let mut plugin = vst_box::Box::from_path(&path);
-
-                plugin.init(48000.0, 48000 / 100);
+            MuekEvent::CreateNewPlugin(id, path) => {
+                let mut plugin = vst_box::Box::from_path_with_id(id, &path);
+
+                let (sample_rate, block_size) = {
+                    let engine_lock = AUDIO_ENGINE.lock().unwrap();
+                    (
+                        engine_lock.config.sample_rate as f32,
+                        engine_lock.config.buffer_size as i64,
+                    )
+                };
+                plugin.init(sample_rate, block_size);
 
                 let mut editor_view = plugin.plugin.get_editor().unwrap();
 
                 let plugin_name = plugin.plugin.get_info().name;
 
-                self.plugins.push(Box::new(plugin));
+                let plugin = Arc::new(Mutex::new(plugin));
+                LOADED_PLUGINS.lock().unwrap().insert(id, plugin.clone());
+                self.plugins.push(plugin);
 
                 let (width, height) = editor_view.size();
 
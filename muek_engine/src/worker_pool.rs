@@ -0,0 +1,258 @@
+// Helper threads for the audio callback. A job is handed out once per block
+// and run by the caller and every worker at the same time; the caller returns
+// once no worker is inside the job anymore. Nothing here allocates or takes a
+// lock after the pool is built. Workers take on the scheduling priority of the
+// first caller, so the audio thread never waits on a thread ranked below it.
+
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Spins before a worker parks, so back to back blocks find it awake.
+const SPIN_BEFORE_PARK: u32 = 2000;
+
+/// `Shared::priority` before any caller has been seen.
+const NO_PRIORITY: u64 = u64::MAX;
+
+type Job = *const (dyn Fn() + Sync);
+
+struct Shared {
+    job: UnsafeCell<Option<Job>>,
+    running: AtomicBool,
+    generation: AtomicU64,
+    /// Workers currently looking at `job`.
+    active: AtomicUsize,
+    shutdown: AtomicBool,
+    /// Priority of the calling thread, as packed by `priority::current`.
+    priority: AtomicU64,
+}
+
+// `job` is only written while `running` is false and no worker is active
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// One worker less than the machine has cores, the caller is the last one.
+    pub fn new() -> Self {
+        let cores = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self::with_workers(cores.saturating_sub(1).min(7))
+    }
+
+    pub fn with_workers(workers: usize) -> Self {
+        let shared = Arc::new(Shared {
+            job: UnsafeCell::new(None),
+            running: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            priority: AtomicU64::new(NO_PRIORITY),
+        });
+
+        let threads = (0..workers)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("muek-dsp-{}", i))
+                    .spawn(move || worker_loop(&shared, 0))
+                    .expect("failed to spawn dsp worker")
+            })
+            .collect();
+
+        Self { shared, threads }
+    }
+
+    /// Runs `job` on this thread and on every worker, returning once all are out of it.
+    /// The job has to split the work itself and return when nothing is left.
+    pub fn run(&self, job: &(dyn Fn() + Sync)) {
+        if self.threads.is_empty() {
+            job();
+            return;
+        }
+
+        let shared = &*self.shared;
+        if shared.priority.load(Ordering::Relaxed) == NO_PRIORITY
+            && let Some(priority) = priority::current()
+        {
+            shared.priority.store(priority, Ordering::Relaxed);
+        }
+
+        // the pointer only escapes until the wait below, so the lifetime can be erased
+        let ptr: Job = unsafe { std::mem::transmute::<&(dyn Fn() + Sync), Job>(job) };
+        unsafe { *shared.job.get() = Some(ptr) };
+        shared.running.store(true, Ordering::SeqCst);
+        shared.generation.fetch_add(1, Ordering::SeqCst);
+        for t in &self.threads {
+            t.thread().unpark();
+        }
+
+        job();
+
+        shared.running.store(false, Ordering::SeqCst);
+        while shared.active.load(Ordering::SeqCst) > 0 {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        for t in &self.threads {
+            t.thread().unpark();
+        }
+        for t in self.threads.drain(..) {
+            t.join().ok();
+        }
+    }
+}
+
+/// `seen` is the generation at spawn, a worker that starts late still joins the first job.
+fn worker_loop(shared: &Shared, mut seen: u64) {
+    let mut applied = NO_PRIORITY;
+    loop {
+        let mut spins = 0;
+        while shared.generation.load(Ordering::SeqCst) == seen {
+            if shared.shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            if spins < SPIN_BEFORE_PARK {
+                spins += 1;
+                std::hint::spin_loop();
+            } else {
+                thread::park();
+            }
+        }
+        seen = shared.generation.load(Ordering::SeqCst);
+
+        // published before the generation, so it's visible by now
+        let priority = shared.priority.load(Ordering::Relaxed);
+        if priority != applied {
+            applied = priority;
+            // without the rights to do so the worker keeps its priority
+            priority::apply(priority);
+        }
+
+        // registering before checking `running` means the caller waits for us
+        shared.active.fetch_add(1, Ordering::SeqCst);
+        if shared.running.load(Ordering::SeqCst)
+            && let Some(job) = unsafe { *shared.job.get() }
+        {
+            unsafe { (*job)() };
+        }
+        shared.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(unix)]
+mod priority {
+    /// Policy in the high half, priority in the low half.
+    pub fn current() -> Option<u64> {
+        let mut policy = 0;
+        let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+        let err =
+            unsafe { libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param) };
+        (err == 0).then_some((policy as u32 as u64) << 32 | param.sched_priority as u32 as u64)
+    }
+
+    pub fn apply(priority: u64) -> bool {
+        let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+        param.sched_priority = priority as u32 as i32;
+        let policy = (priority >> 32) as i32;
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) == 0 }
+    }
+}
+
+#[cfg(windows)]
+mod priority {
+    use windows::Win32::System::Threading::{
+        GetCurrentThread, GetThreadPriority, SetThreadPriority, THREAD_PRIORITY,
+        THREAD_PRIORITY_ERROR_RETURN,
+    };
+
+    pub fn current() -> Option<u64> {
+        let priority = unsafe { GetThreadPriority(GetCurrentThread()) };
+        (priority != THREAD_PRIORITY_ERROR_RETURN as i32).then_some(priority as u32 as u64)
+    }
+
+    pub fn apply(priority: u64) -> bool {
+        unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY(priority as u32 as i32)) }
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    /// Runs a job that every thread of the pool takes part in, returning
+    /// what each of them saw.
+    fn run_on_all<T: Send>(pool: &WorkerPool, workers: usize, f: impl Fn() -> T + Sync) -> Vec<T> {
+        let seen = Mutex::new(Vec::new());
+        let started = Instant::now();
+        pool.run(&|| {
+            let mut guard = seen.lock().unwrap();
+            guard.push(f());
+            drop(guard);
+            // hold everyone in the job until the last worker has joined
+            while seen.lock().unwrap().len() < workers + 1
+                && started.elapsed() < Duration::from_secs(5)
+            {
+                thread::yield_now();
+            }
+        });
+        seen.into_inner().unwrap()
+    }
+
+    #[test]
+    fn job_runs_on_every_worker() {
+        let pool = WorkerPool::with_workers(3);
+        for _ in 0..10 {
+            let threads = run_on_all(&pool, 3, || thread::current().id());
+            assert_eq!(threads.len(), 4);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn workers_adopt_the_callers_priority() {
+        thread::spawn(|| {
+            // unlike a real-time policy, batch needs no rights to switch to
+            let param: libc::sched_param = unsafe { std::mem::zeroed() };
+            let err = unsafe {
+                libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_BATCH, &param)
+            };
+            assert_eq!(err, 0);
+            let caller = priority::current().unwrap();
+
+            let pool = WorkerPool::with_workers(2);
+            let seen = run_on_all(&pool, 2, priority::current);
+            assert_eq!(seen.len(), 3);
+            assert!(seen.iter().all(|p| *p == Some(caller)));
+        })
+        .join()
+        .unwrap();
+    }
+}